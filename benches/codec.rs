@@ -0,0 +1,97 @@
+use bytes::{Bytes, BytesMut};
+use criterion::{BatchSize, BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use softpaw::{
+    codec::{Direction, Packet, PacketCodec},
+    mac::MacAlgorithm,
+};
+use tokio_util::codec::{Decoder, Encoder};
+
+const PAYLOAD_SIZES: [usize; 3] = [64, 1024, 32 * 1024];
+
+/// MACs to run with, `None` being plain packets
+const MACS: [Option<&str>; 2] = [None, Some("hmac-sha2-256")];
+
+fn codec(direction: Direction, mac: Option<&str>) -> PacketCodec {
+    let mut codec = PacketCodec::new(64 * 1024, 0);
+
+    if let Some(mac) = mac.and_then(MacAlgorithm::from_name) {
+        codec
+            .set_mac(direction, mac, &vec![0x42; mac.key_length()])
+            .unwrap();
+    }
+
+    codec
+}
+
+fn encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode");
+
+    for mac in MACS {
+        for size in PAYLOAD_SIZES {
+            let payload = Bytes::from(vec![0xab; size]);
+            let mut codec = codec(Direction::Outgoing, mac);
+            let mut dst = BytesMut::with_capacity(2 * size + 128);
+
+            group.throughput(Throughput::Bytes(size as u64));
+            group.bench_with_input(
+                BenchmarkId::new(mac.unwrap_or("none"), size),
+                &payload,
+                |b, payload| {
+                    b.iter(|| {
+                        dst.clear();
+                        codec
+                            .encode(
+                                Packet {
+                                    payload: payload.clone(),
+                                    mac: None,
+                                },
+                                &mut dst,
+                            )
+                            .unwrap();
+                    })
+                },
+            );
+        }
+    }
+
+    group.finish();
+}
+
+fn decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode");
+
+    for mac in MACS {
+        for size in PAYLOAD_SIZES {
+            let mut wire = BytesMut::new();
+            codec(Direction::Outgoing, mac)
+                .encode(
+                    Packet {
+                        payload: Bytes::from(vec![0xab; size]),
+                        mac: None,
+                    },
+                    &mut wire,
+                )
+                .unwrap();
+
+            group.throughput(Throughput::Bytes(size as u64));
+            group.bench_with_input(
+                BenchmarkId::new(mac.unwrap_or("none"), size),
+                &wire,
+                |b, wire| {
+                    // A fresh codec every time, the MAC covers the sequence
+                    // number
+                    b.iter_batched(
+                        || (codec(Direction::Incoming, mac), wire.clone()),
+                        |(mut codec, mut src)| codec.decode(&mut src).unwrap().unwrap(),
+                        BatchSize::SmallInput,
+                    )
+                },
+            );
+        }
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, encode, decode);
+criterion_main!(benches);
@@ -0,0 +1,52 @@
+#![no_main]
+
+use arbitrary::Arbitrary;
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use softpaw::codec::PacketCodec;
+use tokio_util::codec::Decoder;
+
+#[derive(Debug, Arbitrary)]
+struct Input {
+    max_packet_size: u16,
+    mac_length: u8,
+    /// Sizes of the reads the data arrives in
+    chunks: Vec<u8>,
+    data: Vec<u8>,
+}
+
+fuzz_target!(|input: Input| {
+    let max_packet_size = input.max_packet_size as usize;
+    let mac_length = input.mac_length as usize;
+    // Limits below the smallest frame, 16 bytes and the MAC without a
+    // cipher, are raised to it
+    let packet_size_limit = max_packet_size.max(16 + mac_length);
+
+    let mut codec = PacketCodec::new(max_packet_size, mac_length);
+    let mut buf = BytesMut::new();
+    let mut data = &input.data[..];
+    let mut chunks = input.chunks.iter();
+
+    while !data.is_empty() {
+        // Once the chunk sizes run out, the rest arrives at once
+        let n = match chunks.next() {
+            Some(&n) => (n as usize).max(1).min(data.len()),
+            None => data.len(),
+        };
+
+        buf.extend_from_slice(&data[..n]);
+        data = &data[n..];
+
+        loop {
+            match codec.decode(&mut buf) {
+                Ok(Some(packet)) => {
+                    assert!(packet.payload.len() + 5 + mac_length <= packet_size_limit);
+                    assert_eq!(packet.mac.map_or(0, |mac| mac.len()), mac_length);
+                }
+                Ok(None) => break,
+                // The stream is unusable after an error
+                Err(_) => return,
+            }
+        }
+    }
+});
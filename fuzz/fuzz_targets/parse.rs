@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use softpaw::message::Message;
+
+// Any payload must either parse or fail with an error, never panic
+fuzz_target!(|data: &[u8]| {
+    let mut src = data;
+    let _ = Message::parse(&mut src);
+});
@@ -0,0 +1,232 @@
+use std::{fmt, fs, io, path::Path};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+
+use crate::{
+    hostkey::{HostKeyError, PublicKey},
+    tracing::debug,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum AuthorizedKeyError {
+    #[error("No key in authorized_keys line")]
+    MissingKey,
+    #[error("Unterminated quote in key options")]
+    UnterminatedQuote,
+    #[error("Invalid base64 in key")]
+    Base64(#[from] base64::DecodeError),
+    #[error(transparent)]
+    Key(#[from] HostKeyError),
+    #[error("Key type {name} doesn't match the {key} key")]
+    TypeMismatch { name: String, key: &'static str },
+}
+
+/// A line of an OpenSSH `authorized_keys` file, see the AUTHORIZED_KEYS FILE
+/// FORMAT section of sshd(8).
+///
+/// Options are kept as written but not interpreted, that is up to the
+/// server using them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthorizedKey {
+    /// Comma separated options before the key, e.g. `no-pty` or
+    /// `command="uptime"` with quotes and escapes left in
+    pub options: Vec<String>,
+    pub key: PublicKey,
+    /// Anything after the key, usually `user@host`
+    pub comment: String,
+}
+
+impl PublicKey {
+    /// Parses a `[options] keytype base64 [comment]` line of an
+    /// `authorized_keys` file
+    pub fn from_authorized_keys_line(line: &str) -> Result<AuthorizedKey, AuthorizedKeyError> {
+        let line = line.trim();
+
+        // Like sshd, a line that doesn't start with a key starts with options
+        let error = match parse_key(line) {
+            Ok((key, comment)) => {
+                return Ok(AuthorizedKey {
+                    options: Vec::new(),
+                    key,
+                    comment,
+                });
+            }
+            Err(e) => e,
+        };
+
+        let parsed =
+            split_options(line).and_then(|(options, rest)| Ok((options, parse_key(rest)?)));
+
+        // Nothing after the would-be options, so the key itself was wrong
+        let (options, (key, comment)) = match parsed {
+            Err(AuthorizedKeyError::MissingKey) => return Err(error),
+            parsed => parsed?,
+        };
+
+        Ok(AuthorizedKey {
+            options,
+            key,
+            comment,
+        })
+    }
+}
+
+impl fmt::Display for AuthorizedKey {
+    /// Formats the line back, without any changes to options or comment
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.options.is_empty() {
+            write!(f, "{} ", self.options.join(","))?;
+        }
+
+        write!(
+            f,
+            "{} {}",
+            self.key.key_type(),
+            STANDARD.encode(self.key.to_blob())
+        )?;
+
+        if !self.comment.is_empty() {
+            write!(f, " {}", self.comment)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The keys of an OpenSSH `authorized_keys` file.
+///
+/// Lines that can't be used, such as keys of types this crate doesn't know,
+/// are skipped.
+#[derive(Debug, Clone, Default)]
+pub struct AuthorizedKeys {
+    keys: Vec<AuthorizedKey>,
+}
+
+impl AuthorizedKeys {
+    pub fn parse(contents: &str) -> Self {
+        let keys = contents
+            .lines()
+            .filter_map(|line| {
+                let line = line.trim();
+
+                if line.is_empty() || line.starts_with('#') {
+                    return None;
+                }
+
+                let key = PublicKey::from_authorized_keys_line(line);
+                if let Err(e) = &key {
+                    debug!(?line, error = %e, "Skipping authorized_keys line");
+                }
+
+                key.ok()
+            })
+            .collect();
+
+        Self { keys }
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self::parse(&fs::read_to_string(path)?))
+    }
+
+    /// The entry for `key`, whose options then apply
+    pub fn find(&self, key: &PublicKey) -> Option<&AuthorizedKey> {
+        self.keys.iter().find(|entry| entry.key == *key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &AuthorizedKey> {
+        self.keys.iter()
+    }
+}
+
+/// Parses `keytype base64 [comment]`, the comment being the rest of the line
+fn parse_key(line: &str) -> Result<(PublicKey, String), AuthorizedKeyError> {
+    let mut fields = line.splitn(3, [' ', '\t']);
+
+    let name = fields.next().filter(|name| !name.is_empty());
+    let (Some(name), Some(blob)) = (name, fields.next()) else {
+        return Err(AuthorizedKeyError::MissingKey);
+    };
+
+    let key = PublicKey::parse(&STANDARD.decode(blob)?)?;
+    if key.key_type() != name {
+        return Err(AuthorizedKeyError::TypeMismatch {
+            name: name.to_owned(),
+            key: key.key_type(),
+        });
+    }
+
+    let comment = fields.next().unwrap_or_default().trim().to_owned();
+
+    Ok((key, comment))
+}
+
+/// Splits the options off the front of `line`, up to the first whitespace
+/// outside of double quotes. A backslash escapes a quote inside quotes.
+fn split_options(line: &str) -> Result<(Vec<String>, &str), AuthorizedKeyError> {
+    let mut options = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                options.push(line[start..i].to_owned());
+                start = i + 1;
+            }
+            ' ' | '\t' if !quoted => {
+                options.push(line[start..i].to_owned());
+
+                return Ok((options, line[i..].trim_start()));
+            }
+            _ => {}
+        }
+    }
+
+    match quoted {
+        true => Err(AuthorizedKeyError::UnterminatedQuote),
+        false => Err(AuthorizedKeyError::MissingKey),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RSA: &str = include_str!("../tests/data/id_rsa.pub");
+
+    #[test]
+    fn real_entry() {
+        let line = format!(r#"no-pty,command="echo \"hi\"" {}"#, RSA.trim());
+        let entry = PublicKey::from_authorized_keys_line(&line).unwrap();
+
+        assert_eq!(entry.options, ["no-pty", r#"command="echo \"hi\"""#]);
+        assert_eq!(entry.key.key_type(), "ssh-rsa");
+        assert_eq!(entry.comment, "paw@example.com");
+        assert_eq!(
+            entry.key.fingerprint_sha256(),
+            "SHA256:fMjS73L1a7sVoz0l99lt/e5e0Kc63c0lIvCKzSeJjqw"
+        );
+        assert_eq!(entry.to_string(), line);
+
+        // Without options the line is the .pub file as ssh-keygen wrote it
+        let entry = PublicKey::from_authorized_keys_line(RSA).unwrap();
+        assert!(entry.options.is_empty());
+        assert_eq!(entry.to_string(), RSA.trim());
+    }
+
+    #[test]
+    fn skipped_lines() {
+        let contents = format!("# comment\n\nssh-ed25519 AAAA not-base64!\n{RSA}");
+        let keys = AuthorizedKeys::parse(&contents);
+
+        assert_eq!(keys.iter().count(), 1);
+
+        let key = PublicKey::from_authorized_keys_line(RSA).unwrap().key;
+        assert_eq!(keys.find(&key).unwrap().comment, "paw@example.com");
+    }
+}
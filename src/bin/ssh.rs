@@ -1,59 +1,38 @@
 use anyhow::Result;
-use futures_util::SinkExt;
-use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
-    net::TcpStream,
-};
-use tokio_stream::StreamExt;
-use tokio_util::codec::Framed;
 use tracing::debug;
 
 use softpaw::{
-    codec::{Packet, PacketCodec},
-    message::{Disconnect, Message, ReasonCode},
+    client::{Client, ClientConfig},
+    hostkey::{HostKey, VerifyError},
+    message::ReasonCode,
 };
 
-// SSH-protoversion-softwareversion SP comments CR LF
-const VERSION: &str = "SSH-2.0-softpaw_0.1.0 \r\n";
-
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
 
-    let mut stream = BufReader::new(TcpStream::connect(("0.0.0.0", 2222)).await?);
-    stream.write_all(VERSION.as_bytes()).await?;
-
-    let mut server_version = String::new();
-    stream.read_line(&mut server_version).await?;
-
-    let server_version = server_version
-        .trim_start_matches("SSH-2.0-")
-        .split_whitespace()
-        .next()
-        .unwrap();
-
-    debug!("Connected to server {server_version}");
-
-    let mut framed = Framed::new(stream, PacketCodec::new(35 * 1000, 0));
-
-    while let Some(mut packet) = framed.try_next().await? {
-        let message = Message::parse(&mut packet.payload)?;
-
-        let disconnect = Disconnect {
-            reason_code: ReasonCode::ByApplication,
-            description: "baibai >~<".to_owned(),
-            language_tag: String::new(),
-        };
-
-        framed
-            .send(Packet {
-                payload: disconnect.into_payload(),
-                mac: None,
-            })
-            .await?;
-
-        break;
-    }
+    // Trust on every use, there is no known_hosts handling here
+    let verifier = |hostname: &str, key: &HostKey| -> Result<(), VerifyError> {
+        debug!(
+            hostname,
+            key_type = key.key_type(),
+            fingerprint = %key.fingerprint_sha256(),
+            "Accepting host key"
+        );
+        Ok(())
+    };
+
+    let mut client = Client::connect("0.0.0.0", 2222, &ClientConfig::default(), &verifier).await?;
+    let transport = client.transport_mut();
+
+    debug!(
+        software_version = %transport.remote_id().version.software_version,
+        "Connected to server"
+    );
+
+    transport
+        .disconnect(ReasonCode::ByApplication, "baibai >~<")
+        .await?;
 
     Ok(())
 }
@@ -0,0 +1,276 @@
+mod mux;
+
+pub use mux::{Channel, ChannelMux, ExitSignal, MuxError, TerminalSize};
+
+use crate::message::{ChannelData, ChannelExtendedData, ChannelWindowAdjust};
+
+#[derive(Debug, thiserror::Error)]
+pub enum WindowError {
+    #[error("Channel window would exceed 2^32 - 1 bytes")]
+    Overflow,
+}
+
+/// Flow control window of one direction of a channel, see RFC 4254 section
+/// 5.2
+///
+/// It starts at the initial window size given when the channel was opened,
+/// shrinks as data is sent and grows again with every window adjust.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Window {
+    size: u32,
+}
+
+impl Window {
+    pub fn new(initial_size: u32) -> Self {
+        Self { size: initial_size }
+    }
+
+    /// Bytes that may still be sent before the window has to be adjusted
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        self.size == 0
+    }
+
+    /// Takes `len` bytes out of the window, stopping at zero
+    pub fn consume(&mut self, len: u32) {
+        self.size = self.size.saturating_sub(len);
+    }
+
+    /// Takes the data of an outgoing SSH_MSG_CHANNEL_DATA out of the window
+    pub fn consume_data(&mut self, data: &ChannelData) {
+        self.consume(data.window_length());
+    }
+
+    /// Takes the data of an outgoing SSH_MSG_CHANNEL_EXTENDED_DATA out of
+    /// the window
+    pub fn consume_extended_data(&mut self, data: &ChannelExtendedData) {
+        self.consume(data.window_length());
+    }
+
+    /// Grows the window by `bytes_to_add`.
+    ///
+    /// RFC 4254 caps the window at 2^32 - 1 bytes, an adjust going past that
+    /// is an error and leaves the window unchanged.
+    pub fn adjust(&mut self, bytes_to_add: u32) -> Result<(), WindowError> {
+        self.size = self
+            .size
+            .checked_add(bytes_to_add)
+            .ok_or(WindowError::Overflow)?;
+
+        Ok(())
+    }
+
+    /// Applies an incoming SSH_MSG_CHANNEL_WINDOW_ADJUST
+    pub fn apply_adjust(&mut self, adjust: &ChannelWindowAdjust) -> Result<(), WindowError> {
+        self.adjust(adjust.bytes_to_add)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum StateError {
+    #[error("{event:?} is not allowed in channel state {state:?}")]
+    IllegalTransition {
+        state: ChannelState,
+        event: ChannelEvent,
+    },
+}
+
+/// Where a channel is in its lifecycle, see RFC 4254 section 5.3
+///
+/// Each side sends SSH_MSG_CHANNEL_EOF once it has no more data, and
+/// SSH_MSG_CHANNEL_CLOSE to tear the channel down. The channel only goes
+/// away, freeing its id, once a close has been both sent and received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelState {
+    Open,
+    /// We sent EOF, the peer may still send data
+    EofSent,
+    /// The peer sent EOF, we may still send data
+    EofReceived,
+    /// Both sides sent EOF, no more data either way
+    EofBoth,
+    /// We sent a close and wait for the peer's
+    CloseSent,
+    /// The peer sent a close, which we have to answer with ours
+    CloseReceived,
+    Closed,
+}
+
+/// Something happening on a channel that may change its [`ChannelState`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelEvent {
+    /// Sending SSH_MSG_CHANNEL_DATA or SSH_MSG_CHANNEL_EXTENDED_DATA
+    SendData,
+    /// Receiving SSH_MSG_CHANNEL_DATA or SSH_MSG_CHANNEL_EXTENDED_DATA
+    ReceiveData,
+    SendEof,
+    ReceiveEof,
+    SendClose,
+    ReceiveClose,
+}
+
+impl ChannelState {
+    /// Moves to the state after `event`.
+    ///
+    /// Data after an EOF or close from the same side, a second EOF or close
+    /// and anything at all once closed are errors.
+    pub fn transition(self, event: ChannelEvent) -> Result<Self, StateError> {
+        use ChannelEvent::*;
+        use ChannelState::*;
+
+        let next = match (self, event) {
+            (Open | EofReceived, SendData) | (Open | EofSent, ReceiveData) => self,
+
+            (Open, SendEof) => EofSent,
+            (EofReceived, SendEof) => EofBoth,
+            (Open, ReceiveEof) => EofReceived,
+            (EofSent, ReceiveEof) => EofBoth,
+
+            (Open | EofSent | EofReceived | EofBoth, SendClose) => CloseSent,
+            (Open | EofSent | EofReceived | EofBoth, ReceiveClose) => CloseReceived,
+            (CloseReceived, SendClose) | (CloseSent, ReceiveClose) => Closed,
+
+            // The peer may still be sending what it had queued before
+            // seeing our close
+            (CloseSent, ReceiveData | ReceiveEof) => self,
+
+            _ => return Err(StateError::IllegalTransition { state: self, event }),
+        };
+
+        Ok(next)
+    }
+
+    /// Whether data may still be sent
+    pub fn can_send(&self) -> bool {
+        matches!(self, ChannelState::Open | ChannelState::EofReceived)
+    }
+
+    /// Whether both sides closed the channel, so its id can be reused
+    pub fn is_closed(&self) -> bool {
+        matches!(self, ChannelState::Closed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+
+    #[test]
+    fn window_consumed_to_zero() {
+        let mut window = Window::new(10);
+
+        window.consume(4);
+        assert_eq!(window.size(), 6);
+        assert!(!window.is_exhausted());
+
+        // Going past zero stops there
+        window.consume(7);
+        assert_eq!(window.size(), 0);
+        assert!(window.is_exhausted());
+
+        window.adjust(5).unwrap();
+        assert_eq!(window.size(), 5);
+    }
+
+    #[test]
+    fn window_length() {
+        let data = ChannelData {
+            recipient_channel: 0,
+            data: Bytes::from_static(b"hello"),
+        };
+        let stderr = ChannelExtendedData {
+            recipient_channel: 0,
+            data_type_code: ChannelExtendedData::STDERR,
+            data: Bytes::from_static(b"oops"),
+        };
+
+        // Only the data counts, not the header or the string length
+        assert_eq!(data.window_length(), 5);
+        assert_eq!(stderr.window_length(), 4);
+
+        let mut window = Window::new(10);
+        window.consume_data(&data);
+        window.consume_extended_data(&stderr);
+        assert_eq!(window.size(), 1);
+    }
+
+    #[test]
+    fn window_adjust_overflow() {
+        let mut window = Window::new(u32::MAX - 1);
+
+        window.adjust(1).unwrap();
+        assert_eq!(window.size(), u32::MAX);
+
+        assert!(matches!(window.adjust(1), Err(WindowError::Overflow)));
+        assert_eq!(window.size(), u32::MAX);
+
+        let mut window = Window::new(10);
+        let adjust = ChannelWindowAdjust {
+            recipient_channel: 0,
+            bytes_to_add: u32::MAX,
+        };
+
+        assert!(matches!(
+            window.apply_adjust(&adjust),
+            Err(WindowError::Overflow)
+        ));
+        assert_eq!(window.size(), 10);
+    }
+
+    #[test]
+    fn normal_close() {
+        use ChannelEvent::*;
+
+        let mut state = ChannelState::Open;
+
+        for (event, expected) in [
+            (SendData, ChannelState::Open),
+            (ReceiveEof, ChannelState::EofReceived),
+            (SendData, ChannelState::EofReceived),
+            (SendEof, ChannelState::EofBoth),
+            (ReceiveClose, ChannelState::CloseReceived),
+            (SendClose, ChannelState::Closed),
+        ] {
+            assert!(!state.is_closed());
+            state = state.transition(event).unwrap();
+            assert_eq!(state, expected, "after {:?}", event);
+        }
+
+        assert!(state.is_closed());
+        assert!(!state.can_send());
+    }
+
+    #[test]
+    fn data_after_close() {
+        use ChannelEvent::*;
+
+        let state = ChannelState::Open.transition(SendClose).unwrap();
+
+        // The peer may not have seen our close yet
+        let state = state.transition(ReceiveData).unwrap();
+        assert_eq!(state, ChannelState::CloseSent);
+        assert!(matches!(
+            state.transition(SendData),
+            Err(StateError::IllegalTransition {
+                state: ChannelState::CloseSent,
+                event: SendData,
+            })
+        ));
+
+        let state = state.transition(ReceiveClose).unwrap();
+        assert!(state.is_closed());
+
+        for event in [SendData, ReceiveData, SendEof, SendClose, ReceiveClose] {
+            assert!(state.transition(event).is_err(), "{:?}", event);
+        }
+
+        // Nor after the peer's EOF
+        let state = ChannelState::Open.transition(ReceiveEof).unwrap();
+        assert!(state.transition(ReceiveData).is_err());
+    }
+}
@@ -0,0 +1,732 @@
+use std::collections::HashMap;
+
+use bytes::Bytes;
+
+use super::{ChannelEvent, ChannelState, StateError, Window, WindowError};
+use crate::message::{
+    ChannelClose, ChannelData, ChannelEof, ChannelExtendedData, ChannelFailure, ChannelOpen,
+    ChannelOpenConfirmation, ChannelOpenType, ChannelRequest, ChannelRequestType, ChannelSuccess,
+    ChannelWindowAdjust, Message,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum MuxError {
+    #[error("No channel with id {0}")]
+    UnknownChannel(u32),
+    #[error("No more free channel ids")]
+    NoFreeChannel,
+    #[error("Channel {channel} received {len} bytes with only {window} left in its window")]
+    WindowExceeded {
+        channel: u32,
+        len: usize,
+        window: u32,
+    },
+    #[error("Channel {channel} can't send {len} bytes, at most {limit} fit")]
+    DataTooLarge {
+        channel: u32,
+        len: usize,
+        limit: u32,
+    },
+    #[error(transparent)]
+    Window(#[from] WindowError),
+    #[error(transparent)]
+    State(#[from] StateError),
+}
+
+/// One side of a channel as seen by a [`ChannelMux`]
+#[derive(Debug, Clone)]
+pub struct Channel {
+    local_id: u32,
+    remote_id: u32,
+    state: ChannelState,
+    /// How much the peer may still send us
+    local_window: Window,
+    /// How much we may still send the peer
+    remote_window: Window,
+    /// Largest data the peer accepts in one message
+    remote_maximum_packet_size: u32,
+    exit_status: Option<u32>,
+    exit_signal: Option<ExitSignal>,
+    /// From `env` requests, in the order received
+    env: Vec<(String, String)>,
+    terminal_size: Option<TerminalSize>,
+}
+
+/// A signal that ended the command of a session channel, from an
+/// `exit-signal` request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExitSignal {
+    /// Name without the `SIG` prefix, e.g. `KILL`
+    pub signal_name: String,
+    pub core_dumped: bool,
+    pub error_message: String,
+}
+
+/// Size of the terminal of a session channel, from `pty-req` and
+/// `window-change` requests.
+///
+/// Pixel dimensions are 0 when the client doesn't know them, the character
+/// ones take precedence where both are set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TerminalSize {
+    pub width_chars: u32,
+    pub height_rows: u32,
+    pub width_pixels: u32,
+    pub height_pixels: u32,
+}
+
+impl Channel {
+    pub fn local_id(&self) -> u32 {
+        self.local_id
+    }
+
+    /// The peer's id for the channel, used as recipient in everything sent
+    pub fn remote_id(&self) -> u32 {
+        self.remote_id
+    }
+
+    pub fn state(&self) -> ChannelState {
+        self.state
+    }
+
+    pub fn local_window(&self) -> Window {
+        self.local_window
+    }
+
+    pub fn remote_window(&self) -> Window {
+        self.remote_window
+    }
+
+    /// Most bytes of data that can be sent right now, limited by both the
+    /// peer's window and its maximum packet size
+    pub fn sendable(&self) -> u32 {
+        self.remote_window
+            .size()
+            .min(self.remote_maximum_packet_size)
+    }
+
+    /// Exit status of the command run on a session channel.
+    ///
+    /// The peer sends it before closing the channel, so it's known by the
+    /// time its close is received. `None` if the command hasn't exited yet,
+    /// was killed by a signal or the peer never said.
+    pub fn exit_status(&self) -> Option<u32> {
+        self.exit_status
+    }
+
+    /// The signal the command of a session channel was killed by, see
+    /// [`exit_status`](Self::exit_status)
+    pub fn exit_signal(&self) -> Option<&ExitSignal> {
+        self.exit_signal.as_ref()
+    }
+
+    /// Environment variables the peer asked for with `env` requests on a
+    /// session channel, as name and value in the order they came.
+    ///
+    /// They are only requests. A server usually passes on just the ones it
+    /// allows, like OpenSSH's `AcceptEnv`, and may refuse others, silently
+    /// when `want_reply` isn't set. A name asked for twice is listed twice.
+    pub fn env(&self) -> &[(String, String)] {
+        &self.env
+    }
+
+    /// Current size of the terminal the peer asked for with `pty-req`, kept
+    /// up to date by `window-change` requests. `None` without a terminal.
+    pub fn terminal_size(&self) -> Option<TerminalSize> {
+        self.terminal_size
+    }
+
+    fn new(
+        local_id: u32,
+        remote_id: u32,
+        local_window_size: u32,
+        remote_window_size: u32,
+        remote_maximum_packet_size: u32,
+    ) -> Self {
+        Self {
+            local_id,
+            remote_id,
+            state: ChannelState::Open,
+            local_window: Window::new(local_window_size),
+            remote_window: Window::new(remote_window_size),
+            remote_maximum_packet_size,
+            exit_status: None,
+            exit_signal: None,
+            env: Vec::new(),
+            terminal_size: None,
+        }
+    }
+
+    fn record_request(&mut self, request: &ChannelRequest) {
+        match &request.request {
+            ChannelRequestType::Env { name, value } => {
+                self.env.push((name.clone(), value.clone()));
+            }
+            &ChannelRequestType::PtyReq {
+                width_chars,
+                height_rows,
+                width_pixels,
+                height_pixels,
+                ..
+            } => {
+                self.terminal_size = Some(TerminalSize {
+                    width_chars,
+                    height_rows,
+                    width_pixels,
+                    height_pixels,
+                })
+            }
+            // Resizing only means something once there is a terminal
+            &ChannelRequestType::WindowChange {
+                width_chars,
+                height_rows,
+                width_pixels,
+                height_pixels,
+            } if self.terminal_size.is_some() => {
+                self.terminal_size = Some(TerminalSize {
+                    width_chars,
+                    height_rows,
+                    width_pixels,
+                    height_pixels,
+                })
+            }
+            ChannelRequestType::ExitStatus { exit_status } => self.exit_status = Some(*exit_status),
+            ChannelRequestType::ExitSignal {
+                signal_name,
+                core_dumped,
+                error_message,
+                ..
+            } => {
+                self.exit_signal = Some(ExitSignal {
+                    signal_name: signal_name.clone(),
+                    core_dumped: *core_dumped,
+                    error_message: error_message.clone(),
+                })
+            }
+            _ => {}
+        }
+    }
+
+    fn transition(&mut self, event: ChannelEvent) -> Result<(), StateError> {
+        self.state = self.state.transition(event)?;
+        Ok(())
+    }
+}
+
+/// A channel we asked for that the peer hasn't confirmed yet
+#[derive(Debug, Clone, Copy)]
+struct PendingOpen {
+    initial_window_size: u32,
+}
+
+/// Keeps track of the channels of a connection, see RFC 4254 section 5.
+///
+/// Both sides choose their own id for every channel and address messages to
+/// the other side's id. The mux hands out local ids, remembers the remote
+/// ones and does the translation, as well as the flow control windows and
+/// the [`ChannelState`] of every channel.
+///
+/// It only does the bookkeeping, sending the returned messages is up to the
+/// caller.
+#[derive(Debug, Clone, Default)]
+pub struct ChannelMux {
+    channels: HashMap<u32, Channel>,
+    pending: HashMap<u32, PendingOpen>,
+    next_id: u32,
+}
+
+impl ChannelMux {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn channel(&self, local_id: u32) -> Option<&Channel> {
+        self.channels.get(&local_id)
+    }
+
+    /// Number of open channels, not counting those still being opened
+    pub fn len(&self) -> usize {
+        self.channels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.channels.is_empty()
+    }
+
+    /// Allocates a local id and builds the SSH_MSG_CHANNEL_OPEN asking the
+    /// peer for a channel.
+    ///
+    /// The channel becomes usable once the confirmation is passed to
+    /// [`receive`](Self::receive).
+    pub fn open(
+        &mut self,
+        channel_type: ChannelOpenType,
+        initial_window_size: u32,
+        maximum_packet_size: u32,
+    ) -> Result<ChannelOpen, MuxError> {
+        let local_id = self.allocate_id()?;
+
+        self.pending.insert(
+            local_id,
+            PendingOpen {
+                initial_window_size,
+            },
+        );
+
+        Ok(ChannelOpen {
+            channel_type,
+            sender_channel: local_id,
+            initial_window_size,
+            maximum_packet_size,
+        })
+    }
+
+    /// Accepts a channel the peer asked for, returning the confirmation to
+    /// send back
+    pub fn accept(
+        &mut self,
+        open: &ChannelOpen,
+        initial_window_size: u32,
+        maximum_packet_size: u32,
+    ) -> Result<ChannelOpenConfirmation, MuxError> {
+        let local_id = self.allocate_id()?;
+
+        self.channels.insert(
+            local_id,
+            Channel::new(
+                local_id,
+                open.sender_channel,
+                initial_window_size,
+                open.initial_window_size,
+                open.maximum_packet_size,
+            ),
+        );
+
+        Ok(ChannelOpenConfirmation {
+            recipient_channel: open.sender_channel,
+            sender_channel: local_id,
+            initial_window_size,
+            maximum_packet_size,
+            data: Bytes::new(),
+        })
+    }
+
+    /// Updates the channel an incoming message is for, returning its local
+    /// id.
+    ///
+    /// Messages that aren't about a channel give `None`, as does a
+    /// SSH_MSG_CHANNEL_OPEN which has no local id yet and is answered through
+    /// [`accept`](Self::accept). Once a channel has been closed by both
+    /// sides it is forgotten, and its id returned one last time.
+    ///
+    /// `exit-status` and `exit-signal` requests are recorded, see
+    /// [`Channel::exit_status`], as are `env` ones, see [`Channel::env`], and
+    /// the terminal size, see [`Channel::terminal_size`]. Replies to our own
+    /// requests are only matched to their channel, answering them in order
+    /// is up to the caller.
+    pub fn receive(&mut self, message: &Message) -> Result<Option<u32>, MuxError> {
+        let local_id = match message {
+            Message::ChannelOpenConfirmation(confirmation) => {
+                let local_id = confirmation.recipient_channel;
+                let pending = self
+                    .pending
+                    .remove(&local_id)
+                    .ok_or(MuxError::UnknownChannel(local_id))?;
+
+                self.channels.insert(
+                    local_id,
+                    Channel::new(
+                        local_id,
+                        confirmation.sender_channel,
+                        pending.initial_window_size,
+                        confirmation.initial_window_size,
+                        confirmation.maximum_packet_size,
+                    ),
+                );
+
+                local_id
+            }
+            Message::ChannelOpenFailure(failure) => {
+                let local_id = failure.recipient_channel;
+
+                self.pending
+                    .remove(&local_id)
+                    .ok_or(MuxError::UnknownChannel(local_id))?;
+
+                local_id
+            }
+            Message::ChannelWindowAdjust(adjust) => {
+                let channel = self.get_mut(adjust.recipient_channel)?;
+                channel.remote_window.apply_adjust(adjust)?;
+
+                channel.local_id
+            }
+            Message::ChannelData(ChannelData {
+                recipient_channel,
+                data,
+            })
+            | Message::ChannelExtendedData(ChannelExtendedData {
+                recipient_channel,
+                data,
+                ..
+            }) => {
+                let channel = self.get_mut(*recipient_channel)?;
+                channel.transition(ChannelEvent::ReceiveData)?;
+
+                let window = channel.local_window.size();
+                if data.len() > window as usize {
+                    return Err(MuxError::WindowExceeded {
+                        channel: channel.local_id,
+                        len: data.len(),
+                        window,
+                    });
+                }
+                channel.local_window.consume(data.len() as u32);
+
+                channel.local_id
+            }
+            Message::ChannelEof(eof) => {
+                let channel = self.get_mut(eof.recipient_channel)?;
+                channel.transition(ChannelEvent::ReceiveEof)?;
+
+                channel.local_id
+            }
+            Message::ChannelClose(close) => {
+                let channel = self.get_mut(close.recipient_channel)?;
+                channel.transition(ChannelEvent::ReceiveClose)?;
+
+                self.forget_if_closed(close.recipient_channel)
+            }
+            Message::ChannelRequest(request) => {
+                let channel = self.get_mut(request.recipient_channel)?;
+                channel.record_request(request);
+
+                channel.local_id
+            }
+            Message::ChannelSuccess(ChannelSuccess { recipient_channel })
+            | Message::ChannelFailure(ChannelFailure { recipient_channel }) => {
+                self.get_mut(*recipient_channel)?.local_id
+            }
+            _ => return Ok(None),
+        };
+
+        Ok(Some(local_id))
+    }
+
+    /// Builds an SSH_MSG_CHANNEL_DATA, taking it out of the peer's window.
+    ///
+    /// Data larger than [`Channel::sendable`] is refused rather than split,
+    /// so nothing is sent half way.
+    pub fn data(&mut self, local_id: u32, data: Bytes) -> Result<ChannelData, MuxError> {
+        let channel = self.prepare_send(local_id, data.len())?;
+
+        Ok(ChannelData {
+            recipient_channel: channel.remote_id,
+            data,
+        })
+    }
+
+    /// Like [`data`](Self::data) for SSH_MSG_CHANNEL_EXTENDED_DATA
+    pub fn extended_data(
+        &mut self,
+        local_id: u32,
+        data_type_code: u32,
+        data: Bytes,
+    ) -> Result<ChannelExtendedData, MuxError> {
+        let channel = self.prepare_send(local_id, data.len())?;
+
+        Ok(ChannelExtendedData {
+            recipient_channel: channel.remote_id,
+            data_type_code,
+            data,
+        })
+    }
+
+    /// Grows the window of data the peer may send us
+    pub fn adjust_window(
+        &mut self,
+        local_id: u32,
+        bytes_to_add: u32,
+    ) -> Result<ChannelWindowAdjust, MuxError> {
+        let channel = self.get_mut(local_id)?;
+        channel.local_window.adjust(bytes_to_add)?;
+
+        Ok(ChannelWindowAdjust {
+            recipient_channel: channel.remote_id,
+            bytes_to_add,
+        })
+    }
+
+    pub fn eof(&mut self, local_id: u32) -> Result<ChannelEof, MuxError> {
+        let channel = self.get_mut(local_id)?;
+        channel.transition(ChannelEvent::SendEof)?;
+
+        Ok(ChannelEof {
+            recipient_channel: channel.remote_id,
+        })
+    }
+
+    /// Builds an SSH_MSG_CHANNEL_CLOSE, forgetting the channel if the peer
+    /// already closed it
+    pub fn close(&mut self, local_id: u32) -> Result<ChannelClose, MuxError> {
+        let channel = self.get_mut(local_id)?;
+        channel.transition(ChannelEvent::SendClose)?;
+
+        let close = ChannelClose {
+            recipient_channel: channel.remote_id,
+        };
+
+        self.forget_if_closed(local_id);
+
+        Ok(close)
+    }
+
+    fn get_mut(&mut self, local_id: u32) -> Result<&mut Channel, MuxError> {
+        self.channels
+            .get_mut(&local_id)
+            .ok_or(MuxError::UnknownChannel(local_id))
+    }
+
+    fn prepare_send(&mut self, local_id: u32, len: usize) -> Result<&Channel, MuxError> {
+        let channel = self.get_mut(local_id)?;
+
+        let limit = channel.sendable();
+        if len > limit as usize {
+            return Err(MuxError::DataTooLarge {
+                channel: local_id,
+                len,
+                limit,
+            });
+        }
+
+        channel.transition(ChannelEvent::SendData)?;
+        channel.remote_window.consume(len as u32);
+
+        Ok(channel)
+    }
+
+    fn forget_if_closed(&mut self, local_id: u32) -> u32 {
+        if self
+            .channels
+            .get(&local_id)
+            .is_some_and(|channel| channel.state.is_closed())
+        {
+            self.channels.remove(&local_id);
+        }
+
+        local_id
+    }
+
+    fn allocate_id(&mut self) -> Result<u32, MuxError> {
+        let in_use = self.channels.len() + self.pending.len();
+        if in_use > u32::MAX as usize {
+            return Err(MuxError::NoFreeChannel);
+        }
+
+        loop {
+            let id = self.next_id;
+            self.next_id = self.next_id.wrapping_add(1);
+
+            if !self.channels.contains_key(&id) && !self.pending.contains_key(&id) {
+                return Ok(id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::ChannelOpenFailure;
+
+    /// Opens a session channel the peer confirms with `remote_id`
+    fn open(mux: &mut ChannelMux, remote_id: u32) -> u32 {
+        let open = mux.open(ChannelOpenType::Session, 1024, 512).unwrap();
+
+        let confirmation = Message::ChannelOpenConfirmation(ChannelOpenConfirmation {
+            recipient_channel: open.sender_channel,
+            sender_channel: remote_id,
+            initial_window_size: 2048,
+            maximum_packet_size: 256,
+            data: Bytes::new(),
+        });
+        assert_eq!(
+            mux.receive(&confirmation).unwrap(),
+            Some(open.sender_channel)
+        );
+
+        open.sender_channel
+    }
+
+    fn data(recipient_channel: u32, data: &'static [u8]) -> Message {
+        Message::ChannelData(ChannelData {
+            recipient_channel,
+            data: Bytes::from_static(data),
+        })
+    }
+
+    #[test]
+    fn interleaved_data() {
+        let mut mux = ChannelMux::new();
+        let first = open(&mut mux, 100);
+        let second = open(&mut mux, 200);
+
+        assert_ne!(first, second);
+        assert_eq!(mux.len(), 2);
+
+        for (local_id, bytes) in [(first, &b"one"[..]), (second, b"two!"), (first, b"three")] {
+            assert_eq!(mux.receive(&data(local_id, bytes)).unwrap(), Some(local_id));
+        }
+
+        assert_eq!(mux.channel(first).unwrap().local_window().size(), 1024 - 8);
+        assert_eq!(mux.channel(second).unwrap().local_window().size(), 1024 - 4);
+
+        // Outgoing data is addressed to the peer's ids
+        let sent = mux.data(second, Bytes::from_static(b"hi")).unwrap();
+        assert_eq!(sent.recipient_channel, 200);
+        assert_eq!(mux.channel(second).unwrap().remote_window().size(), 2046);
+        assert_eq!(mux.channel(first).unwrap().remote_window().size(), 2048);
+
+        let adjust = Message::ChannelWindowAdjust(ChannelWindowAdjust {
+            recipient_channel: first,
+            bytes_to_add: 10,
+        });
+        assert_eq!(mux.receive(&adjust).unwrap(), Some(first));
+        assert_eq!(mux.channel(first).unwrap().remote_window().size(), 2058);
+    }
+
+    #[test]
+    fn unknown_channel() {
+        let mut mux = ChannelMux::new();
+        let local_id = open(&mut mux, 100);
+
+        assert!(matches!(
+            mux.receive(&data(local_id + 1, b"?")),
+            Err(MuxError::UnknownChannel(id)) if id == local_id + 1
+        ));
+        assert!(matches!(
+            mux.data(local_id + 1, Bytes::from_static(b"?")),
+            Err(MuxError::UnknownChannel(_))
+        ));
+
+        // Nor can a channel be confirmed that was never asked for
+        let failure = Message::ChannelOpenFailure(ChannelOpenFailure {
+            recipient_channel: local_id,
+            reason_code: crate::message::OpenFailureReason::ConnectFailed,
+            description: String::new(),
+            language_tag: String::new(),
+        });
+        assert!(matches!(
+            mux.receive(&failure),
+            Err(MuxError::UnknownChannel(_))
+        ));
+    }
+
+    #[test]
+    fn request_replies() {
+        let mut mux = ChannelMux::new();
+        let local_id = open(&mut mux, 100);
+
+        let success = Message::ChannelSuccess(ChannelSuccess {
+            recipient_channel: local_id,
+        });
+        assert_eq!(mux.receive(&success).unwrap(), Some(local_id));
+
+        let failure = Message::ChannelFailure(ChannelFailure {
+            recipient_channel: local_id + 1,
+        });
+        assert!(matches!(
+            mux.receive(&failure),
+            Err(MuxError::UnknownChannel(id)) if id == local_id + 1
+        ));
+    }
+
+    #[test]
+    fn close_frees_the_id() {
+        let mut mux = ChannelMux::new();
+        let local_id = open(&mut mux, 100);
+
+        let close = mux.close(local_id).unwrap();
+        assert_eq!(close.recipient_channel, 100);
+        assert_eq!(mux.len(), 1);
+
+        // The peer's data still in flight is fine, its close ends the channel
+        mux.receive(&data(local_id, b"late")).unwrap();
+        let close = Message::ChannelClose(ChannelClose {
+            recipient_channel: local_id,
+        });
+        assert_eq!(mux.receive(&close).unwrap(), Some(local_id));
+        assert!(mux.is_empty());
+
+        assert!(matches!(
+            mux.receive(&data(local_id, b"gone")),
+            Err(MuxError::UnknownChannel(_))
+        ));
+    }
+
+    #[test]
+    fn env_recorded() {
+        let mut mux = ChannelMux::new();
+        let local_id = open(&mut mux, 100);
+
+        let env = Message::ChannelRequest(ChannelRequest {
+            recipient_channel: local_id,
+            want_reply: false,
+            request: ChannelRequestType::Env {
+                name: "LANG".into(),
+                value: "en_US.UTF-8".into(),
+            },
+        });
+        assert_eq!(mux.receive(&env).unwrap(), Some(local_id));
+
+        assert_eq!(
+            mux.channel(local_id).unwrap().env(),
+            [("LANG".into(), "en_US.UTF-8".into())]
+        );
+    }
+
+    #[test]
+    fn window_change_recorded() {
+        let mut mux = ChannelMux::new();
+        let local_id = open(&mut mux, 100);
+
+        let request = |request| {
+            Message::ChannelRequest(ChannelRequest {
+                recipient_channel: local_id,
+                want_reply: false,
+                request,
+            })
+        };
+        let window_change = request(ChannelRequestType::WindowChange {
+            width_chars: 80,
+            height_rows: 24,
+            width_pixels: 0,
+            height_pixels: 0,
+        });
+
+        // Ignored without a terminal to resize
+        mux.receive(&window_change).unwrap();
+        assert_eq!(mux.channel(local_id).unwrap().terminal_size(), None);
+
+        let pty_req = request(ChannelRequestType::PtyReq {
+            term: "xterm".into(),
+            width_chars: 132,
+            height_rows: 43,
+            width_pixels: 0,
+            height_pixels: 0,
+            terminal_modes: Default::default(),
+        });
+        mux.receive(&pty_req).unwrap();
+        assert_eq!(mux.receive(&window_change).unwrap(), Some(local_id));
+
+        assert_eq!(
+            mux.channel(local_id).unwrap().terminal_size(),
+            Some(TerminalSize {
+                width_chars: 80,
+                height_rows: 24,
+                width_pixels: 0,
+                height_pixels: 0,
+            })
+        );
+    }
+}
@@ -0,0 +1,513 @@
+use std::{fmt, io};
+
+use aws_lc_rs::{
+    aead::{
+        AES_128_GCM, AES_256_GCM, Aad, LessSafeKey, NONCE_LEN, Nonce, UnboundKey,
+        chacha20_poly1305_openssh::{self, OpeningKey, SealingKey},
+    },
+    cipher::{AES_128, AES_256, EncryptingKey, EncryptionContext, UnboundCipherKey},
+    iv::FixedLength,
+};
+
+use crate::codec::Direction;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CipherAlgorithm {
+    Aes128Ctr,
+    Aes256Ctr,
+    Aes128Gcm,
+    Aes256Gcm,
+    ChaCha20Poly1305,
+}
+
+impl CipherAlgorithm {
+    /// Looks up a cipher by its SSH algorithm name
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "aes128-ctr" => Some(CipherAlgorithm::Aes128Ctr),
+            "aes256-ctr" => Some(CipherAlgorithm::Aes256Ctr),
+            "aes128-gcm@openssh.com" => Some(CipherAlgorithm::Aes128Gcm),
+            "aes256-gcm@openssh.com" => Some(CipherAlgorithm::Aes256Gcm),
+            "chacha20-poly1305@openssh.com" => Some(CipherAlgorithm::ChaCha20Poly1305),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CipherAlgorithm::Aes128Ctr => "aes128-ctr",
+            CipherAlgorithm::Aes256Ctr => "aes256-ctr",
+            CipherAlgorithm::Aes128Gcm => "aes128-gcm@openssh.com",
+            CipherAlgorithm::Aes256Gcm => "aes256-gcm@openssh.com",
+            CipherAlgorithm::ChaCha20Poly1305 => "chacha20-poly1305@openssh.com",
+        }
+    }
+
+    pub fn key_length(&self) -> usize {
+        match self {
+            CipherAlgorithm::Aes128Ctr | CipherAlgorithm::Aes128Gcm => 16,
+            CipherAlgorithm::Aes256Ctr | CipherAlgorithm::Aes256Gcm => 32,
+            CipherAlgorithm::ChaCha20Poly1305 => chacha20_poly1305_openssh::KEY_LEN,
+        }
+    }
+
+    pub fn iv_length(&self) -> usize {
+        match self {
+            CipherAlgorithm::Aes128Ctr | CipherAlgorithm::Aes256Ctr => 16,
+            // The fixed field and initial invocation counter of the nonce,
+            // see RFC 5647 section 7.1
+            CipherAlgorithm::Aes128Gcm | CipherAlgorithm::Aes256Gcm => NONCE_LEN,
+            // The nonce is the packet sequence number
+            CipherAlgorithm::ChaCha20Poly1305 => 0,
+        }
+    }
+
+    pub fn block_size(&self) -> usize {
+        match self {
+            CipherAlgorithm::Aes128Ctr
+            | CipherAlgorithm::Aes256Ctr
+            | CipherAlgorithm::Aes128Gcm
+            | CipherAlgorithm::Aes256Gcm => 16,
+            CipherAlgorithm::ChaCha20Poly1305 => 8,
+        }
+    }
+
+    /// Length of the authentication tag following the ciphertext
+    pub fn tag_length(&self) -> usize {
+        match self {
+            CipherAlgorithm::Aes128Ctr | CipherAlgorithm::Aes256Ctr => 0,
+            CipherAlgorithm::Aes128Gcm | CipherAlgorithm::Aes256Gcm => AES_128_GCM.tag_len(),
+            CipherAlgorithm::ChaCha20Poly1305 => chacha20_poly1305_openssh::TAG_LEN,
+        }
+    }
+
+    /// Whether the cipher authenticates packets itself, in which case no
+    /// separate MAC is used and the length field isn't part of the padded
+    /// plaintext
+    pub fn is_aead(&self) -> bool {
+        self.tag_length() > 0
+    }
+}
+
+/// Packet encryption for one direction of the connection.
+///
+/// Implementations get whole packets, length field included, and decide
+/// themselves which parts are encrypted. That keeps the codec free of
+/// per-algorithm logic; it only needs to know the sizes involved and
+/// whether the cipher authenticates packets itself.
+pub(crate) trait PacketCipher: Send {
+    /// The algorithm in use, `None` without encryption
+    fn algorithm(&self) -> Option<CipherAlgorithm>;
+
+    /// Alignment of the padded packet
+    fn block_size(&self) -> usize;
+
+    /// Length of the authentication tag following the ciphertext
+    fn tag_length(&self) -> usize {
+        0
+    }
+
+    /// Whether the cipher authenticates packets itself, in which case no
+    /// separate MAC is used and the length field isn't part of the padded
+    /// plaintext
+    fn is_aead(&self) -> bool {
+        self.tag_length() > 0
+    }
+
+    /// Number of bytes needed before the packet length can be read
+    fn head_size(&self) -> usize {
+        4
+    }
+
+    /// Recovers the packet length from the first `head_size` bytes of a
+    /// packet.
+    ///
+    /// This may decrypt in place and advance the cipher, so it must be
+    /// called exactly once per packet.
+    fn decrypt_length(&mut self, seq: u32, head: &mut [u8]) -> io::Result<u32>;
+
+    /// Decrypts (and for AEAD ciphers, authenticates) the length-prefixed
+    /// packet in `sealed` in place once `decrypt_length` has been called on
+    /// it. The tag, if any, makes up the last bytes of `sealed`.
+    ///
+    /// On success the packet holds the plaintext, length field included.
+    fn decrypt_packet(&mut self, seq: u32, sealed: &mut [u8]) -> io::Result<()>;
+
+    /// Encrypts the length-prefixed `packet` in place, writing the
+    /// authentication tag (if any) to `tag`
+    fn encrypt_packet(&mut self, seq: u32, packet: &mut [u8], tag: &mut [u8]) -> io::Result<()>;
+
+    /// Encrypts or decrypts `data` in place without any special handling of
+    /// the length field, as needed by EtM MACs where only the data after the
+    /// length is encrypted.
+    ///
+    /// AEAD ciphers never get here, they bring their own authentication.
+    fn apply_keystream(&mut self, data: &mut [u8]) -> io::Result<()>;
+}
+
+impl fmt::Debug for dyn PacketCipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PacketCipher")
+            .field("algorithm", &self.algorithm())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Keys `algorithm` for `direction`, checking the key and IV lengths
+pub(crate) fn new_cipher(
+    direction: Direction,
+    algorithm: CipherAlgorithm,
+    key: &[u8],
+    iv: &[u8],
+) -> io::Result<Box<dyn PacketCipher>> {
+    if key.len() != algorithm.key_length() || iv.len() != algorithm.iv_length() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid key or IV length for {}", algorithm.name()),
+        ));
+    }
+
+    let cipher: Box<dyn PacketCipher> = match algorithm {
+        CipherAlgorithm::Aes128Ctr | CipherAlgorithm::Aes256Ctr => {
+            let aes = match algorithm {
+                CipherAlgorithm::Aes128Ctr => &AES_128,
+                _ => &AES_256,
+            };
+
+            let key = UnboundCipherKey::new(aes, key)
+                .and_then(EncryptingKey::ctr)
+                .map_err(|e| io::Error::other(format!("Cipher error: {:?}", e)))?;
+
+            let mut counter = [0u8; 16];
+            counter.copy_from_slice(iv);
+
+            Box::new(CtrCipher {
+                algorithm,
+                key,
+                counter,
+            })
+        }
+        CipherAlgorithm::Aes128Gcm | CipherAlgorithm::Aes256Gcm => {
+            let aes = match algorithm {
+                CipherAlgorithm::Aes128Gcm => &AES_128_GCM,
+                _ => &AES_256_GCM,
+            };
+
+            let key = UnboundKey::new(aes, key)
+                .map(LessSafeKey::new)
+                .map_err(|e| io::Error::other(format!("Cipher error: {:?}", e)))?;
+
+            let mut nonce = [0u8; NONCE_LEN];
+            nonce.copy_from_slice(iv);
+
+            Box::new(GcmCipher {
+                algorithm,
+                key,
+                nonce,
+            })
+        }
+        CipherAlgorithm::ChaCha20Poly1305 => {
+            let mut key_material = [0u8; chacha20_poly1305_openssh::KEY_LEN];
+            key_material.copy_from_slice(key);
+
+            match direction {
+                Direction::Outgoing => Box::new(ChaChaSeal(SealingKey::new(&key_material))),
+                Direction::Incoming => Box::new(ChaChaOpen(OpeningKey::new(&key_material))),
+            }
+        }
+    };
+
+    Ok(cipher)
+}
+
+/// No encryption, in place until the first key exchange is done
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct NullCipher;
+
+impl PacketCipher for NullCipher {
+    fn algorithm(&self) -> Option<CipherAlgorithm> {
+        None
+    }
+
+    /// RFC 4253 section 6 pads to 8 bytes without encryption
+    fn block_size(&self) -> usize {
+        8
+    }
+
+    fn decrypt_length(&mut self, _seq: u32, head: &mut [u8]) -> io::Result<u32> {
+        Ok(u32::from_be_bytes([head[0], head[1], head[2], head[3]]))
+    }
+
+    fn decrypt_packet(&mut self, _seq: u32, _sealed: &mut [u8]) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn encrypt_packet(&mut self, _seq: u32, _packet: &mut [u8], _tag: &mut [u8]) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn apply_keystream(&mut self, _data: &mut [u8]) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// `aes128-ctr` and `aes256-ctr`, see RFC 4344
+struct CtrCipher {
+    algorithm: CipherAlgorithm,
+    key: EncryptingKey,
+    /// Big-endian counter block, carried over from packet to packet
+    counter: [u8; 16],
+}
+
+impl PacketCipher for CtrCipher {
+    fn algorithm(&self) -> Option<CipherAlgorithm> {
+        Some(self.algorithm)
+    }
+
+    fn block_size(&self) -> usize {
+        self.algorithm.block_size()
+    }
+
+    /// The length is inside the first block, so all of it is needed
+    fn head_size(&self) -> usize {
+        self.block_size()
+    }
+
+    /// Decrypts the whole first block in place, advancing the cipher
+    fn decrypt_length(&mut self, _seq: u32, head: &mut [u8]) -> io::Result<u32> {
+        apply_ctr(&self.key, &mut self.counter, head)?;
+
+        Ok(u32::from_be_bytes([head[0], head[1], head[2], head[3]]))
+    }
+
+    fn decrypt_packet(&mut self, _seq: u32, sealed: &mut [u8]) -> io::Result<()> {
+        // The first block was decrypted by decrypt_length already
+        let block_size = self.block_size();
+        apply_ctr(&self.key, &mut self.counter, &mut sealed[block_size..])
+    }
+
+    fn encrypt_packet(&mut self, _seq: u32, packet: &mut [u8], _tag: &mut [u8]) -> io::Result<()> {
+        apply_ctr(&self.key, &mut self.counter, packet)
+    }
+
+    fn apply_keystream(&mut self, data: &mut [u8]) -> io::Result<()> {
+        apply_ctr(&self.key, &mut self.counter, data)
+    }
+}
+
+/// `aes128-gcm@openssh.com` and `aes256-gcm@openssh.com`, see RFC 5647.
+///
+/// The length is sent in the clear as associated data and the rest of the
+/// packet encrypted, as OpenSSH does it.
+struct GcmCipher {
+    algorithm: CipherAlgorithm,
+    key: LessSafeKey,
+    /// Fixed field followed by the big-endian invocation counter, which goes
+    /// up by one with every packet
+    nonce: [u8; NONCE_LEN],
+}
+
+impl PacketCipher for GcmCipher {
+    fn algorithm(&self) -> Option<CipherAlgorithm> {
+        Some(self.algorithm)
+    }
+
+    fn block_size(&self) -> usize {
+        self.algorithm.block_size()
+    }
+
+    fn tag_length(&self) -> usize {
+        self.algorithm.tag_length()
+    }
+
+    fn decrypt_length(&mut self, _seq: u32, head: &mut [u8]) -> io::Result<u32> {
+        Ok(u32::from_be_bytes([head[0], head[1], head[2], head[3]]))
+    }
+
+    fn decrypt_packet(&mut self, _seq: u32, sealed: &mut [u8]) -> io::Result<()> {
+        let length = [sealed[0], sealed[1], sealed[2], sealed[3]];
+
+        self.key
+            .open_in_place(
+                Nonce::assume_unique_for_key(self.nonce),
+                Aad::from(length),
+                &mut sealed[4..],
+            )
+            .map_err(|_| authentication_failed())?;
+
+        increment_invocation_counter(&mut self.nonce);
+
+        Ok(())
+    }
+
+    fn encrypt_packet(&mut self, _seq: u32, packet: &mut [u8], tag: &mut [u8]) -> io::Result<()> {
+        let (length, data) = packet.split_at_mut(4);
+
+        let tag_out = self
+            .key
+            .seal_in_place_separate_tag(
+                Nonce::assume_unique_for_key(self.nonce),
+                Aad::from(length),
+                data,
+            )
+            .map_err(|e| io::Error::other(format!("Cipher error: {:?}", e)))?;
+        tag.copy_from_slice(tag_out.as_ref());
+
+        increment_invocation_counter(&mut self.nonce);
+
+        Ok(())
+    }
+
+    fn apply_keystream(&mut self, _data: &mut [u8]) -> io::Result<()> {
+        Err(aead_with_etm())
+    }
+}
+
+/// Sending half of `chacha20-poly1305@openssh.com`, the nonce is the packet
+/// sequence number
+struct ChaChaSeal(SealingKey);
+
+impl PacketCipher for ChaChaSeal {
+    fn algorithm(&self) -> Option<CipherAlgorithm> {
+        Some(CipherAlgorithm::ChaCha20Poly1305)
+    }
+
+    fn block_size(&self) -> usize {
+        CipherAlgorithm::ChaCha20Poly1305.block_size()
+    }
+
+    fn tag_length(&self) -> usize {
+        chacha20_poly1305_openssh::TAG_LEN
+    }
+
+    fn decrypt_length(&mut self, _seq: u32, _head: &mut [u8]) -> io::Result<u32> {
+        Err(wrong_direction())
+    }
+
+    fn decrypt_packet(&mut self, _seq: u32, _sealed: &mut [u8]) -> io::Result<()> {
+        Err(wrong_direction())
+    }
+
+    fn encrypt_packet(&mut self, seq: u32, packet: &mut [u8], tag: &mut [u8]) -> io::Result<()> {
+        let mut tag_out = [0u8; chacha20_poly1305_openssh::TAG_LEN];
+        self.0.seal_in_place(seq, packet, &mut tag_out);
+        tag.copy_from_slice(&tag_out);
+
+        Ok(())
+    }
+
+    fn apply_keystream(&mut self, _data: &mut [u8]) -> io::Result<()> {
+        Err(aead_with_etm())
+    }
+}
+
+/// Receiving half of `chacha20-poly1305@openssh.com`
+struct ChaChaOpen(OpeningKey);
+
+impl PacketCipher for ChaChaOpen {
+    fn algorithm(&self) -> Option<CipherAlgorithm> {
+        Some(CipherAlgorithm::ChaCha20Poly1305)
+    }
+
+    fn block_size(&self) -> usize {
+        CipherAlgorithm::ChaCha20Poly1305.block_size()
+    }
+
+    fn tag_length(&self) -> usize {
+        chacha20_poly1305_openssh::TAG_LEN
+    }
+
+    /// The length has a key of its own, so it decrypts without the rest
+    fn decrypt_length(&mut self, seq: u32, head: &mut [u8]) -> io::Result<u32> {
+        let encrypted = [head[0], head[1], head[2], head[3]];
+
+        Ok(u32::from_be_bytes(
+            self.0.decrypt_packet_length(seq, encrypted),
+        ))
+    }
+
+    fn decrypt_packet(&mut self, seq: u32, sealed: &mut [u8]) -> io::Result<()> {
+        let (packet, tag) = sealed.split_at_mut(sealed.len() - self.tag_length());
+
+        let mut expected = [0u8; chacha20_poly1305_openssh::TAG_LEN];
+        expected.copy_from_slice(tag);
+
+        let encrypted = [packet[0], packet[1], packet[2], packet[3]];
+
+        self.0
+            .open_in_place(seq, packet, &expected)
+            .map_err(|_| authentication_failed())?;
+
+        // The length is left encrypted, put the plaintext one back so the
+        // packet reads the same as an unencrypted one
+        packet[..4].copy_from_slice(&self.0.decrypt_packet_length(seq, encrypted));
+
+        Ok(())
+    }
+
+    fn encrypt_packet(&mut self, _seq: u32, _packet: &mut [u8], _tag: &mut [u8]) -> io::Result<()> {
+        Err(wrong_direction())
+    }
+
+    fn apply_keystream(&mut self, _data: &mut [u8]) -> io::Result<()> {
+        Err(aead_with_etm())
+    }
+}
+
+/// Encrypts or decrypts `data` in place, CTR mode being symmetric.
+///
+/// `data` must be a whole number of blocks so the counter can be carried over
+/// to the next call.
+fn apply_ctr(key: &EncryptingKey, counter: &mut [u8; 16], data: &mut [u8]) -> io::Result<()> {
+    let block_size = key.algorithm().block_len();
+
+    if !data.len().is_multiple_of(block_size) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "SSH packet is not a multiple of the cipher block size: {} bytes",
+                data.len()
+            ),
+        ));
+    }
+
+    if data.is_empty() {
+        return Ok(());
+    }
+
+    let context = EncryptionContext::Iv128(FixedLength::from(*counter));
+
+    key.less_safe_encrypt(data, context)
+        .map_err(|e| io::Error::other(format!("Cipher error: {:?}", e)))?;
+
+    let blocks = (data.len() / block_size) as u128;
+    *counter = u128::from_be_bytes(*counter)
+        .wrapping_add(blocks)
+        .to_be_bytes();
+
+    Ok(())
+}
+
+/// Moves a GCM nonce on to the next packet, the counter wrapping around
+/// like OpenSSH's rather than failing
+fn increment_invocation_counter(nonce: &mut [u8; NONCE_LEN]) {
+    let (_, counter) = nonce.split_at_mut(4);
+    let next = u64::from_be_bytes(counter.try_into().unwrap()).wrapping_add(1);
+
+    counter.copy_from_slice(&next.to_be_bytes());
+}
+
+fn authentication_failed() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "SSH packet authentication failed",
+    )
+}
+
+fn aead_with_etm() -> io::Error {
+    io::Error::other("AEAD cipher used with an EtM MAC")
+}
+
+fn wrong_direction() -> io::Error {
+    io::Error::other("Cipher used in the wrong direction")
+}
@@ -0,0 +1,687 @@
+use std::io;
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+
+use crate::{
+    codec::Direction,
+    hostkey::{HostKey, HostKeyError, HostKeyVerifier, VerifyError},
+    kex::{
+        Curve25519Sha256, DhGroup14Sha256, DhGroupExchangeSha256, EXT_INFO_CLIENT,
+        EcdhSha2Nistp256, ExchangeContext, HashAlg, KexError, NegotiationError, STRICT_KEX_CLIENT,
+        derive_keys, install_keys,
+    },
+    message::{
+        AuthMethod, Disconnect, ExtInfo, Kexinit, Message, MessageType, ParseContext, ParseError,
+        Reader, ServiceAccept, ServiceRequest, UserauthFailure, UserauthInfoRequest,
+        UserauthInfoResponse, UserauthRequest,
+    },
+    openssh_key::{KeyError, PrivateKey},
+    tracing::{debug, info, warn},
+    transport::{LOCAL_ID, Timeouts, Transport, TransportError},
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ClientError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Transport(#[from] TransportError),
+    #[error("Malformed message from the server")]
+    Malformed(#[from] ParseError),
+    #[error(transparent)]
+    Negotiation(#[from] NegotiationError),
+    #[error(transparent)]
+    Kex(#[from] KexError),
+    #[error(transparent)]
+    HostKey(#[from] HostKeyError),
+    #[error(transparent)]
+    Verify(#[from] VerifyError),
+    #[error(transparent)]
+    Key(#[from] KeyError),
+    #[error("Unsupported algorithm: {0}")]
+    UnsupportedAlgorithm(String),
+    #[error("Unexpected message from the server, expected {0}")]
+    UnexpectedMessage(MessageType),
+    #[error("Disconnected by the server: {}", .0.description)]
+    Disconnected(Disconnect),
+    /// The server offered strict key exchange but its KEXINIT wasn't the
+    /// first packet
+    #[error("Server violated strict key exchange")]
+    StrictKex,
+    /// Messages came before the server's KEXINIT while
+    /// [`ClientConfig::reject_messages_before_kexinit`] is set
+    #[error("Server sent {0} messages before its KEXINIT, possibly a Terrapin attack")]
+    MessagesBeforeKexinit(u32),
+}
+
+/// Outcome of an authentication attempt that went through without errors
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthResult {
+    Success,
+    /// The server refused, listing the methods to try next
+    Failure(UserauthFailure),
+}
+
+/// Answers the prompts of `keyboard-interactive` authentication.
+///
+/// Called once per SSH_MSG_USERAUTH_INFO_REQUEST, which may come with no
+/// prompts at all. The returned responses must be in the order of the
+/// prompts, one for each.
+pub trait PromptHandler {
+    fn respond(&mut self, request: &UserauthInfoRequest) -> Vec<String>;
+}
+
+impl<F> PromptHandler for F
+where
+    F: FnMut(&UserauthInfoRequest) -> Vec<String>,
+{
+    fn respond(&mut self, request: &UserauthInfoRequest) -> Vec<String> {
+        self(request)
+    }
+}
+
+/// Settings of the handshake, algorithms being offered to the server most
+/// preferred first
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientConfig {
+    pub kex_algorithms: Vec<String>,
+    pub host_key_algorithms: Vec<String>,
+    /// Used for both directions
+    pub encryption_algorithms: Vec<String>,
+    /// Used for both directions, ignored with AEAD ciphers
+    pub mac_algorithms: Vec<String>,
+    /// Used for both directions
+    pub compression_algorithms: Vec<String>,
+    /// Fail the handshake when the server sends anything before its KEXINIT,
+    /// which is how the Terrapin attack starts, see
+    /// [`Transport::messages_before_kexinit`]. On by default, strict key
+    /// exchange guards against more but needs the server to support it.
+    pub reject_messages_before_kexinit: bool,
+    pub timeouts: Timeouts,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            kex_algorithms: to_strings(&[
+                Curve25519Sha256::NAME,
+                "curve25519-sha256@libssh.org",
+                EcdhSha2Nistp256::NAME,
+                DhGroupExchangeSha256::NAME,
+                DhGroup14Sha256::NAME,
+                EXT_INFO_CLIENT,
+                STRICT_KEX_CLIENT,
+            ]),
+            host_key_algorithms: to_strings(&[
+                "ssh-ed25519",
+                "ecdsa-sha2-nistp256",
+                "rsa-sha2-512",
+                "rsa-sha2-256",
+            ]),
+            encryption_algorithms: to_strings(&[
+                "chacha20-poly1305@openssh.com",
+                "aes256-gcm@openssh.com",
+                "aes128-gcm@openssh.com",
+                "aes256-ctr",
+                "aes128-ctr",
+            ]),
+            mac_algorithms: to_strings(&[
+                "hmac-sha2-256-etm@openssh.com",
+                "hmac-sha2-512-etm@openssh.com",
+                "hmac-sha2-256",
+                "hmac-sha2-512",
+            ]),
+            compression_algorithms: to_strings(&["none"]),
+            reject_messages_before_kexinit: true,
+            timeouts: Timeouts::default(),
+        }
+    }
+}
+
+impl ClientConfig {
+    fn kexinit(&self) -> io::Result<Kexinit> {
+        Kexinit::builder()
+            .kex_algorithms(&self.kex_algorithms)
+            .server_host_key_algorithms(&self.host_key_algorithms)
+            .encryption_algorithms(&self.encryption_algorithms)
+            .mac_algorithms(&self.mac_algorithms)
+            .compression_algorithms(&self.compression_algorithms)
+            .build()
+            .map_err(|_| io::Error::other("Failed to generate a KEXINIT cookie"))
+    }
+}
+
+impl Kexinit {
+    /// A KEXINIT offering every algorithm [`ClientConfig::default`] does,
+    /// with a fresh random cookie.
+    pub fn default_client() -> io::Result<Self> {
+        ClientConfig::default().kexinit()
+    }
+}
+
+fn to_strings(names: &[&str]) -> Vec<String> {
+    names.iter().map(|&name| name.to_owned()).collect()
+}
+
+/// Client end of an SSH connection whose key exchange is done.
+///
+/// The `ssh-userauth` service has already been requested, so the next thing
+/// to send is an SSH_MSG_USERAUTH_REQUEST.
+#[derive(Debug)]
+pub struct Client<S> {
+    transport: Transport<S>,
+    /// Whether `ext-info-c` was offered, letting the server send EXT_INFO
+    accepts_ext_info: bool,
+    /// Latest SSH_MSG_EXT_INFO from the server
+    ext_info: Option<ExtInfo>,
+}
+
+impl Client<TcpStream> {
+    /// Connects to `host` and performs the whole handshake, calling
+    /// `verifier` to decide whether to trust the server's host key
+    pub async fn connect<V: HostKeyVerifier>(
+        host: &str,
+        port: u16,
+        config: &ClientConfig,
+        verifier: &V,
+    ) -> Result<Self, ClientError> {
+        let stream = TcpStream::connect((host, port)).await?;
+
+        // The same form known_hosts files use
+        let hostname = match port {
+            22 => host.to_owned(),
+            _ => format!("[{host}]:{port}"),
+        };
+
+        Self::handshake(stream, &hostname, config, verifier).await
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Client<S> {
+    /// Performs the handshake over an already connected stream.
+    ///
+    /// That is the version exchange, a key exchange and the request for the
+    /// `ssh-userauth` service. `hostname` is only passed on to the verifier.
+    pub async fn handshake<V: HostKeyVerifier>(
+        stream: S,
+        hostname: &str,
+        config: &ClientConfig,
+        verifier: &V,
+    ) -> Result<Self, ClientError> {
+        let mut transport = Transport::connect_with_timeouts(stream, config.timeouts).await?;
+
+        debug!(
+            hostname,
+            software_version = %transport.remote_id().version.software_version,
+            "Connected"
+        );
+
+        key_exchange(&mut transport, hostname, config, verifier).await?;
+
+        let mut client = Self {
+            transport,
+            accepts_ext_info: config
+                .kex_algorithms
+                .iter()
+                .any(|name| name == EXT_INFO_CLIENT),
+            ext_info: None,
+        };
+
+        client
+            .transport
+            .send(Message::ServiceRequest(ServiceRequest {
+                service_name: "ssh-userauth".to_owned(),
+            }))
+            .await?;
+
+        // EXT_INFO, if any, comes right after the server's NEWKEYS
+        loop {
+            match client.transport.recv().await? {
+                Message::ServiceAccept(ServiceAccept { service_name })
+                    if service_name == "ssh-userauth" =>
+                {
+                    break;
+                }
+                Message::ExtInfo(ext_info) if client.accepts_ext_info => {
+                    client.ext_info = Some(ext_info);
+                }
+                message => return Err(unexpected(message, MessageType::ServiceAccept)),
+            }
+        }
+
+        Ok(client)
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Client<S> {
+    /// Tries the `none` method for `user_name` and `ssh-connection`.
+    ///
+    /// This usually fails, listing the methods the server accepts, but some
+    /// servers let users in without any authentication.
+    pub async fn probe_auth_methods(&mut self, user_name: &str) -> Result<AuthResult, ClientError> {
+        debug!(user_name, method = "none", "Requesting authentication");
+
+        self.transport
+            .send(Message::UserauthRequest(UserauthRequest::none(
+                user_name,
+                "ssh-connection",
+            )))
+            .await?;
+
+        loop {
+            let message = self.transport.recv().await?;
+
+            if let Some(result) = self.auth_result(message)? {
+                return Ok(result);
+            }
+        }
+    }
+
+    /// Authenticates `user_name` for `ssh-connection` with
+    /// `keyboard-interactive` (RFC 4256), passing every round of prompts to
+    /// `handler`
+    pub async fn authenticate_keyboard_interactive<H: PromptHandler>(
+        &mut self,
+        user_name: &str,
+        handler: &mut H,
+    ) -> Result<AuthResult, ClientError> {
+        let method = AuthMethod::KeyboardInteractive {
+            language_tag: String::new(),
+            submethods: String::new(),
+        };
+        let context = ParseContext {
+            auth_method: Some(method.name()),
+            ..Default::default()
+        };
+
+        debug!(
+            user_name,
+            method = method.name(),
+            "Requesting authentication"
+        );
+
+        self.transport
+            .send(Message::UserauthRequest(UserauthRequest {
+                user_name: user_name.to_owned(),
+                service_name: "ssh-connection".to_owned(),
+                method: method.clone(),
+            }))
+            .await?;
+
+        loop {
+            match self.transport.recv_with_context(context).await? {
+                Message::UserauthInfoRequest(request) => {
+                    debug!(prompts = request.prompts.len(), "Answering prompts");
+
+                    let responses = handler.respond(&request);
+
+                    self.transport
+                        .send(Message::UserauthInfoResponse(UserauthInfoResponse {
+                            responses,
+                        }))
+                        .await?;
+                }
+                message => {
+                    if let Some(result) = self.auth_result(message)? {
+                        return Ok(result);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Authenticates `user_name` for `ssh-connection` with `publickey`,
+    /// signing with `key` right away rather than asking first whether the
+    /// key would be accepted.
+    ///
+    /// RSA keys sign with the first of their algorithms the server lists in
+    /// `server-sig-algs`, or `rsa-sha2-512` without that list.
+    pub async fn authenticate_publickey(
+        &mut self,
+        user_name: &str,
+        key: &PrivateKey,
+    ) -> Result<AuthResult, ClientError> {
+        let server_sig_algs = match &self.ext_info {
+            Some(ext_info) => ext_info.server_sig_algs()?,
+            None => None,
+        };
+        let algorithm = key
+            .algorithms()
+            .iter()
+            .find(|algorithm| {
+                server_sig_algs
+                    .as_ref()
+                    .is_none_or(|algorithms| algorithms.iter().any(|name| name == *algorithm))
+            })
+            .unwrap_or(&key.algorithms()[0]);
+
+        let mut request = UserauthRequest {
+            user_name: user_name.to_owned(),
+            service_name: "ssh-connection".to_owned(),
+            method: AuthMethod::PublicKey {
+                algorithm: (*algorithm).to_owned(),
+                public_key: key.public_key().to_blob(),
+                signature: None,
+            },
+        };
+
+        // Always there for publickey requests
+        let signed_data = request.signed_data(self.session_id()).unwrap_or_default();
+        if let AuthMethod::PublicKey { signature, .. } = &mut request.method {
+            *signature = Some(key.sign(algorithm, &signed_data)?);
+        }
+
+        debug!(
+            user_name,
+            method = "publickey",
+            algorithm,
+            "Requesting authentication"
+        );
+
+        self.transport
+            .send(Message::UserauthRequest(request))
+            .await?;
+
+        loop {
+            let message = self.transport.recv().await?;
+
+            if let Some(result) = self.auth_result(message)? {
+                return Ok(result);
+            }
+        }
+    }
+
+    /// Handles the messages that may end any authentication attempt,
+    /// `None` meaning the attempt is still going
+    fn auth_result(&mut self, message: Message) -> Result<Option<AuthResult>, ClientError> {
+        match message {
+            Message::UserauthSuccess => {
+                info!("Authentication succeeded");
+
+                self.transport.codec_mut().start_delayed_compression();
+
+                Ok(Some(AuthResult::Success))
+            }
+            Message::UserauthFailure(failure) => {
+                debug!(
+                    methods = ?failure.methods,
+                    partial_success = failure.partial_success,
+                    "Authentication failed"
+                );
+
+                Ok(Some(AuthResult::Failure(failure)))
+            }
+            // Only meant to be shown to the user, which is out of scope here
+            Message::UserauthBanner(banner) => {
+                debug!(message = ?banner.message, "Ignoring banner");
+
+                Ok(None)
+            }
+            // Servers may send an updated one right before success
+            Message::ExtInfo(ext_info) if self.accepts_ext_info => {
+                self.ext_info = Some(ext_info);
+
+                Ok(None)
+            }
+            message => Err(unexpected(message, MessageType::UserauthSuccess)),
+        }
+    }
+}
+
+impl<S> Client<S> {
+    /// Exchange hash of the first key exchange, which identifies the session
+    pub fn session_id(&self) -> &[u8] {
+        // Always set, the handshake includes a key exchange
+        self.transport.session_id().unwrap_or_default()
+    }
+
+    /// Extensions from the server's latest SSH_MSG_EXT_INFO, `None` if it
+    /// sent none or [`EXT_INFO_CLIENT`] wasn't offered
+    pub fn ext_info(&self) -> Option<&ExtInfo> {
+        self.ext_info.as_ref()
+    }
+
+    pub fn transport(&self) -> &Transport<S> {
+        &self.transport
+    }
+
+    pub fn transport_mut(&mut self) -> &mut Transport<S> {
+        &mut self.transport
+    }
+
+    pub fn into_transport(self) -> Transport<S> {
+        self.transport
+    }
+}
+
+/// Runs a key exchange from the KEXINITs up to both NEWKEYS
+async fn key_exchange<S, V>(
+    transport: &mut Transport<S>,
+    hostname: &str,
+    config: &ClientConfig,
+    verifier: &V,
+) -> Result<(), ClientError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    V: HostKeyVerifier,
+{
+    let kexinit = config.kexinit()?;
+    transport
+        .send(Message::Kexinit(Box::new(kexinit.clone())))
+        .await?;
+
+    let peer = match transport.recv().await? {
+        Message::Kexinit(peer) => peer,
+        message => return Err(unexpected(message, MessageType::Kexinit)),
+    };
+
+    let skipped = transport.messages_before_kexinit();
+    if skipped > 0 {
+        warn!(skipped, "Server sent messages before its KEXINIT");
+
+        if config.reject_messages_before_kexinit {
+            return Err(ClientError::MessagesBeforeKexinit(skipped));
+        }
+    }
+
+    let algorithms = kexinit.negotiate(&peer)?;
+    debug!(?algorithms, "Negotiated algorithms");
+
+    // Anything before the KEXINIT could be an attacker making room for
+    // dropped packets, and from here until NEWKEYS every message that isn't
+    // part of the key exchange is unexpected
+    if algorithms.strict_kex {
+        if transport.codec().recv_seq() != 1 {
+            warn!("Server's KEXINIT wasn't its first packet despite strict key exchange");
+            return Err(ClientError::StrictKex);
+        }
+
+        transport.set_strict_kex(true);
+    }
+
+    if peer.first_kex_packet_follows && !peer.guessed_right(&algorithms) {
+        debug!("Discarding the server's wrongly guessed kex packet");
+        transport.recv_payload().await?;
+    }
+
+    // Message numbers 30 and up mean something different to every method
+    let parse_context = ParseContext {
+        kex: Some(&algorithms.kex),
+        ..Default::default()
+    };
+
+    let output = match algorithms.kex.as_str() {
+        Curve25519Sha256::NAME | "curve25519-sha256@libssh.org" => {
+            let kex = Curve25519Sha256::new()?;
+            transport
+                .send(Message::KexEcdhInit(kex.init_message()))
+                .await?;
+
+            let reply = match transport.recv_with_context(parse_context).await? {
+                Message::KexEcdhReply(reply) => reply,
+                message => return Err(unexpected(message, MessageType::KexEcdhReply)),
+            };
+
+            let context = exchange_context(transport);
+            kex.finish(&context, reply)?
+        }
+        EcdhSha2Nistp256::NAME => {
+            let kex = EcdhSha2Nistp256::new()?;
+            transport
+                .send(Message::KexEcdhInit(kex.init_message()))
+                .await?;
+
+            let reply = match transport.recv_with_context(parse_context).await? {
+                Message::KexEcdhReply(reply) => reply,
+                message => return Err(unexpected(message, MessageType::KexEcdhReply)),
+            };
+
+            let context = exchange_context(transport);
+            kex.finish(&context, reply)?
+        }
+        DhGroup14Sha256::NAME => {
+            let kex = DhGroup14Sha256::new()?;
+            transport
+                .send(Message::KexdhInit(kex.init_message()))
+                .await?;
+
+            let reply = match transport.recv_with_context(parse_context).await? {
+                Message::KexdhReply(reply) => reply,
+                message => return Err(unexpected(message, MessageType::KexdhReply)),
+            };
+
+            let context = exchange_context(transport);
+            kex.finish(&context, reply)?
+        }
+        DhGroupExchangeSha256::NAME => {
+            let request = DhGroupExchangeSha256::REQUEST;
+            transport.send(Message::KexDhGexRequest(request)).await?;
+
+            let group = match transport.recv_with_context(parse_context).await? {
+                Message::KexDhGexGroup(group) => group,
+                message => return Err(unexpected(message, MessageType::KexDhGexGroup)),
+            };
+
+            let kex = DhGroupExchangeSha256::new(request, group)?;
+            transport
+                .send(Message::KexDhGexInit(kex.init_message()))
+                .await?;
+
+            let reply = match transport.recv_with_context(parse_context).await? {
+                Message::KexDhGexReply(reply) => reply,
+                message => return Err(unexpected(message, MessageType::KexDhGexReply)),
+            };
+
+            let context = exchange_context(transport);
+            kex.finish(&context, reply)?
+        }
+        _ => return Err(ClientError::UnsupportedAlgorithm(algorithms.kex)),
+    };
+
+    let host_key = HostKey::parse(&output.host_key)?;
+
+    // An RSA key could sign with any of its algorithms, only the negotiated
+    // one is acceptable
+    let mut signature = output.signature.as_ref();
+    let signature_algorithm = Reader::new(&mut signature).read_utf8()?;
+    if signature_algorithm != algorithms.server_host_key {
+        return Err(HostKeyError::AlgorithmMismatch {
+            key: host_key.key_type(),
+            signature: signature_algorithm,
+        }
+        .into());
+    }
+
+    host_key.verify(&output.exchange_hash, &output.signature)?;
+
+    debug!(
+        key_type = host_key.key_type(),
+        fingerprint = %host_key.fingerprint_sha256(),
+        "Verifying host key"
+    );
+    verifier.verify(hostname, &host_key)?;
+
+    transport.set_session_id(&output.exchange_hash);
+    let keys = derive_keys(
+        &output.shared_secret,
+        &output.exchange_hash,
+        transport.session_id().unwrap_or_default(),
+        HashAlg::Sha256,
+    );
+
+    transport.send(Message::Newkeys).await?;
+    install_keys(
+        transport.codec_mut(),
+        Direction::Outgoing,
+        true,
+        &algorithms,
+        &keys,
+    )?;
+    if algorithms.strict_kex {
+        transport.codec_mut().reset_seq(Direction::Outgoing);
+    }
+
+    match transport.recv().await? {
+        Message::Newkeys => {}
+        message => return Err(unexpected(message, MessageType::Newkeys)),
+    }
+    install_keys(
+        transport.codec_mut(),
+        Direction::Incoming,
+        false,
+        &algorithms,
+        &keys,
+    )?;
+    if algorithms.strict_kex {
+        transport.codec_mut().reset_seq(Direction::Incoming);
+        transport.set_strict_kex(false);
+    }
+
+    debug!(
+        kex = %algorithms.kex,
+        strict_kex = algorithms.strict_kex,
+        "Key exchange finished"
+    );
+
+    Ok(())
+}
+
+/// Inputs of the exchange hash, the KEXINITs being those just exchanged
+fn exchange_context<S>(transport: &Transport<S>) -> ExchangeContext<'_> {
+    ExchangeContext {
+        client_version: LOCAL_ID.as_bytes(),
+        server_version: transport.remote_id().identification.as_bytes(),
+        client_kexinit: transport.local_kexinit().unwrap_or_default(),
+        server_kexinit: transport.remote_kexinit().unwrap_or_default(),
+    }
+}
+
+fn unexpected(message: Message, expected: MessageType) -> ClientError {
+    match message {
+        Message::Disconnect(disconnect) => ClientError::Disconnected(disconnect),
+        _ => ClientError::UnexpectedMessage(expected),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_client_kexinit() {
+        let kexinit = Kexinit::default_client().unwrap();
+
+        // Both pseudo-algorithms, after every real method
+        assert_eq!(
+            kexinit.kex_algorithms[kexinit.kex_algorithms.len() - 2..],
+            [EXT_INFO_CLIENT, STRICT_KEX_CLIENT]
+        );
+        assert_eq!(kexinit.kex_algorithms[0], Curve25519Sha256::NAME);
+
+        assert!(!kexinit.first_kex_packet_follows);
+        assert_ne!(kexinit.cookie, Kexinit::default_client().unwrap().cookie);
+    }
+}
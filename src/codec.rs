@@ -1,16 +1,106 @@
-use std::io::{self, Cursor};
+use std::{fmt, io, ops::Range, sync::Arc};
 
 use aws_lc_rs::rand::{SecureRandom, SystemRandom};
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use tokio_util::codec::{Decoder, Encoder};
 
-#[derive(Debug)]
+use crate::{
+    cipher::{CipherAlgorithm, NullCipher, PacketCipher, new_cipher},
+    compression::{Compression, CompressionAlgorithm},
+    mac::{Mac, MacAlgorithm, new_mac},
+    tracing::trace,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Packet {
     pub payload: Bytes,
     pub mac: Option<Bytes>,
 }
 
-#[derive(Debug, Clone)]
+/// A decoded packet still at the front of the decode buffer, see
+/// [`PacketCodec::decode_borrowed`]
+///
+/// It holds on to the buffer it came from, dropping it takes the packet out
+/// of that buffer.
+pub struct PacketRef<'a> {
+    src: &'a mut BytesMut,
+    /// Size of the whole frame, tag and MAC included
+    len: usize,
+    layout: Layout,
+    /// Only set when the payload had to be decompressed
+    decompressed: Option<Vec<u8>>,
+    /// Bytes the buffer should have room for once the packet is gone
+    head_size: usize,
+}
+
+impl PacketRef<'_> {
+    pub fn payload(&self) -> &[u8] {
+        match &self.decompressed {
+            Some(decompressed) => decompressed,
+            None => &self.src[self.layout.payload.clone()],
+        }
+    }
+
+    pub fn mac(&self) -> Option<&[u8]> {
+        self.layout.mac.clone().map(|mac| &self.src[mac])
+    }
+
+    pub fn to_owned(&self) -> Packet {
+        Packet {
+            payload: Bytes::copy_from_slice(self.payload()),
+            mac: self.mac().map(Bytes::copy_from_slice),
+        }
+    }
+}
+
+impl Drop for PacketRef<'_> {
+    fn drop(&mut self) {
+        self.src.advance(self.len);
+        self.src
+            .reserve(self.head_size.saturating_sub(self.src.len()));
+    }
+}
+
+impl fmt::Debug for PacketRef<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PacketRef")
+            .field("payload", &self.payload())
+            .field("mac", &self.mac())
+            .finish()
+    }
+}
+
+/// Counters for the traffic through a [`PacketCodec`], to be exported to
+/// Prometheus, statsd or the like.
+///
+/// Every method does nothing unless implemented. They are called while
+/// encoding and decoding and should be quick, such as bumping an atomic. One
+/// instance can be shared by the codecs of many connections.
+pub trait Metrics: Send + Sync {
+    /// A packet was encoded, taking `len` bytes on the wire with its length
+    /// field, padding and MAC
+    fn on_packet_sent(&self, _len: usize) {}
+
+    /// A packet was decoded, `len` counted like for
+    /// [`on_packet_sent`](Self::on_packet_sent)
+    fn on_packet_received(&self, _len: usize) {}
+
+    /// New keys were installed for `direction`, which every key exchange
+    /// does once per direction, the first one included
+    fn on_rekey(&self, _direction: Direction) {}
+}
+
+impl fmt::Debug for dyn Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Metrics").finish_non_exhaustive()
+    }
+}
+
+/// Encoder and decoder for SSH binary packets (RFC 4253 §6).
+///
+/// The codec isn't `Clone`, since neither the aws-lc-rs cipher contexts nor
+/// the zlib streams it carries can be duplicated mid-connection.
+#[derive(Debug)]
 pub struct PacketCodec {
     /// Decode state machine
     state: DecodeState,
@@ -22,17 +112,100 @@ pub struct PacketCodec {
     cipher_block_size: usize,
     // Used for generating random padding
     rng_provider: SystemRandom,
+    /// Cipher applied to outgoing packets, [`NullCipher`] until keys are
+    /// installed
+    encrypt: Box<dyn PacketCipher>,
+    /// Cipher applied to incoming packets
+    decrypt: Box<dyn PacketCipher>,
+    /// MAC appended to outgoing packets, if any
+    sign: Option<Box<dyn Mac>>,
+    /// MAC checked on incoming packets, if any
+    verify: Option<Box<dyn Mac>>,
+    /// Compression applied to outgoing payloads, if any
+    compress: Option<Compression>,
+    /// Decompression applied to incoming payloads, if any
+    decompress: Option<Compression>,
+    /// Whether `zlib@openssh.com` has been started, after which it is on
+    /// from the first packet under new keys
+    delayed_compression_started: bool,
+    /// Sequence number of the next packet to decode
+    recv_seq: u32,
+    /// Sequence number of the next packet to encode
+    send_seq: u32,
+    /// Bytes after which a rekey is due
+    rekey_bytes: u64,
+    /// Packets after which a rekey is due
+    rekey_packets: u64,
+    /// Bytes sent and received since keys were last installed
+    bytes_since_rekey: u64,
+    /// Packets sent and received since keys were last installed
+    packets_since_rekey: u64,
+    metrics: Option<Arc<dyn Metrics>>,
+}
+
+/// Builder for [`PacketCodec`], see [`PacketCodec::builder`]
+#[derive(Debug, Clone)]
+pub struct PacketCodecBuilder {
+    max_packet_size: usize,
+    mac_length: usize,
+    cipher_block_size: usize,
+    metrics: Option<Arc<dyn Metrics>>,
+}
+
+/// Which half of the connection a setting applies to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Packets we receive and decode
+    Incoming,
+    /// Packets we encode and send
+    Outgoing,
+}
+
+/// Whether the packet length field counts towards the block alignment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PaddingMode {
+    /// The length is encrypted with the rest of the packet, as in RFC 4253
+    IncludeLength,
+    /// The length is kept out of the encrypted blob, as AEAD ciphers do
+    ExcludeLength,
+}
+
+/// Where the payload and MAC are within a decrypted packet
+#[derive(Debug, Clone)]
+struct Layout {
+    payload: Range<usize>,
+    mac: Option<Range<usize>>,
 }
 
+/// Where the decoder is within the current packet.
+///
+/// Decrypting the length moves the cipher forward, so the state only leaves
+/// `Head` once the first block has been decrypted in place. From then on the
+/// head is never looked at again until the whole packet has arrived, however
+/// many calls that takes.
 #[derive(Debug, Clone, Copy)]
 enum DecodeState {
+    /// Waiting for enough bytes to read the packet length
     Head,
+    /// The head has been read (and decrypted), waiting for the given number
+    /// of bytes in total
     Data(usize),
 }
 
 impl PacketCodec {
     const HEAD_SIZE: usize = 4;
     const MIN_BLOCK_SIZE: usize = 8;
+    const MIN_PADDING_LENGTH: u8 = 4;
+
+    /// Largest packet accepted before any limits have been negotiated, RFC
+    /// 4253 section 6.1 requires at least 35000 bytes
+    pub const DEFAULT_MAX_PACKET_SIZE: usize = 35000;
+
+    /// RFC 4253 section 9 recommends rekeying after a gigabyte of data
+    pub const DEFAULT_REKEY_BYTES: u64 = 1 << 30;
+    /// Well below the 2^32 packets at which sequence numbers wrap, see RFC
+    /// 4344 section 3.1
+    pub const DEFAULT_REKEY_PACKETS: u64 = 1 << 31;
 
     pub fn new(max_packet_size: usize, mac_length: usize) -> Self {
         Self {
@@ -41,9 +214,31 @@ impl PacketCodec {
             mac_length,
             cipher_block_size: 0,
             rng_provider: SystemRandom::new(),
+            encrypt: Box::new(NullCipher),
+            decrypt: Box::new(NullCipher),
+            sign: None,
+            verify: None,
+            compress: None,
+            decompress: None,
+            delayed_compression_started: false,
+            recv_seq: 0,
+            send_seq: 0,
+            rekey_bytes: Self::DEFAULT_REKEY_BYTES,
+            rekey_packets: Self::DEFAULT_REKEY_PACKETS,
+            bytes_since_rekey: 0,
+            packets_since_rekey: 0,
+            metrics: None,
         }
     }
 
+    /// Builds a codec with labeled settings. Left alone, it builds one
+    /// without encryption or MAC that accepts packets of up to
+    /// [`DEFAULT_MAX_PACKET_SIZE`](Self::DEFAULT_MAX_PACKET_SIZE) bytes, the
+    /// same as `new(DEFAULT_MAX_PACKET_SIZE, 0)`.
+    pub fn builder() -> PacketCodecBuilder {
+        PacketCodecBuilder::default()
+    }
+
     pub fn max_packet_size(&self) -> usize {
         self.max_packet_size
     }
@@ -56,6 +251,64 @@ impl PacketCodec {
         self.cipher_block_size
     }
 
+    /// Sequence number of the next packet to be decoded.
+    ///
+    /// Starts at 0 and wraps around at 2^32, it is only reset by
+    /// [`reset_seq`](Self::reset_seq).
+    pub fn recv_seq(&self) -> u32 {
+        self.recv_seq
+    }
+
+    /// Sequence number of the next packet to be encoded.
+    ///
+    /// Starts at 0 and wraps around at 2^32, it is only reset by
+    /// [`reset_seq`](Self::reset_seq).
+    pub fn send_seq(&self) -> u32 {
+        self.send_seq
+    }
+
+    /// Sets the sequence number of `direction` back to 0, which strict key
+    /// exchange does after each NEWKEYS.
+    ///
+    /// Plain RFC 4253 connections never reset them.
+    pub fn reset_seq(&mut self, direction: Direction) {
+        match direction {
+            Direction::Incoming => self.recv_seq = 0,
+            Direction::Outgoing => self.send_seq = 0,
+        }
+    }
+
+    /// Whether enough data went through the current keys that a new key
+    /// exchange should be started.
+    ///
+    /// Both directions count towards the thresholds, which start over every
+    /// time a cipher is installed. Time based rekeying is up to the caller.
+    pub fn should_rekey(&self) -> bool {
+        self.bytes_since_rekey >= self.rekey_bytes || self.packets_since_rekey >= self.rekey_packets
+    }
+
+    pub fn set_rekey_bytes(&mut self, bytes: u64) {
+        self.rekey_bytes = bytes;
+    }
+
+    pub fn set_rekey_packets(&mut self, packets: u64) {
+        self.rekey_packets = packets;
+    }
+
+    /// Reports packets and key changes to `metrics` from now on, `None`
+    /// stopping that
+    pub fn set_metrics(&mut self, metrics: Option<Arc<dyn Metrics>>) {
+        self.metrics = metrics;
+    }
+
+    /// Limits the size of whole frames, MAC included, in both directions.
+    ///
+    /// RFC 4253 section 6.1 requires accepting at least
+    /// [`DEFAULT_MAX_PACKET_SIZE`](Self::DEFAULT_MAX_PACKET_SIZE) bytes,
+    /// less only works with peers known to send small packets. Limits too
+    /// small for even an empty payload are raised to fit one with whatever
+    /// cipher and MAC are installed, so no limit makes every packet too
+    /// large.
     pub fn set_max_packet_size(&mut self, val: usize) {
         self.max_packet_size = val;
     }
@@ -68,36 +321,278 @@ impl PacketCodec {
         self.cipher_block_size = block_size;
     }
 
+    /// Returns the cipher currently installed for `direction`, if any
+    pub fn cipher(&self, direction: Direction) -> Option<CipherAlgorithm> {
+        match direction {
+            Direction::Incoming => self.decrypt.algorithm(),
+            Direction::Outgoing => self.encrypt.algorithm(),
+        }
+    }
+
+    /// Installs a cipher for one direction of the connection.
+    ///
+    /// This is meant to be called once the key exchange is done, with the key
+    /// and IV derived for that direction. Every packet encoded (or decoded)
+    /// afterwards goes through the cipher.
+    ///
+    /// AEAD ciphers such as `chacha20-poly1305@openssh.com` authenticate the
+    /// packets themselves and take no IV, their tag is handled separately
+    /// from `mac_length`.
+    pub fn set_cipher(
+        &mut self,
+        direction: Direction,
+        algorithm: CipherAlgorithm,
+        key: &[u8],
+        iv: &[u8],
+    ) -> io::Result<()> {
+        let cipher = new_cipher(direction, algorithm, key, iv)?;
+
+        self.bytes_since_rekey = 0;
+        self.packets_since_rekey = 0;
+
+        match direction {
+            Direction::Incoming => self.decrypt = cipher,
+            Direction::Outgoing => {
+                self.cipher_block_size = algorithm.block_size();
+                self.encrypt = cipher;
+            }
+        }
+
+        if let Some(metrics) = &self.metrics {
+            metrics.on_rekey(direction);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the MAC currently installed for `direction`, if any
+    pub fn mac(&self, direction: Direction) -> Option<MacAlgorithm> {
+        match direction {
+            Direction::Incoming => self.verify.as_ref(),
+            Direction::Outgoing => self.sign.as_ref(),
+        }
+        .map(|mac| mac.algorithm())
+    }
+
+    /// Installs a MAC for one direction of the connection.
+    ///
+    /// Outgoing packets get the MAC of their sequence number and plaintext
+    /// appended, incoming packets are rejected unless theirs matches. The MAC
+    /// is skipped while an AEAD cipher is installed for the same direction.
+    pub fn set_mac(
+        &mut self,
+        direction: Direction,
+        algorithm: MacAlgorithm,
+        key: &[u8],
+    ) -> io::Result<()> {
+        let mac = new_mac(algorithm, key)?;
+
+        match direction {
+            Direction::Incoming => self.verify = Some(mac),
+            Direction::Outgoing => self.sign = Some(mac),
+        }
+
+        Ok(())
+    }
+
+    /// Returns the compression currently installed for `direction`, if any
+    pub fn compression(&self, direction: Direction) -> Option<CompressionAlgorithm> {
+        match direction {
+            Direction::Incoming => self.decompress.as_ref(),
+            Direction::Outgoing => self.compress.as_ref(),
+        }
+        .map(Compression::algorithm)
+    }
+
+    /// Installs compression for one direction of the connection.
+    ///
+    /// Like the cipher and MAC this takes effect with the next packet, except
+    /// for `zlib@openssh.com` which stays off until
+    /// [`start_delayed_compression`](Self::start_delayed_compression) is
+    /// called. Once it has been, rekeying turns it on right away.
+    ///
+    /// Every call starts a new zlib stream, as RFC 4253 section 6.2 has the
+    /// compression context initialized after each key exchange and OpenSSH
+    /// does on both ends.
+    pub fn set_compression(&mut self, direction: Direction, algorithm: CompressionAlgorithm) {
+        let mut compression = match direction {
+            Direction::Incoming => Compression::decompressor(algorithm),
+            Direction::Outgoing => Compression::compressor(algorithm),
+        };
+
+        if self.delayed_compression_started {
+            compression.activate();
+        }
+
+        match direction {
+            Direction::Incoming => self.decompress = Some(compression),
+            Direction::Outgoing => self.compress = Some(compression),
+        }
+    }
+
+    /// Turns on `zlib@openssh.com` compression in both directions, for the
+    /// current keys and any installed later.
+    ///
+    /// To be called once SSH_MSG_USERAUTH_SUCCESS has been sent or received,
+    /// the payloads of every packet after it are compressed.
+    pub fn start_delayed_compression(&mut self) {
+        self.delayed_compression_started = true;
+
+        self.compress.iter_mut().for_each(Compression::activate);
+        self.decompress.iter_mut().for_each(Compression::activate);
+    }
+
+    fn incoming_mac(&self) -> Option<&dyn Mac> {
+        match self.decrypt.is_aead() {
+            true => None,
+            false => self.verify.as_deref(),
+        }
+    }
+
+    fn outgoing_mac(&self) -> Option<&dyn Mac> {
+        match self.encrypt.is_aead() {
+            true => None,
+            false => self.sign.as_deref(),
+        }
+    }
+
+    /// Length of the MAC trailing incoming packets, falling back to the
+    /// unchecked `mac_length` when no MAC is installed
+    fn incoming_mac_length(&self) -> usize {
+        match self.incoming_mac() {
+            Some(mac) => mac.output_length(),
+            None if self.decrypt.is_aead() => 0,
+            None => self.mac_length,
+        }
+    }
+
+    fn outgoing_mac_length(&self) -> usize {
+        match self.outgoing_mac() {
+            Some(mac) => mac.output_length(),
+            None if self.encrypt.is_aead() => 0,
+            None => self.mac_length,
+        }
+    }
+
+    /// Largest frame allowed in `direction`, which is
+    /// [`max_packet_size`](Self::max_packet_size) unless not even a packet
+    /// with an empty payload would fit
+    fn packet_size_limit(&self, direction: Direction) -> usize {
+        let (tag_length, mac_length) = match direction {
+            Direction::Incoming => (self.decrypt.tag_length(), self.incoming_mac_length()),
+            Direction::Outgoing => (self.encrypt.tag_length(), self.outgoing_mac_length()),
+        };
+        let padding_length = self.calculate_padding_length(0, self.padding_mode(direction));
+        let smallest = Self::HEAD_SIZE + 1 + padding_length as usize + tag_length + mac_length;
+
+        self.max_packet_size.max(smallest)
+    }
+
+    /// Number of bytes needed before the length of the next packet can be
+    /// read.
+    ///
+    /// Under encryption the length is inside the first cipher block, so we
+    /// have to wait for the whole block before we can read it. EtM MACs leave
+    /// it in the clear.
+    fn head_size(&self) -> usize {
+        match self.incoming_etm() {
+            true => Self::HEAD_SIZE,
+            false => self.decrypt.head_size(),
+        }
+    }
+
+    fn incoming_etm(&self) -> bool {
+        self.incoming_mac().is_some_and(|mac| mac.is_etm())
+    }
+
+    fn outgoing_etm(&self) -> bool {
+        self.outgoing_mac().is_some_and(|mac| mac.is_etm())
+    }
+
     fn decode_head(&mut self, src: &mut BytesMut) -> io::Result<Option<usize>> {
-        if src.len() < Self::HEAD_SIZE {
-            // Not enough data
+        let head_size = self.head_size();
+
+        if src.len() < head_size {
+            // Not enough data, and nothing has been decrypted yet so this can
+            // be retried safely once more arrives
             return Ok(None);
         }
 
-        // Use Cursor to peek at the length without advancing the buffer
-        // This is more efficient than manual indexing
-        let packet_length = {
-            let mut cursor = Cursor::new(&src[..]);
-            cursor.get_u32()
+        let etm = self.incoming_etm();
+
+        let packet_length = match etm {
+            // Peek at the length without advancing the buffer, EtM sends it
+            // in the clear
+            true => u32::from_be_bytes([src[0], src[1], src[2], src[3]]),
+            // Only reached once per packet, as the cipher state may move
+            // forward with every call. The state switches to `Data` right
+            // after.
+            false => self
+                .decrypt
+                .decrypt_length(self.recv_seq, &mut src[..head_size])?,
         } as usize;
 
+        let tag_length = self.decrypt.tag_length();
+
         // Calculate total frame size
-        // SSH format: [4-byte length][packet_length bytes][mac_length bytes]
-        let total_frame_size = 4 + packet_length + self.mac_length;
+        // SSH format: [4-byte length][packet_length bytes][tag or MAC bytes]
+        //
+        // A length near u32::MAX overflows a 32-bit usize, which counts as
+        // too large rather than wrapping around to something small
+        let total_frame_size = packet_length
+            .checked_add(4 + tag_length)
+            .and_then(|size| size.checked_add(self.incoming_mac_length()));
 
         // Check against max packet size (SSH spec: 35000 bytes)
-        if total_frame_size > self.max_packet_size {
+        let total_frame_size = match total_frame_size {
+            Some(size) if size <= self.packet_size_limit(Direction::Incoming) => size,
+            Some(size) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("SSH packet too large: {} bytes", size),
+                ));
+            }
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("SSH packet length {} overflows", packet_length),
+                ));
+            }
+        };
+
+        // Check minimum packet size (must have at least padding_length byte)
+        if packet_length < 1 {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidData,
-                format!("SSH packet too large: {} bytes", total_frame_size),
+                "SSH packet too small",
             ));
         }
 
-        // Check minimum packet size (must have at least padding_length byte)
-        if packet_length < 1 {
+        // RFC 4253 section 6: the packet is a multiple of the cipher block
+        // size, or of 8 without encryption, and at least 16 bytes long. When
+        // the length field is left out of the blocks OpenSSH happily sends
+        // packets of a single block.
+        let block_size = self.decrypt.block_size();
+
+        let (aligned_length, min_length) = match self.padding_mode(Direction::Incoming) {
+            PaddingMode::IncludeLength => (4 + packet_length, 16),
+            PaddingMode::ExcludeLength => (packet_length, block_size),
+        };
+
+        if !aligned_length.is_multiple_of(block_size) {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidData,
-                "SSH packet too small",
+                format!(
+                    "SSH packet is not a multiple of the block size: {} bytes",
+                    aligned_length
+                ),
+            ));
+        }
+
+        if aligned_length < min_length {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("SSH packet too small: {} bytes", aligned_length),
             ));
         }
 
@@ -108,17 +603,292 @@ impl PacketCodec {
         Ok(Some(total_frame_size))
     }
 
-    fn decode_data(&self, n: usize, src: &mut BytesMut) -> Option<BytesMut> {
+    /// Checks and decrypts the first `n` bytes of `src` in place once they
+    /// have all arrived, returning whether they have
+    fn decode_data(&mut self, n: usize, src: &mut BytesMut) -> io::Result<bool> {
         // At this point, the buffer has already had the required capacity
         // reserved. All there is to do is read.
         if src.len() < n {
-            return None;
+            return Ok(false);
+        }
+
+        // The MAC is never encrypted and the tag follows the ciphertext
+        let mac_length = self.incoming_mac_length();
+        let tag_length = self.decrypt.tag_length();
+        let packet_end = n - mac_length - tag_length;
+
+        if self.incoming_etm() {
+            // The MAC covers the length and ciphertext, so nothing gets
+            // decrypted unless it checks out
+            if let Some(mac) = self.incoming_mac() {
+                mac.verify(self.recv_seq, &src[..packet_end], &src[n - mac_length..n])?;
+            }
+
+            self.decrypt.apply_keystream(&mut src[4..packet_end])?;
+        } else {
+            self.decrypt
+                .decrypt_packet(self.recv_seq, &mut src[..n - mac_length])?;
+
+            // The MAC covers the plaintext, so it can only be checked now
+            if let Some(mac) = self.incoming_mac() {
+                mac.verify(self.recv_seq, &src[..packet_end], &src[n - mac_length..n])?;
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Runs the decode state machine, returning the size of the next packet
+    /// and where its payload and MAC are once it has been decrypted in
+    /// place at the front of `src`
+    fn decode_frame(&mut self, src: &mut BytesMut) -> io::Result<Option<(usize, Layout)>> {
+        let n = match self.state {
+            DecodeState::Head => match self.decode_head(src)? {
+                Some(n) => {
+                    self.state = DecodeState::Data(n);
+                    n
+                }
+                None => return Ok(None),
+            },
+            DecodeState::Data(n) => n,
+        };
+
+        if !self.decode_data(n, src)? {
+            return Ok(None);
+        }
+
+        // Back to a clean state, so that a packet already sitting in `src`
+        // gets decoded by the next call without any more reads
+        self.state = DecodeState::Head;
+
+        let layout = self.layout(&src[..n])?;
+
+        self.recv_seq = self.recv_seq.wrapping_add(1);
+        self.count_for_rekey(n);
+
+        if let Some(metrics) = &self.metrics {
+            metrics.on_packet_received(n);
+        }
+
+        Ok(Some((n, layout)))
+    }
+
+    /// Finds the payload and MAC of a decrypted packet
+    fn layout(&self, frame: &[u8]) -> io::Result<Layout> {
+        // decode_head made sure the frame holds the length, at least the
+        // padding length byte, and the tag and MAC
+        let packet_length = u32::from_be_bytes([frame[0], frame[1], frame[2], frame[3]]);
+        let padding_length = frame[4];
+
+        // RFC 4253 section 6 mandates at least 4 bytes of padding
+        if padding_length < Self::MIN_PADDING_LENGTH {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("SSH padding length {} is below 4", padding_length),
+            ));
         }
 
-        Some(src.split_to(n))
+        // packet_length covers the padding_length byte, the payload and the
+        // padding, so the padding can never exceed it
+        let payload_length = packet_length
+            .checked_sub(padding_length as u32 + 1)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "SSH padding length {} exceeds packet length {}",
+                        padding_length, packet_length
+                    ),
+                )
+            })? as usize;
+
+        trace!(
+            seq = self.recv_seq,
+            packet_length, padding_length, payload_length, "Decoded packet"
+        );
+
+        let payload_start = 5;
+        let payload_end = payload_start + payload_length;
+
+        // The AEAD tag was checked during decryption
+        let mac_start = payload_end + padding_length as usize + self.decrypt.tag_length();
+        let mac_length = self.incoming_mac_length();
+
+        Ok(Layout {
+            payload: payload_start..payload_end,
+            mac: (mac_length > 0).then_some(mac_start..mac_start + mac_length),
+        })
+    }
+
+    /// Like [`decode_packet`](Self::decode_packet) but without taking the
+    /// packet out of `src`, the payload is instead a view into the buffer.
+    ///
+    /// That saves splitting off a new [`Bytes`] for every packet, which
+    /// matters for callers that handle packets one by one without keeping
+    /// them around. The packet stays at the front of `src`, which can't be
+    /// used otherwise meanwhile, until the [`PacketRef`] is dropped. Only
+    /// decompressed payloads are copied.
+    pub fn decode_borrowed<'a>(
+        &mut self,
+        src: &'a mut BytesMut,
+    ) -> io::Result<Option<PacketRef<'a>>> {
+        let Some((len, layout)) = self.decode_frame(src)? else {
+            return Ok(None);
+        };
+
+        let decompressed = match &mut self.decompress {
+            Some(decompress) => {
+                decompress.process_slice(&src[layout.payload.clone()], self.max_packet_size)?
+            }
+            None => None,
+        };
+
+        Ok(Some(PacketRef {
+            src,
+            len,
+            layout,
+            decompressed: decompressed.map(Into::into),
+            head_size: self.head_size(),
+        }))
     }
 
-    fn calculate_padding_length(&self, payload_len: usize) -> u8 {
+    /// Takes the next packet out of `src`, `None` meaning more bytes are
+    /// needed first.
+    ///
+    /// This is what the [`Decoder`] implementation calls, usable without
+    /// tokio by anything that reads into a [`BytesMut`].
+    pub fn decode_packet(&mut self, src: &mut BytesMut) -> io::Result<Option<Packet>> {
+        let Some((n, layout)) = self.decode_frame(src)? else {
+            return Ok(None);
+        };
+
+        let packet = src.split_to(n).freeze();
+
+        // Make sure the buffer has enough space to read the next head
+        src.reserve(self.head_size().saturating_sub(src.len()));
+
+        let payload = packet.slice(layout.payload);
+        let mac = layout.mac.map(|mac| packet.slice(mac));
+
+        let payload = match &mut self.decompress {
+            Some(decompress) => decompress.process(payload, self.max_packet_size)?,
+            None => payload,
+        };
+
+        Ok(Some(Packet { payload, mac }))
+    }
+
+    /// Frames `packet`, appending it to `dst` ready to be written out.
+    ///
+    /// This is what the [`Encoder`] implementation calls, see
+    /// [`decode_packet`](Self::decode_packet).
+    pub fn encode_packet(&mut self, packet: Packet, dst: &mut BytesMut) -> io::Result<()> {
+        let Packet { payload, mac: _mac } = packet;
+
+        // Padding is computed over the payload as sent, so compress first
+        let payload = match &mut self.compress {
+            Some(compress) => compress.process(payload, usize::MAX)?,
+            None => payload,
+        };
+
+        let padding_length =
+            self.calculate_padding_length(payload.len(), self.padding_mode(Direction::Outgoing));
+        let packet_length = 1 + payload.len() + padding_length as usize;
+        let tag_length = self.encrypt.tag_length();
+        let mac_length = self.outgoing_mac_length();
+        let total_size = 4 + packet_length + tag_length + mac_length;
+
+        if total_size > self.packet_size_limit(Direction::Outgoing) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("SSH packet too large: {} bytes", total_size),
+            ));
+        }
+
+        let start = dst.len();
+
+        dst.reserve(total_size);
+        dst.put_u32(packet_length as u32);
+        dst.put_u8(padding_length);
+        dst.extend_from_slice(&payload[..]);
+
+        // Write padding: random if encrypted, zeros before encryption
+        let padding_start = dst.len();
+        dst.put_bytes(0, padding_length as usize);
+
+        // No encryption: zero padding (like OpenSSH), otherwise randomized
+        // in place
+        if self.cipher_block_size != 0 {
+            self.rng_provider
+                .fill(&mut dst[padding_start..])
+                .map_err(|e| io::Error::other(format!("RNG error: {:?}", e)))?;
+        }
+
+        // Room for the tag and MAC, filled in below
+        if tag_length > 0 || self.outgoing_mac().is_some() {
+            dst.put_bytes(0, tag_length + mac_length);
+        }
+
+        let (packet, trailer) = dst[start..].split_at_mut(4 + packet_length);
+        let (tag, mac) = trailer.split_at_mut(tag_length);
+
+        if self.outgoing_etm() {
+            // Only what follows the length is encrypted, then the MAC goes
+            // over the length and ciphertext
+            self.encrypt.apply_keystream(&mut packet[4..])?;
+
+            if let Some(signer) = self.outgoing_mac() {
+                signer.compute(self.send_seq, packet, mac);
+            }
+        } else {
+            // The MAC covers the plaintext, so it has to be computed first
+            if let Some(signer) = self.outgoing_mac() {
+                signer.compute(self.send_seq, packet, mac);
+            }
+
+            self.encrypt.encrypt_packet(self.send_seq, packet, tag)?;
+        }
+
+        trace!(
+            seq = self.send_seq,
+            packet_length,
+            padding_length,
+            payload_length = payload.len(),
+            "Encoded packet"
+        );
+
+        // Less than `total_size` when no MAC is installed to fill the
+        // unchecked `mac_length`
+        let written = dst.len() - start;
+
+        self.send_seq = self.send_seq.wrapping_add(1);
+        self.count_for_rekey(written);
+
+        if let Some(metrics) = &self.metrics {
+            metrics.on_packet_sent(written);
+        }
+
+        Ok(())
+    }
+
+    fn count_for_rekey(&mut self, packet_size: usize) {
+        self.bytes_since_rekey = self.bytes_since_rekey.saturating_add(packet_size as u64);
+        self.packets_since_rekey = self.packets_since_rekey.saturating_add(1);
+    }
+
+    fn padding_mode(&self, direction: Direction) -> PaddingMode {
+        let (cipher, etm) = match direction {
+            Direction::Incoming => (&self.decrypt, self.incoming_etm()),
+            Direction::Outgoing => (&self.encrypt, self.outgoing_etm()),
+        };
+
+        match cipher.is_aead() || etm {
+            true => PaddingMode::ExcludeLength,
+            false => PaddingMode::IncludeLength,
+        }
+    }
+
+    fn calculate_padding_length(&self, payload_len: usize, mode: PaddingMode) -> u8 {
         // Determine effective block size
         let block_size = if self.cipher_block_size == 0 {
             Self::MIN_BLOCK_SIZE // No encryption: use RFC minimum of 8
@@ -128,8 +898,13 @@ impl PacketCodec {
 
         let block_size = block_size.max(8);
 
+        let length_field = match mode {
+            PaddingMode::IncludeLength => 4,
+            PaddingMode::ExcludeLength => 0,
+        };
+
         // Current length: 4 bytes (packet_length) + 1 byte (padding_length) + payload
-        let current_len = 4 + 1 + payload_len;
+        let current_len = length_field + 1 + payload_len;
 
         // Calculate padding needed to reach next block boundary
         let mut padding_len = block_size - (current_len % block_size);
@@ -143,89 +918,913 @@ impl PacketCodec {
     }
 }
 
+impl Default for PacketCodecBuilder {
+    fn default() -> Self {
+        Self {
+            max_packet_size: PacketCodec::DEFAULT_MAX_PACKET_SIZE,
+            mac_length: 0,
+            cipher_block_size: 0,
+            metrics: None,
+        }
+    }
+}
+
+impl PacketCodecBuilder {
+    pub fn max_packet_size(mut self, max_packet_size: usize) -> Self {
+        self.max_packet_size = max_packet_size;
+        self
+    }
+
+    pub fn mac_length(mut self, mac_length: usize) -> Self {
+        self.mac_length = mac_length;
+        self
+    }
+
+    /// Pads outgoing packets to `block_size`, 0 being no encryption.
+    /// Installing a cipher sets this as well.
+    pub fn cipher_block_size(mut self, block_size: usize) -> Self {
+        self.cipher_block_size = block_size;
+        self
+    }
+
+    /// See [`PacketCodec::set_metrics`]
+    pub fn metrics(mut self, metrics: Arc<dyn Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    pub fn build(self) -> PacketCodec {
+        let mut codec = PacketCodec::new(self.max_packet_size, self.mac_length);
+        codec.set_cipher_block_size(self.cipher_block_size);
+        codec.set_metrics(self.metrics);
+
+        codec
+    }
+}
+
 impl Decoder for PacketCodec {
     type Item = Packet;
     type Error = io::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Packet>> {
-        let n = match self.state {
-            DecodeState::Head => match self.decode_head(src)? {
-                Some(n) => {
-                    self.state = DecodeState::Data(n);
-                    n
-                }
-                None => return Ok(None),
-            },
-            DecodeState::Data(n) => n,
+        self.decode_packet(src)
+    }
+}
+
+impl Encoder<Packet> for PacketCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, packet: Packet, dst: &mut BytesMut) -> Result<(), io::Error> {
+        self.encode_packet(packet, dst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    fn packet(payload: &'static [u8]) -> Packet {
+        Packet {
+            payload: Bytes::from_static(payload),
+            mac: None,
+        }
+    }
+
+    /// A sending and a receiving codec sharing keys for `algorithm`
+    fn cipher_pair(algorithm: CipherAlgorithm) -> (PacketCodec, PacketCodec) {
+        let key = vec![0x42; algorithm.key_length()];
+        let iv = vec![0x24; algorithm.iv_length()];
+
+        let mut sender = PacketCodec::builder().build();
+        sender
+            .set_cipher(Direction::Outgoing, algorithm, &key, &iv)
+            .unwrap();
+
+        let mut receiver = PacketCodec::builder().build();
+        receiver
+            .set_cipher(Direction::Incoming, algorithm, &key, &iv)
+            .unwrap();
+
+        (sender, receiver)
+    }
+
+    /// Like [`cipher_pair`] for a MAC without encryption
+    fn mac_pair(algorithm: MacAlgorithm) -> (PacketCodec, PacketCodec) {
+        let key = vec![0x42; algorithm.key_length()];
+
+        let mut sender = PacketCodec::builder().build();
+        sender
+            .set_mac(Direction::Outgoing, algorithm, &key)
+            .unwrap();
+
+        let mut receiver = PacketCodec::builder().build();
+        receiver
+            .set_mac(Direction::Incoming, algorithm, &key)
+            .unwrap();
+
+        (sender, receiver)
+    }
+
+    #[test]
+    fn fresh_codec_wire_format() {
+        // These are the bytes the codec wrote before ciphers existed, with
+        // zero padding up to the 8 byte minimum block size
+        let mut codec = PacketCodec::builder().build();
+        let mut wire = BytesMut::new();
+
+        codec.encode(packet(b"hello"), &mut wire).unwrap();
+        assert_eq!(wire, &b"\0\0\0\x0c\x06hello\0\0\0\0\0\0"[..]);
+
+        wire.clear();
+        codec.encode(packet(b"abc"), &mut wire).unwrap();
+        assert_eq!(wire, &b"\0\0\0\x0c\x08abc\0\0\0\0\0\0\0\0"[..]);
+
+        assert_eq!(codec.send_seq(), 2);
+    }
+
+    #[test]
+    fn inherent_api_matches_traits() {
+        // Without a cipher the padding is all zeros, so both senders write
+        // the same bytes
+        let (mut sender, mut receiver) = mac_pair(MacAlgorithm::HmacSha256);
+        let (mut trait_sender, mut trait_receiver) = mac_pair(MacAlgorithm::HmacSha256);
+
+        for payload in [&b"first"[..], b"second", b""] {
+            let mut wire = BytesMut::new();
+            sender.encode_packet(packet(payload), &mut wire).unwrap();
+
+            let mut trait_wire = BytesMut::new();
+            Encoder::encode(&mut trait_sender, packet(payload), &mut trait_wire).unwrap();
+            assert_eq!(wire, trait_wire);
+
+            let decoded = receiver.decode_packet(&mut wire).unwrap().unwrap();
+            let trait_decoded = Decoder::decode(&mut trait_receiver, &mut trait_wire)
+                .unwrap()
+                .unwrap();
+            assert_eq!(decoded, trait_decoded);
+            assert_eq!(decoded.payload, payload);
+
+            assert_eq!(receiver.decode_packet(&mut wire).unwrap(), None);
+            assert!(wire.is_empty());
+        }
+
+        assert_eq!(sender.send_seq(), trait_sender.send_seq());
+        assert_eq!(receiver.recv_seq(), trait_receiver.recv_seq());
+    }
+
+    #[test]
+    fn packet_length_blocking() {
+        let mut codec = PacketCodec::builder().build();
+
+        // 4 + 5 bytes aren't a multiple of the 8 byte blocks
+        let mut src = BytesMut::new();
+        src.put_u32(5);
+        src.put_u8(4);
+        src.put_bytes(0, 4);
+
+        let err = codec.decode(&mut src).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // 4 + 12 are
+        let mut src = BytesMut::new();
+        src.put_u32(12);
+        src.put_u8(4);
+        src.put_slice(b"payload");
+        src.put_bytes(0, 4);
+
+        let decoded = codec.decode(&mut src).unwrap().unwrap();
+        assert_eq!(decoded.payload, &b"payload"[..]);
+    }
+
+    #[test]
+    fn padding_too_short() {
+        let mut codec = PacketCodec::builder().build();
+
+        // A well aligned packet with only 2 bytes of padding
+        let mut src = BytesMut::new();
+        src.put_u32(12);
+        src.put_u8(2);
+        src.put_slice(b"too short");
+        src.put_bytes(0, 2);
+
+        let err = codec.decode(&mut src).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "SSH padding length 2 is below 4");
+    }
+
+    #[test]
+    fn padding_modes() {
+        let codec = PacketCodec::builder().build();
+
+        // Payload length, then padding with and without the length field
+        // counting towards the 8 byte blocks
+        for (payload_len, include, exclude) in [(0, 11, 7), (3, 8, 4), (7, 4, 8), (11, 8, 4)] {
+            assert_eq!(
+                codec.calculate_padding_length(payload_len, PaddingMode::IncludeLength),
+                include,
+                "{payload_len} bytes"
+            );
+            assert_eq!(
+                codec.calculate_padding_length(payload_len, PaddingMode::ExcludeLength),
+                exclude,
+                "{payload_len} bytes"
+            );
+        }
+
+        for payload_len in 0..64 {
+            for (mode, length_field) in [
+                (PaddingMode::IncludeLength, 4),
+                (PaddingMode::ExcludeLength, 0),
+            ] {
+                let padding_len = codec.calculate_padding_length(payload_len, mode) as usize;
+
+                assert!((4..4 + 8).contains(&padding_len), "{mode:?}");
+                assert_eq!((length_field + 1 + payload_len + padding_len) % 8, 0);
+            }
+        }
+
+        // EtM keeps the length out, as AEAD ciphers do
+        for (algorithm, mode) in [
+            (MacAlgorithm::HmacSha256, PaddingMode::IncludeLength),
+            (MacAlgorithm::HmacSha256Etm, PaddingMode::ExcludeLength),
+        ] {
+            let (sender, receiver) = mac_pair(algorithm);
+            assert_eq!(sender.padding_mode(Direction::Outgoing), mode);
+            assert_eq!(receiver.padding_mode(Direction::Incoming), mode);
+        }
+    }
+
+    #[test]
+    fn padding_written_in_place() {
+        for algorithm in [
+            CipherAlgorithm::Aes128Ctr,
+            CipherAlgorithm::ChaCha20Poly1305,
+        ] {
+            let (mut sender, mut receiver) = cipher_pair(algorithm);
+            let mode = sender.padding_mode(Direction::Outgoing);
+
+            for payload in [&b""[..], b"abc", &[0xaa; 100]] {
+                let expected = sender.calculate_padding_length(payload.len(), mode);
+
+                let mut wire = BytesMut::new();
+                sender
+                    .encode(
+                        Packet {
+                            payload: Bytes::copy_from_slice(payload),
+                            mac: None,
+                        },
+                        &mut wire,
+                    )
+                    .unwrap();
+
+                // The frame is left decrypted in the buffer
+                let (_, layout) = receiver.decode_frame(&mut wire).unwrap().unwrap();
+                let padding_length = wire[4];
+                let padding = &wire[layout.payload.end..][..padding_length as usize];
+
+                assert_eq!(padding_length, expected, "{algorithm:?}");
+                assert_eq!(&wire[layout.payload], payload, "{algorithm:?}");
+                assert!(padding.iter().any(|&byte| byte != 0), "{algorithm:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn builder_matches_new() {
+        for (max_packet_size, mac_length) in [(PacketCodec::DEFAULT_MAX_PACKET_SIZE, 0), (1024, 16)]
+        {
+            let mut built = PacketCodec::builder()
+                .max_packet_size(max_packet_size)
+                .mac_length(mac_length)
+                .build();
+            let mut new = PacketCodec::new(max_packet_size, mac_length);
+
+            assert_eq!(format!("{built:?}"), format!("{new:?}"));
+
+            let mut built_wire = BytesMut::new();
+            let mut new_wire = BytesMut::new();
+            for payload in [&b"first"[..], b"second"] {
+                built.encode(packet(payload), &mut built_wire).unwrap();
+                new.encode(packet(payload), &mut new_wire).unwrap();
+            }
+
+            assert_eq!(built_wire, new_wire, "{mac_length} bytes of MAC");
+        }
+    }
+
+    #[test]
+    fn padding_longer_than_packet() {
+        let mut codec = PacketCodec::builder().build();
+
+        // 12 bytes of packet, all of them claimed by the padding
+        let mut src = BytesMut::new();
+        src.put_u32(12);
+        src.put_u8(12);
+        src.put_bytes(0, 11);
+
+        let err = codec.decode(&mut src).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn aes_ctr_round_trip() {
+        for algorithm in [CipherAlgorithm::Aes128Ctr, CipherAlgorithm::Aes256Ctr] {
+            let (mut sender, mut receiver) = cipher_pair(algorithm);
+
+            let mut wire = BytesMut::new();
+            sender.encode(packet(b"first"), &mut wire).unwrap();
+            sender.encode(packet(b"second"), &mut wire).unwrap();
+
+            // Two 16 byte frames, the first length of 12 is encrypted too
+            assert_eq!(wire.len(), 16 + 16, "{algorithm:?}");
+            assert_ne!(wire[..4], 12u32.to_be_bytes(), "{algorithm:?}");
+            assert!(!wire.windows(5).any(|window| window == b"first"));
+
+            let first = receiver.decode(&mut wire).unwrap().unwrap();
+            let second = receiver.decode(&mut wire).unwrap().unwrap();
+
+            assert_eq!(first.payload, &b"first"[..], "{algorithm:?}");
+            assert_eq!(second.payload, &b"second"[..], "{algorithm:?}");
+            assert!(wire.is_empty());
+        }
+    }
+
+    #[test]
+    fn chacha20_poly1305_round_trip() {
+        let (mut sender, mut receiver) = cipher_pair(CipherAlgorithm::ChaCha20Poly1305);
+
+        let mut wire = BytesMut::new();
+        sender.encode(packet(b"first"), &mut wire).unwrap();
+        sender.encode(packet(b"second"), &mut wire).unwrap();
+
+        // Nothing of the payload is readable on the wire
+        assert!(!wire.windows(5).any(|window| window == b"first"));
+
+        let first = receiver.decode(&mut wire).unwrap().unwrap();
+        let second = receiver.decode(&mut wire).unwrap().unwrap();
+
+        assert_eq!(first.payload, &b"first"[..]);
+        assert_eq!(second.payload, &b"second"[..]);
+        assert!(wire.is_empty());
+    }
+
+    #[test]
+    fn chacha20_poly1305_tampered() {
+        // The frames are 4 bytes of length, 8 of packet and 16 of tag
+        let tampered = [
+            // The length, which then covers 24 bytes and the start of the
+            // next frame
+            (3, 0x10),
+            // The payload
+            (6, 0x01),
+            // The tag
+            (27, 0x80),
+        ];
+
+        for (offset, mask) in tampered {
+            let (mut sender, mut receiver) = cipher_pair(CipherAlgorithm::ChaCha20Poly1305);
+
+            let mut wire = BytesMut::new();
+            sender.encode(packet(b"abc"), &mut wire).unwrap();
+            assert_eq!(wire.len(), 28);
+
+            sender.encode(packet(b"abc"), &mut wire).unwrap();
+            wire[offset] ^= mask;
+
+            let err = receiver.decode(&mut wire).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "offset {}", offset);
+        }
+    }
+
+    #[test]
+    fn aes_gcm_round_trip() {
+        for algorithm in [CipherAlgorithm::Aes128Gcm, CipherAlgorithm::Aes256Gcm] {
+            let (mut sender, mut receiver) = cipher_pair(algorithm);
+
+            let mut wire = BytesMut::new();
+            sender.encode(packet(b"first"), &mut wire).unwrap();
+
+            // The length is only authenticated, as additional data
+            assert_eq!(wire[..4], 16u32.to_be_bytes(), "{algorithm:?}");
+            assert_eq!(wire.len(), 4 + 16 + 16, "{algorithm:?}");
+            assert!(!wire.windows(5).any(|window| window == b"first"));
+
+            sender.encode(packet(b"second"), &mut wire).unwrap();
+
+            let first = receiver.decode(&mut wire).unwrap().unwrap();
+            let second = receiver.decode(&mut wire).unwrap().unwrap();
+
+            assert_eq!(first.payload, &b"first"[..]);
+            assert_eq!(second.payload, &b"second"[..]);
+            assert!(wire.is_empty());
+        }
+    }
+
+    #[test]
+    fn aes_gcm_tampered() {
+        // The frames are 4 bytes of length, 16 of packet and 16 of tag
+        let tampered = [
+            // The length, which then covers 48 bytes of the two frames
+            (3, 0x20),
+            // The payload
+            (6, 0x01),
+            // The tag
+            (35, 0x80),
+        ];
+
+        for (offset, mask) in tampered {
+            let (mut sender, mut receiver) = cipher_pair(CipherAlgorithm::Aes128Gcm);
+
+            let mut wire = BytesMut::new();
+            sender.encode(packet(b"abc"), &mut wire).unwrap();
+            assert_eq!(wire.len(), 36);
+
+            sender.encode(packet(b"abc"), &mut wire).unwrap();
+            wire[offset] ^= mask;
+
+            let err = receiver.decode(&mut wire).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "offset {}", offset);
+        }
+    }
+
+    #[test]
+    fn mac_valid() {
+        let (mut sender, mut receiver) = mac_pair(MacAlgorithm::HmacSha256);
+
+        let mut wire = BytesMut::new();
+        sender.encode(packet(b"hello"), &mut wire).unwrap();
+
+        // HMAC of the sequence number followed by the plaintext packet
+        let (frame, mac) = wire.split_at(wire.len() - 32);
+        let key = aws_lc_rs::hmac::Key::new(aws_lc_rs::hmac::HMAC_SHA256, &[0x42; 32]);
+        let expected = aws_lc_rs::hmac::sign(&key, &[&0u32.to_be_bytes()[..], frame].concat());
+        assert_eq!(mac, expected.as_ref());
+
+        let decoded = receiver.decode(&mut wire).unwrap().unwrap();
+        assert_eq!(decoded.payload, &b"hello"[..]);
+        assert_eq!(decoded.mac.unwrap(), expected.as_ref());
+    }
+
+    #[test]
+    fn mac_corrupted() {
+        for algorithm in [MacAlgorithm::HmacSha256, MacAlgorithm::HmacSha512Etm] {
+            let (mut sender, mut receiver) = mac_pair(algorithm);
+
+            let mut wire = BytesMut::new();
+            sender.encode(packet(b"hello"), &mut wire).unwrap();
+
+            let last = wire.len() - 1;
+            wire[last] ^= 0x01;
+
+            let err = receiver.decode(&mut wire).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{:?}", algorithm);
+        }
+    }
+
+    #[test]
+    fn etm_with_cipher() {
+        let (mut sender, mut receiver) = cipher_pair(CipherAlgorithm::Aes128Ctr);
+        let key = [0x42; 32];
+        sender
+            .set_mac(Direction::Outgoing, MacAlgorithm::HmacSha256Etm, &key)
+            .unwrap();
+        receiver
+            .set_mac(Direction::Incoming, MacAlgorithm::HmacSha256Etm, &key)
+            .unwrap();
+
+        let mut wire = BytesMut::new();
+        sender.encode(packet(b"hello"), &mut wire).unwrap();
+
+        // The length stays in the clear, the padding leaves it out
+        assert_eq!(wire[..4], 16u32.to_be_bytes());
+        assert_eq!(wire.len(), 4 + 16 + 32);
+        assert!(!wire.windows(5).any(|window| window == b"hello"));
+
+        // MAC over the sequence number, the length and the ciphertext
+        let (frame, mac) = wire.split_at(4 + 16);
+        let key = aws_lc_rs::hmac::Key::new(aws_lc_rs::hmac::HMAC_SHA256, &key);
+        let expected = aws_lc_rs::hmac::sign(&key, &[&0u32.to_be_bytes()[..], frame].concat());
+        assert_eq!(mac, expected.as_ref());
+
+        let original = wire.clone();
+        let decoded = receiver.decode(&mut wire).unwrap().unwrap();
+        assert_eq!(decoded.payload, &b"hello"[..]);
+
+        // A flipped bit of ciphertext or MAC fails the MAC check
+        for offset in [6, original.len() - 1] {
+            let (_, mut receiver) = cipher_pair(CipherAlgorithm::Aes128Ctr);
+            receiver
+                .set_mac(
+                    Direction::Incoming,
+                    MacAlgorithm::HmacSha256Etm,
+                    &[0x42; 32],
+                )
+                .unwrap();
+
+            let mut corrupted = original.clone();
+            corrupted[offset] ^= 0x01;
+
+            let err = receiver.decode(&mut corrupted).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "offset {offset}");
+        }
+    }
+
+    #[test]
+    fn mac_sequence_rollover() {
+        let (mut sender, mut receiver) = mac_pair(MacAlgorithm::HmacSha256);
+        sender.send_seq = u32::MAX - 1;
+        receiver.recv_seq = u32::MAX - 1;
+
+        let mut wire = BytesMut::new();
+        for _ in 0..4 {
+            sender.encode(packet(b"hello"), &mut wire).unwrap();
+        }
+
+        for _ in 0..4 {
+            let decoded = receiver.decode(&mut wire).unwrap().unwrap();
+            assert_eq!(decoded.payload, &b"hello"[..]);
+        }
+
+        assert_eq!(sender.send_seq(), 2);
+        assert_eq!(receiver.recv_seq(), 2);
+
+        // Sequence numbers are part of the MAC, so a receiver that is off by
+        // one rejects the packet
+        sender.encode(packet(b"hello"), &mut wire).unwrap();
+        receiver.recv_seq = 3;
+
+        let err = receiver.decode(&mut wire).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn sequence_numbers_advance_and_wrap() {
+        let mut sender = PacketCodec::builder().build();
+        let mut receiver = PacketCodec::builder().build();
+        let mut wire = BytesMut::new();
+
+        for seq in 0..3 {
+            assert_eq!(sender.send_seq(), seq);
+            assert_eq!(receiver.recv_seq(), seq);
+
+            sender.encode(packet(b"ping"), &mut wire).unwrap();
+            receiver.decode(&mut wire).unwrap().unwrap();
+        }
+
+        // Neither direction counts the other
+        assert_eq!(sender.recv_seq(), 0);
+        assert_eq!(receiver.send_seq(), 0);
+
+        sender.send_seq = u32::MAX;
+        receiver.recv_seq = u32::MAX;
+
+        sender.encode(packet(b"ping"), &mut wire).unwrap();
+        receiver.decode(&mut wire).unwrap().unwrap();
+
+        assert_eq!(sender.send_seq(), 0);
+        assert_eq!(receiver.recv_seq(), 0);
+    }
+
+    #[test]
+    fn reset_seq() {
+        let (mut sender, mut receiver) = mac_pair(MacAlgorithm::HmacSha256);
+        let mut wire = BytesMut::new();
+
+        for _ in 0..3 {
+            sender.encode(packet(b"kex"), &mut wire).unwrap();
+            receiver.decode(&mut wire).unwrap().unwrap();
+        }
+        receiver.encode(packet(b"kex"), &mut wire).unwrap();
+        wire.clear();
+
+        // Only the given direction starts over
+        sender.reset_seq(Direction::Outgoing);
+        receiver.reset_seq(Direction::Incoming);
+        assert_eq!((sender.send_seq(), sender.recv_seq()), (0, 0));
+        assert_eq!((receiver.send_seq(), receiver.recv_seq()), (1, 0));
+
+        // The MAC is over the new sequence number
+        sender.encode(packet(b"strict"), &mut wire).unwrap();
+        assert_eq!(
+            receiver.decode(&mut wire).unwrap().unwrap().payload,
+            &b"strict"[..]
+        );
+
+        // One side resetting without the other breaks the MAC
+        sender.reset_seq(Direction::Outgoing);
+        sender.encode(packet(b"strict"), &mut wire).unwrap();
+
+        let err = receiver.decode(&mut wire).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn compression_round_trip() {
+        let payload = Bytes::from(vec![b'a'; 4096]);
+
+        let mut sender = PacketCodec::builder().build();
+        let mut receiver = PacketCodec::builder().build();
+        sender.set_compression(Direction::Outgoing, CompressionAlgorithm::Zlib);
+        receiver.set_compression(Direction::Incoming, CompressionAlgorithm::Zlib);
+
+        for _ in 0..2 {
+            let mut wire = BytesMut::new();
+            sender
+                .encode(
+                    Packet {
+                        payload: payload.clone(),
+                        mac: None,
+                    },
+                    &mut wire,
+                )
+                .unwrap();
+
+            assert!(wire.len() < payload.len() / 10, "{} bytes", wire.len());
+
+            let decoded = receiver.decode(&mut wire).unwrap().unwrap();
+            assert_eq!(decoded.payload, payload);
+        }
+    }
+
+    #[test]
+    fn delayed_compression_across_rekey() {
+        let payload = Bytes::from(vec![b'a'; 4096]);
+
+        let mut sender = PacketCodec::builder().build();
+        let mut receiver = PacketCodec::builder().build();
+        sender.set_compression(Direction::Outgoing, CompressionAlgorithm::ZlibOpenssh);
+        receiver.set_compression(Direction::Incoming, CompressionAlgorithm::ZlibOpenssh);
+
+        let send = |sender: &mut PacketCodec, receiver: &mut PacketCodec| {
+            let mut wire = BytesMut::new();
+            sender
+                .encode(
+                    Packet {
+                        payload: payload.clone(),
+                        mac: None,
+                    },
+                    &mut wire,
+                )
+                .unwrap();
+            let len = wire.len();
+
+            let decoded = receiver.decode(&mut wire).unwrap().unwrap();
+            assert_eq!(decoded.payload, payload);
+
+            len
         };
 
-        match self.decode_data(n, src) {
-            Some(mut packet) => {
-                // Update the decode state
-                self.state = DecodeState::Head;
+        // Nothing is compressed before authentication
+        assert!(send(&mut sender, &mut receiver) > payload.len());
+
+        sender.start_delayed_compression();
+        receiver.start_delayed_compression();
+        assert!(send(&mut sender, &mut receiver) < payload.len() / 10);
+
+        // A rekey after authentication keeps compressing
+        sender.set_compression(Direction::Outgoing, CompressionAlgorithm::ZlibOpenssh);
+        receiver.set_compression(Direction::Incoming, CompressionAlgorithm::ZlibOpenssh);
+        assert!(send(&mut sender, &mut receiver) < payload.len() / 10);
+        assert!(send(&mut sender, &mut receiver) < payload.len() / 10);
+    }
+
+    #[test]
+    fn decode_byte_at_a_time() {
+        for algorithm in [
+            CipherAlgorithm::Aes128Ctr,
+            CipherAlgorithm::ChaCha20Poly1305,
+        ] {
+            let (mut sender, mut receiver) = cipher_pair(algorithm);
+
+            let mut wire = BytesMut::new();
+            sender
+                .encode(packet(b"one byte at a time"), &mut wire)
+                .unwrap();
+
+            let mut src = BytesMut::new();
+            let (last, head) = wire.split_last().unwrap();
 
-                // Make sure the buffer has enough space to read the next head
-                src.reserve(Self::HEAD_SIZE.saturating_sub(src.len()));
+            for &byte in head {
+                src.put_u8(byte);
+                assert_eq!(receiver.decode(&mut src).unwrap(), None, "{:?}", algorithm);
+            }
+
+            src.put_u8(*last);
+            let decoded = receiver.decode(&mut src).unwrap().unwrap();
+
+            assert_eq!(decoded.payload, &b"one byte at a time"[..]);
+            assert_eq!(receiver.decode(&mut src).unwrap(), None);
+            assert!(src.is_empty());
+        }
+    }
+
+    #[test]
+    fn two_frames_in_one_buffer() {
+        let (mut sender, mut receiver) = mac_pair(MacAlgorithm::HmacSha256Etm);
 
-                let packet_length = packet.get_u32();
-                let padding_length = packet.get_u8();
+        let mut wire = BytesMut::new();
+        sender.encode(packet(b"first"), &mut wire).unwrap();
+        sender.encode(packet(b"second"), &mut wire).unwrap();
 
-                let n1 = packet_length - (padding_length as u32) - 1;
+        // Both come out of the one buffer without any more data arriving
+        let first = receiver.decode(&mut wire).unwrap().unwrap();
+        let second = receiver.decode(&mut wire).unwrap().unwrap();
+
+        assert_eq!(first.payload, &b"first"[..]);
+        assert_eq!(second.payload, &b"second"[..]);
+        assert_eq!(receiver.decode(&mut wire).unwrap(), None);
+        assert!(wire.is_empty());
+    }
 
-                let payload = packet.copy_to_bytes(n1 as usize);
+    #[test]
+    fn borrowed_matches_owned() {
+        for compression in [CompressionAlgorithm::None, CompressionAlgorithm::Zlib] {
+            let (mut sender, mut owned) = mac_pair(MacAlgorithm::HmacSha256);
+            let (_, mut borrowed) = mac_pair(MacAlgorithm::HmacSha256);
+            sender.set_compression(Direction::Outgoing, compression);
+            owned.set_compression(Direction::Incoming, compression);
+            borrowed.set_compression(Direction::Incoming, compression);
 
-                packet.advance(padding_length as usize); // Skip random padding
+            let mut wire = BytesMut::new();
+            sender.encode(packet(b"first"), &mut wire).unwrap();
+            sender.encode(packet(b"second"), &mut wire).unwrap();
+            let mut src = wire.clone();
 
-                let mac = if self.mac_length > 0 {
-                    Some(packet.copy_to_bytes(self.mac_length))
-                } else {
-                    None
-                };
+            for _ in 0..2 {
+                let expected = owned.decode(&mut wire).unwrap().unwrap();
+                let len = src.len();
 
-                let packet = Packet { payload, mac };
+                let packet = borrowed.decode_borrowed(&mut src).unwrap().unwrap();
+                assert_eq!(packet.payload(), &expected.payload[..], "{compression:?}");
+                assert_eq!(packet.mac(), expected.mac.as_deref(), "{compression:?}");
+                assert_eq!(packet.to_owned(), expected, "{compression:?}");
+                drop(packet);
 
-                Ok(Some(packet))
+                // Dropping the packet takes it out of the buffer, just like
+                // the owned decode did
+                assert_eq!(src.len(), wire.len(), "{compression:?}");
+                assert!(src.len() < len);
             }
-            None => Ok(None),
+
+            assert!(src.is_empty());
+            assert_eq!(
+                borrowed.decode_borrowed(&mut src).unwrap().map(|_| ()),
+                None
+            );
         }
     }
-}
-impl Encoder<Packet> for PacketCodec {
-    type Error = io::Error;
 
-    fn encode(&mut self, packet: Packet, dst: &mut BytesMut) -> Result<(), io::Error> {
-        let Packet { payload, mac: _mac } = packet;
+    #[test]
+    fn rekey_after_enough_data() {
+        let mut sender = PacketCodec::builder().build();
+        let mut receiver = PacketCodec::builder().build();
+        sender.set_rekey_bytes(100);
+        receiver.set_rekey_packets(3);
 
-        let padding_length = self.calculate_padding_length(payload.len());
-        let packet_length = 1 + payload.len() + padding_length as usize;
-        let total_size = 4 + packet_length + self.mac_length;
+        let mut wire = BytesMut::new();
+        let mut sent = 0;
 
-        if total_size > self.max_packet_size {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                format!("SSH packet too large: {} bytes", total_size),
-            ));
+        while !sender.should_rekey() {
+            let before = wire.len();
+            sender.encode(packet(b"some data"), &mut wire).unwrap();
+            sent += wire.len() - before;
         }
 
-        dst.reserve(total_size);
-        dst.put_u32(packet_length as u32);
-        dst.put_u8(padding_length);
-        dst.extend_from_slice(&payload[..]);
+        assert!((100..100 + 32).contains(&sent), "{} bytes", sent);
 
-        // Write padding: random if encrypted, zeros before encryption
-        if self.cipher_block_size == 0 {
-            // No encryption: zero padding (like OpenSSH)
-            dst.put_bytes(0, padding_length as usize);
-        } else {
-            let mut padding = vec![0u8; padding_length as usize];
+        for n in 1..=3 {
+            assert!(!receiver.should_rekey());
+            receiver.decode(&mut wire).unwrap().unwrap();
+            assert_eq!(receiver.should_rekey(), n == 3);
+        }
 
-            self.rng_provider
-                .fill(&mut padding)
-                .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("RNG error: {:?}", e)))?;
+        // New keys start the count over
+        sender
+            .set_cipher(
+                Direction::Outgoing,
+                CipherAlgorithm::Aes128Ctr,
+                &[0; 16],
+                &[0; 16],
+            )
+            .unwrap();
+        assert!(!sender.should_rekey());
+
+        receiver
+            .set_cipher(
+                Direction::Incoming,
+                CipherAlgorithm::Aes128Ctr,
+                &[0; 16],
+                &[0; 16],
+            )
+            .unwrap();
+        assert!(!receiver.should_rekey());
+    }
+
+    #[test]
+    fn maximum_packet_length() {
+        // On 64 bits only the huge MAC makes the frame size overflow, like a
+        // length near u32::MAX does on 32 bits
+        for mac_length in [0, usize::MAX - 8] {
+            let mut codec = PacketCodec::new(PacketCodec::DEFAULT_MAX_PACKET_SIZE, mac_length);
+
+            let mut src = BytesMut::with_capacity(64);
+            src.put_u32(u32::MAX);
+            src.put_bytes(0, 12);
+
+            let err = codec.decode(&mut src).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
 
-            dst.extend_from_slice(&padding);
+            // Nothing was reserved for the frame
+            assert!(src.capacity() <= 64, "{} bytes reserved", src.capacity());
         }
+    }
 
-        Ok(())
+    #[test]
+    fn tiny_max_packet_size() {
+        for max_packet_size in [0, 1, 15] {
+            let mut sender = PacketCodec::new(max_packet_size, 0);
+            let mut receiver = PacketCodec::new(max_packet_size, 0);
+
+            // The smallest packet there is gets through
+            let mut wire = BytesMut::new();
+            sender.encode(packet(b""), &mut wire).unwrap();
+            assert_eq!(wire.len(), 16);
+
+            let decoded = receiver.decode(&mut wire).unwrap().unwrap();
+            assert!(decoded.payload.is_empty());
+
+            // Up to 7 bytes of payload fit the same 16 bytes, 8 don't
+            sender.encode(packet(b"1234567"), &mut wire).unwrap();
+            assert_eq!(wire.len(), 16);
+            wire.clear();
+
+            let err = sender.encode(packet(b"12345678"), &mut wire).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+
+    /// Adds up what the codec reports
+    #[derive(Default)]
+    struct Counters {
+        sent: AtomicUsize,
+        received: AtomicUsize,
+        rekeys: AtomicUsize,
+    }
+
+    impl Metrics for Counters {
+        fn on_packet_sent(&self, len: usize) {
+            self.sent.fetch_add(len, Ordering::Relaxed);
+        }
+
+        fn on_packet_received(&self, len: usize) {
+            self.received.fetch_add(len, Ordering::Relaxed);
+        }
+
+        fn on_rekey(&self, _direction: Direction) {
+            self.rekeys.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn metrics_lengths() {
+        let counters = Arc::new(Counters::default());
+        let (mut sender, mut receiver) = mac_pair(MacAlgorithm::HmacSha256);
+        sender.set_metrics(Some(counters.clone()));
+        receiver.set_metrics(Some(counters.clone()));
+
+        let mut wire = BytesMut::new();
+        sender.encode(packet(b"hello"), &mut wire).unwrap();
+        sender.encode(packet(b"world!"), &mut wire).unwrap();
+        let len = wire.len();
+        assert_eq!(counters.sent.load(Ordering::Relaxed), len);
+
+        receiver.decode(&mut wire).unwrap().unwrap();
+        receiver.decode(&mut wire).unwrap().unwrap();
+        assert_eq!(counters.received.load(Ordering::Relaxed), len);
+
+        sender
+            .set_cipher(
+                Direction::Outgoing,
+                CipherAlgorithm::Aes128Ctr,
+                &[0; 16],
+                &[0; 16],
+            )
+            .unwrap();
+        assert_eq!(counters.rekeys.load(Ordering::Relaxed), 1);
+
+        // An unchecked MAC length with no MAC installed writes no MAC bytes,
+        // so none are reported
+        let counters = Arc::new(Counters::default());
+        let mut codec = PacketCodec::builder()
+            .mac_length(16)
+            .metrics(counters.clone())
+            .build();
+
+        let mut dst = BytesMut::new();
+        codec.encode(packet(b"hello"), &mut dst).unwrap();
+        assert_eq!(dst.len(), 16);
+        assert_eq!(counters.sent.load(Ordering::Relaxed), dst.len());
     }
 }
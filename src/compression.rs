@@ -0,0 +1,201 @@
+use std::{fmt, io};
+
+use bytes::Bytes;
+use flate2::{Compress, Decompress, FlushCompress, FlushDecompress, Status};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionAlgorithm {
+    None,
+    Zlib,
+    /// Same as `zlib`, but only starts once the user is authenticated
+    ZlibOpenssh,
+}
+
+impl CompressionAlgorithm {
+    /// Looks up a compression algorithm by its SSH algorithm name
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(CompressionAlgorithm::None),
+            "zlib" => Some(CompressionAlgorithm::Zlib),
+            "zlib@openssh.com" => Some(CompressionAlgorithm::ZlibOpenssh),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CompressionAlgorithm::None => "none",
+            CompressionAlgorithm::Zlib => "zlib",
+            CompressionAlgorithm::ZlibOpenssh => "zlib@openssh.com",
+        }
+    }
+
+    /// Whether compression waits for SSH_MSG_USERAUTH_SUCCESS
+    pub fn is_delayed(&self) -> bool {
+        matches!(self, CompressionAlgorithm::ZlibOpenssh)
+    }
+}
+
+/// Compression state for one direction of the connection.
+///
+/// Every packet is part of a single zlib stream, flushed at the end of each
+/// payload so the peer can decompress it right away.
+pub(crate) struct Compression {
+    algorithm: CompressionAlgorithm,
+    active: bool,
+    state: CompressionState,
+}
+
+enum CompressionState {
+    None,
+    Compress(Box<Compress>),
+    Decompress(Box<Decompress>),
+}
+
+impl Compression {
+    pub(crate) fn compressor(algorithm: CompressionAlgorithm) -> Self {
+        let state = match algorithm {
+            CompressionAlgorithm::None => CompressionState::None,
+            _ => CompressionState::Compress(Box::new(Compress::new(
+                flate2::Compression::default(),
+                true,
+            ))),
+        };
+
+        Self::new(algorithm, state)
+    }
+
+    pub(crate) fn decompressor(algorithm: CompressionAlgorithm) -> Self {
+        let state = match algorithm {
+            CompressionAlgorithm::None => CompressionState::None,
+            _ => CompressionState::Decompress(Box::new(Decompress::new(true))),
+        };
+
+        Self::new(algorithm, state)
+    }
+
+    fn new(algorithm: CompressionAlgorithm, state: CompressionState) -> Self {
+        Self {
+            algorithm,
+            active: !algorithm.is_delayed(),
+            state,
+        }
+    }
+
+    pub(crate) fn algorithm(&self) -> CompressionAlgorithm {
+        self.algorithm
+    }
+
+    /// Starts delayed compression, doing nothing for other algorithms
+    pub(crate) fn activate(&mut self) {
+        self.active = true;
+    }
+
+    /// Compresses or decompresses `payload` depending on the direction.
+    ///
+    /// Decompressed payloads larger than `limit` are rejected, so a tiny
+    /// packet can't expand into an unbounded amount of memory.
+    pub(crate) fn process(&mut self, payload: Bytes, limit: usize) -> io::Result<Bytes> {
+        Ok(self.process_slice(&payload, limit)?.unwrap_or(payload))
+    }
+
+    /// Like [`process`](Self::process), giving `None` when the payload is
+    /// left as it is
+    pub(crate) fn process_slice(
+        &mut self,
+        payload: &[u8],
+        limit: usize,
+    ) -> io::Result<Option<Bytes>> {
+        if !self.active {
+            return Ok(None);
+        }
+
+        match &mut self.state {
+            CompressionState::None => Ok(None),
+            CompressionState::Compress(compress) => compress_payload(compress, payload).map(Some),
+            CompressionState::Decompress(decompress) => {
+                decompress_payload(decompress, payload, limit).map(Some)
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Compression")
+            .field("algorithm", &self.algorithm)
+            .field("active", &self.active)
+            .finish_non_exhaustive()
+    }
+}
+
+fn compress_payload(compress: &mut Compress, payload: &[u8]) -> io::Result<Bytes> {
+    let mut out = Vec::with_capacity(payload.len() + 64);
+    let mut consumed = 0;
+
+    loop {
+        out.reserve(64);
+
+        let before = compress.total_in();
+        compress
+            .compress_vec(&payload[consumed..], &mut out, FlushCompress::Partial)
+            .map_err(|e| io::Error::other(format!("Compression error: {}", e)))?;
+        consumed += (compress.total_in() - before) as usize;
+
+        // Spare room left over means the flush has been fully written out
+        if consumed == payload.len() && out.len() < out.capacity() {
+            break;
+        }
+    }
+
+    Ok(out.into())
+}
+
+fn decompress_payload(
+    decompress: &mut Decompress,
+    payload: &[u8],
+    limit: usize,
+) -> io::Result<Bytes> {
+    let mut out = Vec::with_capacity(payload.len() * 2 + 64);
+    let mut consumed = 0;
+
+    loop {
+        out.reserve(64);
+
+        let (before_in, before_out) = (decompress.total_in(), decompress.total_out());
+        let status = decompress
+            .decompress_vec(&payload[consumed..], &mut out, FlushDecompress::Sync)
+            .map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("SSH payload decompression failed: {}", e),
+                )
+            })?;
+        consumed += (decompress.total_in() - before_in) as usize;
+
+        if out.len() > limit {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("SSH decompressed payload exceeds {} bytes", limit),
+            ));
+        }
+
+        let stalled = decompress.total_in() == before_in && decompress.total_out() == before_out;
+
+        if status == Status::StreamEnd
+            || consumed == payload.len() && (stalled || out.len() < out.capacity())
+        {
+            break;
+        }
+
+        // Input left over but nothing coming out of it
+        if stalled {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "SSH payload decompression stalled",
+            ));
+        }
+    }
+
+    Ok(out.into())
+}
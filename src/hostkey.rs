@@ -0,0 +1,386 @@
+use aws_lc_rs::{
+    digest,
+    signature::{
+        ECDSA_P256_SHA256_FIXED, ED25519, RSA_PKCS1_2048_8192_SHA256, RSA_PKCS1_2048_8192_SHA512,
+        RsaPublicKeyComponents, UnparsedPublicKey,
+    },
+};
+use base64::{Engine, engine::general_purpose::STANDARD_NO_PAD};
+use bytes::Bytes;
+
+use crate::message::{ParseError, Reader, Writer};
+
+/// RSA moduli aws-lc accepts for signature verification
+const RSA_MODULUS_BITS: std::ops::RangeInclusive<usize> = 2048..=8192;
+
+/// Length of a P-256 coordinate or scalar
+const P256_SCALAR_LENGTH: usize = 32;
+
+#[derive(Debug, thiserror::Error)]
+pub enum HostKeyError {
+    #[error("Unknown host key type: {0}")]
+    UnknownKeyType(String),
+    #[error("Malformed host key or signature")]
+    Malformed(#[from] ParseError),
+    #[error("Invalid {0} public key")]
+    InvalidKey(&'static str),
+    #[error("Unsupported RSA modulus size: {0} bits")]
+    UnsupportedKeySize(usize),
+    #[error("Signature algorithm {signature} doesn't match host key type {key}")]
+    AlgorithmMismatch {
+        key: &'static str,
+        signature: String,
+    },
+    #[error("Host key signature verification failed")]
+    BadSignature,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum VerifyError {
+    #[error("Host key for {0} was rejected")]
+    Rejected(String),
+    #[error("No known host key for {0}")]
+    UnknownHost(String),
+    #[error("Host key for {0} doesn't match the known one, the connection may be intercepted")]
+    KeyMismatch(String),
+    #[error("Host key for {0} has been revoked")]
+    Revoked(String),
+}
+
+/// Decides whether a server's host key is trusted.
+///
+/// Called during the key exchange once the server proved it holds the key,
+/// the connection is dropped unless this returns `Ok`. `hostname` is in the
+/// form used by `known_hosts`, `[host]:port` for ports other than 22.
+pub trait HostKeyVerifier {
+    fn verify(&self, hostname: &str, key: &HostKey) -> Result<(), VerifyError>;
+}
+
+impl<F> HostKeyVerifier for F
+where
+    F: Fn(&str, &HostKey) -> Result<(), VerifyError>,
+{
+    fn verify(&self, hostname: &str, key: &HostKey) -> Result<(), VerifyError> {
+        self(hostname, key)
+    }
+}
+
+/// Hash of a key [fingerprint](PublicKey::fingerprint)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FingerprintHash {
+    /// `SHA256:` and unpadded base64, what OpenSSH shows by default
+    #[default]
+    Sha256,
+    /// `MD5:` and colon separated hex, from before OpenSSH 6.8
+    Md5,
+}
+
+/// A server's public host key, as sent in K_S
+pub type HostKey = PublicKey;
+
+/// A public key in its SSH form, a server's host key or a user's key for
+/// `publickey` authentication
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PublicKey {
+    Ed25519([u8; 32]),
+    /// Public exponent and modulus, as unsigned big-endian integers
+    Rsa {
+        e: Bytes,
+        n: Bytes,
+    },
+    /// Uncompressed SEC1 point, `0x04 || x || y`
+    EcdsaP256(Bytes),
+}
+
+impl PublicKey {
+    /// Parses a public key blob, see RFC 4253 section 6.6
+    pub fn parse(blob: &[u8]) -> Result<Self, HostKeyError> {
+        let mut blob = blob;
+        let mut src = Reader::new(&mut blob);
+
+        let key_type = src.read_utf8()?;
+
+        let key = match key_type.as_str() {
+            "ssh-ed25519" => {
+                let key = src.read_string()?;
+
+                PublicKey::Ed25519(
+                    key.as_ref()
+                        .try_into()
+                        .map_err(|_| HostKeyError::InvalidKey("ssh-ed25519"))?,
+                )
+            }
+            "ssh-rsa" => {
+                let e = src.read_mpint()?;
+                let n = src.read_mpint()?;
+
+                let bits = n
+                    .first()
+                    .map_or(0, |b| n.len() * 8 - b.leading_zeros() as usize);
+                if !RSA_MODULUS_BITS.contains(&bits) {
+                    return Err(HostKeyError::UnsupportedKeySize(bits));
+                }
+
+                PublicKey::Rsa { e, n }
+            }
+            "ecdsa-sha2-nistp256" => {
+                // RFC 5656 section 3.1, the curve is named a second time
+                let curve = src.read_utf8()?;
+                if curve != "nistp256" {
+                    return Err(HostKeyError::InvalidKey("ecdsa-sha2-nistp256"));
+                }
+
+                let point = src.read_string()?;
+                if point.len() != 1 + 2 * P256_SCALAR_LENGTH || point[0] != 0x04 {
+                    return Err(HostKeyError::InvalidKey("ecdsa-sha2-nistp256"));
+                }
+
+                PublicKey::EcdsaP256(point)
+            }
+            _ => return Err(HostKeyError::UnknownKeyType(key_type)),
+        };
+
+        src.finish()?;
+
+        Ok(key)
+    }
+
+    /// Name of the key type, e.g. `ssh-ed25519`
+    pub fn key_type(&self) -> &'static str {
+        match self {
+            PublicKey::Ed25519(_) => "ssh-ed25519",
+            PublicKey::Rsa { .. } => "ssh-rsa",
+            PublicKey::EcdsaP256(_) => "ecdsa-sha2-nistp256",
+        }
+    }
+
+    /// Encodes the key back into a public key blob
+    pub fn to_blob(&self) -> Bytes {
+        let mut blob = Writer::new();
+
+        blob.write_string(self.key_type());
+
+        match self {
+            PublicKey::Ed25519(key) => blob.write_string(key),
+            PublicKey::Rsa { e, n } => {
+                blob.write_mpint(e);
+                blob.write_mpint(n);
+            }
+            PublicKey::EcdsaP256(point) => {
+                blob.write_string("nistp256");
+                blob.write_string(point);
+            }
+        }
+
+        blob.into_bytes()
+    }
+
+    /// Fingerprint of the key blob as `ssh-keygen -l -E` prints it
+    pub fn fingerprint(&self, hash: FingerprintHash) -> String {
+        let blob = self.to_blob();
+
+        match hash {
+            FingerprintHash::Sha256 => {
+                let hash = digest::digest(&digest::SHA256, &blob);
+
+                format!("SHA256:{}", STANDARD_NO_PAD.encode(hash))
+            }
+            FingerprintHash::Md5 => {
+                let hex: Vec<String> = md5::compute(&blob)
+                    .iter()
+                    .map(|b| format!("{b:02x}"))
+                    .collect();
+
+                format!("MD5:{}", hex.join(":"))
+            }
+        }
+    }
+
+    /// The `SHA256:` fingerprint OpenSSH shows for the key
+    pub fn fingerprint_sha256(&self) -> String {
+        self.fingerprint(FingerprintHash::Sha256)
+    }
+
+    /// Checks a signature blob (`string algorithm, string signature`) over
+    /// `message`, usually the exchange hash H.
+    ///
+    /// RSA keys only accept `rsa-sha2-256` and `rsa-sha2-512` signatures,
+    /// SHA-1 based `ssh-rsa` ones are refused.
+    pub fn verify(&self, message: &[u8], signature: &[u8]) -> Result<(), HostKeyError> {
+        let mut blob = signature;
+        let mut src = Reader::new(&mut blob);
+
+        let algorithm = src.read_utf8()?;
+        let signature = src.read_string()?;
+
+        src.finish()?;
+
+        match self {
+            PublicKey::Ed25519(key) => {
+                if algorithm != "ssh-ed25519" {
+                    return Err(HostKeyError::AlgorithmMismatch {
+                        key: self.key_type(),
+                        signature: algorithm,
+                    });
+                }
+
+                UnparsedPublicKey::new(&ED25519, key)
+                    .verify(message, &signature)
+                    .map_err(|_| HostKeyError::BadSignature)
+            }
+            PublicKey::Rsa { e, n } => {
+                let parameters = match algorithm.as_str() {
+                    "rsa-sha2-256" => &RSA_PKCS1_2048_8192_SHA256,
+                    "rsa-sha2-512" => &RSA_PKCS1_2048_8192_SHA512,
+                    _ => {
+                        return Err(HostKeyError::AlgorithmMismatch {
+                            key: self.key_type(),
+                            signature: algorithm,
+                        });
+                    }
+                };
+
+                RsaPublicKeyComponents { n, e }
+                    .verify(parameters, message, &signature)
+                    .map_err(|_| HostKeyError::BadSignature)
+            }
+            PublicKey::EcdsaP256(point) => {
+                if algorithm != "ecdsa-sha2-nistp256" {
+                    return Err(HostKeyError::AlgorithmMismatch {
+                        key: self.key_type(),
+                        signature: algorithm,
+                    });
+                }
+
+                let signature = ecdsa_fixed_signature(&signature)?;
+
+                UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, point)
+                    .verify(message, &signature)
+                    .map_err(|_| HostKeyError::BadSignature)
+            }
+        }
+    }
+}
+
+/// Turns an SSH ECDSA signature, mpints r and s (RFC 5656 section 3.1.2),
+/// into the fixed `r || s` form aws-lc verifies.
+///
+/// The mpints carry no leading zeros of their own but a zero byte in front
+/// whenever the high bit is set, so their lengths vary with every signature
+/// and each has to be padded back to the scalar length.
+fn ecdsa_fixed_signature(signature: &[u8]) -> Result<Vec<u8>, HostKeyError> {
+    let mut blob = signature;
+    let mut src = Reader::new(&mut blob);
+
+    let r = src.read_mpint()?;
+    let s = src.read_mpint()?;
+
+    src.finish()?;
+
+    let mut fixed = vec![0u8; 2 * P256_SCALAR_LENGTH];
+
+    for (scalar, out) in [r, s]
+        .iter()
+        .zip(fixed.chunks_exact_mut(P256_SCALAR_LENGTH))
+    {
+        if scalar.len() > P256_SCALAR_LENGTH {
+            return Err(HostKeyError::BadSignature);
+        }
+
+        out[P256_SCALAR_LENGTH - scalar.len()..].copy_from_slice(scalar);
+    }
+
+    Ok(fixed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What the `.sig` files in tests/data are signatures over, made with
+    /// Python's `cryptography` from the matching private keys
+    const MESSAGE: &[u8] = b"exchange hash";
+
+    fn public_key(line: &str) -> PublicKey {
+        PublicKey::from_authorized_keys_line(line).unwrap().key
+    }
+
+    /// Checks `signature` verifies and no longer does once tampered with
+    fn assert_verifies(key: &PublicKey, signature: &[u8]) {
+        key.verify(MESSAGE, signature).unwrap();
+
+        assert!(matches!(
+            key.verify(b"another hash", signature),
+            Err(HostKeyError::BadSignature)
+        ));
+
+        let mut tampered = signature.to_vec();
+        *tampered.last_mut().unwrap() ^= 0x01;
+        assert!(key.verify(MESSAGE, &tampered).is_err());
+    }
+
+    #[test]
+    fn fingerprints() {
+        // As printed by `ssh-keygen -l -E sha256|md5 -f`
+        for (line, sha256, md5) in [
+            (
+                include_str!("../tests/data/id_ed25519.pub"),
+                "SHA256:T2QCI970Vd0Y1L3QZIbpWjYQ5FnJqB52i09U4ATcVDA",
+                "MD5:7b:33:f1:6b:fc:b5:5f:13:02:ba:f0:55:b8:73:48:11",
+            ),
+            (
+                include_str!("../tests/data/id_rsa.pub"),
+                "SHA256:fMjS73L1a7sVoz0l99lt/e5e0Kc63c0lIvCKzSeJjqw",
+                "MD5:6c:2e:98:0b:fa:f7:7d:15:6c:b7:e0:b0:cc:03:31:7e",
+            ),
+        ] {
+            let key = public_key(line);
+
+            assert_eq!(key.fingerprint(FingerprintHash::Sha256), sha256);
+            assert_eq!(key.fingerprint(FingerprintHash::Md5), md5);
+            assert_eq!(key.fingerprint(FingerprintHash::default()), sha256);
+        }
+    }
+
+    #[test]
+    fn ed25519_signature() {
+        let key = public_key(include_str!("../tests/data/id_ed25519.pub"));
+
+        assert_verifies(&key, include_bytes!("../tests/data/id_ed25519.sig"));
+    }
+
+    #[test]
+    fn rsa_signatures() {
+        for (line, sha256, sha512) in [
+            (
+                include_str!("../tests/data/id_rsa.pub"),
+                &include_bytes!("../tests/data/id_rsa.rsa-sha2-256.sig")[..],
+                &include_bytes!("../tests/data/id_rsa.rsa-sha2-512.sig")[..],
+            ),
+            (
+                include_str!("../tests/data/id_rsa4096.pub"),
+                &include_bytes!("../tests/data/id_rsa4096.rsa-sha2-256.sig")[..],
+                &include_bytes!("../tests/data/id_rsa4096.rsa-sha2-512.sig")[..],
+            ),
+        ] {
+            let key = public_key(line);
+
+            assert_verifies(&key, sha256);
+            assert_verifies(&key, sha512);
+        }
+    }
+
+    #[test]
+    fn ecdsa_p256_signature() {
+        let key = public_key(include_str!("../tests/data/id_ecdsa.pub"));
+        let signature = include_bytes!("../tests/data/id_ecdsa.sig");
+
+        assert_verifies(&key, signature);
+
+        // An ed25519 signature isn't even tried
+        assert!(matches!(
+            key.verify(MESSAGE, include_bytes!("../tests/data/id_ed25519.sig")),
+            Err(HostKeyError::AlgorithmMismatch { .. })
+        ));
+    }
+}
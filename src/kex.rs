@@ -0,0 +1,1585 @@
+use aws_lc_rs::{
+    agreement::{self, ECDH_P256, EphemeralPrivateKey, UnparsedPublicKey, X25519},
+    digest::{self, SHA256, SHA384, SHA512},
+    rand::{SecureRandom, SystemRandom},
+};
+use std::{
+    fmt::{self, Display},
+    io,
+};
+
+use bytes::Bytes;
+use crypto_bigint::{
+    BoxedUint, Odd, U512, U2048,
+    modular::{BoxedMontyForm, BoxedMontyParams, FixedMontyForm, FixedMontyParams},
+};
+
+use crate::{
+    cipher::CipherAlgorithm,
+    codec::{Direction, PacketCodec},
+    compression::CompressionAlgorithm,
+    mac::MacAlgorithm,
+    message::{
+        KexDhGexGroup, KexDhGexInit, KexDhGexReply, KexDhGexRequest, KexEcdhInit, KexEcdhReply,
+        KexdhInit, KexdhReply, Kexinit, Writer,
+    },
+    tracing::debug,
+};
+
+/// Pseudo-algorithm a client adds to its kex algorithms to offer OpenSSH's
+/// strict key exchange.
+///
+/// With both sides offering it, KEXINIT has to be the first packet, nothing
+/// but key exchange messages may be sent until NEWKEYS and sequence numbers
+/// start over from 0 after each NEWKEYS. That closes the Terrapin attack
+/// (CVE-2023-48795), where an attacker drops packets at the start of the
+/// encrypted stream while fixing up sequence numbers with injected messages
+/// before it.
+pub const STRICT_KEX_CLIENT: &str = "kex-strict-c-v00@openssh.com";
+/// The server's counterpart of [`STRICT_KEX_CLIENT`]
+pub const STRICT_KEX_SERVER: &str = "kex-strict-s-v00@openssh.com";
+
+/// Pseudo-algorithm a client adds to its kex algorithms to accept
+/// SSH_MSG_EXT_INFO from the server, see RFC 8308 section 2.1
+pub const EXT_INFO_CLIENT: &str = "ext-info-c";
+/// Pseudo-algorithm a server adds to its kex algorithms to accept
+/// SSH_MSG_EXT_INFO from the client
+pub const EXT_INFO_SERVER: &str = "ext-info-s";
+
+#[derive(Debug, thiserror::Error)]
+pub enum KexError {
+    #[error("Invalid ephemeral public key")]
+    InvalidPublicKey,
+    #[error("Key agreement failed")]
+    AgreementFailed,
+    #[error("Unacceptable Diffie-Hellman group")]
+    InvalidGroup,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum NegotiationError {
+    #[error("No common {0} algorithm")]
+    NoCommonAlgorithm(AlgorithmCategory),
+}
+
+/// The name-lists of a KEXINIT that get negotiated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlgorithmCategory {
+    Kex,
+    ServerHostKey,
+    EncryptionClientToServer,
+    EncryptionServerToClient,
+    MacClientToServer,
+    MacServerToClient,
+    CompressionClientToServer,
+    CompressionServerToClient,
+}
+
+impl Display for AlgorithmCategory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AlgorithmCategory::Kex => write!(f, "key exchange"),
+            AlgorithmCategory::ServerHostKey => write!(f, "server host key"),
+            AlgorithmCategory::EncryptionClientToServer => {
+                write!(f, "client to server encryption")
+            }
+            AlgorithmCategory::EncryptionServerToClient => {
+                write!(f, "server to client encryption")
+            }
+            AlgorithmCategory::MacClientToServer => write!(f, "client to server MAC"),
+            AlgorithmCategory::MacServerToClient => write!(f, "server to client MAC"),
+            AlgorithmCategory::CompressionClientToServer => {
+                write!(f, "client to server compression")
+            }
+            AlgorithmCategory::CompressionServerToClient => {
+                write!(f, "server to client compression")
+            }
+        }
+    }
+}
+
+/// Algorithms agreed on by both sides of the connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NegotiatedAlgorithms {
+    pub kex: String,
+    pub server_host_key: String,
+    pub encryption_client_to_server: String,
+    pub encryption_server_to_client: String,
+    /// `None` when the cipher is an AEAD, which needs no separate MAC
+    pub mac_client_to_server: Option<String>,
+    /// `None` when the cipher is an AEAD, which needs no separate MAC
+    pub mac_server_to_client: Option<String>,
+    pub compression_client_to_server: String,
+    pub compression_server_to_client: String,
+    /// Whether both sides offered strict key exchange, see
+    /// [`STRICT_KEX_CLIENT`]
+    pub strict_kex: bool,
+}
+
+impl Kexinit {
+    /// Picks the algorithms to use per RFC 4253 section 7.1, `self` being the
+    /// client's KEXINIT and `peer` the server's.
+    ///
+    /// For every category the first algorithm on the client's list that the
+    /// server also supports wins.
+    pub fn negotiate(&self, peer: &Kexinit) -> Result<NegotiatedAlgorithms, NegotiationError> {
+        let kex = choose(
+            &self.kex_algorithms,
+            &peer.kex_algorithms,
+            AlgorithmCategory::Kex,
+        )?;
+        let server_host_key = choose(
+            &self.server_host_key_algorithms,
+            &peer.server_host_key_algorithms,
+            AlgorithmCategory::ServerHostKey,
+        )?;
+
+        let encryption_client_to_server = choose(
+            &self.encryption_algorithms_client_to_server,
+            &peer.encryption_algorithms_client_to_server,
+            AlgorithmCategory::EncryptionClientToServer,
+        )?;
+        let encryption_server_to_client = choose(
+            &self.encryption_algorithms_server_to_client,
+            &peer.encryption_algorithms_server_to_client,
+            AlgorithmCategory::EncryptionServerToClient,
+        )?;
+
+        let mac_client_to_server = if is_aead(&encryption_client_to_server) {
+            None
+        } else {
+            Some(choose(
+                &self.mac_algorithms_client_to_server,
+                &peer.mac_algorithms_client_to_server,
+                AlgorithmCategory::MacClientToServer,
+            )?)
+        };
+        let mac_server_to_client = if is_aead(&encryption_server_to_client) {
+            None
+        } else {
+            Some(choose(
+                &self.mac_algorithms_server_to_client,
+                &peer.mac_algorithms_server_to_client,
+                AlgorithmCategory::MacServerToClient,
+            )?)
+        };
+
+        Ok(NegotiatedAlgorithms {
+            kex,
+            server_host_key,
+            encryption_client_to_server,
+            encryption_server_to_client,
+            mac_client_to_server,
+            mac_server_to_client,
+            compression_client_to_server: choose(
+                &self.compression_algorithms_client_to_server,
+                &peer.compression_algorithms_client_to_server,
+                AlgorithmCategory::CompressionClientToServer,
+            )?,
+            compression_server_to_client: choose(
+                &self.compression_algorithms_server_to_client,
+                &peer.compression_algorithms_server_to_client,
+                AlgorithmCategory::CompressionServerToClient,
+            )?,
+            strict_kex: self
+                .kex_algorithms
+                .iter()
+                .any(|name| name == STRICT_KEX_CLIENT)
+                && peer
+                    .kex_algorithms
+                    .iter()
+                    .any(|name| name == STRICT_KEX_SERVER),
+        })
+    }
+
+    /// Whether the key exchange packet guessed by the side that sent `self`
+    /// is right, per RFC 4253 section 7.
+    ///
+    /// With [`first_kex_packet_follows`](Kexinit::first_kex_packet_follows)
+    /// set, a packet for its preferred kex and host key algorithms comes
+    /// right after the KEXINIT. It is to be ignored unless both are the ones
+    /// negotiated.
+    pub fn guessed_right(&self, algorithms: &NegotiatedAlgorithms) -> bool {
+        self.kex_algorithms.first() == Some(&algorithms.kex)
+            && self.server_host_key_algorithms.first() == Some(&algorithms.server_host_key)
+    }
+}
+
+fn choose(
+    client: &[String],
+    server: &[String],
+    category: AlgorithmCategory,
+) -> Result<String, NegotiationError> {
+    client
+        .iter()
+        .find(|name| server.contains(name))
+        .cloned()
+        .ok_or(NegotiationError::NoCommonAlgorithm(category))
+}
+
+fn is_aead(cipher: &str) -> bool {
+    CipherAlgorithm::from_name(cipher).is_some_and(|cipher| cipher.is_aead())
+}
+
+/// Hash function of a key exchange method, used for H and key derivation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlg {
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+impl HashAlg {
+    /// Looks up the hash of a key exchange method by its SSH name
+    pub fn for_kex(kex: &str) -> Option<Self> {
+        match kex {
+            "curve25519-sha256"
+            | "curve25519-sha256@libssh.org"
+            | "ecdh-sha2-nistp256"
+            | "diffie-hellman-group14-sha256"
+            | "diffie-hellman-group-exchange-sha256" => Some(HashAlg::Sha256),
+            "ecdh-sha2-nistp384" => Some(HashAlg::Sha384),
+            "ecdh-sha2-nistp521"
+            | "diffie-hellman-group16-sha512"
+            | "diffie-hellman-group18-sha512" => Some(HashAlg::Sha512),
+            _ => None,
+        }
+    }
+
+    pub fn output_length(&self) -> usize {
+        self.algorithm().output_len()
+    }
+
+    pub fn digest(&self, data: &[u8]) -> Vec<u8> {
+        digest::digest(self.algorithm(), data).as_ref().to_vec()
+    }
+
+    fn algorithm(&self) -> &'static digest::Algorithm {
+        match self {
+            HashAlg::Sha256 => &SHA256,
+            HashAlg::Sha384 => &SHA384,
+            HashAlg::Sha512 => &SHA512,
+        }
+    }
+}
+
+/// Computes the exchange hash H of an ECDH style key exchange (RFC 5656
+/// section 4, RFC 8731):
+///
+/// ```text
+/// H = HASH(string V_C || string V_S || string I_C || string I_S ||
+///          string K_S || string Q_C || string Q_S || mpint K)
+/// ```
+///
+/// The H of the first key exchange is also the session identifier, which
+/// stays the same for the rest of the connection.
+pub fn compute_exchange_hash(
+    hash: HashAlg,
+    context: &ExchangeContext<'_>,
+    host_key: &[u8],
+    client_public_key: &[u8],
+    server_public_key: &[u8],
+    shared_secret: &[u8],
+) -> Vec<u8> {
+    let mut hash_input = Writer::new();
+
+    hash_input.write_string(context.client_version);
+    hash_input.write_string(context.server_version);
+    hash_input.write_string(context.client_kexinit);
+    hash_input.write_string(context.server_kexinit);
+    hash_input.write_string(host_key);
+    hash_input.write_string(client_public_key);
+    hash_input.write_string(server_public_key);
+    hash_input.write_mpint(shared_secret);
+
+    hash.digest(hash_input.as_ref())
+}
+
+/// Computes the exchange hash H of a classic Diffie-Hellman key exchange
+/// (RFC 4253 section 8):
+///
+/// ```text
+/// H = HASH(string V_C || string V_S || string I_C || string I_S ||
+///          string K_S || mpint e || mpint f || mpint K)
+/// ```
+///
+/// The same as [`compute_exchange_hash`] except that the public values are
+/// mpints rather than strings.
+pub fn compute_dh_exchange_hash(
+    hash: HashAlg,
+    context: &ExchangeContext<'_>,
+    host_key: &[u8],
+    e: &[u8],
+    f: &[u8],
+    shared_secret: &[u8],
+) -> Vec<u8> {
+    let mut hash_input = Writer::new();
+
+    hash_input.write_string(context.client_version);
+    hash_input.write_string(context.server_version);
+    hash_input.write_string(context.client_kexinit);
+    hash_input.write_string(context.server_kexinit);
+    hash_input.write_string(host_key);
+    hash_input.write_mpint(e);
+    hash_input.write_mpint(f);
+    hash_input.write_mpint(shared_secret);
+
+    hash.digest(hash_input.as_ref())
+}
+
+/// Computes the exchange hash H of a Diffie-Hellman group exchange (RFC 4419
+/// section 3):
+///
+/// ```text
+/// H = HASH(string V_C || string V_S || string I_C || string I_S ||
+///          string K_S || uint32 min || uint32 n || uint32 max ||
+///          mpint p || mpint g || mpint e || mpint f || mpint K)
+/// ```
+///
+/// `request` has to be the SSH_MSG_KEX_DH_GEX_REQUEST exactly as sent,
+/// `group` the SSH_MSG_KEX_DH_GEX_GROUP the server answered it with.
+#[allow(clippy::too_many_arguments)]
+pub fn compute_gex_exchange_hash(
+    hash: HashAlg,
+    context: &ExchangeContext<'_>,
+    host_key: &[u8],
+    request: &KexDhGexRequest,
+    group: &KexDhGexGroup,
+    e: &[u8],
+    f: &[u8],
+    shared_secret: &[u8],
+) -> Vec<u8> {
+    let mut hash_input = Writer::new();
+
+    hash_input.write_string(context.client_version);
+    hash_input.write_string(context.server_version);
+    hash_input.write_string(context.client_kexinit);
+    hash_input.write_string(context.server_kexinit);
+    hash_input.write_string(host_key);
+    hash_input.write_u32(request.min);
+    hash_input.write_u32(request.n);
+    hash_input.write_u32(request.max);
+    hash_input.write_mpint(&group.p);
+    hash_input.write_mpint(&group.g);
+    hash_input.write_mpint(e);
+    hash_input.write_mpint(f);
+    hash_input.write_mpint(shared_secret);
+
+    hash.digest(hash_input.as_ref())
+}
+
+/// Keys for both directions of the connection, derived from a key exchange
+///
+/// Every key is [`DerivedKeys::KEY_LENGTH`] bytes long, enough for any
+/// supported algorithm. Extending a key only appends to it, so an algorithm
+/// needing fewer bytes takes a prefix of the key.
+#[derive(Clone)]
+pub struct DerivedKeys {
+    /// Initial IV client to server, letter A
+    pub iv_client_to_server: Vec<u8>,
+    /// Initial IV server to client, letter B
+    pub iv_server_to_client: Vec<u8>,
+    /// Encryption key client to server, letter C
+    pub encryption_key_client_to_server: Vec<u8>,
+    /// Encryption key server to client, letter D
+    pub encryption_key_server_to_client: Vec<u8>,
+    /// Integrity key client to server, letter E
+    pub integrity_key_client_to_server: Vec<u8>,
+    /// Integrity key server to client, letter F
+    pub integrity_key_server_to_client: Vec<u8>,
+}
+
+impl DerivedKeys {
+    /// Length of every derived key, the longest being the 64 byte key of
+    /// `chacha20-poly1305@openssh.com` and `hmac-sha2-512`
+    pub const KEY_LENGTH: usize = 64;
+}
+
+impl fmt::Debug for DerivedKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut f = f.debug_struct("DerivedKeys");
+
+        for (name, key) in [
+            ("iv_client_to_server", &self.iv_client_to_server),
+            ("iv_server_to_client", &self.iv_server_to_client),
+            (
+                "encryption_key_client_to_server",
+                &self.encryption_key_client_to_server,
+            ),
+            (
+                "encryption_key_server_to_client",
+                &self.encryption_key_server_to_client,
+            ),
+            (
+                "integrity_key_client_to_server",
+                &self.integrity_key_client_to_server,
+            ),
+            (
+                "integrity_key_server_to_client",
+                &self.integrity_key_server_to_client,
+            ),
+        ] {
+            f.field(name, &format_args!("<redacted, {} bytes>", key.len()));
+        }
+
+        f.finish()
+    }
+}
+
+/// Derives the IVs and keys from the shared secret `k` and exchange hash `h`
+/// as described in RFC 4253 section 7.2:
+///
+/// ```text
+/// K1 = HASH(K || H || X || session_id)
+/// K2 = HASH(K || H || K1)
+/// key = K1 || K2 || ...
+/// ```
+///
+/// `k` is the shared secret as an unsigned big-endian integer, it gets
+/// encoded as an mpint.
+pub fn derive_keys(k: &[u8], h: &[u8], session_id: &[u8], hash: HashAlg) -> DerivedKeys {
+    let mut prefix = Writer::new();
+    prefix.write_mpint(k);
+    prefix.write_raw(h);
+
+    let derive = |letter: u8| {
+        let mut input = prefix.clone();
+        input.write_u8(letter);
+        input.write_raw(session_id);
+
+        let mut key = hash.digest(input.as_ref());
+
+        while key.len() < DerivedKeys::KEY_LENGTH {
+            let mut input = prefix.clone();
+            input.write_raw(&key);
+
+            key.extend_from_slice(&hash.digest(input.as_ref()));
+        }
+
+        key.truncate(DerivedKeys::KEY_LENGTH);
+        key
+    };
+
+    DerivedKeys {
+        iv_client_to_server: derive(b'A'),
+        iv_server_to_client: derive(b'B'),
+        encryption_key_client_to_server: derive(b'C'),
+        encryption_key_server_to_client: derive(b'D'),
+        integrity_key_client_to_server: derive(b'E'),
+        integrity_key_server_to_client: derive(b'F'),
+    }
+}
+
+/// Installs the negotiated algorithms and keys for one direction of `codec`.
+///
+/// `client_to_server` picks the half of `algorithms` and `keys` to use, the
+/// client's outgoing direction and the server's incoming one. Algorithms
+/// this crate doesn't implement fail with [`io::ErrorKind::Unsupported`].
+pub(crate) fn install_keys(
+    codec: &mut PacketCodec,
+    direction: Direction,
+    client_to_server: bool,
+    algorithms: &NegotiatedAlgorithms,
+    keys: &DerivedKeys,
+) -> io::Result<()> {
+    let (cipher, mac, compression, iv, key, integrity_key) = match client_to_server {
+        true => (
+            &algorithms.encryption_client_to_server,
+            &algorithms.mac_client_to_server,
+            &algorithms.compression_client_to_server,
+            &keys.iv_client_to_server,
+            &keys.encryption_key_client_to_server,
+            &keys.integrity_key_client_to_server,
+        ),
+        false => (
+            &algorithms.encryption_server_to_client,
+            &algorithms.mac_server_to_client,
+            &algorithms.compression_server_to_client,
+            &keys.iv_server_to_client,
+            &keys.encryption_key_server_to_client,
+            &keys.integrity_key_server_to_client,
+        ),
+    };
+
+    debug!(
+        ?direction,
+        %cipher,
+        mac = ?mac,
+        %compression,
+        "Installing keys"
+    );
+
+    let unsupported = |name: &str| {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("Unsupported algorithm: {name}"),
+        )
+    };
+
+    let cipher = CipherAlgorithm::from_name(cipher).ok_or_else(|| unsupported(cipher))?;
+    let compression =
+        CompressionAlgorithm::from_name(compression).ok_or_else(|| unsupported(compression))?;
+
+    codec.set_cipher(
+        direction,
+        cipher,
+        &key[..cipher.key_length()],
+        &iv[..cipher.iv_length()],
+    )?;
+
+    if let Some(mac) = mac {
+        let mac = MacAlgorithm::from_name(mac).ok_or_else(|| unsupported(mac))?;
+
+        codec.set_mac(direction, mac, &integrity_key[..mac.key_length()])?;
+    }
+
+    codec.set_compression(direction, compression);
+
+    Ok(())
+}
+
+/// Everything both sides sent before the key exchange proper, hashed into H
+#[derive(Debug, Clone, Copy)]
+pub struct ExchangeContext<'a> {
+    /// Client identification string, without the trailing CR LF
+    pub client_version: &'a [u8],
+    /// Server identification string, without the trailing CR LF
+    pub server_version: &'a [u8],
+    /// Payload of the client's SSH_MSG_KEXINIT
+    pub client_kexinit: &'a [u8],
+    /// Payload of the server's SSH_MSG_KEXINIT
+    pub server_kexinit: &'a [u8],
+}
+
+/// Result of a completed key exchange
+pub struct KexOutput {
+    /// The shared secret K, as an unsigned big-endian integer
+    pub shared_secret: Vec<u8>,
+    /// The exchange hash H
+    pub exchange_hash: Vec<u8>,
+    /// The server's public host key blob K_S
+    pub host_key: Bytes,
+    /// The server's signature over H, still to be verified against `host_key`
+    /// by the client. Empty on the server until it signed H.
+    pub signature: Bytes,
+}
+
+impl fmt::Debug for KexOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KexOutput")
+            .field(
+                "shared_secret",
+                &format_args!("<redacted, {} bytes>", self.shared_secret.len()),
+            )
+            .field("exchange_hash", &self.exchange_hash)
+            .field("host_key", &self.host_key)
+            .field("signature", &self.signature)
+            .finish()
+    }
+}
+
+/// `curve25519-sha256` (RFC 8731), for either side of the exchange
+#[derive(Debug)]
+pub struct Curve25519Sha256 {
+    private_key: EphemeralPrivateKey,
+    public_key: Vec<u8>,
+}
+
+impl Curve25519Sha256 {
+    pub const NAME: &str = "curve25519-sha256";
+
+    /// Generates a fresh ephemeral keypair
+    pub fn new() -> Result<Self, KexError> {
+        let (private_key, public_key) = generate_ephemeral(&X25519)?;
+
+        Ok(Self {
+            private_key,
+            public_key,
+        })
+    }
+
+    /// Our ephemeral public key, Q_C on the client and Q_S on the server
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    /// Builds the SSH_MSG_KEX_ECDH_INIT message carrying Q_C
+    pub fn init_message(&self) -> KexEcdhInit {
+        KexEcdhInit {
+            ephemeral_public_key: Bytes::copy_from_slice(&self.public_key),
+        }
+    }
+
+    /// Consumes the server's SSH_MSG_KEX_ECDH_REPLY, computing the shared
+    /// secret and exchange hash.
+    ///
+    /// The host key signature is returned as is, it is up to the caller to
+    /// verify it before trusting the output.
+    pub fn finish(
+        self,
+        context: &ExchangeContext<'_>,
+        reply: KexEcdhReply,
+    ) -> Result<KexOutput, KexError> {
+        if reply.ephemeral_public_key.len() != 32 {
+            return Err(KexError::InvalidPublicKey);
+        }
+
+        // The all-zero output of a low order point is rejected by
+        // aws-lc itself, as required by RFC 8731
+        ecdh_finish(&X25519, self.private_key, &self.public_key, context, reply)
+    }
+
+    /// Server side, answers the client's SSH_MSG_KEX_ECDH_INIT for the host
+    /// key blob `host_key`.
+    ///
+    /// The output's signature is left empty, the caller signs its exchange
+    /// hash and sends it along with [`public_key`](Self::public_key) in the
+    /// SSH_MSG_KEX_ECDH_REPLY.
+    pub fn respond(
+        self,
+        context: &ExchangeContext<'_>,
+        host_key: Bytes,
+        init: KexEcdhInit,
+    ) -> Result<KexOutput, KexError> {
+        if init.ephemeral_public_key.len() != 32 {
+            return Err(KexError::InvalidPublicKey);
+        }
+
+        ecdh_respond(
+            &X25519,
+            self.private_key,
+            &self.public_key,
+            context,
+            host_key,
+            init,
+        )
+    }
+}
+
+/// `ecdh-sha2-nistp256` (RFC 5656), for either side of the exchange
+#[derive(Debug)]
+pub struct EcdhSha2Nistp256 {
+    private_key: EphemeralPrivateKey,
+    public_key: Vec<u8>,
+}
+
+impl EcdhSha2Nistp256 {
+    pub const NAME: &str = "ecdh-sha2-nistp256";
+
+    /// Generates a fresh ephemeral keypair
+    pub fn new() -> Result<Self, KexError> {
+        let (private_key, public_key) = generate_ephemeral(&ECDH_P256)?;
+
+        Ok(Self {
+            private_key,
+            public_key,
+        })
+    }
+
+    /// Our ephemeral public key, Q_C on the client and Q_S on the server, an
+    /// uncompressed SEC1 point
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    /// Builds the SSH_MSG_KEX_ECDH_INIT message carrying Q_C
+    pub fn init_message(&self) -> KexEcdhInit {
+        KexEcdhInit {
+            ephemeral_public_key: Bytes::copy_from_slice(&self.public_key),
+        }
+    }
+
+    /// Consumes the server's SSH_MSG_KEX_ECDH_REPLY, see
+    /// [`Curve25519Sha256::finish`].
+    ///
+    /// aws-lc checks that Q_S is on the curve, as RFC 5656 requires.
+    pub fn finish(
+        self,
+        context: &ExchangeContext<'_>,
+        reply: KexEcdhReply,
+    ) -> Result<KexOutput, KexError> {
+        ecdh_finish(
+            &ECDH_P256,
+            self.private_key,
+            &self.public_key,
+            context,
+            reply,
+        )
+    }
+
+    /// Server side, answers the client's SSH_MSG_KEX_ECDH_INIT, see
+    /// [`Curve25519Sha256::respond`]
+    pub fn respond(
+        self,
+        context: &ExchangeContext<'_>,
+        host_key: Bytes,
+        init: KexEcdhInit,
+    ) -> Result<KexOutput, KexError> {
+        ecdh_respond(
+            &ECDH_P256,
+            self.private_key,
+            &self.public_key,
+            context,
+            host_key,
+            init,
+        )
+    }
+}
+
+fn generate_ephemeral(
+    algorithm: &'static agreement::Algorithm,
+) -> Result<(EphemeralPrivateKey, Vec<u8>), KexError> {
+    let rng = SystemRandom::new();
+
+    let private_key =
+        EphemeralPrivateKey::generate(algorithm, &rng).map_err(|_| KexError::AgreementFailed)?;
+    let public_key = private_key
+        .compute_public_key()
+        .map_err(|_| KexError::AgreementFailed)?
+        .as_ref()
+        .to_vec();
+
+    Ok((private_key, public_key))
+}
+
+/// The part shared by every ECDH style exchange, where K is the x
+/// coordinate (or the X25519 output) as an unsigned integer
+fn ecdh_finish(
+    algorithm: &'static agreement::Algorithm,
+    private_key: EphemeralPrivateKey,
+    client_public_key: &[u8],
+    context: &ExchangeContext<'_>,
+    reply: KexEcdhReply,
+) -> Result<KexOutput, KexError> {
+    let KexEcdhReply {
+        host_key,
+        ephemeral_public_key: server_public_key,
+        signature,
+    } = reply;
+
+    let shared_secret = agreement::agree_ephemeral(
+        private_key,
+        UnparsedPublicKey::new(algorithm, &server_public_key),
+        KexError::AgreementFailed,
+        |secret| Ok(secret.to_vec()),
+    )?;
+
+    let exchange_hash = compute_exchange_hash(
+        HashAlg::Sha256,
+        context,
+        &host_key,
+        client_public_key,
+        &server_public_key,
+        &shared_secret,
+    );
+
+    Ok(KexOutput {
+        shared_secret,
+        exchange_hash,
+        host_key,
+        signature,
+    })
+}
+
+/// The server half of [`ecdh_finish`], Q_C coming from the client this time
+fn ecdh_respond(
+    algorithm: &'static agreement::Algorithm,
+    private_key: EphemeralPrivateKey,
+    server_public_key: &[u8],
+    context: &ExchangeContext<'_>,
+    host_key: Bytes,
+    init: KexEcdhInit,
+) -> Result<KexOutput, KexError> {
+    let client_public_key = init.ephemeral_public_key;
+
+    let shared_secret = agreement::agree_ephemeral(
+        private_key,
+        UnparsedPublicKey::new(algorithm, &client_public_key),
+        KexError::AgreementFailed,
+        |secret| Ok(secret.to_vec()),
+    )?;
+
+    let exchange_hash = compute_exchange_hash(
+        HashAlg::Sha256,
+        context,
+        &host_key,
+        &client_public_key,
+        server_public_key,
+        &shared_secret,
+    );
+
+    Ok(KexOutput {
+        shared_secret,
+        exchange_hash,
+        host_key,
+        signature: Bytes::new(),
+    })
+}
+
+/// The 2048-bit MODP group of RFC 3526 section 3, its generator is 2
+const GROUP14_PRIME: Odd<U2048> = Odd::<U2048>::from_be_hex(concat!(
+    "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD1",
+    "29024E088A67CC74020BBEA63B139B22514A08798E3404DD",
+    "EF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245",
+    "E485B576625E7EC6F44C42E9A637ED6B0BFF5CB6F406B7ED",
+    "EE386BFB5A899FA5AE9F24117C4B1FE649286651ECE45B3D",
+    "C2007CB8A163BF0598DA48361C55D39A69163FA8FD24CF5F",
+    "83655D23DCA3AD961C62F356208552BB9ED529077096966D",
+    "670C354E4ABC9804F1746C08CA18217C32905E462E36CE3B",
+    "E39E772C180E86039B2783A2EC07A28FB5C55DF06F4C52C9",
+    "DE2BCBF6955817183995497CEA956AE515D2261898FA0510",
+    "15728E5A8AACAA68FFFFFFFFFFFFFFFF",
+));
+
+/// `diffie-hellman-group14-sha256` (RFC 8268), a classic Diffie-Hellman
+/// exchange in the 2048-bit group 14, for either side of the exchange
+pub struct DhGroup14Sha256 {
+    /// The exponent, x on the client and y on the server. Twice the group's
+    /// 112 bit strength would do but a few more bits are cheap
+    private_key: U512,
+    /// e = g^x mod p on the client, f = g^y mod p on the server
+    public_key: U2048,
+}
+
+impl DhGroup14Sha256 {
+    pub const NAME: &str = "diffie-hellman-group14-sha256";
+
+    /// Generates a fresh ephemeral keypair
+    pub fn new() -> Result<Self, KexError> {
+        let mut exponent = [0u8; U512::BYTES];
+        SystemRandom::new()
+            .fill(&mut exponent)
+            .map_err(|_| KexError::AgreementFailed)?;
+
+        let private_key = U512::from_be_slice(&exponent);
+        let public_key = group14_pow(&U2048::from_u8(2), &private_key);
+
+        if !group14_is_valid(&public_key) {
+            return Err(KexError::AgreementFailed);
+        }
+
+        Ok(Self {
+            private_key,
+            public_key,
+        })
+    }
+
+    /// Our public value, e on the client and f on the server, as an
+    /// unsigned big-endian integer
+    pub fn public_key(&self) -> Vec<u8> {
+        to_unsigned_bytes(&self.public_key)
+    }
+
+    /// Builds the SSH_MSG_KEXDH_INIT message carrying e
+    pub fn init_message(&self) -> KexdhInit {
+        KexdhInit {
+            e: self.public_key().into(),
+        }
+    }
+
+    /// Consumes the server's SSH_MSG_KEXDH_REPLY, computing the shared
+    /// secret and exchange hash.
+    ///
+    /// Values of f that aren't strictly between 1 and p - 1 are rejected, as
+    /// RFC 8268 asks. The host key signature is returned as is, it is up to
+    /// the caller to verify it before trusting the output.
+    pub fn finish(
+        self,
+        context: &ExchangeContext<'_>,
+        reply: KexdhReply,
+    ) -> Result<KexOutput, KexError> {
+        let KexdhReply {
+            host_key,
+            f,
+            signature,
+        } = reply;
+
+        let server_public_key = group14_element(&f).ok_or(KexError::InvalidPublicKey)?;
+
+        let shared_secret = to_unsigned_bytes(&group14_pow(&server_public_key, &self.private_key));
+
+        let exchange_hash = compute_dh_exchange_hash(
+            HashAlg::Sha256,
+            context,
+            &host_key,
+            &self.public_key(),
+            &f,
+            &shared_secret,
+        );
+
+        Ok(KexOutput {
+            shared_secret,
+            exchange_hash,
+            host_key,
+            signature,
+        })
+    }
+
+    /// Server side, answers the client's SSH_MSG_KEXDH_INIT for the host key
+    /// blob `host_key`.
+    ///
+    /// Values of e are checked like f in [`finish`](Self::finish). The
+    /// output's signature is left empty, the caller signs its exchange hash
+    /// and sends it along with [`public_key`](Self::public_key) as f in the
+    /// SSH_MSG_KEXDH_REPLY.
+    pub fn respond(
+        self,
+        context: &ExchangeContext<'_>,
+        host_key: Bytes,
+        init: KexdhInit,
+    ) -> Result<KexOutput, KexError> {
+        let client_public_key = group14_element(&init.e).ok_or(KexError::InvalidPublicKey)?;
+
+        let shared_secret = to_unsigned_bytes(&group14_pow(&client_public_key, &self.private_key));
+
+        let exchange_hash = compute_dh_exchange_hash(
+            HashAlg::Sha256,
+            context,
+            &host_key,
+            &init.e,
+            &self.public_key(),
+            &shared_secret,
+        );
+
+        Ok(KexOutput {
+            shared_secret,
+            exchange_hash,
+            host_key,
+            signature: Bytes::new(),
+        })
+    }
+}
+
+impl fmt::Debug for DhGroup14Sha256 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DhGroup14Sha256")
+            .field("private_key", &format_args!("<redacted>"))
+            .field("public_key", &self.public_key)
+            .finish()
+    }
+}
+
+/// `base^exponent mod p` in constant time
+fn group14_pow(base: &U2048, exponent: &U512) -> U2048 {
+    let params = FixedMontyParams::new_vartime(GROUP14_PRIME);
+
+    FixedMontyForm::new(base, &params).pow(exponent).retrieve()
+}
+
+/// Whether `1 < value < p - 1`, anything else would give away or force the
+/// shared secret
+fn group14_is_valid(value: &U2048) -> bool {
+    let max = GROUP14_PRIME.get().wrapping_sub(&U2048::ONE);
+
+    *value > U2048::ONE && *value < max
+}
+
+/// Decodes a public value of the peer, if it's valid
+fn group14_element(value: &[u8]) -> Option<U2048> {
+    if value.len() > U2048::BYTES {
+        return None;
+    }
+
+    let mut padded = [0u8; U2048::BYTES];
+    padded[U2048::BYTES - value.len()..].copy_from_slice(value);
+
+    let value = U2048::from_be_slice(&padded);
+
+    group14_is_valid(&value).then_some(value)
+}
+
+/// `value` as big-endian bytes without leading zeros
+fn to_unsigned_bytes(value: &U2048) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let start = bytes.iter().position(|&b| b != 0).unwrap_or(bytes.len());
+
+    bytes[start..].to_vec()
+}
+
+/// Client side of `diffie-hellman-group-exchange-sha256` (RFC 4419), a
+/// classic Diffie-Hellman exchange in a group the server picks.
+///
+/// The client first sends [`REQUEST`](Self::REQUEST) and creates this from
+/// the server's SSH_MSG_KEX_DH_GEX_GROUP, the rest goes as with
+/// [`DhGroup14Sha256`].
+pub struct DhGroupExchangeSha256 {
+    request: KexDhGexRequest,
+    group: KexDhGexGroup,
+    params: BoxedMontyParams,
+    /// The exponent x, as for group 14
+    private_key: BoxedUint,
+    /// e = g^x mod p
+    public_key: BoxedUint,
+}
+
+impl DhGroupExchangeSha256 {
+    pub const NAME: &str = "diffie-hellman-group-exchange-sha256";
+
+    /// Group sizes to ask for, the 2048 bit minimum is from RFC 8270 and the
+    /// rest matches OpenSSH
+    pub const REQUEST: KexDhGexRequest = KexDhGexRequest {
+        min: 2048,
+        n: 3072,
+        max: 8192,
+    };
+
+    /// Checks the group the server picked for `request` and generates a
+    /// fresh ephemeral keypair in it.
+    ///
+    /// The size of p has to be within what was requested and g strictly
+    /// between 1 and p - 1. Whether p is a safe prime isn't checked, that is
+    /// far too slow to do on every connection.
+    pub fn new(request: KexDhGexRequest, group: KexDhGexGroup) -> Result<Self, KexError> {
+        let prime = BoxedUint::from_be_slice_vartime(&group.p);
+        if !(request.min..=request.max).contains(&prime.bits()) {
+            return Err(KexError::InvalidGroup);
+        }
+
+        let prime = Odd::new(prime)
+            .into_option()
+            .ok_or(KexError::InvalidGroup)?;
+        let params = BoxedMontyParams::new_vartime(prime);
+
+        let generator = group_element(&group.g, &params).ok_or(KexError::InvalidGroup)?;
+
+        let mut exponent = [0u8; U512::BYTES];
+        SystemRandom::new()
+            .fill(&mut exponent)
+            .map_err(|_| KexError::AgreementFailed)?;
+
+        let private_key = BoxedUint::from_be_slice_vartime(&exponent);
+        let public_key = BoxedMontyForm::new(generator, &params)
+            .pow(&private_key)
+            .retrieve();
+
+        if !is_group_element(&public_key, &params) {
+            return Err(KexError::AgreementFailed);
+        }
+
+        Ok(Self {
+            request,
+            group,
+            params,
+            private_key,
+            public_key,
+        })
+    }
+
+    /// The client's public value e, as an unsigned big-endian integer
+    pub fn public_key(&self) -> Vec<u8> {
+        self.public_key.to_be_bytes_trimmed_vartime().into_vec()
+    }
+
+    /// Builds the SSH_MSG_KEX_DH_GEX_INIT message carrying e
+    pub fn init_message(&self) -> KexDhGexInit {
+        KexDhGexInit {
+            e: self.public_key().into(),
+        }
+    }
+
+    /// Consumes the server's SSH_MSG_KEX_DH_GEX_REPLY, computing the shared
+    /// secret and exchange hash.
+    ///
+    /// Values of f that aren't strictly between 1 and p - 1 are rejected. The
+    /// host key signature is returned as is, it is up to the caller to verify
+    /// it before trusting the output.
+    pub fn finish(
+        self,
+        context: &ExchangeContext<'_>,
+        reply: KexDhGexReply,
+    ) -> Result<KexOutput, KexError> {
+        let KexDhGexReply {
+            host_key,
+            f,
+            signature,
+        } = reply;
+
+        let server_public_key =
+            group_element(&f, &self.params).ok_or(KexError::InvalidPublicKey)?;
+
+        let shared_secret = BoxedMontyForm::new(server_public_key, &self.params)
+            .pow(&self.private_key)
+            .retrieve()
+            .to_be_bytes_trimmed_vartime()
+            .into_vec();
+
+        let exchange_hash = compute_gex_exchange_hash(
+            HashAlg::Sha256,
+            context,
+            &host_key,
+            &self.request,
+            &self.group,
+            &self.public_key(),
+            &f,
+            &shared_secret,
+        );
+
+        Ok(KexOutput {
+            shared_secret,
+            exchange_hash,
+            host_key,
+            signature,
+        })
+    }
+}
+
+impl fmt::Debug for DhGroupExchangeSha256 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DhGroupExchangeSha256")
+            .field("request", &self.request)
+            .field("group", &self.group)
+            .field("private_key", &format_args!("<redacted>"))
+            .field("public_key", &self.public_key)
+            .finish()
+    }
+}
+
+/// Decodes `value` as an element of the group, at the modulus' precision,
+/// if `1 < value < p - 1`
+fn group_element(value: &[u8], params: &BoxedMontyParams) -> Option<BoxedUint> {
+    let value = BoxedUint::from_be_slice(value, params.bits_precision()).ok()?;
+
+    is_group_element(&value, params).then_some(value)
+}
+
+/// Whether `1 < value < p - 1`, see [`group14_is_valid`]
+fn is_group_element(value: &BoxedUint, params: &BoxedMontyParams) -> bool {
+    let one = BoxedUint::one_with_precision(params.bits_precision());
+    let max = params.modulus().as_ref().wrapping_sub(&one);
+
+    *value > one && *value < max
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hostkey::PublicKey;
+
+    /// Inputs for the known-answer tests, whose expected values were worked
+    /// out separately from the RFCs' hash layouts
+    const CLIENT_VERSION: &[u8] = b"SSH-2.0-OpenSSH_9.2p1 Debian-2+deb12u6";
+    const SERVER_VERSION: &[u8] = b"SSH-2.0-softpaw_0.1.0";
+    const CLIENT_KEXINIT: &[u8] =
+        b"\x14\x00\x01\x02\x03\x04\x05\x06\x07\x08\x09\x0a\x0b\x0c\x0d\x0e\x0fclient kexinit";
+    const SERVER_KEXINIT: &[u8] =
+        b"\x14\x10\x11\x12\x13\x14\x15\x16\x17\x18\x19\x1a\x1b\x1c\x1d\x1e\x1fserver kexinit";
+
+    fn context() -> ExchangeContext<'static> {
+        ExchangeContext {
+            client_version: CLIENT_VERSION,
+            server_version: SERVER_VERSION,
+            client_kexinit: CLIENT_KEXINIT,
+            server_kexinit: SERVER_KEXINIT,
+        }
+    }
+
+    /// K_S, the blob of the ed25519 test key
+    fn host_key() -> Bytes {
+        let line = include_str!("../tests/data/id_ed25519.pub");
+
+        PublicKey::from_authorized_keys_line(line)
+            .unwrap()
+            .key
+            .to_blob()
+    }
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn kexinit(kex: &[&str], host_key: &[&str], cipher: &[&str]) -> Kexinit {
+        Kexinit::builder()
+            .kex_algorithms(kex.iter().copied())
+            .server_host_key_algorithms(host_key.iter().copied())
+            .encryption_algorithms(cipher.iter().copied())
+            .mac_algorithms(["hmac-sha2-256-etm@openssh.com", "hmac-sha2-256"])
+            .compression_algorithms(["none"])
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn client_preference_wins() {
+        let client = kexinit(
+            &["curve25519-sha256", "diffie-hellman-group14-sha256"],
+            &["ssh-ed25519", "rsa-sha2-256"],
+            &["aes128-ctr", "aes256-ctr"],
+        );
+        let server = kexinit(
+            &["diffie-hellman-group14-sha256", "curve25519-sha256"],
+            &["rsa-sha2-256", "ssh-ed25519"],
+            &["aes256-ctr", "aes128-ctr"],
+        );
+
+        let algorithms = client.negotiate(&server).unwrap();
+        assert_eq!(algorithms.kex, "curve25519-sha256");
+        assert_eq!(algorithms.server_host_key, "ssh-ed25519");
+        assert_eq!(algorithms.encryption_client_to_server, "aes128-ctr");
+        assert_eq!(algorithms.encryption_server_to_client, "aes128-ctr");
+        assert_eq!(
+            algorithms.mac_client_to_server.as_deref(),
+            Some("hmac-sha2-256-etm@openssh.com")
+        );
+        assert_eq!(algorithms.compression_server_to_client, "none");
+        assert!(!algorithms.strict_kex);
+
+        // The other way around the server's list is the client's
+        let algorithms = server.negotiate(&client).unwrap();
+        assert_eq!(algorithms.kex, "diffie-hellman-group14-sha256");
+        assert_eq!(algorithms.server_host_key, "rsa-sha2-256");
+    }
+
+    #[test]
+    fn nothing_in_common() {
+        // Host keys don't match either, kex is reported as it comes first
+        let client = kexinit(&["curve25519-sha256"], &["ssh-ed25519"], &["aes128-ctr"]);
+        let server = kexinit(
+            &["diffie-hellman-group14-sha256"],
+            &["rsa-sha2-256"],
+            &["aes128-ctr"],
+        );
+
+        assert!(matches!(
+            client.negotiate(&server),
+            Err(NegotiationError::NoCommonAlgorithm(AlgorithmCategory::Kex))
+        ));
+
+        let server = kexinit(&["curve25519-sha256"], &["rsa-sha2-256"], &["aes128-ctr"]);
+        assert!(matches!(
+            client.negotiate(&server),
+            Err(NegotiationError::NoCommonAlgorithm(
+                AlgorithmCategory::ServerHostKey
+            ))
+        ));
+    }
+
+    #[test]
+    fn aead_needs_no_mac() {
+        let mut client = kexinit(
+            &["curve25519-sha256", STRICT_KEX_CLIENT],
+            &["ssh-ed25519"],
+            &["chacha20-poly1305@openssh.com", "aes128-ctr"],
+        );
+        let server = kexinit(
+            &["curve25519-sha256", STRICT_KEX_SERVER],
+            &["ssh-ed25519"],
+            &["aes128-ctr", "chacha20-poly1305@openssh.com"],
+        );
+
+        let algorithms = client.negotiate(&server).unwrap();
+        assert_eq!(
+            algorithms.encryption_client_to_server,
+            "chacha20-poly1305@openssh.com"
+        );
+        assert_eq!(algorithms.mac_client_to_server, None);
+        assert_eq!(algorithms.mac_server_to_client, None);
+        assert!(algorithms.strict_kex);
+
+        // Not even a MAC list in common is needed then
+        client.mac_algorithms_client_to_server = vec!["hmac-sha2-512".to_owned()];
+        client.mac_algorithms_server_to_client = vec!["hmac-sha2-512".to_owned()];
+        assert_eq!(client.negotiate(&server).unwrap(), algorithms);
+    }
+
+    #[test]
+    fn curve25519_exchange_hash() {
+        // The X25519 keys and shared secret of RFC 7748 section 6.1
+        let client_public_key =
+            hex("8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a");
+        let server_public_key =
+            hex("de9edb7d7b7dc1b4d35b61c2ece435373f8343c85b78674dadfc7e146f882b4f");
+        let shared_secret = hex("4a5d9d5ba4ce2de1728e3bf480350f25e07e21c947d19e3376f09b3c1e161742");
+
+        let exchange_hash = compute_exchange_hash(
+            HashAlg::for_kex(Curve25519Sha256::NAME).unwrap(),
+            &context(),
+            &host_key(),
+            &client_public_key,
+            &server_public_key,
+            &shared_secret,
+        );
+
+        assert_eq!(
+            exchange_hash,
+            hex("fcf1f07c6f2cd5712fa58f8c09d06e162b983946dcaa62ff8d7f7d21fe550fed")
+        );
+    }
+
+    #[test]
+    fn curve25519_both_sides_agree() {
+        let client = Curve25519Sha256::new().unwrap();
+        let server = Curve25519Sha256::new().unwrap();
+        let server_public_key = Bytes::copy_from_slice(server.public_key());
+
+        let init = client.init_message();
+        let server_output = server.respond(&context(), host_key(), init).unwrap();
+
+        let reply = KexEcdhReply {
+            host_key: server_output.host_key.clone(),
+            ephemeral_public_key: server_public_key,
+            signature: Bytes::from_static(b"signature"),
+        };
+        let client_output = client.finish(&context(), reply).unwrap();
+
+        assert_eq!(client_output.shared_secret, server_output.shared_secret);
+        assert_eq!(client_output.exchange_hash, server_output.exchange_hash);
+        assert_eq!(client_output.host_key, host_key());
+        assert_eq!(client_output.signature, &b"signature"[..]);
+
+        // Q_S of the wrong length never reaches aws-lc
+        let reply = KexEcdhReply {
+            host_key: host_key(),
+            ephemeral_public_key: Bytes::from_static(&[9; 31]),
+            signature: Bytes::new(),
+        };
+        assert!(matches!(
+            Curve25519Sha256::new().unwrap().finish(&context(), reply),
+            Err(KexError::InvalidPublicKey)
+        ));
+    }
+
+    #[test]
+    fn exchange_hash_per_algorithm() {
+        assert_eq!(
+            HashAlg::for_kex("ecdh-sha2-nistp256"),
+            Some(HashAlg::Sha256)
+        );
+        assert_eq!(
+            HashAlg::for_kex("ecdh-sha2-nistp384"),
+            Some(HashAlg::Sha384)
+        );
+        assert_eq!(
+            HashAlg::for_kex("diffie-hellman-group16-sha512"),
+            Some(HashAlg::Sha512)
+        );
+        assert_eq!(HashAlg::for_kex("diffie-hellman-group1-sha1"), None);
+
+        // Uncompressed points, and a K with leading zeros and the high bit
+        // set after them, which take an mpint sign byte
+        let client_public_key = [&[4][..], &[1; 64]].concat();
+        let server_public_key = [&[4][..], &[2; 64]].concat();
+        let shared_secret: Vec<u8> = [0, 0]
+            .into_iter()
+            .chain((0..30).map(|i| 0x80 | i))
+            .collect();
+
+        let compute = |hash| {
+            compute_exchange_hash(
+                hash,
+                &context(),
+                &host_key(),
+                &client_public_key,
+                &server_public_key,
+                &shared_secret,
+            )
+        };
+
+        assert_eq!(
+            compute(HashAlg::Sha256),
+            hex("5408f39283901ed406cc68e1bcfd8968db9905cf4fc2f99f33922a17928896f6")
+        );
+        assert_eq!(
+            compute(HashAlg::Sha512),
+            hex(concat!(
+                "f8617d15b99b2cdcc55979e2ffabaee44ded24f7b356dc45ed6626d85db4ba45",
+                "c7e6f84e993e05d8fd95b07933a7f447607f896d9ee9bdc52b9beed2f5e7ff28",
+            ))
+        );
+    }
+
+    #[test]
+    fn derive_keys_known_answer() {
+        let shared_secret = hex("80878e959ca3aab1b8bfc6cdd4dbe2e9f0f7fe858c939aa1a8afb6bdc4cbd2d9");
+        let exchange_hash = hex("7bde03b6174b06ac22d902ada587e02f46b15e82e96fcc34d951b86efe0642f8");
+        let session_id = hex("1cc1c70c03d3fa98125ac304150c6b2ab44b1a308f55aab9e46e6de9f92ae871");
+
+        // SHA-256 gives 32 bytes at a time, so each key is extended once
+        let keys = derive_keys(&shared_secret, &exchange_hash, &session_id, HashAlg::Sha256);
+
+        for (key, expected) in [
+            (
+                &keys.iv_client_to_server,
+                concat!(
+                    "98a1f02abe8cafe44c99c773bd7a496a53ea5204c3ba1c3e3def73ef658bfeea",
+                    "bb0b43535d18bef643bea9e8e2147da9b37ed4424e76bd4f3507a20ca4fccf7d",
+                ),
+            ),
+            (
+                &keys.iv_server_to_client,
+                concat!(
+                    "4c4a80a9deb7bb706e66c1991edb5e1d946f9182eef7237deed5cd69d3c08389",
+                    "844d5ae9a202e82ac35dc6fdab855b646e27d16ef12a194509e7d0defb0cd755",
+                ),
+            ),
+            (
+                &keys.encryption_key_client_to_server,
+                concat!(
+                    "b92750047e987e25492ef86a0eed773fe5ddc8a3bcd249e2ee62bd6dec849155",
+                    "55ca37a4a6e38aa74e4e862262e742b31c0879320321bde94594fee2a9c09bf3",
+                ),
+            ),
+            (
+                &keys.encryption_key_server_to_client,
+                concat!(
+                    "df61fc02bfb845da14e621202dd6e991b8e6b4d673e610e0b36558fbd97d6d43",
+                    "ff7906960f468c08167a8e6e6f1c100a98ed8e890c4baa164ebbb5e611a0576e",
+                ),
+            ),
+            (
+                &keys.integrity_key_client_to_server,
+                concat!(
+                    "a4dba2bbd00c9d16686c294ca5d574cbacfafd9f1a2c6c97246d68490ee8055f",
+                    "74e86f6e06145be0fd19298f706b7739eb5509c036d964d6a2aff58a36fdbb1b",
+                ),
+            ),
+            (
+                &keys.integrity_key_server_to_client,
+                concat!(
+                    "303998e3e44150f4dcd1d40b74fcb23001b4e8ba96e4480a77932348aaea7192",
+                    "173347312ee1c580409d2fec9c4da7a958a59d74e47717803ec6e828a0d6d41c",
+                ),
+            ),
+        ] {
+            assert_eq!(*key, hex(expected));
+        }
+    }
+
+    /// e, f and K as a 2048-bit exchange could give them, e and K with the
+    /// high bit set and f with a leading zero
+    fn dh_values() -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+        let e = vec![0xe5; 256];
+        let f = [&[0][..], &[0x11; 255]].concat();
+        let k = vec![0x9a; 256];
+
+        (e, f, k)
+    }
+
+    #[test]
+    fn dh_exchange_hash() {
+        let (e, f, k) = dh_values();
+        let exchange_hash =
+            compute_dh_exchange_hash(HashAlg::Sha256, &context(), &host_key(), &e, &f, &k);
+
+        assert_eq!(
+            exchange_hash,
+            hex("4d83e426a693b381e1d233f01a8defe7cfa02231ff916626282f2060bfbf9ad0")
+        );
+    }
+
+    /// Group 14 as a server might offer it for a group exchange
+    fn group14() -> KexDhGexGroup {
+        KexDhGexGroup {
+            p: to_unsigned_bytes(GROUP14_PRIME.as_ref()).into(),
+            g: Bytes::from_static(&[2]),
+        }
+    }
+
+    #[test]
+    fn gex_exchange_hash() {
+        let (e, f, k) = dh_values();
+        let exchange_hash = compute_gex_exchange_hash(
+            HashAlg::Sha256,
+            &context(),
+            &host_key(),
+            &DhGroupExchangeSha256::REQUEST,
+            &group14(),
+            &e,
+            &f,
+            &k,
+        );
+
+        // min, n and max go in as uint32 and p and g as mpints between K_S
+        // and e, unlike anything in the other exchange hashes
+        assert_eq!(
+            exchange_hash,
+            hex("6187228ba6d5f5cc6b0c2d9f5fea0f3488a11d601d5c1e98de778b6660a00844")
+        );
+    }
+
+    #[test]
+    fn gex_agrees_with_the_server() {
+        let client = DhGroupExchangeSha256::new(DhGroupExchangeSha256::REQUEST, group14()).unwrap();
+        let e = client.public_key();
+
+        // The server half of group 14 computes the same K
+        let server = DhGroup14Sha256::new().unwrap();
+        let f = server.public_key();
+        let server_output = server
+            .respond(
+                &context(),
+                host_key(),
+                KexdhInit {
+                    e: e.clone().into(),
+                },
+            )
+            .unwrap();
+
+        let reply = KexDhGexReply {
+            host_key: host_key(),
+            f: f.clone().into(),
+            signature: Bytes::new(),
+        };
+        let output = client.finish(&context(), reply).unwrap();
+
+        assert_eq!(output.shared_secret, server_output.shared_secret);
+        assert_eq!(
+            output.exchange_hash,
+            compute_gex_exchange_hash(
+                HashAlg::Sha256,
+                &context(),
+                &host_key(),
+                &DhGroupExchangeSha256::REQUEST,
+                &group14(),
+                &e,
+                &f,
+                &output.shared_secret,
+            )
+        );
+    }
+
+    #[test]
+    fn gex_group_outside_request() {
+        let request = KexDhGexRequest {
+            min: 3072,
+            ..DhGroupExchangeSha256::REQUEST
+        };
+
+        assert!(matches!(
+            DhGroupExchangeSha256::new(request, group14()),
+            Err(KexError::InvalidGroup)
+        ));
+    }
+
+    #[test]
+    fn debug_redacts_secrets() {
+        let secret = vec![0xab; DerivedKeys::KEY_LENGTH];
+        let keys = DerivedKeys {
+            iv_client_to_server: secret.clone(),
+            iv_server_to_client: secret.clone(),
+            encryption_key_client_to_server: secret.clone(),
+            encryption_key_server_to_client: secret.clone(),
+            integrity_key_client_to_server: secret.clone(),
+            integrity_key_server_to_client: secret.clone(),
+        };
+        let debug = format!("{keys:?}");
+        assert!(!debug.contains("171"), "{debug}");
+        assert!(debug.contains("<redacted, 64 bytes>"), "{debug}");
+
+        let output = KexOutput {
+            shared_secret: secret,
+            exchange_hash: vec![1; 32],
+            host_key: host_key(),
+            signature: Bytes::new(),
+        };
+        let debug = format!("{output:?}");
+        assert!(!debug.contains("171"), "{debug}");
+
+        // The exponent in hex, however its digits are cased
+        let dh = DhGroup14Sha256::new().unwrap();
+        let debug = format!("{dh:?}").to_lowercase();
+        assert!(!debug.contains(&format!("{:x}", dh.private_key)), "{debug}");
+    }
+}
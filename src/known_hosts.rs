@@ -0,0 +1,281 @@
+use std::{fs, io, path::Path};
+
+use aws_lc_rs::hmac::{self, HMAC_SHA1_FOR_LEGACY_USE_ONLY};
+use base64::{Engine, engine::general_purpose::STANDARD};
+
+use crate::{
+    hostkey::{HostKey, HostKeyVerifier, VerifyError},
+    tracing::debug,
+};
+
+/// Host keys from an OpenSSH `known_hosts` file, see the SSH_KNOWN_HOSTS
+/// FILE FORMAT section of sshd(8).
+///
+/// Supported are comma separated host patterns with `*`, `?` and `!`
+/// negation, hashed hostnames and `@revoked` markers. Lines that can't be
+/// used, such as `@cert-authority` entries or key types this crate doesn't
+/// know, are skipped.
+#[derive(Debug, Clone, Default)]
+pub struct KnownHosts {
+    entries: Vec<Entry>,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    hosts: Hosts,
+    key: HostKey,
+    revoked: bool,
+}
+
+#[derive(Debug, Clone)]
+enum Hosts {
+    Patterns(Vec<String>),
+    /// `|1|salt|hash|`, the hash being HMAC-SHA1 of the hostname keyed with
+    /// the salt
+    Hashed {
+        salt: Vec<u8>,
+        hash: Vec<u8>,
+    },
+}
+
+impl KnownHosts {
+    pub fn parse(contents: &str) -> Self {
+        let entries = contents
+            .lines()
+            .filter_map(|line| {
+                let line = line.trim();
+
+                if line.is_empty() || line.starts_with('#') {
+                    return None;
+                }
+
+                let entry = parse_entry(line);
+                if entry.is_none() {
+                    debug!(?line, "Skipping known_hosts line");
+                }
+
+                entry
+            })
+            .collect();
+
+        Self { entries }
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self::parse(&fs::read_to_string(path)?))
+    }
+}
+
+impl HostKeyVerifier for KnownHosts {
+    /// Accepts `key` if an entry for `hostname` has it.
+    ///
+    /// Entries with a different type than `key` don't count against it, a
+    /// server offering a new type of key is merely unknown. A known key of
+    /// the same type that differs is a mismatch though. A key marked
+    /// `@revoked` is rejected even when another entry lists it as well.
+    fn verify(&self, hostname: &str, key: &HostKey) -> Result<(), VerifyError> {
+        let hostname = hostname.to_ascii_lowercase();
+        let entries = self
+            .entries
+            .iter()
+            .filter(|entry| entry.hosts.matches(&hostname));
+
+        // A revocation holds wherever it is in the file, so it has to be
+        // looked for before accepting anything
+        if entries
+            .clone()
+            .any(|entry| entry.revoked && entry.key == *key)
+        {
+            return Err(VerifyError::Revoked(hostname));
+        }
+
+        let mut mismatch = false;
+
+        for entry in entries.filter(|entry| !entry.revoked) {
+            if entry.key == *key {
+                return Ok(());
+            }
+
+            if entry.key.key_type() == key.key_type() {
+                mismatch = true;
+            }
+        }
+
+        match mismatch {
+            true => Err(VerifyError::KeyMismatch(hostname)),
+            false => Err(VerifyError::UnknownHost(hostname)),
+        }
+    }
+}
+
+fn parse_entry(line: &str) -> Option<Entry> {
+    let mut fields = line.split_ascii_whitespace();
+
+    let mut hosts = fields.next()?;
+    let mut revoked = false;
+
+    if let Some(marker) = hosts.strip_prefix('@') {
+        match marker {
+            "revoked" => revoked = true,
+            _ => return None,
+        }
+
+        hosts = fields.next()?;
+    }
+
+    let key_type = fields.next()?;
+    let blob = STANDARD.decode(fields.next()?).ok()?;
+
+    let key = HostKey::parse(&blob).ok()?;
+    if key.key_type() != key_type {
+        return None;
+    }
+
+    Some(Entry {
+        hosts: Hosts::parse(hosts)?,
+        key,
+        revoked,
+    })
+}
+
+impl Hosts {
+    fn parse(hosts: &str) -> Option<Self> {
+        match hosts.strip_prefix("|1|") {
+            Some(hashed) => {
+                let (salt, hash) = hashed.split_once('|')?;
+
+                Some(Hosts::Hashed {
+                    salt: STANDARD.decode(salt).ok()?,
+                    hash: STANDARD.decode(hash).ok()?,
+                })
+            }
+            None => Some(Hosts::Patterns(
+                hosts.split(',').map(str::to_ascii_lowercase).collect(),
+            )),
+        }
+    }
+
+    /// Whether `hostname` matches, a negated pattern overriding any other
+    fn matches(&self, hostname: &str) -> bool {
+        match self {
+            Hosts::Patterns(patterns) => {
+                let mut matched = false;
+
+                for pattern in patterns {
+                    match pattern.strip_prefix('!') {
+                        Some(negated) if wildcard_match(negated, hostname) => return false,
+                        Some(_) => {}
+                        None => matched |= wildcard_match(pattern, hostname),
+                    }
+                }
+
+                matched
+            }
+            Hosts::Hashed { salt, hash } => {
+                let key = hmac::Key::new(HMAC_SHA1_FOR_LEGACY_USE_ONLY, salt);
+
+                hmac::sign(&key, hostname.as_bytes()).as_ref() == hash.as_slice()
+            }
+        }
+    }
+}
+
+/// Matches `*` against any run of characters and `?` against exactly one
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let (pattern, text) = (pattern.as_bytes(), text.as_bytes());
+    let (mut p, mut t) = (0, 0);
+    // Position after the last `*` and the text position it was tried at
+    let mut backtrack = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                p += 1;
+                backtrack = Some((p, t));
+            }
+            Some(&c) if c == b'?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star_p, star_t)) => {
+                    p = star_p;
+                    t = star_t + 1;
+                    backtrack = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY_A: &str = "AAAAC3NzaC1lZDI1NTE5AAAAIEe4VoSQGhpbBGvJZkVFXsz1DvtmwRFgpibZkjeCNmYC";
+    const KEY_B: &str = "AAAAC3NzaC1lZDI1NTE5AAAAIKdXubOMJE1bw90sJl6uF8lnsLjh847tTrOzh/Ao+iM8";
+
+    fn key(base64: &str) -> HostKey {
+        HostKey::parse(&STANDARD.decode(base64).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn matching_entry() {
+        let known_hosts = KnownHosts::parse(&format!(
+            "# comment\nother.org ssh-ed25519 {KEY_B}\nexample.com,*.example.net ssh-ed25519 {KEY_A}\n"
+        ));
+
+        known_hosts.verify("example.com", &key(KEY_A)).unwrap();
+        known_hosts.verify("EXAMPLE.com", &key(KEY_A)).unwrap();
+        known_hosts.verify("www.example.net", &key(KEY_A)).unwrap();
+
+        assert!(matches!(
+            known_hosts.verify("example.org", &key(KEY_A)),
+            Err(VerifyError::UnknownHost(_))
+        ));
+    }
+
+    #[test]
+    fn mismatched_key() {
+        let known_hosts = KnownHosts::parse(&format!("example.com ssh-ed25519 {KEY_A}"));
+
+        assert!(matches!(
+            known_hosts.verify("example.com", &key(KEY_B)),
+            Err(VerifyError::KeyMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn hashed_hostname() {
+        // From `ssh-keygen -H` for example.com
+        let known_hosts = KnownHosts::parse(&format!(
+            "|1|TOgUrfM2ms0VAUImVGNvsAqTjM8=|pgceEQ3EnFjW+ylODkQlibgd5bs= ssh-ed25519 {KEY_A}"
+        ));
+
+        known_hosts.verify("example.com", &key(KEY_A)).unwrap();
+
+        assert!(matches!(
+            known_hosts.verify("example.org", &key(KEY_A)),
+            Err(VerifyError::UnknownHost(_))
+        ));
+        assert!(matches!(
+            known_hosts.verify("example.com", &key(KEY_B)),
+            Err(VerifyError::KeyMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn revoked_after_accepted() {
+        let known_hosts = KnownHosts::parse(&format!(
+            "example.com ssh-ed25519 {KEY_A}\n@revoked * ssh-ed25519 {KEY_A}\n"
+        ));
+
+        assert!(matches!(
+            known_hosts.verify("example.com", &key(KEY_A)),
+            Err(VerifyError::Revoked(_))
+        ));
+    }
+}
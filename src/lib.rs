@@ -1,15 +1,342 @@
+//! Without the default `std` feature only [`message`] is left, the parsing
+//! and encoding of SSH messages, which then needs nothing but `alloc`.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+#[cfg(feature = "std")]
+pub mod authorized_keys;
+#[cfg(feature = "std")]
+pub mod channel;
+#[cfg(feature = "std")]
+pub mod cipher;
+#[cfg(feature = "std")]
+pub mod client;
+#[cfg(feature = "std")]
 pub mod codec;
+#[cfg(feature = "std")]
+pub mod compression;
+#[cfg(feature = "std")]
+pub mod hostkey;
+#[cfg(feature = "std")]
+pub mod kex;
+#[cfg(feature = "std")]
+pub mod known_hosts;
+#[cfg(feature = "std")]
+pub mod mac;
 pub mod message;
+#[cfg(feature = "std")]
+pub mod openssh_key;
+#[cfg(feature = "std")]
+pub mod server;
+#[cfg(feature = "std")]
+pub mod transport;
+#[cfg(feature = "std")]
+pub mod version;
 
 #[cfg(feature = "tracing")]
+#[allow(clippy::single_component_path_imports)] // Gives it the shim's path
 pub(crate) use tracing;
 
+/// Stand-ins for what the crate uses from `tracing`, so that logging compiles
+/// away without the feature. Arguments are type checked but never evaluated,
+/// in the forms `tracing` takes them: `target:` and `parent:` first, then
+/// `field = value`, `field = %value`, `field = ?value`, `%field`, `?field`
+/// or a bare `field`, then a format string and its arguments.
 #[cfg(not(feature = "tracing"))]
 #[allow(unused)]
 pub(crate) mod tracing {
+    /// Borrows every value in an `if false`, for the other macros to wrap
+    macro_rules! consume {
+        () => {};
+        (target: $target:expr, $($rest:tt)*) => {
+            let _ = &$target;
+            $crate::tracing::consume!($($rest)*);
+        };
+        (parent: $parent:expr, $($rest:tt)*) => {
+            let _ = &$parent;
+            $crate::tracing::consume!($($rest)*);
+        };
+        ($($field:ident).+ = % $value:expr $(, $($rest:tt)*)?) => {
+            let _ = &$value;
+            $crate::tracing::consume!($($($rest)*)?);
+        };
+        ($($field:ident).+ = ? $value:expr $(, $($rest:tt)*)?) => {
+            let _ = &$value;
+            $crate::tracing::consume!($($($rest)*)?);
+        };
+        ($($field:ident).+ = $value:expr $(, $($rest:tt)*)?) => {
+            let _ = &$value;
+            $crate::tracing::consume!($($($rest)*)?);
+        };
+        (% $($field:ident).+ $(, $($rest:tt)*)?) => {
+            let _ = &$($field).+;
+            $crate::tracing::consume!($($($rest)*)?);
+        };
+        (? $($field:ident).+ $(, $($rest:tt)*)?) => {
+            let _ = &$($field).+;
+            $crate::tracing::consume!($($($rest)*)?);
+        };
+        ($($field:ident).+ $(, $($rest:tt)*)?) => {
+            let _ = &$($field).+;
+            $crate::tracing::consume!($($($rest)*)?);
+        };
+        ($message:literal $($rest:tt)*) => {
+            let _ = format_args!($message $($rest)*);
+        };
+
+        // A level, as `event!` and `span!` take first
+        (@level target: $target:expr, $($rest:tt)*) => {
+            let _ = &$target;
+            $crate::tracing::consume!(@level $($rest)*);
+        };
+        (@level parent: $parent:expr, $($rest:tt)*) => {
+            let _ = &$parent;
+            $crate::tracing::consume!(@level $($rest)*);
+        };
+        (@level $level:expr $(, $($rest:tt)*)?) => {
+            let _: &$crate::tracing::Level = &$level;
+            $crate::tracing::consume!($($($rest)*)?);
+        };
+
+        // A span name, then its fields
+        (@span target: $target:expr, $($rest:tt)*) => {
+            let _ = &$target;
+            $crate::tracing::consume!(@span $($rest)*);
+        };
+        (@span parent: $parent:expr, $($rest:tt)*) => {
+            let _ = &$parent;
+            $crate::tracing::consume!(@span $($rest)*);
+        };
+        (@span $name:literal $(, $($rest:tt)*)?) => {
+            let _: &str = $name;
+            $crate::tracing::consume!($($($rest)*)?);
+        };
+    }
+
+    macro_rules! trace {
+        ($($arg:tt)*) => {
+            if false {
+                $crate::tracing::consume!($($arg)*);
+            }
+        };
+    }
+
     macro_rules! debug {
-        ($($tt:tt)*) => {};
+        ($($arg:tt)*) => {
+            if false {
+                $crate::tracing::consume!($($arg)*);
+            }
+        };
+    }
+
+    macro_rules! info {
+        ($($arg:tt)*) => {
+            if false {
+                $crate::tracing::consume!($($arg)*);
+            }
+        };
+    }
+
+    // Named so as not to clash with the `warn` attribute
+    macro_rules! warn_ {
+        ($($arg:tt)*) => {
+            if false {
+                $crate::tracing::consume!($($arg)*);
+            }
+        };
+    }
+
+    macro_rules! error {
+        ($($arg:tt)*) => {
+            if false {
+                $crate::tracing::consume!($($arg)*);
+            }
+        };
+    }
+
+    macro_rules! event {
+        ($($arg:tt)*) => {
+            if false {
+                $crate::tracing::consume!(@level $($arg)*);
+            }
+        };
+    }
+
+    macro_rules! span {
+        (target: $target:expr, $level:expr, $($arg:tt)*) => {{
+            if false {
+                let _ = &$target;
+            }
+            $crate::tracing::span!($level, $($arg)*)
+        }};
+        (parent: $parent:expr, $level:expr, $($arg:tt)*) => {{
+            if false {
+                let _ = &$parent;
+            }
+            $crate::tracing::span!($level, $($arg)*)
+        }};
+        ($level:expr, $($arg:tt)*) => {{
+            if false {
+                let _: &$crate::tracing::Level = &$level;
+                $crate::tracing::consume!(@span $($arg)*);
+            }
+            $crate::tracing::Span::none()
+        }};
+    }
+
+    macro_rules! trace_span {
+        ($($arg:tt)*) => {
+            $crate::tracing::span!($crate::tracing::Level::TRACE, $($arg)*)
+        };
+    }
+
+    macro_rules! debug_span {
+        ($($arg:tt)*) => {
+            $crate::tracing::span!($crate::tracing::Level::DEBUG, $($arg)*)
+        };
+    }
+
+    macro_rules! info_span {
+        ($($arg:tt)*) => {
+            $crate::tracing::span!($crate::tracing::Level::INFO, $($arg)*)
+        };
+    }
+
+    macro_rules! warn_span {
+        ($($arg:tt)*) => {
+            $crate::tracing::span!($crate::tracing::Level::WARN, $($arg)*)
+        };
+    }
+
+    macro_rules! error_span {
+        ($($arg:tt)*) => {
+            $crate::tracing::span!($crate::tracing::Level::ERROR, $($arg)*)
+        };
     }
 
-    pub(crate) use debug;
+    pub(crate) use {
+        consume, debug, debug_span, error, error_span, event, info, info_span, span, trace,
+        trace_span, warn_ as warn, warn_span,
+    };
+
+    /// For `span!` and `event!`, which take the level first
+    pub(crate) struct Level;
+
+    impl Level {
+        pub(crate) const TRACE: Level = Level;
+        pub(crate) const DEBUG: Level = Level;
+        pub(crate) const INFO: Level = Level;
+        pub(crate) const WARN: Level = Level;
+        pub(crate) const ERROR: Level = Level;
+    }
+
+    /// What the span macros give, entering it does nothing
+    #[derive(Debug, Clone, Default)]
+    pub(crate) struct Span;
+
+    impl Span {
+        pub(crate) fn none() -> Self {
+            Span
+        }
+
+        pub(crate) fn current() -> Self {
+            Span
+        }
+
+        pub(crate) fn enter(&self) -> Span {
+            Span
+        }
+
+        pub(crate) fn entered(self) -> Span {
+            self
+        }
+
+        pub(crate) fn in_scope<T>(&self, f: impl FnOnce() -> T) -> T {
+            f()
+        }
+
+        pub(crate) fn record<V>(&self, _field: &str, _value: V) -> &Self {
+            self
+        }
+
+        pub(crate) fn is_disabled(&self) -> bool {
+            true
+        }
+    }
+
+    /// Instrumenting a future leaves it as it is
+    pub(crate) trait Instrument: Sized {
+        fn instrument(self, _span: Span) -> Self {
+            self
+        }
+
+        fn in_current_span(self) -> Self {
+            self
+        }
+    }
+
+    impl<T> Instrument for T {}
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{borrow::ToOwned, string::String};
+
+    use crate::tracing::{
+        Instrument, Level, Span, debug, debug_span, error, error_span, event, info, info_span,
+        span, trace, trace_span, warn, warn_span,
+    };
+
+    /// Every form of the logging macros the crate may use, which has to
+    /// compile both with `tracing` and with the shim, also without `std`
+    #[test]
+    fn logging_macros() {
+        #[derive(Debug)]
+        struct Peer {
+            name: String,
+        }
+
+        let peer = Peer {
+            name: "paw".to_owned(),
+        };
+        let count = 3;
+        let error = core::fmt::Error;
+
+        trace!("Plain message");
+        debug!("Formatted {} {count}", peer.name);
+        info!(count, "Bare field");
+        warn!(%error, ?peer, "Shorthand fields");
+        error!(
+            name = %peer.name,
+            peer = ?peer,
+            peer.name = peer.name.as_str(),
+            count = count + 1,
+            "Named fields {}",
+            count,
+        );
+        debug!(target: "softpaw", count);
+        warn!(count);
+
+        event!(Level::INFO, count, "Event");
+        event!(target: "softpaw", Level::DEBUG, ?peer);
+
+        let span = span!(Level::TRACE, "span", %count);
+        let _guard = span.enter();
+        span.record("count", 4);
+
+        let _ = trace_span!("trace_span");
+        let _ = debug_span!("debug_span", ?peer);
+        let _ = info_span!(target: "softpaw", "info_span", count);
+        let _ = warn_span!(parent: &span, "warn_span");
+        let _ = error_span!("error_span", name = %peer.name);
+
+        assert_eq!(Span::current().in_scope(|| count), 3);
+        drop(async {}.instrument(span.clone()).in_current_span());
+
+        // A log-only variable, which doesn't need a leading underscore
+        let unused_otherwise = "only logged";
+        debug!(unused_otherwise);
+    }
 }
@@ -0,0 +1,228 @@
+use std::{fmt, io};
+
+use aws_lc_rs::{constant_time, hmac};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MacAlgorithm {
+    HmacSha256,
+    HmacSha512,
+    HmacSha256Etm,
+    HmacSha512Etm,
+}
+
+impl MacAlgorithm {
+    /// Looks up a MAC by its SSH algorithm name
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "hmac-sha2-256" => Some(MacAlgorithm::HmacSha256),
+            "hmac-sha2-512" => Some(MacAlgorithm::HmacSha512),
+            "hmac-sha2-256-etm@openssh.com" => Some(MacAlgorithm::HmacSha256Etm),
+            "hmac-sha2-512-etm@openssh.com" => Some(MacAlgorithm::HmacSha512Etm),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            MacAlgorithm::HmacSha256 => "hmac-sha2-256",
+            MacAlgorithm::HmacSha512 => "hmac-sha2-512",
+            MacAlgorithm::HmacSha256Etm => "hmac-sha2-256-etm@openssh.com",
+            MacAlgorithm::HmacSha512Etm => "hmac-sha2-512-etm@openssh.com",
+        }
+    }
+
+    pub fn key_length(&self) -> usize {
+        self.output_length()
+    }
+
+    pub fn output_length(&self) -> usize {
+        match self {
+            MacAlgorithm::HmacSha256 | MacAlgorithm::HmacSha256Etm => 32,
+            MacAlgorithm::HmacSha512 | MacAlgorithm::HmacSha512Etm => 64,
+        }
+    }
+
+    /// Whether this is an encrypt-then-MAC variant, which leaves the packet
+    /// length unencrypted and computes the MAC over the ciphertext
+    pub fn is_etm(&self) -> bool {
+        matches!(
+            self,
+            MacAlgorithm::HmacSha256Etm | MacAlgorithm::HmacSha512Etm
+        )
+    }
+}
+
+/// Packet authentication for one direction of the connection, for ciphers
+/// that don't bring their own
+pub(crate) trait Mac: Send {
+    fn algorithm(&self) -> MacAlgorithm;
+
+    fn output_length(&self) -> usize {
+        self.algorithm().output_length()
+    }
+
+    /// Whether the MAC covers the ciphertext rather than the plaintext, see
+    /// [`MacAlgorithm::is_etm`]
+    fn is_etm(&self) -> bool {
+        self.algorithm().is_etm()
+    }
+
+    /// Computes `MAC(key, sequence_number || packet)` into `out`, `packet`
+    /// being the plaintext, or the length and ciphertext with EtM
+    fn compute(&self, seq: u32, packet: &[u8], out: &mut [u8]);
+
+    /// Checks `tag` against the MAC of `packet`, in constant time
+    fn verify(&self, seq: u32, packet: &[u8], tag: &[u8]) -> io::Result<()>;
+}
+
+impl fmt::Debug for dyn Mac {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mac")
+            .field("algorithm", &self.algorithm())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Keys `algorithm`, checking the key length
+pub(crate) fn new_mac(algorithm: MacAlgorithm, key: &[u8]) -> io::Result<Box<dyn Mac>> {
+    if key.len() != algorithm.key_length() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid key length for {}", algorithm.name()),
+        ));
+    }
+
+    let hmac_algorithm = match algorithm {
+        MacAlgorithm::HmacSha256 | MacAlgorithm::HmacSha256Etm => hmac::HMAC_SHA256,
+        MacAlgorithm::HmacSha512 | MacAlgorithm::HmacSha512Etm => hmac::HMAC_SHA512,
+    };
+
+    Ok(Box::new(Hmac {
+        algorithm,
+        key: hmac::Key::new(hmac_algorithm, key),
+    }))
+}
+
+/// The `hmac-sha2-*` MACs of RFC 6668 and their EtM variants
+struct Hmac {
+    algorithm: MacAlgorithm,
+    key: hmac::Key,
+}
+
+impl Hmac {
+    fn sign(&self, seq: u32, packet: &[u8]) -> hmac::Tag {
+        let mut context = hmac::Context::with_key(&self.key);
+        context.update(&seq.to_be_bytes());
+        context.update(packet);
+
+        context.sign()
+    }
+}
+
+impl Mac for Hmac {
+    fn algorithm(&self) -> MacAlgorithm {
+        self.algorithm
+    }
+
+    fn compute(&self, seq: u32, packet: &[u8], out: &mut [u8]) {
+        out.copy_from_slice(self.sign(seq, packet).as_ref());
+    }
+
+    fn verify(&self, seq: u32, packet: &[u8], tag: &[u8]) -> io::Result<()> {
+        if !ct_eq(self.sign(seq, packet).as_ref(), tag) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "SSH packet MAC mismatch",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Compares two byte strings in constant time.
+///
+/// Security relevant: anything an attacker can choose and have checked
+/// against a secret, like a MAC tag, must be compared with this and never
+/// with `==`, which returns early at the first differing byte and so leaks
+/// through its timing how much of a forgery was right. Only the lengths are
+/// allowed to leak.
+pub(crate) fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    constant_time::verify_slices_are_equal(a, b).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALGORITHMS: [MacAlgorithm; 4] = [
+        MacAlgorithm::HmacSha256,
+        MacAlgorithm::HmacSha512,
+        MacAlgorithm::HmacSha256Etm,
+        MacAlgorithm::HmacSha512Etm,
+    ];
+
+    #[test]
+    fn ct_eq_compares_contents() {
+        assert!(ct_eq(b"", b""));
+        assert!(ct_eq(b"same tag", b"same tag"));
+
+        assert!(!ct_eq(b"same tag", b"same taG"));
+        assert!(!ct_eq(b"Same tag", b"same tag"));
+    }
+
+    #[test]
+    fn ct_eq_different_lengths() {
+        assert!(!ct_eq(b"tag", b"tag and more"));
+        assert!(!ct_eq(b"tag and more", b"tag"));
+        assert!(!ct_eq(b"", b"tag"));
+    }
+
+    #[test]
+    fn output_lengths() {
+        for (algorithm, length, hmac_algorithm) in [
+            (MacAlgorithm::HmacSha256, 32, hmac::HMAC_SHA256),
+            (MacAlgorithm::HmacSha512, 64, hmac::HMAC_SHA512),
+            (MacAlgorithm::HmacSha256Etm, 32, hmac::HMAC_SHA256),
+            (MacAlgorithm::HmacSha512Etm, 64, hmac::HMAC_SHA512),
+        ] {
+            let key = vec![0x42; algorithm.key_length()];
+            let mac = new_mac(algorithm, &key).unwrap();
+            assert_eq!(mac.output_length(), length, "{algorithm:?}");
+
+            let mut tag = vec![0; length];
+            mac.compute(0, b"packet", &mut tag);
+
+            // MAC(key, sequence_number || packet)
+            let expected = hmac::sign(&hmac::Key::new(hmac_algorithm, &key), b"\0\0\0\0packet");
+            assert_eq!(tag, expected.as_ref(), "{algorithm:?}");
+        }
+    }
+
+    #[test]
+    fn verify_round_trip() {
+        for algorithm in ALGORITHMS {
+            let mac = new_mac(algorithm, &vec![0x42; algorithm.key_length()]).unwrap();
+
+            let mut tag = vec![0; mac.output_length()];
+            mac.compute(7, b"packet", &mut tag);
+            mac.verify(7, b"packet", &tag).unwrap();
+
+            // The sequence number is covered as well as the packet
+            assert!(mac.verify(8, b"packet", &tag).is_err(), "{algorithm:?}");
+            assert!(mac.verify(7, b"Packet", &tag).is_err(), "{algorithm:?}");
+
+            let last = tag.len() - 1;
+            tag[last] ^= 1;
+            let err = mac.verify(7, b"packet", &tag).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{algorithm:?}");
+
+            // A truncated tag is no match either
+            tag[last] ^= 1;
+            assert!(
+                mac.verify(7, b"packet", &tag[..16]).is_err(),
+                "{algorithm:?}"
+            );
+        }
+    }
+}
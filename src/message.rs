@@ -1,18 +1,57 @@
-use std::{fmt::Display, string::FromUtf8Error};
+mod channel;
+mod ext_info;
+mod global;
+mod reader;
+mod terminal_modes;
+mod userauth;
+mod writer;
 
-use crate::tracing::debug;
-use bytes::{Buf, BufMut, Bytes, BytesMut};
+pub use channel::{
+    ChannelClose, ChannelData, ChannelEof, ChannelExtendedData, ChannelFailure, ChannelOpen,
+    ChannelOpenConfirmation, ChannelOpenFailure, ChannelOpenType, ChannelRequest,
+    ChannelRequestType, ChannelSuccess, ChannelWindowAdjust, OpenFailureReason,
+};
+pub use ext_info::ExtInfo;
+pub use global::{GlobalRequest, GlobalRequestType, RequestSuccess};
+pub use reader::Reader;
+pub use terminal_modes::TerminalModes;
+pub use userauth::{
+    AuthMethod, Prompt, UserauthBanner, UserauthFailure, UserauthInfoRequest, UserauthInfoResponse,
+    UserauthRequest,
+};
+pub use writer::Writer;
+
+use alloc::{
+    boxed::Box,
+    string::{FromUtf8Error, String},
+    vec::Vec,
+};
+use core::fmt::Display;
+
+use crate::tracing::trace;
+#[cfg(feature = "std")]
+use aws_lc_rs::{
+    error::Unspecified,
+    rand::{SecureRandom, SystemRandom},
+};
+use bytes::{Buf, Bytes};
 
 #[derive(Debug, thiserror::Error)]
 pub enum ParseError {
     #[error("Invalid name-list encoding")]
     InvalidNameList(#[from] FromUtf8Error),
+    #[error("Invalid UTF-8 string")]
+    InvalidUtf8(#[source] FromUtf8Error),
+    #[error("Invalid mpint encoding")]
+    InvalidMpint,
     #[error("Invalid message length")]
     InvalidLength,
     #[error("Unsupported message: {0}")]
     UnsupportedMessage(MessageType),
     #[error("Unknown message type: {0}")]
     UnknownMessageType(u8),
+    #[error("{0} can't be parsed without knowing the method in use")]
+    NeedsContext(MessageType),
 }
 
 #[repr(u8)]
@@ -24,12 +63,28 @@ pub enum MessageType {
     Debug = 4,
     ServiceRequest = 5,
     ServiceAccept = 6,
+    /// From RFC 8308
+    ExtInfo = 7,
     Kexinit = 20,
     Newkeys = 21,
+    /// Numbers 30 and 31 mean something different for every key exchange
+    /// method, this is their meaning for ECDH. They are also
+    /// SSH_MSG_KEXDH_INIT and SSH_MSG_KEXDH_REPLY for the fixed
+    /// Diffie-Hellman groups, and 31 is SSH_MSG_KEX_DH_GEX_GROUP for group
+    /// exchange.
+    KexEcdhInit = 30,
+    KexEcdhReply = 31,
+    KexDhGexInit = 32,
+    KexDhGexReply = 33,
+    KexDhGexRequest = 34,
     UserauthRequest = 50,
     UserauthFailure = 51,
     UserauthSuccess = 52,
     UserauthBanner = 53,
+    /// Number 60 means something different for every authentication method,
+    /// this is its meaning for `keyboard-interactive`
+    UserauthInfoRequest = 60,
+    UserauthInfoResponse = 61,
     GlobalRequest = 80,
     RequestSuccess = 81,
     RequestFailure = 82,
@@ -46,20 +101,65 @@ pub enum MessageType {
     ChannelFailure = 100,
 }
 
+/// Other names of the numbers that depend on the key exchange method, for
+/// code that expects one of them. They display like the ECDH variants they
+/// alias.
+#[allow(non_upper_case_globals)]
+impl MessageType {
+    /// SSH_MSG_KEXDH_INIT of the fixed Diffie-Hellman groups
+    pub const KexdhInit: MessageType = MessageType::KexEcdhInit;
+    /// SSH_MSG_KEXDH_REPLY of the fixed Diffie-Hellman groups
+    pub const KexdhReply: MessageType = MessageType::KexEcdhReply;
+    /// SSH_MSG_KEX_DH_GEX_GROUP of group exchange
+    pub const KexDhGexGroup: MessageType = MessageType::KexEcdhReply;
+}
+
 impl TryFrom<u8> for MessageType {
     type Error = ParseError;
 
     fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
             1 => Ok(MessageType::Disconnect),
+            2 => Ok(MessageType::Ignore),
+            3 => Ok(MessageType::Unimplemented),
+            4 => Ok(MessageType::Debug),
+            5 => Ok(MessageType::ServiceRequest),
+            6 => Ok(MessageType::ServiceAccept),
+            7 => Ok(MessageType::ExtInfo),
             20 => Ok(MessageType::Kexinit),
+            21 => Ok(MessageType::Newkeys),
+            30 => Ok(MessageType::KexEcdhInit),
+            31 => Ok(MessageType::KexEcdhReply),
+            32 => Ok(MessageType::KexDhGexInit),
+            33 => Ok(MessageType::KexDhGexReply),
+            34 => Ok(MessageType::KexDhGexRequest),
+            50 => Ok(MessageType::UserauthRequest),
+            51 => Ok(MessageType::UserauthFailure),
+            52 => Ok(MessageType::UserauthSuccess),
+            53 => Ok(MessageType::UserauthBanner),
+            60 => Ok(MessageType::UserauthInfoRequest),
+            61 => Ok(MessageType::UserauthInfoResponse),
+            80 => Ok(MessageType::GlobalRequest),
+            81 => Ok(MessageType::RequestSuccess),
+            82 => Ok(MessageType::RequestFailure),
+            90 => Ok(MessageType::ChannelOpen),
+            91 => Ok(MessageType::ChannelOpenConfirmation),
+            92 => Ok(MessageType::ChannelOpenFailure),
+            93 => Ok(MessageType::ChannelWindowAdjust),
+            94 => Ok(MessageType::ChannelData),
+            95 => Ok(MessageType::ChannelExtendedData),
+            96 => Ok(MessageType::ChannelEof),
+            97 => Ok(MessageType::ChannelClose),
+            98 => Ok(MessageType::ChannelRequest),
+            99 => Ok(MessageType::ChannelSuccess),
+            100 => Ok(MessageType::ChannelFailure),
             _ => Err(ParseError::UnknownMessageType(value)),
         }
     }
 }
 
 impl Display for MessageType {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
             MessageType::Disconnect => write!(f, "SSH_MSG_DISCONNECT"),
             MessageType::Ignore => write!(f, "SSH_MSG_IGNORE"),
@@ -67,12 +167,20 @@ impl Display for MessageType {
             MessageType::Debug => write!(f, "SSH_MSG_DEBUG"),
             MessageType::ServiceRequest => write!(f, "SSH_MSG_SERVICE_REQUEST"),
             MessageType::ServiceAccept => write!(f, "SSH_MSG_SERVICE_ACCEPT"),
+            MessageType::ExtInfo => write!(f, "SSH_MSG_EXT_INFO"),
             MessageType::Kexinit => write!(f, "SSH_MSG_KEXINIT"),
             MessageType::Newkeys => write!(f, "SSH_MSG_NEWKEYS"),
+            MessageType::KexEcdhInit => write!(f, "SSH_MSG_KEX_ECDH_INIT"),
+            MessageType::KexEcdhReply => write!(f, "SSH_MSG_KEX_ECDH_REPLY"),
+            MessageType::KexDhGexInit => write!(f, "SSH_MSG_KEX_DH_GEX_INIT"),
+            MessageType::KexDhGexReply => write!(f, "SSH_MSG_KEX_DH_GEX_REPLY"),
+            MessageType::KexDhGexRequest => write!(f, "SSH_MSG_KEX_DH_GEX_REQUEST"),
             MessageType::UserauthRequest => write!(f, "SSH_MSG_USERAUTH_REQUEST"),
             MessageType::UserauthFailure => write!(f, "SSH_MSG_USERAUTH_FAILURE"),
             MessageType::UserauthSuccess => write!(f, "SSH_MSG_USERAUTH_SUCCESS"),
             MessageType::UserauthBanner => write!(f, "SSH_MSG_USERAUTH_BANNER"),
+            MessageType::UserauthInfoRequest => write!(f, "SSH_MSG_USERAUTH_INFO_REQUEST"),
+            MessageType::UserauthInfoResponse => write!(f, "SSH_MSG_USERAUTH_INFO_RESPONSE"),
             MessageType::GlobalRequest => write!(f, "SSH_MSG_GLOBAL_REQUEST"),
             MessageType::RequestSuccess => write!(f, "SSH_MSG_REQUEST_SUCCESS"),
             MessageType::RequestFailure => write!(f, "SSH_MSG_REQUEST_FAILURE"),
@@ -91,40 +199,468 @@ impl Display for MessageType {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Message {
     Disconnect(Disconnect),
-    Kexinit(Kexinit),
+    /// Carries nothing of interest and is to be skipped, it may be sent as
+    /// a keepalive or to make traffic analysis harder
+    Ignore(Ignore),
+    /// Reply to a message of a type the sender doesn't know
+    Unimplemented(Unimplemented),
+    /// Diagnostic text from the peer, which may be logged or shown to the
+    /// user but otherwise doesn't affect the connection
+    Debug(DebugMessage),
+    ServiceRequest(ServiceRequest),
+    ServiceAccept(ServiceAccept),
+    ExtInfo(ExtInfo),
+    Kexinit(Box<Kexinit>),
+    /// Ends a key exchange, carrying no data.
+    ///
+    /// Once it has been sent, outgoing packets must use the new keys, so the
+    /// outgoing cipher and MAC of the `PacketCodec` should be installed right
+    /// after. Likewise the incoming ones get installed as soon as the peer's
+    /// SSH_MSG_NEWKEYS has been received.
+    Newkeys,
+    KexEcdhInit(KexEcdhInit),
+    KexEcdhReply(KexEcdhReply),
+    KexdhInit(KexdhInit),
+    KexdhReply(KexdhReply),
+    KexDhGexRequest(KexDhGexRequest),
+    KexDhGexGroup(KexDhGexGroup),
+    KexDhGexInit(KexDhGexInit),
+    KexDhGexReply(KexDhGexReply),
+    UserauthRequest(UserauthRequest),
+    UserauthFailure(UserauthFailure),
+    /// Ends authentication, carrying no data
+    UserauthSuccess,
+    UserauthBanner(UserauthBanner),
+    UserauthInfoRequest(UserauthInfoRequest),
+    UserauthInfoResponse(UserauthInfoResponse),
+    GlobalRequest(GlobalRequest),
+    RequestSuccess(RequestSuccess),
+    /// Refuses a global request, carrying no data
+    RequestFailure,
+    ChannelOpen(ChannelOpen),
+    ChannelOpenConfirmation(ChannelOpenConfirmation),
+    ChannelOpenFailure(ChannelOpenFailure),
+    ChannelWindowAdjust(ChannelWindowAdjust),
+    ChannelData(ChannelData),
+    ChannelExtendedData(ChannelExtendedData),
+    ChannelEof(ChannelEof),
+    ChannelClose(ChannelClose),
+    ChannelRequest(ChannelRequest),
+    ChannelSuccess(ChannelSuccess),
+    ChannelFailure(ChannelFailure),
+    /// A message [`ParseContext::lenient`] let through unparsed
+    Unknown {
+        message_type: u8,
+        /// Everything after the message type
+        raw: Bytes,
+    },
+}
+
+/// One line summary of a message, for logs.
+///
+/// Free-form text such as descriptions is printed escaped and binary fields
+/// only by their length. Secrets such as passwords and prompt responses are
+/// left out.
+impl Display for Message {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Message::Disconnect(disconnect) => write!(
+                f,
+                "{} reason={} {:?}",
+                MessageType::Disconnect,
+                disconnect.reason_code,
+                disconnect.description
+            ),
+            Message::Ignore(ignore) => {
+                write!(f, "{} ({} bytes)", MessageType::Ignore, ignore.data.len())
+            }
+            Message::Unimplemented(unimplemented) => {
+                write!(
+                    f,
+                    "{} seq={}",
+                    MessageType::Unimplemented,
+                    unimplemented.seq
+                )
+            }
+            Message::Debug(debug) => write!(
+                f,
+                "{} always_display={} {:?}",
+                MessageType::Debug,
+                debug.always_display,
+                debug.message
+            ),
+            Message::ServiceRequest(request) => write!(
+                f,
+                "{} {}",
+                MessageType::ServiceRequest,
+                request.service_name
+            ),
+            Message::ServiceAccept(accept) => {
+                write!(f, "{} {}", MessageType::ServiceAccept, accept.service_name)
+            }
+            Message::ExtInfo(ext_info) => {
+                write!(f, "{} extensions=[", MessageType::ExtInfo)?;
+                for (i, (name, _)) in ext_info.extensions.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    f.write_str(name)?;
+                }
+                f.write_str("]")
+            }
+            Message::Kexinit(kexinit) => write!(
+                f,
+                "{} kex=[{}] host_key=[{}] encryption=[{}] mac=[{}] compression=[{}]",
+                MessageType::Kexinit,
+                NameList(&kexinit.kex_algorithms),
+                NameList(&kexinit.server_host_key_algorithms),
+                NameList(&kexinit.encryption_algorithms_client_to_server),
+                NameList(&kexinit.mac_algorithms_client_to_server),
+                NameList(&kexinit.compression_algorithms_client_to_server),
+            ),
+            Message::Newkeys => write!(f, "{}", MessageType::Newkeys),
+            Message::KexEcdhInit(init) => write!(
+                f,
+                "{} q_c=({} bytes)",
+                MessageType::KexEcdhInit,
+                init.ephemeral_public_key.len()
+            ),
+            Message::KexEcdhReply(reply) => write!(
+                f,
+                "{} k_s=({} bytes) q_s=({} bytes)",
+                MessageType::KexEcdhReply,
+                reply.host_key.len(),
+                reply.ephemeral_public_key.len()
+            ),
+            Message::KexdhInit(init) => write!(f, "SSH_MSG_KEXDH_INIT e=({} bytes)", init.e.len()),
+            Message::KexdhReply(reply) => write!(
+                f,
+                "SSH_MSG_KEXDH_REPLY k_s=({} bytes) f=({} bytes)",
+                reply.host_key.len(),
+                reply.f.len()
+            ),
+            Message::KexDhGexRequest(request) => write!(
+                f,
+                "{} min={} n={} max={}",
+                MessageType::KexDhGexRequest,
+                request.min,
+                request.n,
+                request.max
+            ),
+            Message::KexDhGexGroup(group) => {
+                write!(f, "SSH_MSG_KEX_DH_GEX_GROUP p=({} bytes)", group.p.len())
+            }
+            Message::KexDhGexInit(init) => write!(
+                f,
+                "{} e=({} bytes)",
+                MessageType::KexDhGexInit,
+                init.e.len()
+            ),
+            Message::KexDhGexReply(reply) => write!(
+                f,
+                "{} k_s=({} bytes) f=({} bytes)",
+                MessageType::KexDhGexReply,
+                reply.host_key.len(),
+                reply.f.len()
+            ),
+            Message::UserauthRequest(request) => write!(
+                f,
+                "{} user={:?} service={} method={}",
+                MessageType::UserauthRequest,
+                request.user_name,
+                request.service_name,
+                request.method.name()
+            ),
+            Message::UserauthFailure(failure) => write!(
+                f,
+                "{} methods=[{}] partial_success={}",
+                MessageType::UserauthFailure,
+                NameList(&failure.methods),
+                failure.partial_success
+            ),
+            Message::UserauthSuccess => write!(f, "{}", MessageType::UserauthSuccess),
+            Message::UserauthBanner(banner) => {
+                write!(f, "{} {:?}", MessageType::UserauthBanner, banner.message)
+            }
+            Message::UserauthInfoRequest(request) => write!(
+                f,
+                "{} name={:?} prompts={}",
+                MessageType::UserauthInfoRequest,
+                request.name,
+                request.prompts.len()
+            ),
+            Message::UserauthInfoResponse(response) => write!(
+                f,
+                "{} responses={}",
+                MessageType::UserauthInfoResponse,
+                response.responses.len()
+            ),
+            Message::GlobalRequest(request) => write!(
+                f,
+                "{} {} want_reply={}",
+                MessageType::GlobalRequest,
+                request.request.name(),
+                request.want_reply
+            ),
+            Message::RequestSuccess(success) => write!(
+                f,
+                "{} ({} bytes)",
+                MessageType::RequestSuccess,
+                success.data.len()
+            ),
+            Message::RequestFailure => write!(f, "{}", MessageType::RequestFailure),
+            Message::ChannelOpen(open) => write!(
+                f,
+                "{} {} sender={} window={} max_packet={}",
+                MessageType::ChannelOpen,
+                open.channel_type.name(),
+                open.sender_channel,
+                open.initial_window_size,
+                open.maximum_packet_size
+            ),
+            Message::ChannelOpenConfirmation(confirmation) => write!(
+                f,
+                "{} recipient={} sender={} window={} max_packet={}",
+                MessageType::ChannelOpenConfirmation,
+                confirmation.recipient_channel,
+                confirmation.sender_channel,
+                confirmation.initial_window_size,
+                confirmation.maximum_packet_size
+            ),
+            Message::ChannelOpenFailure(failure) => write!(
+                f,
+                "{} recipient={} reason={:?} {:?}",
+                MessageType::ChannelOpenFailure,
+                failure.recipient_channel,
+                failure.reason_code,
+                failure.description
+            ),
+            Message::ChannelWindowAdjust(adjust) => write!(
+                f,
+                "{} recipient={} bytes_to_add={}",
+                MessageType::ChannelWindowAdjust,
+                adjust.recipient_channel,
+                adjust.bytes_to_add
+            ),
+            Message::ChannelData(data) => write!(
+                f,
+                "{} recipient={} ({} bytes)",
+                MessageType::ChannelData,
+                data.recipient_channel,
+                data.data.len()
+            ),
+            Message::ChannelExtendedData(data) => write!(
+                f,
+                "{} recipient={} type={} ({} bytes)",
+                MessageType::ChannelExtendedData,
+                data.recipient_channel,
+                data.data_type_code,
+                data.data.len()
+            ),
+            Message::ChannelEof(eof) => write!(
+                f,
+                "{} recipient={}",
+                MessageType::ChannelEof,
+                eof.recipient_channel
+            ),
+            Message::ChannelClose(close) => write!(
+                f,
+                "{} recipient={}",
+                MessageType::ChannelClose,
+                close.recipient_channel
+            ),
+            Message::ChannelRequest(request) => write!(
+                f,
+                "{} recipient={} {} want_reply={}",
+                MessageType::ChannelRequest,
+                request.recipient_channel,
+                request.request.name(),
+                request.want_reply
+            ),
+            Message::ChannelSuccess(success) => write!(
+                f,
+                "{} recipient={}",
+                MessageType::ChannelSuccess,
+                success.recipient_channel
+            ),
+            Message::ChannelFailure(failure) => write!(
+                f,
+                "{} recipient={}",
+                MessageType::ChannelFailure,
+                failure.recipient_channel
+            ),
+            Message::Unknown { message_type, raw } => {
+                match MessageType::try_from(*message_type) {
+                    Ok(ty) => write!(f, "{ty}")?,
+                    Err(_) => write!(f, "message {message_type}")?,
+                }
+
+                write!(f, " ({} bytes, unparsed)", raw.len())
+            }
+        }
+    }
 }
 
-#[derive(Debug)]
+/// Prints a name-list the way it is sent, comma separated
+struct NameList<'a>(&'a [String]);
+
+impl Display for NameList<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for (i, name) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            f.write_str(name)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Disconnect {
     pub reason_code: ReasonCode,
     pub description: String,
     pub language_tag: String,
 }
 
-#[repr(u32)]
-#[derive(Debug)]
+/// SSH_MSG_IGNORE, see RFC 4253 section 11.2
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ignore {
+    pub data: Bytes,
+}
+
+/// SSH_MSG_UNIMPLEMENTED, see RFC 4253 section 11.4
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Unimplemented {
+    /// Sequence number of the packet that wasn't understood
+    pub seq: u32,
+}
+
+/// SSH_MSG_DEBUG, see RFC 4253 section 11.3
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DebugMessage {
+    /// Whether the message should be shown even when the user didn't ask
+    /// for debugging output
+    pub always_display: bool,
+    pub message: String,
+    pub language_tag: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ReasonCode {
-    HostNotAllowedToConnect = 1,
-    ProtocolError = 2,
-    KeyExchangeFailed = 3,
-    Reserved = 4,
-    MacError = 5,
-    CompressionError = 6,
-    ServiceNotAvailable = 7,
-    ProtocolVersionNotSupported = 8,
-    HostKeyNotVerifiable = 9,
-    ConnectionLost = 10,
-    ByApplication = 11,
-    TooManyConnections = 12,
-    AuthCancelledByUser = 13,
-    NoMoreAuthMethodsAvailable = 14,
-    IllegalUserName = 15,
-}
-
-#[derive(Debug)]
+    HostNotAllowedToConnect,
+    ProtocolError,
+    KeyExchangeFailed,
+    Reserved,
+    MacError,
+    CompressionError,
+    ServiceNotAvailable,
+    ProtocolVersionNotSupported,
+    HostKeyNotVerifiable,
+    ConnectionLost,
+    ByApplication,
+    TooManyConnections,
+    AuthCancelledByUser,
+    NoMoreAuthMethodsAvailable,
+    IllegalUserName,
+    /// Any code not listed in RFC 4250, such as those from 0xFE000000 up
+    /// that are kept for private use
+    Other(u32),
+}
+
+impl From<u32> for ReasonCode {
+    fn from(value: u32) -> Self {
+        match value {
+            1 => ReasonCode::HostNotAllowedToConnect,
+            2 => ReasonCode::ProtocolError,
+            3 => ReasonCode::KeyExchangeFailed,
+            4 => ReasonCode::Reserved,
+            5 => ReasonCode::MacError,
+            6 => ReasonCode::CompressionError,
+            7 => ReasonCode::ServiceNotAvailable,
+            8 => ReasonCode::ProtocolVersionNotSupported,
+            9 => ReasonCode::HostKeyNotVerifiable,
+            10 => ReasonCode::ConnectionLost,
+            11 => ReasonCode::ByApplication,
+            12 => ReasonCode::TooManyConnections,
+            13 => ReasonCode::AuthCancelledByUser,
+            14 => ReasonCode::NoMoreAuthMethodsAvailable,
+            15 => ReasonCode::IllegalUserName,
+            _ => ReasonCode::Other(value),
+        }
+    }
+}
+
+impl From<ReasonCode> for u32 {
+    fn from(reason: ReasonCode) -> Self {
+        match reason {
+            ReasonCode::HostNotAllowedToConnect => 1,
+            ReasonCode::ProtocolError => 2,
+            ReasonCode::KeyExchangeFailed => 3,
+            ReasonCode::Reserved => 4,
+            ReasonCode::MacError => 5,
+            ReasonCode::CompressionError => 6,
+            ReasonCode::ServiceNotAvailable => 7,
+            ReasonCode::ProtocolVersionNotSupported => 8,
+            ReasonCode::HostKeyNotVerifiable => 9,
+            ReasonCode::ConnectionLost => 10,
+            ReasonCode::ByApplication => 11,
+            ReasonCode::TooManyConnections => 12,
+            ReasonCode::AuthCancelledByUser => 13,
+            ReasonCode::NoMoreAuthMethodsAvailable => 14,
+            ReasonCode::IllegalUserName => 15,
+            ReasonCode::Other(value) => value,
+        }
+    }
+}
+
+/// The symbolic names of RFC 4250 section 4.2.2, other codes being shown as
+/// their number
+impl Display for ReasonCode {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ReasonCode::HostNotAllowedToConnect => {
+                write!(f, "SSH_DISCONNECT_HOST_NOT_ALLOWED_TO_CONNECT")
+            }
+            ReasonCode::ProtocolError => write!(f, "SSH_DISCONNECT_PROTOCOL_ERROR"),
+            ReasonCode::KeyExchangeFailed => write!(f, "SSH_DISCONNECT_KEY_EXCHANGE_FAILED"),
+            ReasonCode::Reserved => write!(f, "SSH_DISCONNECT_RESERVED"),
+            ReasonCode::MacError => write!(f, "SSH_DISCONNECT_MAC_ERROR"),
+            ReasonCode::CompressionError => write!(f, "SSH_DISCONNECT_COMPRESSION_ERROR"),
+            ReasonCode::ServiceNotAvailable => write!(f, "SSH_DISCONNECT_SERVICE_NOT_AVAILABLE"),
+            ReasonCode::ProtocolVersionNotSupported => {
+                write!(f, "SSH_DISCONNECT_PROTOCOL_VERSION_NOT_SUPPORTED")
+            }
+            ReasonCode::HostKeyNotVerifiable => write!(f, "SSH_DISCONNECT_HOST_KEY_NOT_VERIFIABLE"),
+            ReasonCode::ConnectionLost => write!(f, "SSH_DISCONNECT_CONNECTION_LOST"),
+            ReasonCode::ByApplication => write!(f, "SSH_DISCONNECT_BY_APPLICATION"),
+            ReasonCode::TooManyConnections => write!(f, "SSH_DISCONNECT_TOO_MANY_CONNECTIONS"),
+            ReasonCode::AuthCancelledByUser => write!(f, "SSH_DISCONNECT_AUTH_CANCELLED_BY_USER"),
+            ReasonCode::NoMoreAuthMethodsAvailable => {
+                write!(f, "SSH_DISCONNECT_NO_MORE_AUTH_METHODS_AVAILABLE")
+            }
+            ReasonCode::IllegalUserName => write!(f, "SSH_DISCONNECT_ILLEGAL_USER_NAME"),
+            ReasonCode::Other(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceRequest {
+    /// Service to start, e.g. `ssh-userauth`
+    pub service_name: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceAccept {
+    pub service_name: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Kexinit {
     pub cookie: [u8; 16],
     pub kex_algorithms: Vec<String>,
@@ -141,48 +677,516 @@ pub struct Kexinit {
     __reserved: u32,
 }
 
+/// Builder for [`Kexinit`], see [`Kexinit::builder`]
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Default)]
+pub struct KexinitBuilder {
+    cookie: Option<[u8; 16]>,
+    kex_algorithms: Vec<String>,
+    server_host_key_algorithms: Vec<String>,
+    encryption_algorithms_client_to_server: Vec<String>,
+    encryption_algorithms_server_to_client: Vec<String>,
+    mac_algorithms_client_to_server: Vec<String>,
+    mac_algorithms_server_to_client: Vec<String>,
+    compression_algorithms_client_to_server: Vec<String>,
+    compression_algorithms_server_to_client: Vec<String>,
+    languages_client_to_server: Vec<String>,
+    languages_server_to_client: Vec<String>,
+    first_kex_packet_follows: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KexEcdhInit {
+    /// Client's ephemeral public key Q_C
+    pub ephemeral_public_key: Bytes,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KexEcdhReply {
+    /// Server's public host key blob K_S
+    pub host_key: Bytes,
+    /// Server's ephemeral public key Q_S
+    pub ephemeral_public_key: Bytes,
+    /// Signature over the exchange hash, made with the host key
+    pub signature: Bytes,
+}
+
+/// SSH_MSG_KEXDH_INIT, see RFC 4253 section 8
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KexdhInit {
+    /// Client's public value e, as an unsigned big-endian integer
+    pub e: Bytes,
+}
+
+/// SSH_MSG_KEXDH_REPLY
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KexdhReply {
+    /// Server's public host key blob K_S
+    pub host_key: Bytes,
+    /// Server's public value f, as an unsigned big-endian integer
+    pub f: Bytes,
+    /// Signature over the exchange hash, made with the host key
+    pub signature: Bytes,
+}
+
+/// SSH_MSG_KEX_DH_GEX_REQUEST, see RFC 4419 section 3
+///
+/// Sizes are in bits, the server picks a group as close to `n` as it has
+/// while staying within `min` and `max`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KexDhGexRequest {
+    pub min: u32,
+    /// Preferred size
+    pub n: u32,
+    pub max: u32,
+}
+
+/// SSH_MSG_KEX_DH_GEX_GROUP
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KexDhGexGroup {
+    /// Safe prime p, as an unsigned big-endian integer
+    pub p: Bytes,
+    /// Generator g of a subgroup of GF(p), as an unsigned big-endian integer
+    pub g: Bytes,
+}
+
+/// SSH_MSG_KEX_DH_GEX_INIT
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KexDhGexInit {
+    /// Client's public value e, as an unsigned big-endian integer
+    pub e: Bytes,
+}
+
+/// SSH_MSG_KEX_DH_GEX_REPLY
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KexDhGexReply {
+    /// Server's public host key blob K_S
+    pub host_key: Bytes,
+    /// Server's public value f, as an unsigned big-endian integer
+    pub f: Bytes,
+    /// Signature over the exchange hash, made with the host key
+    pub signature: Bytes,
+}
+
+/// State of the connection that decides how some messages are parsed.
+///
+/// A few message numbers are reused by different methods, e.g. 60 is
+/// SSH_MSG_USERAUTH_INFO_REQUEST for `keyboard-interactive` but
+/// SSH_MSG_USERAUTH_PK_OK for `publickey`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ParseContext<'a> {
+    /// Method of the authentication in progress, for message numbers 60 to
+    /// 79
+    pub auth_method: Option<&'a str>,
+    /// Negotiated key exchange method, for message numbers 30 to 49
+    pub kex: Option<&'a str>,
+    /// Give [`Message::Unknown`] for messages that can't be parsed for lack
+    /// of support or context, instead of [`ParseError::UnknownMessageType`],
+    /// [`ParseError::UnsupportedMessage`] or [`ParseError::NeedsContext`].
+    ///
+    /// A proxy can pass those on untouched this way. Malformed messages of a
+    /// supported type still give an error.
+    pub lenient: bool,
+}
+
+/// Whether `kex` is one of the elliptic curve methods, which share the
+/// SSH_MSG_KEX_ECDH_* messages
+fn is_ecdh(kex: &str) -> bool {
+    matches!(
+        kex,
+        "curve25519-sha256"
+            | "curve25519-sha256@libssh.org"
+            | "ecdh-sha2-nistp256"
+            | "ecdh-sha2-nistp384"
+            | "ecdh-sha2-nistp521"
+    )
+}
+
+/// Whether `kex` is one of the fixed group Diffie-Hellman methods, which
+/// share the SSH_MSG_KEXDH_* messages
+fn is_fixed_group_dh(kex: &str) -> bool {
+    matches!(
+        kex,
+        "diffie-hellman-group1-sha1"
+            | "diffie-hellman-group14-sha1"
+            | "diffie-hellman-group14-sha256"
+            | "diffie-hellman-group16-sha512"
+            | "diffie-hellman-group18-sha512"
+    )
+}
+
+/// Whether `kex` is one of the group exchange methods of RFC 4419
+fn is_group_exchange(kex: &str) -> bool {
+    matches!(
+        kex,
+        "diffie-hellman-group-exchange-sha1" | "diffie-hellman-group-exchange-sha256"
+    )
+}
+
 impl Message {
+    /// Parses a packet payload without any [`ParseContext`], message numbers
+    /// that need one give [`ParseError::NeedsContext`].
+    ///
+    /// The payload comes straight off the network, so every read goes through
+    /// [`Reader`] and is bounds-checked: truncated or otherwise malformed
+    /// input gives an error, never a panic.
     pub fn parse<B: Buf>(src: &mut B) -> Result<Self, ParseError> {
-        let message_type: MessageType = src.get_u8().try_into()?;
+        Self::parse_with_context(src, ParseContext::default())
+    }
+
+    /// Parses a packet payload, see [`Message::parse`]
+    pub fn parse_with_context<B: Buf>(
+        src: &mut B,
+        context: ParseContext<'_>,
+    ) -> Result<Self, ParseError> {
+        let mut src = Reader::new(src);
+        let src = &mut src;
 
-        debug!("Received message {message_type}");
+        let message_type = src.read_u8()?;
+
+        match Self::parse_known(message_type, src, context) {
+            Err(
+                ParseError::UnknownMessageType(_)
+                | ParseError::UnsupportedMessage(_)
+                | ParseError::NeedsContext(_),
+            ) if context.lenient => Ok(Message::Unknown {
+                message_type,
+                raw: src.read_rest(),
+            }),
+            result => result,
+        }
+    }
+
+    /// Parses the body of a message, those errors that leave `src` untouched
+    /// are what [`ParseContext::lenient`] turns into [`Message::Unknown`]
+    fn parse_known<B: Buf>(
+        message_type: u8,
+        src: &mut Reader<'_, B>,
+        context: ParseContext<'_>,
+    ) -> Result<Self, ParseError> {
+        let message_type: MessageType = message_type.try_into()?;
+
+        trace!(%message_type, "Received message");
 
         match message_type {
+            MessageType::Disconnect => {
+                let disconnect = Disconnect {
+                    reason_code: src.read_u32()?.into(),
+                    description: src.read_utf8()?,
+                    language_tag: src.read_utf8()?,
+                };
+
+                src.finish()?;
+
+                Ok(Message::Disconnect(disconnect))
+            }
+
+            MessageType::Ignore => {
+                let ignore = Ignore {
+                    data: src.read_string()?,
+                };
+
+                src.finish()?;
+
+                Ok(Message::Ignore(ignore))
+            }
+
+            MessageType::Unimplemented => {
+                let unimplemented = Unimplemented {
+                    seq: src.read_u32()?,
+                };
+
+                src.finish()?;
+
+                Ok(Message::Unimplemented(unimplemented))
+            }
+
+            MessageType::Debug => {
+                let debug = DebugMessage {
+                    always_display: src.read_bool()?,
+                    message: src.read_utf8()?,
+                    language_tag: src.read_utf8()?,
+                };
+
+                src.finish()?;
+
+                Ok(Message::Debug(debug))
+            }
+
+            MessageType::ServiceRequest => {
+                let service_request = ServiceRequest {
+                    service_name: src.read_utf8()?,
+                };
+
+                src.finish()?;
+
+                Ok(Message::ServiceRequest(service_request))
+            }
+
+            MessageType::ServiceAccept => {
+                let service_accept = ServiceAccept {
+                    service_name: src.read_utf8()?,
+                };
+
+                src.finish()?;
+
+                Ok(Message::ServiceAccept(service_accept))
+            }
+
+            MessageType::ExtInfo => Ok(Message::ExtInfo(ExtInfo::parse(src)?)),
+
             MessageType::Kexinit => {
-                let mut cookie = [0u8; 16];
-                src.copy_to_slice(&mut cookie);
+                let cookie = src.read_array()?;
 
                 let kex_init = Kexinit {
                     cookie,
-                    kex_algorithms: parse_name_list(src)?,
-                    server_host_key_algorithms: parse_name_list(src)?,
-                    encryption_algorithms_client_to_server: parse_name_list(src)?,
-                    encryption_algorithms_server_to_client: parse_name_list(src)?,
-                    mac_algorithms_client_to_server: parse_name_list(src)?,
-                    mac_algorithms_server_to_client: parse_name_list(src)?,
-                    compression_algorithms_client_to_server: parse_name_list(src)?,
-                    compression_algorithms_server_to_client: parse_name_list(src)?,
-                    languages_client_to_server: parse_name_list(src)?,
-                    languages_server_to_client: parse_name_list(src)?,
-                    first_kex_packet_follows: src.get_u8() != 0,
-                    __reserved: src.get_u32(),
+                    kex_algorithms: src.read_name_list()?,
+                    server_host_key_algorithms: src.read_name_list()?,
+                    encryption_algorithms_client_to_server: src.read_name_list()?,
+                    encryption_algorithms_server_to_client: src.read_name_list()?,
+                    mac_algorithms_client_to_server: src.read_name_list()?,
+                    mac_algorithms_server_to_client: src.read_name_list()?,
+                    compression_algorithms_client_to_server: src.read_name_list()?,
+                    compression_algorithms_server_to_client: src.read_name_list()?,
+                    languages_client_to_server: src.read_name_list()?,
+                    languages_server_to_client: src.read_name_list()?,
+                    first_kex_packet_follows: src.read_bool()?,
+                    __reserved: src.read_u32()?,
                 };
 
-                if src.has_remaining() {
-                    return Err(ParseError::InvalidLength);
+                src.finish()?;
+
+                Ok(Message::Kexinit(Box::new(kex_init)))
+            }
+
+            MessageType::Newkeys => {
+                src.finish()?;
+
+                Ok(Message::Newkeys)
+            }
+
+            MessageType::KexEcdhInit if context.kex.is_some_and(is_fixed_group_dh) => {
+                let kexdh_init = KexdhInit {
+                    e: src.read_mpint()?,
+                };
+
+                src.finish()?;
+
+                Ok(Message::KexdhInit(kexdh_init))
+            }
+
+            MessageType::KexEcdhReply if context.kex.is_some_and(is_fixed_group_dh) => {
+                let kexdh_reply = KexdhReply {
+                    host_key: src.read_string()?,
+                    f: src.read_mpint()?,
+                    signature: src.read_string()?,
+                };
+
+                src.finish()?;
+
+                Ok(Message::KexdhReply(kexdh_reply))
+            }
+
+            MessageType::KexEcdhInit if context.kex.is_some_and(is_ecdh) => {
+                let kex_ecdh_init = KexEcdhInit {
+                    ephemeral_public_key: src.read_string()?,
+                };
+
+                src.finish()?;
+
+                Ok(Message::KexEcdhInit(kex_ecdh_init))
+            }
+
+            MessageType::KexEcdhReply if context.kex.is_some_and(is_ecdh) => {
+                let kex_ecdh_reply = KexEcdhReply {
+                    host_key: src.read_string()?,
+                    ephemeral_public_key: src.read_string()?,
+                    signature: src.read_string()?,
+                };
+
+                src.finish()?;
+
+                Ok(Message::KexEcdhReply(kex_ecdh_reply))
+            }
+
+            MessageType::KexDhGexRequest if context.kex.is_some_and(is_group_exchange) => {
+                let request = KexDhGexRequest {
+                    min: src.read_u32()?,
+                    n: src.read_u32()?,
+                    max: src.read_u32()?,
+                };
+
+                src.finish()?;
+
+                Ok(Message::KexDhGexRequest(request))
+            }
+
+            MessageType::KexEcdhReply if context.kex.is_some_and(is_group_exchange) => {
+                let group = KexDhGexGroup {
+                    p: src.read_mpint()?,
+                    g: src.read_mpint()?,
+                };
+
+                src.finish()?;
+
+                Ok(Message::KexDhGexGroup(group))
+            }
+
+            MessageType::KexDhGexInit if context.kex.is_some_and(is_group_exchange) => {
+                let init = KexDhGexInit {
+                    e: src.read_mpint()?,
+                };
+
+                src.finish()?;
+
+                Ok(Message::KexDhGexInit(init))
+            }
+
+            MessageType::KexDhGexReply if context.kex.is_some_and(is_group_exchange) => {
+                let reply = KexDhGexReply {
+                    host_key: src.read_string()?,
+                    f: src.read_mpint()?,
+                    signature: src.read_string()?,
+                };
+
+                src.finish()?;
+
+                Ok(Message::KexDhGexReply(reply))
+            }
+
+            // Number 30 of group exchange is the obsolete
+            // SSH_MSG_KEX_DH_GEX_REQUEST_OLD, which isn't supported
+            MessageType::KexEcdhInit
+            | MessageType::KexEcdhReply
+            | MessageType::KexDhGexInit
+            | MessageType::KexDhGexReply
+            | MessageType::KexDhGexRequest => match context.kex {
+                Some(_) => Err(ParseError::UnsupportedMessage(message_type)),
+                None => Err(ParseError::NeedsContext(message_type)),
+            },
+
+            MessageType::UserauthRequest => {
+                Ok(Message::UserauthRequest(UserauthRequest::parse(src)?))
+            }
+
+            MessageType::UserauthFailure => {
+                Ok(Message::UserauthFailure(UserauthFailure::parse(src)?))
+            }
+
+            MessageType::UserauthSuccess => {
+                src.finish()?;
+
+                Ok(Message::UserauthSuccess)
+            }
+
+            MessageType::UserauthBanner => Ok(Message::UserauthBanner(UserauthBanner::parse(src)?)),
+
+            MessageType::UserauthInfoRequest | MessageType::UserauthInfoResponse => {
+                match context.auth_method {
+                    Some("keyboard-interactive") => {}
+                    Some(_) => return Err(ParseError::UnsupportedMessage(message_type)),
+                    None => return Err(ParseError::NeedsContext(message_type)),
                 }
 
-                Ok(Message::Kexinit(kex_init))
+                match message_type {
+                    MessageType::UserauthInfoRequest => Ok(Message::UserauthInfoRequest(
+                        UserauthInfoRequest::parse(src)?,
+                    )),
+                    _ => Ok(Message::UserauthInfoResponse(UserauthInfoResponse::parse(
+                        src,
+                    )?)),
+                }
+            }
+
+            MessageType::GlobalRequest => Ok(Message::GlobalRequest(GlobalRequest::parse(src)?)),
+
+            MessageType::RequestSuccess => Ok(Message::RequestSuccess(RequestSuccess::parse(src)?)),
+
+            MessageType::RequestFailure => {
+                src.finish()?;
+
+                Ok(Message::RequestFailure)
+            }
+
+            MessageType::ChannelOpen => Ok(Message::ChannelOpen(ChannelOpen::parse(src)?)),
+
+            MessageType::ChannelOpenConfirmation => Ok(Message::ChannelOpenConfirmation(
+                ChannelOpenConfirmation::parse(src)?,
+            )),
+
+            MessageType::ChannelOpenFailure => {
+                Ok(Message::ChannelOpenFailure(ChannelOpenFailure::parse(src)?))
             }
 
-            ty => Err(ParseError::UnsupportedMessage(ty)),
+            MessageType::ChannelWindowAdjust => Ok(Message::ChannelWindowAdjust(
+                ChannelWindowAdjust::parse(src)?,
+            )),
+
+            MessageType::ChannelData => Ok(Message::ChannelData(ChannelData::parse(src)?)),
+
+            MessageType::ChannelExtendedData => Ok(Message::ChannelExtendedData(
+                ChannelExtendedData::parse(src)?,
+            )),
+
+            MessageType::ChannelEof => Ok(Message::ChannelEof(ChannelEof::parse(src)?)),
+
+            MessageType::ChannelClose => Ok(Message::ChannelClose(ChannelClose::parse(src)?)),
+
+            MessageType::ChannelRequest => Ok(Message::ChannelRequest(ChannelRequest::parse(src)?)),
+
+            MessageType::ChannelSuccess => Ok(Message::ChannelSuccess(ChannelSuccess::parse(src)?)),
+
+            MessageType::ChannelFailure => Ok(Message::ChannelFailure(ChannelFailure::parse(src)?)),
         }
     }
 
     pub fn into_payload(self) -> Bytes {
         match self {
             Message::Disconnect(disconnect) => disconnect.into_payload(),
+            Message::Ignore(ignore) => ignore.into_payload(),
+            Message::Unimplemented(unimplemented) => unimplemented.into_payload(),
+            Message::Debug(debug) => debug.into_payload(),
+            Message::ServiceRequest(service_request) => service_request.into_payload(),
+            Message::ServiceAccept(service_accept) => service_accept.into_payload(),
+            Message::ExtInfo(ext_info) => ext_info.into_payload(),
             Message::Kexinit(kex_init) => kex_init.into_payload(),
+            Message::Newkeys => Bytes::from_static(&[MessageType::Newkeys as u8]),
+            Message::KexEcdhInit(kex_ecdh_init) => kex_ecdh_init.into_payload(),
+            Message::KexEcdhReply(kex_ecdh_reply) => kex_ecdh_reply.into_payload(),
+            Message::KexdhInit(kexdh_init) => kexdh_init.into_payload(),
+            Message::KexdhReply(kexdh_reply) => kexdh_reply.into_payload(),
+            Message::KexDhGexRequest(request) => request.into_payload(),
+            Message::KexDhGexGroup(group) => group.into_payload(),
+            Message::KexDhGexInit(init) => init.into_payload(),
+            Message::KexDhGexReply(reply) => reply.into_payload(),
+            Message::UserauthRequest(userauth_request) => userauth_request.into_payload(),
+            Message::UserauthFailure(userauth_failure) => userauth_failure.into_payload(),
+            Message::UserauthSuccess => Bytes::from_static(&[MessageType::UserauthSuccess as u8]),
+            Message::UserauthBanner(userauth_banner) => userauth_banner.into_payload(),
+            Message::UserauthInfoRequest(info_request) => info_request.into_payload(),
+            Message::UserauthInfoResponse(info_response) => info_response.into_payload(),
+            Message::GlobalRequest(global_request) => global_request.into_payload(),
+            Message::RequestSuccess(request_success) => request_success.into_payload(),
+            Message::RequestFailure => Bytes::from_static(&[MessageType::RequestFailure as u8]),
+            Message::ChannelOpen(channel_open) => channel_open.into_payload(),
+            Message::ChannelOpenConfirmation(confirmation) => confirmation.into_payload(),
+            Message::ChannelOpenFailure(failure) => failure.into_payload(),
+            Message::ChannelWindowAdjust(window_adjust) => window_adjust.into_payload(),
+            Message::ChannelData(channel_data) => channel_data.into_payload(),
+            Message::ChannelExtendedData(extended_data) => extended_data.into_payload(),
+            Message::ChannelEof(eof) => eof.into_payload(),
+            Message::ChannelClose(close) => close.into_payload(),
+            Message::ChannelRequest(channel_request) => channel_request.into_payload(),
+            Message::ChannelSuccess(success) => success.into_payload(),
+            Message::ChannelFailure(failure) => failure.into_payload(),
+            Message::Unknown { message_type, raw } => {
+                let mut payload = Writer::new();
+
+                payload.write_u8(message_type);
+                payload.write_raw(&raw);
+
+                payload.into_bytes()
+            }
         }
     }
 }
@@ -195,19 +1199,102 @@ impl Disconnect {
             language_tag,
         } = self;
 
-        let mut payload = BytesMut::new();
+        let mut payload = Writer::new();
 
-        payload.put_u8(MessageType::Disconnect as u8);
-        payload.put_u32(reason_code as u32);
+        payload.write_u8(MessageType::Disconnect as u8);
+        payload.write_u32(reason_code.into());
+
+        payload.write_string(&description);
+        payload.write_string(&language_tag);
+
+        payload.into_bytes()
+    }
+}
+
+impl Ignore {
+    pub fn into_payload(self) -> Bytes {
+        let mut payload = Writer::new();
+
+        payload.write_u8(MessageType::Ignore as u8);
+        payload.write_string(&self.data);
+
+        payload.into_bytes()
+    }
+}
+
+impl Unimplemented {
+    pub fn into_payload(self) -> Bytes {
+        let mut payload = Writer::new();
 
-        put_string(&mut payload, &description);
-        put_string(&mut payload, &language_tag);
+        payload.write_u8(MessageType::Unimplemented as u8);
+        payload.write_u32(self.seq);
 
-        payload.freeze()
+        payload.into_bytes()
+    }
+}
+
+impl DebugMessage {
+    pub fn into_payload(self) -> Bytes {
+        let DebugMessage {
+            always_display,
+            message,
+            language_tag,
+        } = self;
+
+        let mut payload = Writer::new();
+
+        payload.write_u8(MessageType::Debug as u8);
+        payload.write_bool(always_display);
+        payload.write_string(&message);
+        payload.write_string(&language_tag);
+
+        payload.into_bytes()
+    }
+}
+
+impl ServiceRequest {
+    pub fn into_payload(self) -> Bytes {
+        let mut payload = Writer::new();
+
+        payload.write_u8(MessageType::ServiceRequest as u8);
+        payload.write_string(&self.service_name);
+
+        payload.into_bytes()
+    }
+}
+
+impl ServiceAccept {
+    pub fn into_payload(self) -> Bytes {
+        let mut payload = Writer::new();
+
+        payload.write_u8(MessageType::ServiceAccept as u8);
+        payload.write_string(&self.service_name);
+
+        payload.into_bytes()
     }
 }
 
 impl Kexinit {
+    /// Starts building a KEXINIT.
+    ///
+    /// Every algorithm list starts out empty, as do the language lists which
+    /// are rarely used. The cookie is filled with random bytes unless one is
+    /// given explicitly.
+    #[cfg(feature = "std")]
+    pub fn builder() -> KexinitBuilder {
+        KexinitBuilder::default()
+    }
+
+    /// Replaces the cookie with fresh random bytes.
+    ///
+    /// RFC 4253 requires a random cookie in every KEXINIT, so this should be
+    /// called when sending the same algorithm lists again, e.g. on rekey.
+    #[cfg(feature = "std")]
+    pub fn with_random_cookie(mut self) -> Result<Self, Unspecified> {
+        self.cookie = random_cookie()?;
+        Ok(self)
+    }
+
     pub fn into_payload(self) -> Bytes {
         let Kexinit {
             cookie,
@@ -225,48 +1312,645 @@ impl Kexinit {
             __reserved,
         } = self;
 
-        let mut payload = BytesMut::new();
+        let mut payload = Writer::new();
+
+        payload.write_u8(MessageType::Kexinit as u8);
+        payload.write_raw(&cookie);
+
+        payload.write_name_list(&kex_algorithms);
+        payload.write_name_list(&server_host_key_algorithms);
+        payload.write_name_list(&encryption_algorithms_client_to_server);
+        payload.write_name_list(&encryption_algorithms_server_to_client);
+        payload.write_name_list(&mac_algorithms_client_to_server);
+        payload.write_name_list(&mac_algorithms_server_to_client);
+        payload.write_name_list(&compression_algorithms_client_to_server);
+        payload.write_name_list(&compression_algorithms_server_to_client);
+        payload.write_name_list(&languages_client_to_server);
+        payload.write_name_list(&languages_server_to_client);
+
+        payload.write_bool(first_kex_packet_follows);
+        payload.write_u32(__reserved);
+
+        payload.into_bytes()
+    }
+}
+
+impl KexEcdhInit {
+    pub fn into_payload(self) -> Bytes {
+        let mut payload = Writer::new();
+
+        payload.write_u8(MessageType::KexEcdhInit as u8);
+        payload.write_string(&self.ephemeral_public_key);
+
+        payload.into_bytes()
+    }
+}
+
+impl KexEcdhReply {
+    pub fn into_payload(self) -> Bytes {
+        let KexEcdhReply {
+            host_key,
+            ephemeral_public_key,
+            signature,
+        } = self;
+
+        let mut payload = Writer::new();
+
+        payload.write_u8(MessageType::KexEcdhReply as u8);
+
+        payload.write_string(&host_key);
+        payload.write_string(&ephemeral_public_key);
+        payload.write_string(&signature);
+
+        payload.into_bytes()
+    }
+}
+
+impl KexdhInit {
+    pub fn into_payload(self) -> Bytes {
+        let mut payload = Writer::new();
+
+        payload.write_u8(MessageType::KexEcdhInit as u8);
+        payload.write_mpint(&self.e);
+
+        payload.into_bytes()
+    }
+}
+
+impl KexdhReply {
+    pub fn into_payload(self) -> Bytes {
+        let mut payload = Writer::new();
+
+        payload.write_u8(MessageType::KexEcdhReply as u8);
+        payload.write_string(&self.host_key);
+        payload.write_mpint(&self.f);
+        payload.write_string(&self.signature);
+
+        payload.into_bytes()
+    }
+}
+
+impl KexDhGexRequest {
+    pub fn into_payload(self) -> Bytes {
+        let mut payload = Writer::new();
+
+        payload.write_u8(MessageType::KexDhGexRequest as u8);
+        payload.write_u32(self.min);
+        payload.write_u32(self.n);
+        payload.write_u32(self.max);
+
+        payload.into_bytes()
+    }
+}
+
+impl KexDhGexGroup {
+    pub fn into_payload(self) -> Bytes {
+        let mut payload = Writer::new();
+
+        payload.write_u8(MessageType::KexEcdhReply as u8);
+        payload.write_mpint(&self.p);
+        payload.write_mpint(&self.g);
+
+        payload.into_bytes()
+    }
+}
+
+impl KexDhGexInit {
+    pub fn into_payload(self) -> Bytes {
+        let mut payload = Writer::new();
+
+        payload.write_u8(MessageType::KexDhGexInit as u8);
+        payload.write_mpint(&self.e);
 
-        payload.put_u8(MessageType::Kexinit as u8);
-        payload.put_slice(&cookie);
+        payload.into_bytes()
+    }
+}
 
-        put_name_list(&mut payload, kex_algorithms);
-        put_name_list(&mut payload, server_host_key_algorithms);
-        put_name_list(&mut payload, encryption_algorithms_client_to_server);
-        put_name_list(&mut payload, encryption_algorithms_server_to_client);
-        put_name_list(&mut payload, mac_algorithms_client_to_server);
-        put_name_list(&mut payload, mac_algorithms_server_to_client);
-        put_name_list(&mut payload, compression_algorithms_client_to_server);
-        put_name_list(&mut payload, compression_algorithms_server_to_client);
-        put_name_list(&mut payload, languages_client_to_server);
-        put_name_list(&mut payload, languages_server_to_client);
+impl KexDhGexReply {
+    pub fn into_payload(self) -> Bytes {
+        let mut payload = Writer::new();
 
-        payload.put_u8(first_kex_packet_follows as u8);
-        payload.put_u32(__reserved);
+        payload.write_u8(MessageType::KexDhGexReply as u8);
+        payload.write_string(&self.host_key);
+        payload.write_mpint(&self.f);
+        payload.write_string(&self.signature);
 
-        payload.freeze()
+        payload.into_bytes()
     }
 }
 
-fn parse_name_list<B: Buf>(src: &mut B) -> Result<Vec<String>, ParseError> {
-    let len = src.get_u32();
-    let content = src.copy_to_bytes(len as usize);
+#[cfg(feature = "std")]
+impl KexinitBuilder {
+    /// Uses a fixed cookie instead of generating a random one
+    pub fn cookie(mut self, cookie: [u8; 16]) -> Self {
+        self.cookie = Some(cookie);
+        self
+    }
+
+    pub fn kex_algorithms<I: IntoIterator<Item = S>, S: Into<String>>(mut self, names: I) -> Self {
+        self.kex_algorithms = names.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn server_host_key_algorithms<I: IntoIterator<Item = S>, S: Into<String>>(
+        mut self,
+        names: I,
+    ) -> Self {
+        self.server_host_key_algorithms = names.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Sets the encryption algorithms for both directions
+    pub fn encryption_algorithms<I: IntoIterator<Item = S>, S: Into<String>>(
+        mut self,
+        names: I,
+    ) -> Self {
+        self.encryption_algorithms_client_to_server = names.into_iter().map(Into::into).collect();
+        self.encryption_algorithms_server_to_client =
+            self.encryption_algorithms_client_to_server.clone();
+        self
+    }
+
+    pub fn encryption_algorithms_client_to_server<I: IntoIterator<Item = S>, S: Into<String>>(
+        mut self,
+        names: I,
+    ) -> Self {
+        self.encryption_algorithms_client_to_server = names.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn encryption_algorithms_server_to_client<I: IntoIterator<Item = S>, S: Into<String>>(
+        mut self,
+        names: I,
+    ) -> Self {
+        self.encryption_algorithms_server_to_client = names.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Sets the MAC algorithms for both directions
+    pub fn mac_algorithms<I: IntoIterator<Item = S>, S: Into<String>>(mut self, names: I) -> Self {
+        self.mac_algorithms_client_to_server = names.into_iter().map(Into::into).collect();
+        self.mac_algorithms_server_to_client = self.mac_algorithms_client_to_server.clone();
+        self
+    }
+
+    pub fn mac_algorithms_client_to_server<I: IntoIterator<Item = S>, S: Into<String>>(
+        mut self,
+        names: I,
+    ) -> Self {
+        self.mac_algorithms_client_to_server = names.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn mac_algorithms_server_to_client<I: IntoIterator<Item = S>, S: Into<String>>(
+        mut self,
+        names: I,
+    ) -> Self {
+        self.mac_algorithms_server_to_client = names.into_iter().map(Into::into).collect();
+        self
+    }
 
-    String::from_utf8(content.to_vec())
-        .map_err(ParseError::InvalidNameList)
-        .map(|s| s.split(',').map(str::to_string).collect())
+    /// Sets the compression algorithms for both directions
+    pub fn compression_algorithms<I: IntoIterator<Item = S>, S: Into<String>>(
+        mut self,
+        names: I,
+    ) -> Self {
+        self.compression_algorithms_client_to_server = names.into_iter().map(Into::into).collect();
+        self.compression_algorithms_server_to_client =
+            self.compression_algorithms_client_to_server.clone();
+        self
+    }
+
+    pub fn compression_algorithms_client_to_server<I: IntoIterator<Item = S>, S: Into<String>>(
+        mut self,
+        names: I,
+    ) -> Self {
+        self.compression_algorithms_client_to_server = names.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn compression_algorithms_server_to_client<I: IntoIterator<Item = S>, S: Into<String>>(
+        mut self,
+        names: I,
+    ) -> Self {
+        self.compression_algorithms_server_to_client = names.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn languages_client_to_server<I: IntoIterator<Item = S>, S: Into<String>>(
+        mut self,
+        names: I,
+    ) -> Self {
+        self.languages_client_to_server = names.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn languages_server_to_client<I: IntoIterator<Item = S>, S: Into<String>>(
+        mut self,
+        names: I,
+    ) -> Self {
+        self.languages_server_to_client = names.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn first_kex_packet_follows(mut self, follows: bool) -> Self {
+        self.first_kex_packet_follows = follows;
+        self
+    }
+
+    /// Builds the KEXINIT, only failing if no random cookie could be
+    /// generated
+    pub fn build(self) -> Result<Kexinit, Unspecified> {
+        let cookie = match self.cookie {
+            Some(cookie) => cookie,
+            None => random_cookie()?,
+        };
+
+        Ok(Kexinit {
+            cookie,
+            kex_algorithms: self.kex_algorithms,
+            server_host_key_algorithms: self.server_host_key_algorithms,
+            encryption_algorithms_client_to_server: self.encryption_algorithms_client_to_server,
+            encryption_algorithms_server_to_client: self.encryption_algorithms_server_to_client,
+            mac_algorithms_client_to_server: self.mac_algorithms_client_to_server,
+            mac_algorithms_server_to_client: self.mac_algorithms_server_to_client,
+            compression_algorithms_client_to_server: self.compression_algorithms_client_to_server,
+            compression_algorithms_server_to_client: self.compression_algorithms_server_to_client,
+            languages_client_to_server: self.languages_client_to_server,
+            languages_server_to_client: self.languages_server_to_client,
+            first_kex_packet_follows: self.first_kex_packet_follows,
+            __reserved: 0,
+        })
+    }
 }
 
-fn put_name_list(src: &mut BytesMut, list: Vec<String>) {
-    let list = list.join(",");
-    src.put_u32(list.len() as u32);
+#[cfg(feature = "std")]
+fn random_cookie() -> Result<[u8; 16], Unspecified> {
+    let mut cookie = [0u8; 16];
+    SystemRandom::new().fill(&mut cookie)?;
 
-    src.put_slice(list.as_bytes());
+    Ok(cookie)
 }
 
-fn put_string<S: AsRef<[u8]>>(src: &mut BytesMut, string: S) {
-    let string = string.as_ref();
+#[cfg(test)]
+mod tests {
+    use alloc::{string::ToString, vec};
 
-    src.put_u32(string.len() as u32);
-    src.put_slice(string);
+    use super::*;
+
+    /// What parsing `message` takes, its number alone may not be enough
+    fn context(message: &Message) -> ParseContext<'static> {
+        match message {
+            Message::KexEcdhInit(_) | Message::KexEcdhReply(_) => ParseContext {
+                kex: Some("curve25519-sha256"),
+                ..Default::default()
+            },
+            Message::UserauthInfoRequest(_) | Message::UserauthInfoResponse(_) => ParseContext {
+                auth_method: Some("keyboard-interactive"),
+                ..Default::default()
+            },
+            _ => ParseContext::default(),
+        }
+    }
+
+    fn round_trip(message: Message) {
+        let payload = message.clone().into_payload();
+
+        assert_eq!(
+            Message::parse_with_context(&mut payload.clone(), context(&message)).unwrap(),
+            message
+        );
+    }
+
+    #[test]
+    fn disconnect_round_trip() {
+        round_trip(Message::Disconnect(Disconnect {
+            reason_code: ReasonCode::ByApplication,
+            description: "bye".into(),
+            language_tag: "en".into(),
+        }));
+    }
+
+    #[test]
+    fn disconnect_bytes() {
+        let disconnect = Message::Disconnect(Disconnect {
+            reason_code: ReasonCode::ByApplication,
+            description: "bye".into(),
+            language_tag: "en".into(),
+        });
+
+        assert_eq!(
+            &disconnect.into_payload()[..],
+            b"\x01\0\0\0\x0b\0\0\0\x03bye\0\0\0\x02en"
+        );
+    }
+
+    #[test]
+    fn disconnect_unknown_reason() {
+        round_trip(Message::Disconnect(Disconnect {
+            reason_code: ReasonCode::Other(0xfe00_0001),
+            description: "private".into(),
+            language_tag: String::new(),
+        }));
+    }
+
+    #[test]
+    fn disconnect_display() {
+        let disconnect = |reason_code| {
+            Message::Disconnect(Disconnect {
+                reason_code,
+                description: "bye\n".into(),
+                language_tag: "en".into(),
+            })
+            .to_string()
+        };
+
+        assert_eq!(
+            disconnect(ReasonCode::ByApplication),
+            r#"SSH_MSG_DISCONNECT reason=SSH_DISCONNECT_BY_APPLICATION "bye\n""#
+        );
+        assert_eq!(
+            disconnect(ReasonCode::Other(0xfe00_0001)),
+            r#"SSH_MSG_DISCONNECT reason=4261412865 "bye\n""#
+        );
+    }
+
+    #[test]
+    fn kex_ecdh_reply_needs_context() {
+        let reply = Message::KexEcdhReply(KexEcdhReply {
+            host_key: Bytes::from_static(&[1; 51]),
+            ephemeral_public_key: Bytes::from_static(&[2; 32]),
+            signature: Bytes::from_static(&[3; 83]),
+        });
+        let payload = reply.clone().into_payload();
+
+        let context = ParseContext {
+            kex: Some("curve25519-sha256"),
+            ..Default::default()
+        };
+        assert_eq!(
+            Message::parse_with_context(&mut payload.clone(), context).unwrap(),
+            reply
+        );
+
+        // Message number 31 means something else to every kex method
+        assert!(matches!(
+            Message::parse(&mut payload.clone()),
+            Err(ParseError::NeedsContext(MessageType::KexEcdhReply))
+        ));
+    }
+
+    #[test]
+    fn lenient_parsing() {
+        let lenient = ParseContext {
+            lenient: true,
+            ..Default::default()
+        };
+
+        // An unassigned number, and one that needs the kex method
+        for payload in [&b"\xc8private extension"[..], b"\x1fkex reply"] {
+            let message = Message::parse_with_context(&mut &payload[..], lenient).unwrap();
+            assert_eq!(
+                message,
+                Message::Unknown {
+                    message_type: payload[0],
+                    raw: Bytes::copy_from_slice(&payload[1..]),
+                }
+            );
+
+            // Passed on untouched
+            assert_eq!(message.into_payload(), payload);
+        }
+
+        assert!(matches!(
+            Message::parse(&mut &b"\xc8private extension"[..]),
+            Err(ParseError::UnknownMessageType(0xc8))
+        ));
+
+        // Malformed messages still fail
+        assert!(Message::parse_with_context(&mut &b"\x15trailing"[..], lenient).is_err());
+    }
+
+    #[test]
+    fn newkeys_is_one_byte() {
+        assert_eq!(&Message::Newkeys.into_payload()[..], [21]);
+    }
+
+    #[test]
+    fn none_method_bytes() {
+        let request = Message::UserauthRequest(UserauthRequest {
+            user_name: "paw".into(),
+            service_name: "ssh-connection".into(),
+            method: AuthMethod::None,
+        });
+
+        // byte 50, then the user name, service name and method name, RFC 4252 §5.2
+        assert_eq!(
+            &request.into_payload()[..],
+            b"\x32\0\0\0\x03paw\0\0\0\x0essh-connection\0\0\0\x04none"
+        );
+    }
+
+    #[test]
+    fn forward_any_port() {
+        round_trip(Message::GlobalRequest(GlobalRequest {
+            want_reply: true,
+            request: GlobalRequestType::TcpipForward {
+                address_to_bind: String::new(),
+                port_to_bind: 0,
+            },
+        }));
+
+        // The server answers with the port it picked
+        let reply = Message::parse(&mut &b"\x51\0\0\xc3\x50"[..]).unwrap();
+        let Message::RequestSuccess(success) = &reply else {
+            panic!("{reply:?}");
+        };
+        assert_eq!(success.bound_port(), Some(50000));
+        round_trip(reply);
+
+        let reply = RequestSuccess { data: Bytes::new() };
+        assert_eq!(reply.bound_port(), None);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn random_cookies_differ() {
+        let kexinit = Kexinit::builder().kex_algorithms(["curve25519-sha256"]);
+        let first = kexinit.clone().build().unwrap();
+        let second = first.clone().with_random_cookie().unwrap();
+
+        assert_ne!(first.cookie, second.cookie);
+        assert_ne!(first.cookie, kexinit.build().unwrap().cookie);
+    }
+
+    fn samples() -> Vec<Message> {
+        let names = |names: &[&str]| names.iter().map(|&name| name.into()).collect();
+
+        // Built by hand, the builder needs `std` for its random cookie
+        let kexinit = Kexinit {
+            cookie: [7; 16],
+            kex_algorithms: names(&["curve25519-sha256", "ext-info-c"]),
+            server_host_key_algorithms: names(&["ssh-ed25519"]),
+            encryption_algorithms_client_to_server: names(&["chacha20-poly1305@openssh.com"]),
+            encryption_algorithms_server_to_client: names(&["chacha20-poly1305@openssh.com"]),
+            mac_algorithms_client_to_server: names(&["hmac-sha2-256"]),
+            mac_algorithms_server_to_client: names(&["hmac-sha2-256"]),
+            compression_algorithms_client_to_server: names(&["none"]),
+            compression_algorithms_server_to_client: names(&["none"]),
+            languages_client_to_server: Vec::new(),
+            languages_server_to_client: Vec::new(),
+            first_kex_packet_follows: false,
+            __reserved: 0,
+        };
+
+        #[cfg_attr(not(feature = "std"), allow(unused_mut))]
+        let mut samples = vec![
+            Message::Kexinit(Box::new(kexinit)),
+            Message::KexEcdhInit(KexEcdhInit {
+                ephemeral_public_key: Bytes::from_static(&[4; 32]),
+            }),
+            Message::KexEcdhReply(KexEcdhReply {
+                host_key: Bytes::from_static(&[1; 51]),
+                ephemeral_public_key: Bytes::from_static(&[5; 32]),
+                signature: Bytes::from_static(&[2; 83]),
+            }),
+            Message::Newkeys,
+            Message::Disconnect(Disconnect {
+                reason_code: ReasonCode::ProtocolError,
+                description: "bad".into(),
+                language_tag: String::new(),
+            }),
+            Message::Ignore(Ignore {
+                data: Bytes::from_static(b"padding"),
+            }),
+            Message::Debug(DebugMessage {
+                always_display: true,
+                message: "rekeying".into(),
+                language_tag: "en".into(),
+            }),
+            Message::Unimplemented(Unimplemented { seq: 0x0102_0304 }),
+            Message::ServiceRequest(ServiceRequest {
+                service_name: "ssh-userauth".into(),
+            }),
+            Message::UserauthRequest(UserauthRequest {
+                user_name: "paw".into(),
+                service_name: "ssh-connection".into(),
+                method: AuthMethod::PublicKey {
+                    algorithm: "ssh-ed25519".into(),
+                    public_key: Bytes::from_static(&[1; 51]),
+                    signature: Some(Bytes::from_static(&[2; 83])),
+                },
+            }),
+            Message::UserauthRequest(UserauthRequest {
+                user_name: "root".into(),
+                service_name: "ssh-connection".into(),
+                method: AuthMethod::Password {
+                    password: "hunter2".into(),
+                    new_password: None,
+                },
+            }),
+            Message::UserauthFailure(UserauthFailure {
+                methods: vec!["publickey".into(), "password".into()],
+                partial_success: false,
+            }),
+            Message::UserauthBanner(UserauthBanner {
+                message: "Authorized use only.\r\nAll activity is logged.\r\n".into(),
+                language_tag: String::new(),
+            }),
+            Message::UserauthInfoRequest(UserauthInfoRequest {
+                name: "Login".into(),
+                instruction: String::new(),
+                language_tag: String::new(),
+                prompts: vec![
+                    Prompt {
+                        prompt: "Password: ".into(),
+                        echo: false,
+                    },
+                    Prompt {
+                        prompt: "Token: ".into(),
+                        echo: true,
+                    },
+                ],
+            }),
+            Message::GlobalRequest(GlobalRequest {
+                want_reply: true,
+                request: GlobalRequestType::TcpipForward {
+                    address_to_bind: "localhost".into(),
+                    port_to_bind: 8080,
+                },
+            }),
+            Message::RequestSuccess(RequestSuccess { data: Bytes::new() }),
+            Message::ChannelOpen(ChannelOpen {
+                channel_type: ChannelOpenType::DirectTcpip {
+                    host_to_connect: "example.com".into(),
+                    port_to_connect: 80,
+                    originator_ip: "127.0.0.1".into(),
+                    originator_port: 4242,
+                },
+                sender_channel: 0,
+                initial_window_size: 65536,
+                maximum_packet_size: 32768,
+            }),
+            Message::ChannelRequest(ChannelRequest {
+                recipient_channel: 0,
+                want_reply: true,
+                request: ChannelRequestType::PtyReq {
+                    term: "xterm".into(),
+                    width_chars: 80,
+                    height_rows: 24,
+                    width_pixels: 0,
+                    height_pixels: 0,
+                    terminal_modes: TerminalModes::default(),
+                },
+            }),
+            Message::ChannelData(ChannelData {
+                recipient_channel: 0,
+                data: Bytes::from_static(b"data"),
+            }),
+        ];
+
+        // And one from the builder, as the client and server make them
+        #[cfg(feature = "std")]
+        samples.push(Message::Kexinit(Box::new(
+            Kexinit::builder()
+                .kex_algorithms(["curve25519-sha256", "kex-strict-c-v00@openssh.com"])
+                .server_host_key_algorithms(["ssh-ed25519", "rsa-sha2-512"])
+                .encryption_algorithms_client_to_server(["aes128-ctr"])
+                .encryption_algorithms_server_to_client(["aes256-ctr"])
+                .mac_algorithms(["hmac-sha2-256-etm@openssh.com"])
+                .compression_algorithms(["none", "zlib@openssh.com"])
+                .languages_client_to_server(["en"])
+                .first_kex_packet_follows(true)
+                .build()
+                .unwrap(),
+        )));
+
+        samples
+    }
+
+    #[test]
+    fn samples_round_trip() {
+        samples().into_iter().for_each(round_trip);
+    }
+
+    #[test]
+    fn truncated_at_every_offset() {
+        for message in samples() {
+            let context = context(&message);
+            let payload = message.into_payload();
+
+            for len in 0..payload.len() {
+                let mut truncated = payload.slice(..len);
+
+                assert!(
+                    Message::parse_with_context(&mut truncated, context).is_err(),
+                    "{:?} parsed from {} of {} bytes",
+                    payload[0],
+                    len,
+                    payload.len()
+                );
+            }
+        }
+    }
 }
@@ -0,0 +1,791 @@
+use alloc::string::String;
+
+use bytes::{Buf, Bytes};
+
+use super::{MessageType, ParseError, Reader, TerminalModes, Writer};
+
+/// SSH_MSG_CHANNEL_OPEN, see RFC 4254 section 5.1
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelOpen {
+    pub channel_type: ChannelOpenType,
+    pub sender_channel: u32,
+    pub initial_window_size: u32,
+    pub maximum_packet_size: u32,
+}
+
+/// Type of a [`ChannelOpen`] and its type-specific fields
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChannelOpenType {
+    Session,
+    /// A TCP connection the server should make, as for `ssh -L`, see RFC 4254
+    /// section 7.2
+    DirectTcpip {
+        /// Host name or IP address to connect to
+        host_to_connect: String,
+        port_to_connect: u32,
+        /// Where the connection being forwarded came from
+        originator_ip: String,
+        originator_port: u32,
+    },
+    /// A connection to a port forwarded with `tcpip-forward`, opened by the
+    /// server, see RFC 4254 section 7.2
+    ForwardedTcpip {
+        /// Address and port of the listener the connection came in on, as
+        /// bound rather than as requested
+        connected_address: String,
+        connected_port: u32,
+        originator_ip: String,
+        originator_port: u32,
+    },
+    /// A channel type this crate doesn't model, its data is kept as is
+    Unknown {
+        channel_type: String,
+        data: Bytes,
+    },
+}
+
+/// SSH_MSG_CHANNEL_OPEN_CONFIRMATION
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelOpenConfirmation {
+    pub recipient_channel: u32,
+    pub sender_channel: u32,
+    pub initial_window_size: u32,
+    pub maximum_packet_size: u32,
+    /// Channel type specific data, kept as is
+    pub data: Bytes,
+}
+
+/// SSH_MSG_CHANNEL_OPEN_FAILURE
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelOpenFailure {
+    pub recipient_channel: u32,
+    pub reason_code: OpenFailureReason,
+    pub description: String,
+    pub language_tag: String,
+}
+
+/// SSH_MSG_CHANNEL_WINDOW_ADJUST
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelWindowAdjust {
+    pub recipient_channel: u32,
+    pub bytes_to_add: u32,
+}
+
+/// SSH_MSG_CHANNEL_DATA
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelData {
+    pub recipient_channel: u32,
+    pub data: Bytes,
+}
+
+/// SSH_MSG_CHANNEL_EXTENDED_DATA, see RFC 4254 section 5.2
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelExtendedData {
+    pub recipient_channel: u32,
+    /// Kind of data, the only one defined being
+    /// [`ChannelExtendedData::STDERR`]
+    pub data_type_code: u32,
+    pub data: Bytes,
+}
+
+/// SSH_MSG_CHANNEL_EOF, the sender won't send any more data, see RFC 4254
+/// section 5.3
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelEof {
+    pub recipient_channel: u32,
+}
+
+/// SSH_MSG_CHANNEL_CLOSE, see RFC 4254 section 5.3
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelClose {
+    pub recipient_channel: u32,
+}
+
+/// SSH_MSG_CHANNEL_REQUEST, see RFC 4254
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelRequest {
+    pub recipient_channel: u32,
+    /// Whether the peer should answer with SSH_MSG_CHANNEL_SUCCESS or
+    /// SSH_MSG_CHANNEL_FAILURE
+    pub want_reply: bool,
+    pub request: ChannelRequestType,
+}
+
+/// SSH_MSG_CHANNEL_SUCCESS, the answer to a [`ChannelRequest`] that wanted
+/// a reply and was granted, see RFC 4254 section 5.4
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelSuccess {
+    pub recipient_channel: u32,
+}
+
+/// SSH_MSG_CHANNEL_FAILURE, like [`ChannelSuccess`] for a refused request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelFailure {
+    pub recipient_channel: u32,
+}
+
+/// Type of a [`ChannelRequest`] and its type-specific fields
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChannelRequestType {
+    PtyReq {
+        /// Value of the TERM environment variable, e.g. `xterm-256color`
+        term: String,
+        width_chars: u32,
+        height_rows: u32,
+        width_pixels: u32,
+        height_pixels: u32,
+        terminal_modes: TerminalModes,
+    },
+    Shell,
+    Exec {
+        command: String,
+    },
+    Env {
+        name: String,
+        value: String,
+    },
+    /// Sent by the server once the command of a session has exited
+    ExitStatus {
+        exit_status: u32,
+    },
+    /// Sent by the server instead of `exit-status` when the command was
+    /// killed by a signal
+    ExitSignal {
+        /// Name without the `SIG` prefix, e.g. `KILL`
+        signal_name: String,
+        core_dumped: bool,
+        error_message: String,
+        language_tag: String,
+    },
+    /// Sent by the client when the terminal of a `pty-req` was resized,
+    /// never with `want_reply`
+    WindowChange {
+        width_chars: u32,
+        height_rows: u32,
+        width_pixels: u32,
+        height_pixels: u32,
+    },
+    /// A request type this crate doesn't model, its data is kept as is
+    Unknown {
+        request_type: String,
+        data: Bytes,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenFailureReason {
+    AdministrativelyProhibited,
+    ConnectFailed,
+    UnknownChannelType,
+    ResourceShortage,
+    /// Any code not listed in RFC 4254, such as those from 0xFE000000 up
+    /// that are kept for private use
+    Other(u32),
+}
+
+impl From<u32> for OpenFailureReason {
+    fn from(value: u32) -> Self {
+        match value {
+            1 => OpenFailureReason::AdministrativelyProhibited,
+            2 => OpenFailureReason::ConnectFailed,
+            3 => OpenFailureReason::UnknownChannelType,
+            4 => OpenFailureReason::ResourceShortage,
+            _ => OpenFailureReason::Other(value),
+        }
+    }
+}
+
+impl From<OpenFailureReason> for u32 {
+    fn from(reason: OpenFailureReason) -> Self {
+        match reason {
+            OpenFailureReason::AdministrativelyProhibited => 1,
+            OpenFailureReason::ConnectFailed => 2,
+            OpenFailureReason::UnknownChannelType => 3,
+            OpenFailureReason::ResourceShortage => 4,
+            OpenFailureReason::Other(value) => value,
+        }
+    }
+}
+
+impl ChannelOpenType {
+    pub fn name(&self) -> &str {
+        match self {
+            ChannelOpenType::Session => "session",
+            ChannelOpenType::DirectTcpip { .. } => "direct-tcpip",
+            ChannelOpenType::ForwardedTcpip { .. } => "forwarded-tcpip",
+            ChannelOpenType::Unknown { channel_type, .. } => channel_type,
+        }
+    }
+}
+
+impl ChannelOpen {
+    pub(super) fn parse<B: Buf>(src: &mut Reader<'_, B>) -> Result<Self, ParseError> {
+        let channel_type = src.read_utf8()?;
+        let sender_channel = src.read_u32()?;
+        let initial_window_size = src.read_u32()?;
+        let maximum_packet_size = src.read_u32()?;
+
+        let channel_type = match channel_type.as_str() {
+            "session" => ChannelOpenType::Session,
+            "direct-tcpip" => ChannelOpenType::DirectTcpip {
+                host_to_connect: src.read_utf8()?,
+                port_to_connect: src.read_u32()?,
+                originator_ip: src.read_utf8()?,
+                originator_port: src.read_u32()?,
+            },
+            "forwarded-tcpip" => ChannelOpenType::ForwardedTcpip {
+                connected_address: src.read_utf8()?,
+                connected_port: src.read_u32()?,
+                originator_ip: src.read_utf8()?,
+                originator_port: src.read_u32()?,
+            },
+            _ => ChannelOpenType::Unknown {
+                channel_type,
+                data: src.read_rest(),
+            },
+        };
+
+        src.finish()?;
+
+        Ok(ChannelOpen {
+            channel_type,
+            sender_channel,
+            initial_window_size,
+            maximum_packet_size,
+        })
+    }
+
+    pub fn into_payload(self) -> Bytes {
+        let mut payload = Writer::new();
+
+        payload.write_u8(MessageType::ChannelOpen as u8);
+        payload.write_string(self.channel_type.name());
+        payload.write_u32(self.sender_channel);
+        payload.write_u32(self.initial_window_size);
+        payload.write_u32(self.maximum_packet_size);
+
+        match self.channel_type {
+            ChannelOpenType::Session => {}
+            ChannelOpenType::DirectTcpip {
+                host_to_connect: address,
+                port_to_connect: port,
+                originator_ip,
+                originator_port,
+            }
+            | ChannelOpenType::ForwardedTcpip {
+                connected_address: address,
+                connected_port: port,
+                originator_ip,
+                originator_port,
+            } => {
+                payload.write_string(&address);
+                payload.write_u32(port);
+                payload.write_string(&originator_ip);
+                payload.write_u32(originator_port);
+            }
+            ChannelOpenType::Unknown { data, .. } => payload.write_raw(&data),
+        }
+
+        payload.into_bytes()
+    }
+}
+
+impl ChannelOpenConfirmation {
+    pub(super) fn parse<B: Buf>(src: &mut Reader<'_, B>) -> Result<Self, ParseError> {
+        Ok(ChannelOpenConfirmation {
+            recipient_channel: src.read_u32()?,
+            sender_channel: src.read_u32()?,
+            initial_window_size: src.read_u32()?,
+            maximum_packet_size: src.read_u32()?,
+            data: src.read_rest(),
+        })
+    }
+
+    pub fn into_payload(self) -> Bytes {
+        let mut payload = Writer::new();
+
+        payload.write_u8(MessageType::ChannelOpenConfirmation as u8);
+        payload.write_u32(self.recipient_channel);
+        payload.write_u32(self.sender_channel);
+        payload.write_u32(self.initial_window_size);
+        payload.write_u32(self.maximum_packet_size);
+        payload.write_raw(&self.data);
+
+        payload.into_bytes()
+    }
+}
+
+impl ChannelOpenFailure {
+    pub(super) fn parse<B: Buf>(src: &mut Reader<'_, B>) -> Result<Self, ParseError> {
+        let failure = ChannelOpenFailure {
+            recipient_channel: src.read_u32()?,
+            reason_code: src.read_u32()?.into(),
+            description: src.read_utf8()?,
+            language_tag: src.read_utf8()?,
+        };
+
+        src.finish()?;
+
+        Ok(failure)
+    }
+
+    pub fn into_payload(self) -> Bytes {
+        let mut payload = Writer::new();
+
+        payload.write_u8(MessageType::ChannelOpenFailure as u8);
+        payload.write_u32(self.recipient_channel);
+        payload.write_u32(self.reason_code.into());
+        payload.write_string(&self.description);
+        payload.write_string(&self.language_tag);
+
+        payload.into_bytes()
+    }
+}
+
+impl ChannelWindowAdjust {
+    pub(super) fn parse<B: Buf>(src: &mut Reader<'_, B>) -> Result<Self, ParseError> {
+        let window_adjust = ChannelWindowAdjust {
+            recipient_channel: src.read_u32()?,
+            bytes_to_add: src.read_u32()?,
+        };
+
+        src.finish()?;
+
+        Ok(window_adjust)
+    }
+
+    pub fn into_payload(self) -> Bytes {
+        let mut payload = Writer::new();
+
+        payload.write_u8(MessageType::ChannelWindowAdjust as u8);
+        payload.write_u32(self.recipient_channel);
+        payload.write_u32(self.bytes_to_add);
+
+        payload.into_bytes()
+    }
+}
+
+impl ChannelData {
+    /// Number of bytes this message takes out of the channel window.
+    ///
+    /// Only the data itself counts towards the window, not the message
+    /// header or the length prefix of the string.
+    pub fn window_length(&self) -> u32 {
+        self.data.len() as u32
+    }
+
+    pub(super) fn parse<B: Buf>(src: &mut Reader<'_, B>) -> Result<Self, ParseError> {
+        let channel_data = ChannelData {
+            recipient_channel: src.read_u32()?,
+            data: src.read_string()?,
+        };
+
+        src.finish()?;
+
+        Ok(channel_data)
+    }
+
+    pub fn into_payload(self) -> Bytes {
+        let mut payload = Writer::new();
+
+        payload.write_u8(MessageType::ChannelData as u8);
+        payload.write_u32(self.recipient_channel);
+        payload.write_string(&self.data);
+
+        payload.into_bytes()
+    }
+}
+
+impl ChannelExtendedData {
+    /// `SSH_EXTENDED_DATA_STDERR`
+    pub const STDERR: u32 = 1;
+
+    pub fn is_stderr(&self) -> bool {
+        self.data_type_code == Self::STDERR
+    }
+
+    /// Number of bytes this message takes out of the channel window, which
+    /// is shared with regular data
+    pub fn window_length(&self) -> u32 {
+        self.data.len() as u32
+    }
+
+    pub(super) fn parse<B: Buf>(src: &mut Reader<'_, B>) -> Result<Self, ParseError> {
+        let extended_data = ChannelExtendedData {
+            recipient_channel: src.read_u32()?,
+            data_type_code: src.read_u32()?,
+            data: src.read_string()?,
+        };
+
+        src.finish()?;
+
+        Ok(extended_data)
+    }
+
+    pub fn into_payload(self) -> Bytes {
+        let mut payload = Writer::new();
+
+        payload.write_u8(MessageType::ChannelExtendedData as u8);
+        payload.write_u32(self.recipient_channel);
+        payload.write_u32(self.data_type_code);
+        payload.write_string(&self.data);
+
+        payload.into_bytes()
+    }
+}
+
+impl ChannelEof {
+    pub(super) fn parse<B: Buf>(src: &mut Reader<'_, B>) -> Result<Self, ParseError> {
+        let eof = ChannelEof {
+            recipient_channel: src.read_u32()?,
+        };
+
+        src.finish()?;
+
+        Ok(eof)
+    }
+
+    pub fn into_payload(self) -> Bytes {
+        let mut payload = Writer::new();
+
+        payload.write_u8(MessageType::ChannelEof as u8);
+        payload.write_u32(self.recipient_channel);
+
+        payload.into_bytes()
+    }
+}
+
+impl ChannelClose {
+    pub(super) fn parse<B: Buf>(src: &mut Reader<'_, B>) -> Result<Self, ParseError> {
+        let close = ChannelClose {
+            recipient_channel: src.read_u32()?,
+        };
+
+        src.finish()?;
+
+        Ok(close)
+    }
+
+    pub fn into_payload(self) -> Bytes {
+        let mut payload = Writer::new();
+
+        payload.write_u8(MessageType::ChannelClose as u8);
+        payload.write_u32(self.recipient_channel);
+
+        payload.into_bytes()
+    }
+}
+
+impl ChannelSuccess {
+    pub(super) fn parse<B: Buf>(src: &mut Reader<'_, B>) -> Result<Self, ParseError> {
+        let success = ChannelSuccess {
+            recipient_channel: src.read_u32()?,
+        };
+
+        src.finish()?;
+
+        Ok(success)
+    }
+
+    pub fn into_payload(self) -> Bytes {
+        let mut payload = Writer::new();
+
+        payload.write_u8(MessageType::ChannelSuccess as u8);
+        payload.write_u32(self.recipient_channel);
+
+        payload.into_bytes()
+    }
+}
+
+impl ChannelFailure {
+    pub(super) fn parse<B: Buf>(src: &mut Reader<'_, B>) -> Result<Self, ParseError> {
+        let failure = ChannelFailure {
+            recipient_channel: src.read_u32()?,
+        };
+
+        src.finish()?;
+
+        Ok(failure)
+    }
+
+    pub fn into_payload(self) -> Bytes {
+        let mut payload = Writer::new();
+
+        payload.write_u8(MessageType::ChannelFailure as u8);
+        payload.write_u32(self.recipient_channel);
+
+        payload.into_bytes()
+    }
+}
+
+impl ChannelRequestType {
+    pub fn name(&self) -> &str {
+        match self {
+            ChannelRequestType::PtyReq { .. } => "pty-req",
+            ChannelRequestType::Shell => "shell",
+            ChannelRequestType::Exec { .. } => "exec",
+            ChannelRequestType::Env { .. } => "env",
+            ChannelRequestType::ExitStatus { .. } => "exit-status",
+            ChannelRequestType::ExitSignal { .. } => "exit-signal",
+            ChannelRequestType::WindowChange { .. } => "window-change",
+            ChannelRequestType::Unknown { request_type, .. } => request_type,
+        }
+    }
+}
+
+impl ChannelRequest {
+    pub(super) fn parse<B: Buf>(src: &mut Reader<'_, B>) -> Result<Self, ParseError> {
+        let recipient_channel = src.read_u32()?;
+        let request_type = src.read_utf8()?;
+        let want_reply = src.read_bool()?;
+
+        let request = match request_type.as_str() {
+            "pty-req" => ChannelRequestType::PtyReq {
+                term: src.read_utf8()?,
+                width_chars: src.read_u32()?,
+                height_rows: src.read_u32()?,
+                width_pixels: src.read_u32()?,
+                height_pixels: src.read_u32()?,
+                terminal_modes: TerminalModes::parse(&src.read_string()?)?,
+            },
+            "shell" => ChannelRequestType::Shell,
+            "exec" => ChannelRequestType::Exec {
+                command: src.read_utf8()?,
+            },
+            "env" => ChannelRequestType::Env {
+                name: src.read_utf8()?,
+                value: src.read_utf8()?,
+            },
+            "exit-status" => ChannelRequestType::ExitStatus {
+                exit_status: src.read_u32()?,
+            },
+            "exit-signal" => ChannelRequestType::ExitSignal {
+                signal_name: src.read_utf8()?,
+                core_dumped: src.read_bool()?,
+                error_message: src.read_utf8()?,
+                language_tag: src.read_utf8()?,
+            },
+            "window-change" => ChannelRequestType::WindowChange {
+                width_chars: src.read_u32()?,
+                height_rows: src.read_u32()?,
+                width_pixels: src.read_u32()?,
+                height_pixels: src.read_u32()?,
+            },
+            _ => ChannelRequestType::Unknown {
+                request_type,
+                data: src.read_rest(),
+            },
+        };
+
+        src.finish()?;
+
+        Ok(ChannelRequest {
+            recipient_channel,
+            want_reply,
+            request,
+        })
+    }
+
+    pub fn into_payload(self) -> Bytes {
+        let mut payload = Writer::new();
+
+        payload.write_u8(MessageType::ChannelRequest as u8);
+        payload.write_u32(self.recipient_channel);
+        payload.write_string(self.request.name());
+        payload.write_bool(self.want_reply);
+
+        match self.request {
+            ChannelRequestType::PtyReq {
+                term,
+                width_chars,
+                height_rows,
+                width_pixels,
+                height_pixels,
+                terminal_modes,
+            } => {
+                payload.write_string(&term);
+                payload.write_u32(width_chars);
+                payload.write_u32(height_rows);
+                payload.write_u32(width_pixels);
+                payload.write_u32(height_pixels);
+                payload.write_string(terminal_modes.encode());
+            }
+            ChannelRequestType::Shell => {}
+            ChannelRequestType::Exec { command } => payload.write_string(&command),
+            ChannelRequestType::Env { name, value } => {
+                payload.write_string(&name);
+                payload.write_string(&value);
+            }
+            ChannelRequestType::ExitStatus { exit_status } => payload.write_u32(exit_status),
+            ChannelRequestType::ExitSignal {
+                signal_name,
+                core_dumped,
+                error_message,
+                language_tag,
+            } => {
+                payload.write_string(&signal_name);
+                payload.write_bool(core_dumped);
+                payload.write_string(&error_message);
+                payload.write_string(&language_tag);
+            }
+            ChannelRequestType::WindowChange {
+                width_chars,
+                height_rows,
+                width_pixels,
+                height_pixels,
+            } => {
+                payload.write_u32(width_chars);
+                payload.write_u32(height_rows);
+                payload.write_u32(width_pixels);
+                payload.write_u32(height_pixels);
+            }
+            ChannelRequestType::Unknown { data, .. } => payload.write_raw(&data),
+        }
+
+        payload.into_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Message;
+
+    fn round_trip(message: Message) {
+        let payload = message.clone().into_payload();
+
+        assert_eq!(Message::parse(&mut payload.clone()).unwrap(), message);
+    }
+
+    #[test]
+    fn session_open_round_trip() {
+        round_trip(Message::ChannelOpen(ChannelOpen {
+            channel_type: ChannelOpenType::Session,
+            sender_channel: 3,
+            initial_window_size: 2 * 1024 * 1024,
+            maximum_packet_size: 32768,
+        }));
+    }
+
+    #[test]
+    fn open_failure_round_trip() {
+        for reason_code in [
+            OpenFailureReason::ConnectFailed,
+            OpenFailureReason::Other(0xfe00_0002),
+        ] {
+            round_trip(Message::ChannelOpenFailure(ChannelOpenFailure {
+                recipient_channel: 3,
+                reason_code,
+                description: "no".into(),
+                language_tag: String::new(),
+            }));
+        }
+    }
+
+    #[test]
+    fn exec_and_exit_status_round_trip() {
+        round_trip(Message::ChannelRequest(ChannelRequest {
+            recipient_channel: 0,
+            want_reply: true,
+            request: ChannelRequestType::Exec {
+                command: "uname -a".into(),
+            },
+        }));
+        round_trip(Message::ChannelSuccess(ChannelSuccess {
+            recipient_channel: 7,
+        }));
+        round_trip(Message::ChannelFailure(ChannelFailure {
+            recipient_channel: 7,
+        }));
+
+        round_trip(Message::ChannelRequest(ChannelRequest {
+            recipient_channel: 7,
+            want_reply: false,
+            request: ChannelRequestType::ExitStatus { exit_status: 0 },
+        }));
+    }
+
+    #[test]
+    fn stderr_round_trip() {
+        let stderr = Message::ChannelExtendedData(ChannelExtendedData {
+            recipient_channel: 2,
+            data_type_code: ChannelExtendedData::STDERR,
+            data: Bytes::from_static(b"not found\n"),
+        });
+
+        assert_eq!(
+            &stderr.clone().into_payload()[..9],
+            b"\x5f\0\0\0\x02\0\0\0\x01"
+        );
+        round_trip(stderr);
+    }
+
+    #[test]
+    fn exit_status_command_not_found() {
+        let exit_status = Message::ChannelRequest(ChannelRequest {
+            recipient_channel: 0,
+            want_reply: false,
+            request: ChannelRequestType::ExitStatus { exit_status: 127 },
+        });
+
+        assert_eq!(
+            &exit_status.clone().into_payload()[..],
+            b"\x62\0\0\0\0\0\0\0\x0bexit-status\0\0\0\0\x7f"
+        );
+        round_trip(exit_status);
+    }
+
+    #[test]
+    fn pty_req_terminal_modes() {
+        let mut terminal_modes = TerminalModes::new();
+        terminal_modes
+            .set(TerminalModes::VINTR, 3)
+            .set(TerminalModes::ECHO, 1)
+            .set(TerminalModes::TTY_OP_ISPEED, 38400);
+
+        // Each opcode and its uint32, then TTY_OP_END
+        assert_eq!(
+            &terminal_modes.encode()[..],
+            b"\x01\0\0\0\x03\x35\0\0\0\x01\x80\0\0\x96\0\0"
+        );
+
+        round_trip(Message::ChannelRequest(ChannelRequest {
+            recipient_channel: 0,
+            want_reply: true,
+            request: ChannelRequestType::PtyReq {
+                term: "xterm-256color".into(),
+                width_chars: 80,
+                height_rows: 24,
+                width_pixels: 640,
+                height_pixels: 480,
+                terminal_modes,
+            },
+        }));
+    }
+
+    #[test]
+    fn env_round_trip() {
+        round_trip(Message::ChannelRequest(ChannelRequest {
+            recipient_channel: 0,
+            want_reply: false,
+            request: ChannelRequestType::Env {
+                name: "LANG".into(),
+                value: "en_US.UTF-8".into(),
+            },
+        }));
+    }
+
+    #[test]
+    fn window_change_round_trip() {
+        round_trip(Message::ChannelRequest(ChannelRequest {
+            recipient_channel: 0,
+            want_reply: false,
+            request: ChannelRequestType::WindowChange {
+                width_chars: 80,
+                height_rows: 24,
+                width_pixels: 0,
+                height_pixels: 0,
+            },
+        }));
+    }
+}
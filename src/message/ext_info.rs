@@ -0,0 +1,79 @@
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use bytes::{Buf, Bytes};
+
+use super::{MessageType, ParseError, Reader, Writer};
+
+/// SSH_MSG_EXT_INFO, see RFC 8308.
+///
+/// Only sent to a peer that listed `ext-info-c` or `ext-info-s` among its
+/// kex algorithms, right after the first SSH_MSG_NEWKEYS. Servers may send
+/// another one right before SSH_MSG_USERAUTH_SUCCESS.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExtInfo {
+    /// Extension names and their values, in the order they were sent
+    pub extensions: Vec<(String, Bytes)>,
+}
+
+impl ExtInfo {
+    /// Signature algorithms the server accepts for `publickey`
+    /// authentication, see RFC 8308 section 3.1
+    pub const SERVER_SIG_ALGS: &str = "server-sig-algs";
+
+    pub(super) fn parse<B: Buf>(src: &mut Reader<'_, B>) -> Result<Self, ParseError> {
+        let count = src.read_u32()?;
+
+        // Not preallocated, the count is whatever the peer claims
+        let mut extensions = Vec::new();
+        for _ in 0..count {
+            extensions.push((src.read_utf8()?, src.read_string()?));
+        }
+
+        src.finish()?;
+
+        Ok(ExtInfo { extensions })
+    }
+
+    /// Value of the extension `name`, if the peer sent it
+    pub fn get(&self, name: &str) -> Option<&Bytes> {
+        self.extensions
+            .iter()
+            .find(|(extension, _)| extension == name)
+            .map(|(_, value)| value)
+    }
+
+    /// The [`SERVER_SIG_ALGS`](Self::SERVER_SIG_ALGS) name-list, `None` if
+    /// the server didn't send one.
+    ///
+    /// Servers that don't send it may still accept `rsa-sha2-256` and
+    /// `rsa-sha2-512`, they just don't say so.
+    pub fn server_sig_algs(&self) -> Result<Option<Vec<String>>, ParseError> {
+        let Some(value) = self.get(Self::SERVER_SIG_ALGS) else {
+            return Ok(None);
+        };
+
+        if value.is_empty() {
+            return Ok(Some(Vec::new()));
+        }
+
+        let names = String::from_utf8(value.to_vec())?;
+
+        Ok(Some(names.split(',').map(str::to_string).collect()))
+    }
+
+    pub fn into_payload(self) -> Bytes {
+        let mut payload = Writer::new();
+
+        payload.write_u8(MessageType::ExtInfo as u8);
+        payload.write_u32(self.extensions.len() as u32);
+        for (name, value) in &self.extensions {
+            payload.write_string(name);
+            payload.write_string(value);
+        }
+
+        payload.into_bytes()
+    }
+}
@@ -0,0 +1,140 @@
+use alloc::string::String;
+
+use bytes::{Buf, Bytes};
+
+use super::{MessageType, ParseError, Reader, Writer};
+
+/// SSH_MSG_GLOBAL_REQUEST, see RFC 4254 section 4
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GlobalRequest {
+    /// Whether the peer should answer with SSH_MSG_REQUEST_SUCCESS or
+    /// SSH_MSG_REQUEST_FAILURE
+    pub want_reply: bool,
+    pub request: GlobalRequestType,
+}
+
+/// Type of a [`GlobalRequest`] and its type-specific fields
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GlobalRequestType {
+    /// Asks the server to listen for connections and forward them over
+    /// `forwarded-tcpip` channels, as for `ssh -R`, see RFC 4254 section 7.1
+    TcpipForward {
+        /// Address to listen on, `""` meaning all of them and `localhost`
+        /// only the loopback ones
+        address_to_bind: String,
+        /// Port to listen on, 0 letting the server pick one it then sends
+        /// back, see [`RequestSuccess::bound_port`]
+        port_to_bind: u32,
+    },
+    /// Stops a forwarding started with
+    /// [`TcpipForward`](Self::TcpipForward), with the same address and port
+    CancelTcpipForward {
+        address_to_bind: String,
+        port_to_bind: u32,
+    },
+    /// A request this crate doesn't model, its data is kept as is
+    Unknown { request_name: String, data: Bytes },
+}
+
+/// SSH_MSG_REQUEST_SUCCESS
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestSuccess {
+    /// Response specific data, usually empty
+    pub data: Bytes,
+}
+
+impl GlobalRequestType {
+    pub fn name(&self) -> &str {
+        match self {
+            GlobalRequestType::TcpipForward { .. } => "tcpip-forward",
+            GlobalRequestType::CancelTcpipForward { .. } => "cancel-tcpip-forward",
+            GlobalRequestType::Unknown { request_name, .. } => request_name,
+        }
+    }
+}
+
+impl GlobalRequest {
+    pub(super) fn parse<B: Buf>(src: &mut Reader<'_, B>) -> Result<Self, ParseError> {
+        let request_name = src.read_utf8()?;
+        let want_reply = src.read_bool()?;
+
+        let request = match request_name.as_str() {
+            "tcpip-forward" => GlobalRequestType::TcpipForward {
+                address_to_bind: src.read_utf8()?,
+                port_to_bind: src.read_u32()?,
+            },
+            "cancel-tcpip-forward" => GlobalRequestType::CancelTcpipForward {
+                address_to_bind: src.read_utf8()?,
+                port_to_bind: src.read_u32()?,
+            },
+            _ => GlobalRequestType::Unknown {
+                request_name,
+                data: src.read_rest(),
+            },
+        };
+
+        src.finish()?;
+
+        Ok(GlobalRequest {
+            want_reply,
+            request,
+        })
+    }
+
+    pub fn into_payload(self) -> Bytes {
+        let mut payload = Writer::new();
+
+        payload.write_u8(MessageType::GlobalRequest as u8);
+        payload.write_string(self.request.name());
+        payload.write_bool(self.want_reply);
+
+        match self.request {
+            GlobalRequestType::TcpipForward {
+                address_to_bind,
+                port_to_bind,
+            }
+            | GlobalRequestType::CancelTcpipForward {
+                address_to_bind,
+                port_to_bind,
+            } => {
+                payload.write_string(&address_to_bind);
+                payload.write_u32(port_to_bind);
+            }
+            GlobalRequestType::Unknown { data, .. } => payload.write_raw(&data),
+        }
+
+        payload.into_bytes()
+    }
+}
+
+impl RequestSuccess {
+    pub(super) fn parse<B: Buf>(src: &mut Reader<'_, B>) -> Result<Self, ParseError> {
+        Ok(RequestSuccess {
+            data: src.read_rest(),
+        })
+    }
+
+    /// Port the server bound for a `tcpip-forward` request asking for port
+    /// 0, see RFC 4254 section 7.1.
+    ///
+    /// Replies to requests for a given port carry no data, so this is `None`
+    /// for them and the requested port applies.
+    pub fn bound_port(&self) -> Option<u32> {
+        let mut data = self.data.as_ref();
+        let mut src = Reader::new(&mut data);
+
+        let port = src.read_u32().ok()?;
+        src.finish().ok()?;
+
+        Some(port)
+    }
+
+    pub fn into_payload(self) -> Bytes {
+        let mut payload = Writer::new();
+
+        payload.write_u8(MessageType::RequestSuccess as u8);
+        payload.write_raw(&self.data);
+
+        payload.into_bytes()
+    }
+}
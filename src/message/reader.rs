@@ -0,0 +1,295 @@
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use bytes::{Buf, Bytes};
+
+use super::ParseError;
+
+/// Bounds-checked reader for the SSH data types of RFC 4251 section 5.
+///
+/// Every method fails with [`ParseError::InvalidLength`] instead of panicking
+/// when the buffer runs out.
+#[derive(Debug)]
+pub struct Reader<'a, B> {
+    src: &'a mut B,
+}
+
+impl<'a, B: Buf> Reader<'a, B> {
+    pub fn new(src: &'a mut B) -> Self {
+        Self { src }
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.src.remaining()
+    }
+
+    pub fn has_remaining(&self) -> bool {
+        self.src.has_remaining()
+    }
+
+    /// Fails unless the whole buffer has been read
+    pub fn finish(&self) -> Result<(), ParseError> {
+        if self.src.has_remaining() {
+            return Err(ParseError::InvalidLength);
+        }
+
+        Ok(())
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, ParseError> {
+        self.ensure(1)?;
+        Ok(self.src.get_u8())
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, ParseError> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, ParseError> {
+        self.ensure(4)?;
+        Ok(self.src.get_u32())
+    }
+
+    /// Reads `N` raw bytes, e.g. the KEXINIT cookie
+    pub fn read_array<const N: usize>(&mut self) -> Result<[u8; N], ParseError> {
+        self.ensure(N)?;
+
+        let mut array = [0u8; N];
+        self.src.copy_to_slice(&mut array);
+
+        Ok(array)
+    }
+
+    /// Reads a length-prefixed byte string, making sure the declared length
+    /// doesn't run past the end of the buffer
+    pub fn read_string(&mut self) -> Result<Bytes, ParseError> {
+        let len = self.read_u32()? as usize;
+
+        self.ensure(len)?;
+        Ok(self.src.copy_to_bytes(len))
+    }
+
+    /// Reads a string that has to be valid UTF-8
+    pub fn read_utf8(&mut self) -> Result<String, ParseError> {
+        let content = self.read_string()?;
+
+        String::from_utf8(content.to_vec()).map_err(ParseError::InvalidUtf8)
+    }
+
+    pub fn read_name_list(&mut self) -> Result<Vec<String>, ParseError> {
+        let content = self.read_string()?;
+
+        // An empty name-list is a valid (and common) value, splitting it
+        // would yield a single empty name instead
+        if content.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        String::from_utf8(content.to_vec())
+            .map_err(ParseError::InvalidNameList)
+            .map(|s| s.split(',').map(str::to_string).collect())
+    }
+
+    /// Reads a non-negative mpint, returning its unsigned big-endian
+    /// magnitude without leading zero bytes, empty for zero.
+    ///
+    /// RFC 4251 forbids more leading zeros than it takes to keep the high
+    /// bit clear, but OpenSSH skips any and so does this, as peers do send
+    /// them.
+    pub fn read_mpint(&mut self) -> Result<Bytes, ParseError> {
+        let mut value = self.read_string()?;
+
+        // Negative numbers have no business in SSH
+        if value.first().is_some_and(|first| first & 0x80 != 0) {
+            return Err(ParseError::InvalidMpint);
+        }
+
+        let zeros = value.iter().take_while(|&&byte| byte == 0).count();
+        value.advance(zeros);
+
+        Ok(value)
+    }
+
+    /// Reads everything left, for fields whose format isn't known
+    pub fn read_rest(&mut self) -> Bytes {
+        self.src.copy_to_bytes(self.src.remaining())
+    }
+
+    fn ensure(&self, len: usize) -> Result<(), ParseError> {
+        if self.src.remaining() < len {
+            return Err(ParseError::InvalidLength);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read<'a, T>(
+        mut bytes: &'a [u8],
+        f: impl FnOnce(&mut Reader<'_, &'a [u8]>) -> Result<T, ParseError>,
+    ) -> Result<T, ParseError> {
+        f(&mut Reader::new(&mut bytes))
+    }
+
+    #[test]
+    fn u8_and_bool() {
+        assert_eq!(read(&[7], |r| r.read_u8()).unwrap(), 7);
+        assert!(matches!(
+            read(&[], |r| r.read_u8()),
+            Err(ParseError::InvalidLength)
+        ));
+
+        assert!(!read(&[0], |r| r.read_bool()).unwrap());
+        // Any non-zero value is true, RFC 4251 section 5
+        assert!(read(&[2], |r| r.read_bool()).unwrap());
+        assert!(matches!(
+            read(&[], |r| r.read_bool()),
+            Err(ParseError::InvalidLength)
+        ));
+    }
+
+    #[test]
+    fn u32() {
+        assert_eq!(read(&[0, 0, 1, 2], |r| r.read_u32()).unwrap(), 0x0102);
+        assert!(matches!(
+            read(&[0, 0, 1], |r| r.read_u32()),
+            Err(ParseError::InvalidLength)
+        ));
+    }
+
+    #[test]
+    fn array() {
+        assert_eq!(
+            read(&[1, 2, 3], |r| r.read_array::<3>()).unwrap(),
+            [1, 2, 3]
+        );
+        assert!(matches!(
+            read(&[1, 2], |r| r.read_array::<3>()),
+            Err(ParseError::InvalidLength)
+        ));
+    }
+
+    #[test]
+    fn string() {
+        assert_eq!(
+            read(&[0, 0, 0, 2, b'h', b'i'], |r| r.read_string()).unwrap(),
+            &b"hi"[..]
+        );
+        assert_eq!(read(&[0, 0, 0, 0], |r| r.read_string()).unwrap(), &b""[..]);
+
+        // The length or the content cut short
+        assert!(matches!(
+            read(&[0, 0, 0], |r| r.read_string()),
+            Err(ParseError::InvalidLength)
+        ));
+        assert!(matches!(
+            read(&[0, 0, 0, 3, b'h', b'i'], |r| r.read_string()),
+            Err(ParseError::InvalidLength)
+        ));
+        assert!(matches!(
+            read(&[0xff, 0xff, 0xff, 0xff], |r| r.read_string()),
+            Err(ParseError::InvalidLength)
+        ));
+    }
+
+    #[test]
+    fn utf8() {
+        assert_eq!(
+            read(&[0, 0, 0, 2, b'h', b'i'], |r| r.read_utf8()).unwrap(),
+            "hi"
+        );
+        assert!(matches!(
+            read(&[0, 0, 0, 1, 0xff], |r| r.read_utf8()),
+            Err(ParseError::InvalidUtf8(_))
+        ));
+        assert!(matches!(
+            read(&[0, 0, 0, 1], |r| r.read_utf8()),
+            Err(ParseError::InvalidLength)
+        ));
+    }
+
+    #[test]
+    fn name_list() {
+        assert_eq!(
+            read(&[0, 0, 0, 3, b'a', b',', b'b'], |r| r.read_name_list()).unwrap(),
+            ["a", "b"]
+        );
+        // Not a single empty name
+        assert_eq!(
+            read(&[0, 0, 0, 0], |r| r.read_name_list()).unwrap(),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn name_list_truncated() {
+        let list = b"\0\0\0\x07aes,ctr";
+
+        for len in 0..list.len() {
+            assert!(matches!(
+                read(&list[..len], |r| r.read_name_list()),
+                Err(ParseError::InvalidLength)
+            ));
+        }
+
+        // A length far past the end
+        assert!(matches!(
+            read(&[0xff, 0xff, 0xff, 0xff, b'a'], |r| r.read_name_list()),
+            Err(ParseError::InvalidLength)
+        ));
+    }
+
+    #[test]
+    fn mpint() {
+        assert_eq!(
+            read(&[0, 0, 0, 1, 0x7f], |r| r.read_mpint()).unwrap(),
+            &[0x7f][..]
+        );
+        // The leading zero keeping the high bit clear is dropped
+        assert_eq!(
+            read(&[0, 0, 0, 2, 0, 0x80], |r| r.read_mpint()).unwrap(),
+            &[0x80][..]
+        );
+
+        // Zero, however it's written
+        assert!(read(&[0, 0, 0, 0], |r| r.read_mpint()).unwrap().is_empty());
+        assert!(
+            read(&[0, 0, 0, 1, 0], |r| r.read_mpint())
+                .unwrap()
+                .is_empty()
+        );
+
+        // Redundant leading zeros are skipped like OpenSSH does
+        assert_eq!(
+            read(&[0, 0, 0, 3, 0, 0, 0x01], |r| r.read_mpint()).unwrap(),
+            &[0x01][..]
+        );
+
+        assert!(matches!(
+            read(&[0, 0, 0, 1, 0x80], |r| r.read_mpint()),
+            Err(ParseError::InvalidMpint)
+        ));
+        assert!(matches!(
+            read(&[0, 0, 0, 2, 0], |r| r.read_mpint()),
+            Err(ParseError::InvalidLength)
+        ));
+    }
+
+    #[test]
+    fn rest_and_finish() {
+        let mut bytes = &[1, 2, 3][..];
+        let mut reader = Reader::new(&mut bytes);
+
+        assert!(matches!(reader.finish(), Err(ParseError::InvalidLength)));
+        assert_eq!(reader.read_u8().unwrap(), 1);
+        assert_eq!(reader.read_rest(), &[2, 3][..]);
+        assert!(!reader.has_remaining());
+        reader.finish().unwrap();
+    }
+}
@@ -0,0 +1,187 @@
+use alloc::vec::Vec;
+
+use bytes::Bytes;
+
+use super::{ParseError, Reader, Writer};
+
+/// Encoded terminal modes of a `pty-req` channel request, see RFC 4254
+/// section 8.
+///
+/// The encoding is a list of opcodes, each followed by a `uint32` argument,
+/// ended by [`TTY_OP_END`](Self::TTY_OP_END). Modes keep the order they were
+/// set or parsed in, setting one again replaces its value.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TerminalModes {
+    modes: Vec<(u8, u32)>,
+}
+
+impl TerminalModes {
+    /// Ends the encoded modes, not a mode itself
+    pub const TTY_OP_END: u8 = 0;
+
+    /// Interrupt character
+    pub const VINTR: u8 = 1;
+    /// Quit character, sends SIGQUIT on POSIX systems
+    pub const VQUIT: u8 = 2;
+    /// Erase the character to the left of the cursor
+    pub const VERASE: u8 = 3;
+    /// Kill the current input line
+    pub const VKILL: u8 = 4;
+    /// End-of-file character, sends EOF from the terminal
+    pub const VEOF: u8 = 5;
+    /// End-of-line character in addition to carriage return and line feed
+    pub const VEOL: u8 = 6;
+    /// Additional end-of-line character
+    pub const VEOL2: u8 = 7;
+    /// Continues paused output
+    pub const VSTART: u8 = 8;
+    /// Pauses output
+    pub const VSTOP: u8 = 9;
+    /// Suspends the current program
+    pub const VSUSP: u8 = 10;
+    /// Another suspend character
+    pub const VDSUSP: u8 = 11;
+    /// Reprints the current input line
+    pub const VREPRINT: u8 = 12;
+    /// Erases a word left of the cursor
+    pub const VWERASE: u8 = 13;
+    /// Enter the next character typed literally
+    pub const VLNEXT: u8 = 14;
+    /// Character to flush output
+    pub const VFLUSH: u8 = 15;
+    /// Switch to a different shell layer
+    pub const VSWTCH: u8 = 16;
+    /// Prints system status line
+    pub const VSTATUS: u8 = 17;
+    /// Toggles the flushing of terminal output
+    pub const VDISCARD: u8 = 18;
+
+    // Flags, with a value of 0 or 1, named after their POSIX termios
+    // counterparts
+    pub const IGNPAR: u8 = 30;
+    pub const PARMRK: u8 = 31;
+    pub const INPCK: u8 = 32;
+    pub const ISTRIP: u8 = 33;
+    pub const INLCR: u8 = 34;
+    pub const IGNCR: u8 = 35;
+    pub const ICRNL: u8 = 36;
+    pub const IUCLC: u8 = 37;
+    pub const IXON: u8 = 38;
+    pub const IXANY: u8 = 39;
+    pub const IXOFF: u8 = 40;
+    pub const IMAXBEL: u8 = 41;
+    /// Input is UTF-8, from RFC 8160
+    pub const IUTF8: u8 = 42;
+
+    pub const ISIG: u8 = 50;
+    pub const ICANON: u8 = 51;
+    pub const XCASE: u8 = 52;
+    pub const ECHO: u8 = 53;
+    pub const ECHOE: u8 = 54;
+    pub const ECHOK: u8 = 55;
+    pub const ECHONL: u8 = 56;
+    pub const NOFLSH: u8 = 57;
+    pub const TOSTOP: u8 = 58;
+    pub const IEXTEN: u8 = 59;
+    pub const ECHOCTL: u8 = 60;
+    pub const ECHOKE: u8 = 61;
+    pub const PENDIN: u8 = 62;
+
+    pub const OPOST: u8 = 70;
+    pub const OLCUC: u8 = 71;
+    pub const ONLCR: u8 = 72;
+    pub const OCRNL: u8 = 73;
+    pub const ONOCR: u8 = 74;
+    pub const ONLRET: u8 = 75;
+
+    /// 7 bit mode
+    pub const CS7: u8 = 90;
+    /// 8 bit mode
+    pub const CS8: u8 = 91;
+    pub const PARENB: u8 = 92;
+    pub const PARODD: u8 = 93;
+
+    /// Input baud rate in bits per second
+    pub const TTY_OP_ISPEED: u8 = 128;
+    /// Output baud rate in bits per second
+    pub const TTY_OP_OSPEED: u8 = 129;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets `opcode` to `value`, replacing any earlier value.
+    ///
+    /// # Panics
+    ///
+    /// If `opcode` is [`TTY_OP_END`](Self::TTY_OP_END) or 160 and above,
+    /// which RFC 4254 leaves undefined and parsers stop at.
+    pub fn set(&mut self, opcode: u8, value: u32) -> &mut Self {
+        assert!(
+            (1..160).contains(&opcode),
+            "invalid terminal mode opcode {opcode}"
+        );
+
+        match self.modes.iter_mut().find(|(op, _)| *op == opcode) {
+            Some((_, old)) => *old = value,
+            None => self.modes.push((opcode, value)),
+        }
+
+        self
+    }
+
+    pub fn get(&self, opcode: u8) -> Option<u32> {
+        self.modes
+            .iter()
+            .find(|(op, _)| *op == opcode)
+            .map(|&(_, value)| value)
+    }
+
+    /// Opcodes and their values, in order
+    pub fn iter(&self) -> impl Iterator<Item = (u8, u32)> + '_ {
+        self.modes.iter().copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.modes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.modes.is_empty()
+    }
+
+    /// Decodes the modes of a `pty-req`.
+    ///
+    /// Parsing stops at the first opcode of 160 or more as RFC 4254 asks,
+    /// their arguments aren't defined. Missing the final
+    /// [`TTY_OP_END`](Self::TTY_OP_END) is an error.
+    pub fn parse(mut encoded: &[u8]) -> Result<Self, ParseError> {
+        let mut src = Reader::new(&mut encoded);
+        let mut modes = Self::new();
+
+        loop {
+            match src.read_u8()? {
+                Self::TTY_OP_END => break,
+                opcode @ 1..160 => {
+                    let value = src.read_u32()?;
+                    modes.set(opcode, value);
+                }
+                _ => break,
+            }
+        }
+
+        Ok(modes)
+    }
+
+    pub fn encode(&self) -> Bytes {
+        let mut encoded = Writer::new();
+
+        for (opcode, value) in self.iter() {
+            encoded.write_u8(opcode);
+            encoded.write_u32(value);
+        }
+        encoded.write_u8(Self::TTY_OP_END);
+
+        encoded.into_bytes()
+    }
+}
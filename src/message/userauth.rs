@@ -0,0 +1,438 @@
+use alloc::{borrow::ToOwned, string::String, vec::Vec};
+use core::fmt;
+
+use bytes::{Buf, Bytes};
+
+use super::{MessageType, ParseError, Reader, Writer};
+
+/// SSH_MSG_USERAUTH_REQUEST, see RFC 4252
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserauthRequest {
+    pub user_name: String,
+    /// Service to start once authenticated, usually `ssh-connection`
+    pub service_name: String,
+    pub method: AuthMethod,
+}
+
+/// SSH_MSG_USERAUTH_FAILURE
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserauthFailure {
+    /// Methods that may productively continue the authentication
+    pub methods: Vec<String>,
+    /// Whether the request that failed was itself successful, but more
+    /// methods are required
+    pub partial_success: bool,
+}
+
+/// SSH_MSG_USERAUTH_BANNER, text to show the user before authenticating
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserauthBanner {
+    /// May span multiple lines
+    pub message: String,
+    pub language_tag: String,
+}
+
+/// SSH_MSG_USERAUTH_INFO_REQUEST, the server's questions during
+/// `keyboard-interactive` authentication, see RFC 4256 section 3.2
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserauthInfoRequest {
+    pub name: String,
+    pub instruction: String,
+    /// Deprecated and usually empty
+    pub language_tag: String,
+    /// May be empty, which still needs an (empty) response
+    pub prompts: Vec<Prompt>,
+}
+
+/// One question of a [`UserauthInfoRequest`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Prompt {
+    pub prompt: String,
+    /// Whether the answer may be shown while it's typed in
+    pub echo: bool,
+}
+
+/// SSH_MSG_USERAUTH_INFO_RESPONSE, answering every prompt of a
+/// [`UserauthInfoRequest`] in order
+///
+/// The responses are typically passwords or one time codes, so they are
+/// left out of the `Debug` output.
+#[derive(Clone, PartialEq, Eq)]
+pub struct UserauthInfoResponse {
+    pub responses: Vec<String>,
+}
+
+/// Authentication method of a [`UserauthRequest`] and its method-specific
+/// fields
+///
+/// Passwords and the data of unknown methods are left out of the `Debug`
+/// output, so requests can be logged safely.
+#[derive(Clone, PartialEq, Eq)]
+pub enum AuthMethod {
+    /// Asks for the list of methods that can continue, or is accepted
+    /// outright by servers that need no authentication
+    None,
+    Password {
+        password: String,
+        /// Set when the client is changing an expired password
+        new_password: Option<String>,
+    },
+    PublicKey {
+        /// Public key algorithm name, e.g. `ssh-ed25519`
+        algorithm: String,
+        /// Public key blob
+        public_key: Bytes,
+        /// Signature over [`UserauthRequest::signed_data`].
+        ///
+        /// Leaving it out asks the server whether the key would be accepted
+        /// without having to sign anything yet.
+        signature: Option<Bytes>,
+    },
+    /// RFC 4256, the server then sends [`UserauthInfoRequest`]s
+    KeyboardInteractive {
+        /// Deprecated and usually empty
+        language_tag: String,
+        /// Comma separated hints of what to use, e.g. `pam`, usually empty
+        submethods: String,
+    },
+    /// A method this crate doesn't model, its fields are kept as is
+    Unknown { method_name: String, data: Bytes },
+}
+
+impl AuthMethod {
+    pub fn name(&self) -> &str {
+        match self {
+            AuthMethod::None => "none",
+            AuthMethod::Password { .. } => "password",
+            AuthMethod::PublicKey { .. } => "publickey",
+            AuthMethod::KeyboardInteractive { .. } => "keyboard-interactive",
+            AuthMethod::Unknown { method_name, .. } => method_name,
+        }
+    }
+}
+
+impl fmt::Debug for AuthMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let redacted = format_args!("<redacted>");
+
+        match self {
+            AuthMethod::None => f.write_str("None"),
+            AuthMethod::Password { new_password, .. } => f
+                .debug_struct("Password")
+                .field("password", &redacted)
+                .field("new_password", &new_password.as_ref().map(|_| redacted))
+                .finish(),
+            AuthMethod::PublicKey {
+                algorithm,
+                public_key,
+                signature,
+            } => f
+                .debug_struct("PublicKey")
+                .field("algorithm", algorithm)
+                .field("public_key", public_key)
+                .field("signature", signature)
+                .finish(),
+            AuthMethod::KeyboardInteractive {
+                language_tag,
+                submethods,
+            } => f
+                .debug_struct("KeyboardInteractive")
+                .field("language_tag", language_tag)
+                .field("submethods", submethods)
+                .finish(),
+            AuthMethod::Unknown { method_name, data } => f
+                .debug_struct("Unknown")
+                .field("method_name", method_name)
+                .field("data", &format_args!("<redacted, {} bytes>", data.len()))
+                .finish(),
+        }
+    }
+}
+
+impl UserauthRequest {
+    /// A request with the `none` method, conventionally the first one sent.
+    ///
+    /// Unless the server lets `user_name` in without authentication it
+    /// fails, and the [`UserauthFailure`] tells which methods to try.
+    pub fn none(user_name: &str, service_name: &str) -> Self {
+        Self {
+            user_name: user_name.to_owned(),
+            service_name: service_name.to_owned(),
+            method: AuthMethod::None,
+        }
+    }
+
+    pub(super) fn parse<B: Buf>(src: &mut Reader<'_, B>) -> Result<Self, ParseError> {
+        let user_name = src.read_utf8()?;
+        let service_name = src.read_utf8()?;
+        let method_name = src.read_utf8()?;
+
+        let method = match method_name.as_str() {
+            "none" => AuthMethod::None,
+            "password" => {
+                let change_password = src.read_bool()?;
+                let password = src.read_utf8()?;
+
+                let new_password = if change_password {
+                    Some(src.read_utf8()?)
+                } else {
+                    None
+                };
+
+                AuthMethod::Password {
+                    password,
+                    new_password,
+                }
+            }
+            "publickey" => {
+                let has_signature = src.read_bool()?;
+                let algorithm = src.read_utf8()?;
+                let public_key = src.read_string()?;
+
+                let signature = if has_signature {
+                    Some(src.read_string()?)
+                } else {
+                    None
+                };
+
+                AuthMethod::PublicKey {
+                    algorithm,
+                    public_key,
+                    signature,
+                }
+            }
+            "keyboard-interactive" => AuthMethod::KeyboardInteractive {
+                language_tag: src.read_utf8()?,
+                submethods: src.read_utf8()?,
+            },
+            _ => AuthMethod::Unknown {
+                method_name,
+                data: src.read_rest(),
+            },
+        };
+
+        src.finish()?;
+
+        Ok(UserauthRequest {
+            user_name,
+            service_name,
+            method,
+        })
+    }
+
+    /// Returns the data a `publickey` request signature is made over, or
+    /// `None` for other methods.
+    ///
+    /// This is the session identifier followed by the request itself with
+    /// the signature flag set, but without the signature.
+    pub fn signed_data(&self, session_id: &[u8]) -> Option<Bytes> {
+        let AuthMethod::PublicKey {
+            algorithm,
+            public_key,
+            ..
+        } = &self.method
+        else {
+            return None;
+        };
+
+        let mut data = Writer::new();
+
+        data.write_string(session_id);
+        data.write_u8(MessageType::UserauthRequest as u8);
+        data.write_string(&self.user_name);
+        data.write_string(&self.service_name);
+        data.write_string(self.method.name());
+        data.write_bool(true);
+        data.write_string(algorithm);
+        data.write_string(public_key);
+
+        Some(data.into_bytes())
+    }
+
+    pub fn into_payload(self) -> Bytes {
+        let UserauthRequest {
+            user_name,
+            service_name,
+            method,
+        } = self;
+
+        let mut payload = Writer::new();
+
+        payload.write_u8(MessageType::UserauthRequest as u8);
+
+        payload.write_string(&user_name);
+        payload.write_string(&service_name);
+        payload.write_string(method.name());
+
+        match method {
+            AuthMethod::None => {}
+            AuthMethod::Password {
+                password,
+                new_password,
+            } => {
+                payload.write_bool(new_password.is_some());
+                payload.write_string(&password);
+
+                if let Some(new_password) = new_password {
+                    payload.write_string(&new_password);
+                }
+            }
+            AuthMethod::PublicKey {
+                algorithm,
+                public_key,
+                signature,
+            } => {
+                payload.write_bool(signature.is_some());
+                payload.write_string(&algorithm);
+                payload.write_string(&public_key);
+
+                if let Some(signature) = signature {
+                    payload.write_string(&signature);
+                }
+            }
+            AuthMethod::KeyboardInteractive {
+                language_tag,
+                submethods,
+            } => {
+                payload.write_string(&language_tag);
+                payload.write_string(&submethods);
+            }
+            AuthMethod::Unknown { data, .. } => payload.write_raw(&data),
+        }
+
+        payload.into_bytes()
+    }
+}
+
+impl UserauthFailure {
+    pub(super) fn parse<B: Buf>(src: &mut Reader<'_, B>) -> Result<Self, ParseError> {
+        let methods = src.read_name_list()?;
+
+        let partial_success = src.read_bool()?;
+
+        src.finish()?;
+
+        Ok(UserauthFailure {
+            methods,
+            partial_success,
+        })
+    }
+
+    pub fn into_payload(self) -> Bytes {
+        let mut payload = Writer::new();
+
+        payload.write_u8(MessageType::UserauthFailure as u8);
+        payload.write_name_list(&self.methods);
+        payload.write_bool(self.partial_success);
+
+        payload.into_bytes()
+    }
+}
+
+impl UserauthBanner {
+    pub(super) fn parse<B: Buf>(src: &mut Reader<'_, B>) -> Result<Self, ParseError> {
+        let banner = UserauthBanner {
+            message: src.read_utf8()?,
+            language_tag: src.read_utf8()?,
+        };
+
+        src.finish()?;
+
+        Ok(banner)
+    }
+
+    pub fn into_payload(self) -> Bytes {
+        let mut payload = Writer::new();
+
+        payload.write_u8(MessageType::UserauthBanner as u8);
+        payload.write_string(&self.message);
+        payload.write_string(&self.language_tag);
+
+        payload.into_bytes()
+    }
+}
+
+impl UserauthInfoRequest {
+    pub(super) fn parse<B: Buf>(src: &mut Reader<'_, B>) -> Result<Self, ParseError> {
+        let name = src.read_utf8()?;
+        let instruction = src.read_utf8()?;
+        let language_tag = src.read_utf8()?;
+
+        // The count isn't trusted for allocating, every prompt takes at
+        // least 5 bytes so a bogus one runs out of input soon enough
+        let num_prompts = src.read_u32()?;
+        let mut prompts = Vec::new();
+
+        for _ in 0..num_prompts {
+            prompts.push(Prompt {
+                prompt: src.read_utf8()?,
+                echo: src.read_bool()?,
+            });
+        }
+
+        src.finish()?;
+
+        Ok(UserauthInfoRequest {
+            name,
+            instruction,
+            language_tag,
+            prompts,
+        })
+    }
+
+    pub fn into_payload(self) -> Bytes {
+        let mut payload = Writer::new();
+
+        payload.write_u8(MessageType::UserauthInfoRequest as u8);
+        payload.write_string(&self.name);
+        payload.write_string(&self.instruction);
+        payload.write_string(&self.language_tag);
+        payload.write_u32(self.prompts.len() as u32);
+
+        for prompt in &self.prompts {
+            payload.write_string(&prompt.prompt);
+            payload.write_bool(prompt.echo);
+        }
+
+        payload.into_bytes()
+    }
+}
+
+impl UserauthInfoResponse {
+    pub(super) fn parse<B: Buf>(src: &mut Reader<'_, B>) -> Result<Self, ParseError> {
+        let num_responses = src.read_u32()?;
+        let mut responses = Vec::new();
+
+        for _ in 0..num_responses {
+            responses.push(src.read_utf8()?);
+        }
+
+        src.finish()?;
+
+        Ok(UserauthInfoResponse { responses })
+    }
+
+    pub fn into_payload(self) -> Bytes {
+        let mut payload = Writer::new();
+
+        payload.write_u8(MessageType::UserauthInfoResponse as u8);
+        payload.write_u32(self.responses.len() as u32);
+
+        for response in &self.responses {
+            payload.write_string(response);
+        }
+
+        payload.into_bytes()
+    }
+}
+
+impl fmt::Debug for UserauthInfoResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UserauthInfoResponse")
+            .field(
+                "responses",
+                &format_args!("<{} redacted>", self.responses.len()),
+            )
+            .finish()
+    }
+}
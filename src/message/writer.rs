@@ -0,0 +1,88 @@
+use bytes::{BufMut, Bytes, BytesMut};
+
+/// Builds payloads out of the SSH data types of RFC 4251 section 5, the
+/// counterpart of [`Reader`](super::Reader)
+#[derive(Debug, Clone, Default)]
+pub struct Writer {
+    buf: BytesMut,
+}
+
+impl Writer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.buf.put_u8(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.buf.put_u8(value as u8);
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.buf.put_u32(value);
+    }
+
+    /// Writes bytes as they are, without a length prefix
+    pub fn write_raw(&mut self, bytes: &[u8]) {
+        self.buf.put_slice(bytes);
+    }
+
+    /// Writes a length-prefixed string, which may hold arbitrary bytes
+    pub fn write_string<S: AsRef<[u8]>>(&mut self, string: S) {
+        let string = string.as_ref();
+
+        self.buf.put_u32(string.len() as u32);
+        self.buf.put_slice(string);
+    }
+
+    /// Writes names as a comma-separated name-list
+    pub fn write_name_list<S: AsRef<str>>(&mut self, names: &[S]) {
+        let len = names.iter().map(|name| name.as_ref().len()).sum::<usize>()
+            + names.len().saturating_sub(1);
+
+        self.buf.put_u32(len as u32);
+
+        for (i, name) in names.iter().enumerate() {
+            if i > 0 {
+                self.buf.put_u8(b',');
+            }
+            self.buf.put_slice(name.as_ref().as_bytes());
+        }
+    }
+
+    /// Writes `value`, an unsigned big-endian integer, as an mpint
+    pub fn write_mpint(&mut self, value: &[u8]) {
+        // Leading zeros are redundant, and zero itself is the empty string
+        let start = value.iter().position(|&b| b != 0).unwrap_or(value.len());
+        let value = &value[start..];
+
+        // A set high bit would make the number negative
+        let sign_byte = value.first().is_some_and(|&b| b & 0x80 != 0);
+
+        self.buf.put_u32((value.len() + sign_byte as usize) as u32);
+        if sign_byte {
+            self.buf.put_u8(0);
+        }
+        self.buf.put_slice(value);
+    }
+
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    pub fn into_bytes(self) -> Bytes {
+        self.buf.freeze()
+    }
+}
+
+impl AsRef<[u8]> for Writer {
+    fn as_ref(&self) -> &[u8] {
+        &self.buf
+    }
+}
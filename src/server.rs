@@ -0,0 +1,455 @@
+use std::{io, mem, net::SocketAddr, path::Path, sync::Arc};
+
+use bytes::Bytes;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+};
+
+use crate::{
+    client::ClientConfig,
+    codec::{Direction, Metrics},
+    kex::{
+        Curve25519Sha256, DhGroup14Sha256, EcdhSha2Nistp256, ExchangeContext, HashAlg, KexError,
+        NegotiationError, STRICT_KEX_SERVER, derive_keys, install_keys,
+    },
+    message::{
+        Disconnect, KexEcdhReply, KexdhReply, Kexinit, Message, MessageType, ParseContext,
+        ParseError,
+    },
+    openssh_key::{KeyError, PrivateKey},
+    tracing::{debug, warn},
+    transport::{LOCAL_ID, Timeouts, Transport, TransportError},
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ServerError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Transport(#[from] TransportError),
+    #[error("Malformed message from the client")]
+    Malformed(#[from] ParseError),
+    #[error(transparent)]
+    Negotiation(#[from] NegotiationError),
+    #[error(transparent)]
+    Kex(#[from] KexError),
+    #[error(transparent)]
+    Key(#[from] KeyError),
+    #[error("Unsupported algorithm: {0}")]
+    UnsupportedAlgorithm(String),
+    /// Negotiation picked a host key algorithm none of the host keys can
+    /// sign with, only possible with a changed KEXINIT
+    #[error("No host key for {0}")]
+    NoHostKey(String),
+    #[error("Unexpected message from the client, expected {0}")]
+    UnexpectedMessage(MessageType),
+    #[error("Disconnected by the client: {}", .0.description)]
+    Disconnected(Disconnect),
+    /// The client offered strict key exchange but its KEXINIT wasn't the
+    /// first packet
+    #[error("Client violated strict key exchange")]
+    StrictKex,
+}
+
+/// Private host keys of a server, at most one of each key type.
+///
+/// Every key is offered with all the signature algorithms it supports, the
+/// one negotiated decides which key signs the exchange hash.
+#[derive(Debug, Default)]
+pub struct HostKeyStore {
+    keys: Vec<PrivateKey>,
+}
+
+impl HostKeyStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads unencrypted OpenSSH private keys such as
+    /// `/etc/ssh/ssh_host_ed25519_key`, most preferred first
+    pub fn from_files<I, P>(paths: I) -> Result<Self, KeyError>
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        let mut store = Self::new();
+
+        for path in paths {
+            store.insert(PrivateKey::from_file(path)?);
+        }
+
+        Ok(store)
+    }
+
+    /// Adds `key`, taking the place of a key of the same type which is then
+    /// returned
+    pub fn insert(&mut self, key: PrivateKey) -> Option<PrivateKey> {
+        let key_type = key.public_key().key_type();
+
+        match self
+            .keys
+            .iter_mut()
+            .find(|existing| existing.public_key().key_type() == key_type)
+        {
+            Some(existing) => Some(mem::replace(existing, key)),
+            None => {
+                self.keys.push(key);
+                None
+            }
+        }
+    }
+
+    /// Signature algorithms of all keys, as offered in
+    /// `server_host_key_algorithms`
+    pub fn algorithms(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.keys.iter().flat_map(|key| key.algorithms()).copied()
+    }
+
+    /// The key that signs with `algorithm`, usually the negotiated host key
+    /// algorithm
+    pub fn select(&self, algorithm: &str) -> Option<&PrivateKey> {
+        self.keys
+            .iter()
+            .find(|key| key.algorithms().contains(&algorithm))
+    }
+
+    /// Signs `exchange_hash` with the key selected for `algorithm`, giving the
+    /// signature blob for the key exchange reply
+    pub fn sign(&self, algorithm: &str, exchange_hash: &[u8]) -> Result<Bytes, ServerError> {
+        let key = self
+            .select(algorithm)
+            .ok_or_else(|| ServerError::NoHostKey(algorithm.to_owned()))?;
+
+        Ok(key.sign(algorithm, exchange_hash)?)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &PrivateKey> {
+        self.keys.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+/// Settings of the handshake, algorithms being offered to the client most
+/// preferred first
+#[derive(Debug)]
+pub struct ServerConfig {
+    /// Keys proving the server's identity, offered in the order they were
+    /// added
+    pub host_keys: HostKeyStore,
+    pub kex_algorithms: Vec<String>,
+    /// Used for both directions
+    pub encryption_algorithms: Vec<String>,
+    /// Used for both directions, ignored with AEAD ciphers
+    pub mac_algorithms: Vec<String>,
+    /// Used for both directions
+    pub compression_algorithms: Vec<String>,
+    pub timeouts: Timeouts,
+    /// Installed on the codec of every connection before its key exchange
+    pub metrics: Option<Arc<dyn Metrics>>,
+}
+
+impl ServerConfig {
+    /// Default algorithms for `host_keys`.
+    ///
+    /// Those are the key exchanges of [`ClientConfig::default`] but group
+    /// exchange, which would need a set of groups to pick from, and its
+    /// ciphers, MACs and compression.
+    pub fn new(host_keys: HostKeyStore) -> Self {
+        let client = ClientConfig::default();
+
+        Self {
+            host_keys,
+            kex_algorithms: [
+                Curve25519Sha256::NAME,
+                "curve25519-sha256@libssh.org",
+                EcdhSha2Nistp256::NAME,
+                DhGroup14Sha256::NAME,
+                STRICT_KEX_SERVER,
+            ]
+            .map(str::to_owned)
+            .to_vec(),
+            encryption_algorithms: client.encryption_algorithms,
+            mac_algorithms: client.mac_algorithms,
+            compression_algorithms: client.compression_algorithms,
+            timeouts: client.timeouts,
+            metrics: None,
+        }
+    }
+
+    fn kexinit(&self) -> io::Result<Kexinit> {
+        let host_key_algorithms: Vec<String> =
+            self.host_keys.algorithms().map(str::to_owned).collect();
+
+        Kexinit::builder()
+            .kex_algorithms(&self.kex_algorithms)
+            .server_host_key_algorithms(&host_key_algorithms)
+            .encryption_algorithms(&self.encryption_algorithms)
+            .mac_algorithms(&self.mac_algorithms)
+            .compression_algorithms(&self.compression_algorithms)
+            .build()
+            .map_err(|_| io::Error::other("Failed to generate a KEXINIT cookie"))
+    }
+}
+
+/// SSH server listening on a TCP socket
+#[derive(Debug)]
+pub struct Server {
+    listener: TcpListener,
+    config: ServerConfig,
+}
+
+impl Server {
+    pub async fn bind<A: ToSocketAddrs>(addr: A, config: ServerConfig) -> io::Result<Self> {
+        Ok(Self {
+            listener: TcpListener::bind(addr).await?,
+            config,
+        })
+    }
+
+    /// Address the server listens on, to find the port when binding port 0
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn config(&self) -> &ServerConfig {
+        &self.config
+    }
+
+    /// Waits for the next client and performs the handshake with it.
+    ///
+    /// Clients are handled one at a time, a slow one holds up those behind
+    /// it. Serving several at once takes a listener of one's own and
+    /// [`handshake`](Self::handshake) in a task per connection.
+    pub async fn accept(&self) -> Result<(Transport<TcpStream>, SocketAddr), ServerError> {
+        let (stream, addr) = self.listener.accept().await?;
+
+        debug!(%addr, "Accepted connection");
+
+        Ok((Self::handshake(stream, &self.config).await?, addr))
+    }
+
+    /// Performs the server half of the handshake over an already connected
+    /// stream.
+    ///
+    /// That is the version exchange and a key exchange, signed with one of
+    /// the host keys. The client usually sends an SSH_MSG_SERVICE_REQUEST
+    /// for `ssh-userauth` next.
+    pub async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+        stream: S,
+        config: &ServerConfig,
+    ) -> Result<Transport<S>, ServerError> {
+        let mut transport = Transport::connect_with_timeouts(stream, config.timeouts).await?;
+        transport.codec_mut().set_metrics(config.metrics.clone());
+
+        debug!(
+            software_version = %transport.remote_id().version.software_version,
+            "Client connected"
+        );
+
+        key_exchange(&mut transport, config).await?;
+
+        Ok(transport)
+    }
+}
+
+/// Runs a key exchange from the KEXINITs up to both NEWKEYS
+async fn key_exchange<S>(
+    transport: &mut Transport<S>,
+    config: &ServerConfig,
+) -> Result<(), ServerError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let kexinit = config.kexinit()?;
+    transport
+        .send(Message::Kexinit(Box::new(kexinit.clone())))
+        .await?;
+
+    let peer = match transport.recv().await? {
+        Message::Kexinit(peer) => peer,
+        message => return Err(unexpected(message, MessageType::Kexinit)),
+    };
+
+    // Unlike the client this doesn't reject messages ahead of the peer's
+    // KEXINIT. Terrapin makes the victim miss its peer's first packets under
+    // the new keys, and without `ext-info-s` being offered the client's first
+    // is the SSH_MSG_SERVICE_REQUEST the server waits for. Dropping it stalls
+    // the connection rather than going unnoticed.
+
+    // The client's preferences win
+    let algorithms = peer.negotiate(&kexinit)?;
+    debug!(?algorithms, "Negotiated algorithms");
+
+    if algorithms.strict_kex {
+        if transport.codec().recv_seq() != 1 {
+            warn!("Client's KEXINIT wasn't its first packet despite strict key exchange");
+            return Err(ServerError::StrictKex);
+        }
+
+        transport.set_strict_kex(true);
+    }
+
+    if peer.first_kex_packet_follows && !peer.guessed_right(&algorithms) {
+        debug!("Discarding the client's wrongly guessed kex packet");
+        transport.recv_payload().await?;
+    }
+
+    let host_keys = &config.host_keys;
+    let host_key = host_keys
+        .select(&algorithms.server_host_key)
+        .ok_or_else(|| ServerError::NoHostKey(algorithms.server_host_key.clone()))?
+        .public_key();
+    let host_key_blob = host_key.to_blob();
+
+    debug!(
+        key_type = host_key.key_type(),
+        fingerprint = %host_key.fingerprint_sha256(),
+        "Signing with host key"
+    );
+
+    let parse_context = ParseContext {
+        kex: Some(&algorithms.kex),
+        ..Default::default()
+    };
+
+    let output = match algorithms.kex.as_str() {
+        Curve25519Sha256::NAME | "curve25519-sha256@libssh.org" => {
+            let init = match transport.recv_with_context(parse_context).await? {
+                Message::KexEcdhInit(init) => init,
+                message => return Err(unexpected(message, MessageType::KexEcdhInit)),
+            };
+
+            let kex = Curve25519Sha256::new()?;
+            let ephemeral_public_key = Bytes::copy_from_slice(kex.public_key());
+
+            let context = exchange_context(transport);
+            let mut output = kex.respond(&context, host_key_blob, init)?;
+            output.signature =
+                host_keys.sign(&algorithms.server_host_key, &output.exchange_hash)?;
+
+            transport
+                .send(Message::KexEcdhReply(KexEcdhReply {
+                    host_key: output.host_key.clone(),
+                    ephemeral_public_key,
+                    signature: output.signature.clone(),
+                }))
+                .await?;
+
+            output
+        }
+        EcdhSha2Nistp256::NAME => {
+            let init = match transport.recv_with_context(parse_context).await? {
+                Message::KexEcdhInit(init) => init,
+                message => return Err(unexpected(message, MessageType::KexEcdhInit)),
+            };
+
+            let kex = EcdhSha2Nistp256::new()?;
+            let ephemeral_public_key = Bytes::copy_from_slice(kex.public_key());
+
+            let context = exchange_context(transport);
+            let mut output = kex.respond(&context, host_key_blob, init)?;
+            output.signature =
+                host_keys.sign(&algorithms.server_host_key, &output.exchange_hash)?;
+
+            transport
+                .send(Message::KexEcdhReply(KexEcdhReply {
+                    host_key: output.host_key.clone(),
+                    ephemeral_public_key,
+                    signature: output.signature.clone(),
+                }))
+                .await?;
+
+            output
+        }
+        DhGroup14Sha256::NAME => {
+            let init = match transport.recv_with_context(parse_context).await? {
+                Message::KexdhInit(init) => init,
+                message => return Err(unexpected(message, MessageType::KexdhInit)),
+            };
+
+            let kex = DhGroup14Sha256::new()?;
+            let f = kex.public_key().into();
+
+            let context = exchange_context(transport);
+            let mut output = kex.respond(&context, host_key_blob, init)?;
+            output.signature =
+                host_keys.sign(&algorithms.server_host_key, &output.exchange_hash)?;
+
+            transport
+                .send(Message::KexdhReply(KexdhReply {
+                    host_key: output.host_key.clone(),
+                    f,
+                    signature: output.signature.clone(),
+                }))
+                .await?;
+
+            output
+        }
+        _ => return Err(ServerError::UnsupportedAlgorithm(algorithms.kex)),
+    };
+
+    transport.set_session_id(&output.exchange_hash);
+    let keys = derive_keys(
+        &output.shared_secret,
+        &output.exchange_hash,
+        transport.session_id().unwrap_or_default(),
+        HashAlg::Sha256,
+    );
+
+    transport.send(Message::Newkeys).await?;
+    install_keys(
+        transport.codec_mut(),
+        Direction::Outgoing,
+        false,
+        &algorithms,
+        &keys,
+    )?;
+    if algorithms.strict_kex {
+        transport.codec_mut().reset_seq(Direction::Outgoing);
+    }
+
+    match transport.recv().await? {
+        Message::Newkeys => {}
+        message => return Err(unexpected(message, MessageType::Newkeys)),
+    }
+    install_keys(
+        transport.codec_mut(),
+        Direction::Incoming,
+        true,
+        &algorithms,
+        &keys,
+    )?;
+    if algorithms.strict_kex {
+        transport.codec_mut().reset_seq(Direction::Incoming);
+        transport.set_strict_kex(false);
+    }
+
+    debug!(
+        kex = %algorithms.kex,
+        strict_kex = algorithms.strict_kex,
+        "Key exchange finished"
+    );
+
+    Ok(())
+}
+
+/// Inputs of the exchange hash, the KEXINITs being those just exchanged
+fn exchange_context<S>(transport: &Transport<S>) -> ExchangeContext<'_> {
+    ExchangeContext {
+        client_version: transport.remote_id().identification.as_bytes(),
+        server_version: LOCAL_ID.as_bytes(),
+        client_kexinit: transport.remote_kexinit().unwrap_or_default(),
+        server_kexinit: transport.local_kexinit().unwrap_or_default(),
+    }
+}
+
+fn unexpected(message: Message, expected: MessageType) -> ServerError {
+    match message {
+        Message::Disconnect(disconnect) => ServerError::Disconnected(disconnect),
+        _ => ServerError::UnexpectedMessage(expected),
+    }
+}
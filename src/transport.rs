@@ -0,0 +1,586 @@
+use std::{collections::VecDeque, future::Future, io, ops::RangeInclusive, time::Duration};
+
+use bytes::Bytes;
+use futures_util::{SinkExt, TryStreamExt};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, BufReader},
+    time::timeout,
+};
+use tokio_util::codec::Framed;
+
+use crate::{
+    codec::{Packet, PacketCodec},
+    message::{
+        Disconnect, GlobalRequest, GlobalRequestType, Message, MessageType, ParseContext,
+        ParseError, ReasonCode, Unimplemented,
+    },
+    tracing::{debug, trace, warn},
+    version::{RemoteId, exchange_versions},
+};
+
+/// Identification string we send to the peer
+pub const LOCAL_ID: &str = concat!("SSH-2.0-softpaw_", env!("CARGO_PKG_VERSION"));
+
+/// Global request name of keepalives, the one OpenSSH uses
+pub const KEEPALIVE_REQUEST: &str = "keepalive@openssh.com";
+
+/// Message numbers of the `ssh-connection` service, see RFC 4250 section
+/// 4.1.2
+const CONNECTION_MESSAGES: RangeInclusive<u8> = 80..=127;
+
+/// Why no message could be received
+#[derive(Debug, thiserror::Error)]
+pub enum TransportError {
+    /// The peer closed the connection, reconnecting may help
+    #[error("Connection closed by the peer")]
+    ConnectionClosed,
+    /// A packet failed to decrypt, authenticate or frame. The connection is
+    /// unusable after this.
+    #[error("Protocol violation by the peer")]
+    Protocol(#[source] io::Error),
+    /// A packet arrived fine but its message didn't parse
+    #[error("Malformed message from the peer")]
+    Malformed(#[from] ParseError),
+    /// The peer took longer than the [`Timeouts`] allow
+    #[error("Timed out waiting for the peer")]
+    Timeout,
+    /// More keepalives than [`Keepalive::max_missed`] went unanswered, the
+    /// peer or the network in between is most likely gone
+    #[error("Peer stopped answering keepalives")]
+    KeepaliveTimeout,
+    /// A message of the `ssh-connection` service, such as
+    /// SSH_MSG_CHANNEL_OPEN, came before user authentication succeeded
+    #[error("Peer sent {0} before authentication")]
+    NotAuthenticated(MessageType),
+    #[error(transparent)]
+    Io(io::Error),
+}
+
+impl From<io::Error> for TransportError {
+    /// Codec errors of kind [`io::ErrorKind::InvalidData`] are protocol
+    /// violations, anything else comes from the stream itself
+    fn from(error: io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::InvalidData => TransportError::Protocol(error),
+            _ => TransportError::Io(error),
+        }
+    }
+}
+
+/// How long a [`Transport`] waits on the peer, `None` waiting forever
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    /// For the whole identification string exchange, which a peer could
+    /// otherwise stretch out by sending one byte at a time
+    pub version_exchange: Option<Duration>,
+    /// For every packet received, off by default.
+    ///
+    /// Quiet connections would run into it as well, and an interactive
+    /// session may sit idle for hours. Keepalives, see
+    /// [`Transport::set_keepalive`], notice a dead peer without that.
+    pub read: Option<Duration>,
+    /// For every packet sent, including waiting for the peer to read
+    pub write: Option<Duration>,
+}
+
+impl Timeouts {
+    pub const DEFAULT: Duration = Duration::from_secs(30);
+}
+
+impl Default for Timeouts {
+    /// [`DEFAULT`](Self::DEFAULT) for the version exchange and writes, no
+    /// read timeout
+    fn default() -> Self {
+        Self {
+            version_exchange: Some(Self::DEFAULT),
+            read: None,
+            write: Some(Self::DEFAULT),
+        }
+    }
+}
+
+/// Keepalives sent while the peer is quiet, see [`Transport::set_keepalive`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keepalive {
+    /// How long the peer may stay quiet before a keepalive is sent
+    pub interval: Duration,
+    /// Unanswered keepalives after which the peer counts as gone
+    pub max_missed: u32,
+}
+
+impl Default for Keepalive {
+    /// Every 15 seconds, giving up after 3 missed like OpenSSH's
+    /// `ServerAliveCountMax`
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(15),
+            max_missed: 3,
+        }
+    }
+}
+
+/// An SSH connection that sends and receives whole messages.
+///
+/// Keys, compression and everything else about the packet layer are set on
+/// the codec, which is reachable through [`Transport::codec_mut`].
+#[derive(Debug)]
+pub struct Transport<S> {
+    framed: Framed<BufReader<S>, PacketCodec>,
+    remote_id: RemoteId,
+    /// Whether SSH_MSG_DISCONNECT has been sent and the write half shut down
+    disconnected: bool,
+    /// Whether a strict key exchange is running, which hands out
+    /// SSH_MSG_IGNORE and SSH_MSG_DEBUG instead of skipping them
+    strict_kex: bool,
+    /// Whether the peer's first KEXINIT has arrived
+    kexinit_received: bool,
+    /// SSH_MSG_IGNORE, SSH_MSG_DEBUG and SSH_MSG_UNIMPLEMENTED skipped before
+    /// the peer's first KEXINIT
+    messages_before_kexinit: u32,
+    timeouts: Timeouts,
+    keepalive: Option<Keepalive>,
+    /// Global requests sent with `want_reply` and not answered yet, in
+    /// order, `true` marking keepalives
+    pending_replies: VecDeque<bool>,
+    /// Keepalives among [`pending_replies`](Self::pending_replies)
+    unanswered_keepalives: u32,
+    /// Payloads of the last KEXINIT sent and received, as the exchange hash
+    /// covers them
+    local_kexinit: Option<Bytes>,
+    remote_kexinit: Option<Bytes>,
+    /// Exchange hash of the first key exchange
+    session_id: Option<Vec<u8>>,
+    /// Whether SSH_MSG_USERAUTH_SUCCESS went either way, starting the
+    /// `ssh-connection` service
+    authenticated: bool,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Transport<S> {
+    /// Exchanges identification strings over `stream` and sets up an
+    /// unencrypted packet layer on top of it, with the default [`Timeouts`].
+    pub async fn connect(stream: S) -> Result<Self, TransportError> {
+        Self::connect_with_timeouts(stream, Timeouts::default()).await
+    }
+
+    /// Like [`connect`](Self::connect), waiting on the peer no longer than
+    /// `timeouts` allow
+    pub async fn connect_with_timeouts(
+        stream: S,
+        timeouts: Timeouts,
+    ) -> Result<Self, TransportError> {
+        let mut stream = BufReader::new(stream);
+        let remote_id = with_timeout(
+            timeouts.version_exchange,
+            exchange_versions(&mut stream, LOCAL_ID),
+        )
+        .await?;
+
+        Ok(Self {
+            framed: Framed::new(stream, PacketCodec::builder().build()),
+            remote_id,
+            disconnected: false,
+            strict_kex: false,
+            kexinit_received: false,
+            messages_before_kexinit: 0,
+            timeouts,
+            keepalive: None,
+            pending_replies: VecDeque::new(),
+            unanswered_keepalives: 0,
+            local_kexinit: None,
+            remote_kexinit: None,
+            session_id: None,
+            authenticated: false,
+        })
+    }
+
+    pub async fn send(&mut self, message: Message) -> Result<(), TransportError> {
+        // Replies come in the order requests were sent, keeping track lets
+        // the ones to keepalives be told apart
+        if let Message::GlobalRequest(request) = &message
+            && request.want_reply
+        {
+            self.pending_replies.push_back(false);
+        }
+
+        let success = matches!(message, Message::UserauthSuccess);
+        self.send_payload(message.into_payload()).await?;
+
+        // Whatever the client sends next belongs to `ssh-connection`
+        if success {
+            self.authenticated = true;
+        }
+
+        Ok(())
+    }
+
+    /// Sends a `keepalive@openssh.com` global request asking for a reply.
+    ///
+    /// Peers answer with SSH_MSG_REQUEST_FAILURE as they don't know the
+    /// request, which is all it takes. The reply is swallowed by
+    /// [`recv`](Self::recv) rather than returned.
+    pub async fn keepalive(&mut self) -> Result<(), TransportError> {
+        self.send_payload(
+            GlobalRequest {
+                want_reply: true,
+                request: GlobalRequestType::Unknown {
+                    request_name: KEEPALIVE_REQUEST.to_owned(),
+                    data: Bytes::new(),
+                },
+            }
+            .into_payload(),
+        )
+        .await?;
+
+        self.pending_replies.push_back(true);
+        self.unanswered_keepalives += 1;
+
+        trace!(unanswered = self.unanswered_keepalives, "Sent keepalive");
+
+        Ok(())
+    }
+
+    /// Waits for the next message, a closed connection is
+    /// [`TransportError::ConnectionClosed`].
+    ///
+    /// SSH_MSG_IGNORE and SSH_MSG_DEBUG are not returned, the former is
+    /// skipped and the latter only logged. During a strict key exchange they
+    /// are, so that it can reject them. SSH_MSG_UNIMPLEMENTED is skipped too
+    /// before the peer's first KEXINIT, see
+    /// [`messages_before_kexinit`](Transport::messages_before_kexinit).
+    ///
+    /// Messages of the `ssh-connection` service fail with
+    /// [`TransportError::NotAuthenticated`] until
+    /// [`is_authenticated`](Transport::is_authenticated).
+    pub async fn recv(&mut self) -> Result<Message, TransportError> {
+        self.recv_with_context(ParseContext::default()).await
+    }
+
+    /// Like [`recv`](Self::recv), for messages that only parse given a
+    /// [`ParseContext`]
+    pub async fn recv_with_context(
+        &mut self,
+        context: ParseContext<'_>,
+    ) -> Result<Message, TransportError> {
+        let mut payload = self.recv_payload().await?;
+
+        Ok(Message::parse_with_context(&mut payload, context)?)
+    }
+
+    /// Sends SSH_MSG_DISCONNECT and shuts down the write half of the stream.
+    ///
+    /// Calling it again does nothing, the peer has been told already.
+    pub async fn disconnect(
+        &mut self,
+        reason: ReasonCode,
+        description: &str,
+    ) -> Result<(), TransportError> {
+        if self.disconnected {
+            return Ok(());
+        }
+
+        self.send(Message::Disconnect(Disconnect {
+            reason_code: reason,
+            description: description.to_owned(),
+            language_tag: String::new(),
+        }))
+        .await?;
+        self.disconnected = true;
+
+        // Flushes anything still buffered before shutting down
+        with_timeout(self.timeouts.write, self.framed.close()).await
+    }
+
+    /// Sends an already serialized message
+    pub(crate) async fn send_payload(&mut self, payload: Bytes) -> Result<(), TransportError> {
+        if payload.first() == Some(&(MessageType::Kexinit as u8)) {
+            self.local_kexinit = Some(payload.clone());
+        }
+
+        with_timeout(
+            self.timeouts.write,
+            self.framed.send(Packet { payload, mac: None }),
+        )
+        .await
+    }
+
+    /// Waits for the next packet, sending keepalives meanwhile if enabled
+    async fn recv_packet(&mut self) -> Result<Packet, TransportError> {
+        loop {
+            let Some(keepalive) = self.keepalive else {
+                return with_timeout(self.timeouts.read, self.framed.try_next())
+                    .await?
+                    .ok_or(TransportError::ConnectionClosed);
+            };
+
+            // Cancelling the read loses nothing, the codec keeps partial
+            // packets buffered
+            match timeout(keepalive.interval, self.framed.try_next()).await {
+                Ok(packet) => return packet?.ok_or(TransportError::ConnectionClosed),
+                Err(_) if self.unanswered_keepalives >= keepalive.max_missed => {
+                    warn!(
+                        missed = self.unanswered_keepalives,
+                        "Peer stopped answering keepalives"
+                    );
+                    return Err(TransportError::KeepaliveTimeout);
+                }
+                Err(_) => self.keepalive().await?,
+            }
+        }
+    }
+
+    /// Receives a message without parsing it, for one that is only to be
+    /// skipped such as a wrongly guessed key exchange packet
+    pub(crate) async fn recv_payload(&mut self) -> Result<Bytes, TransportError> {
+        loop {
+            let packet = self.recv_packet().await?;
+
+            if packet.payload.first() == Some(&(MessageType::Kexinit as u8)) {
+                self.remote_kexinit = Some(packet.payload.clone());
+            }
+
+            if self.strict_kex {
+                return Ok(packet.payload);
+            }
+
+            let ty = packet.payload.first().copied();
+            if !self.kexinit_received {
+                match ty {
+                    Some(ty) if ty == MessageType::Kexinit as u8 => self.kexinit_received = true,
+                    Some(ty)
+                        if ty == MessageType::Ignore as u8
+                            || ty == MessageType::Debug as u8
+                            || ty == MessageType::Unimplemented as u8 =>
+                    {
+                        self.messages_before_kexinit += 1;
+                    }
+                    _ => {}
+                }
+            }
+
+            if let Some(ty) = ty {
+                self.check_service(ty)?;
+            }
+
+            if ty == Some(MessageType::RequestSuccess as u8)
+                || ty == Some(MessageType::RequestFailure as u8)
+            {
+                match self.pending_replies.pop_front() {
+                    Some(true) => {
+                        self.unanswered_keepalives -= 1;
+                        continue;
+                    }
+                    Some(false) | None => return Ok(packet.payload),
+                }
+            }
+
+            match ty {
+                Some(ty) if ty == MessageType::Ignore as u8 => {}
+                Some(ty) if ty == MessageType::Unimplemented as u8 && !self.kexinit_received => {}
+                Some(ty) if ty == MessageType::Debug as u8 => {
+                    debug!(
+                        message = ?Message::parse(&mut packet.payload.clone()),
+                        "Peer sent SSH_MSG_DEBUG"
+                    );
+                }
+                _ => return Ok(packet.payload),
+            }
+        }
+    }
+}
+
+impl<S> Transport<S> {
+    /// Tracks user authentication from the messages received, rejecting
+    /// `ssh-connection` ones before it succeeded as RFC 4252 section 6 asks
+    fn check_service(&mut self, ty: u8) -> Result<(), TransportError> {
+        if ty == MessageType::UserauthSuccess as u8 {
+            self.authenticated = true;
+        }
+
+        // Replies to our own global requests, such as keepalives, are fine
+        let reply =
+            ty == MessageType::RequestSuccess as u8 || ty == MessageType::RequestFailure as u8;
+        if self.authenticated
+            || !CONNECTION_MESSAGES.contains(&ty)
+            || (reply && !self.pending_replies.is_empty())
+        {
+            return Ok(());
+        }
+
+        match MessageType::try_from(ty) {
+            Ok(message_type) => {
+                warn!(%message_type, "Peer sent a connection message before authentication");
+                Err(TransportError::NotAuthenticated(message_type))
+            }
+            // Left for parsing to reject
+            Err(_) => Ok(()),
+        }
+    }
+
+    /// Turns the handling of strict key exchange on or off, see
+    /// [`STRICT_KEX_CLIENT`](crate::kex::STRICT_KEX_CLIENT).
+    ///
+    /// While on, nothing is skipped by [`recv`](Transport::recv).
+    pub(crate) fn set_strict_kex(&mut self, strict: bool) {
+        self.strict_kex = strict;
+    }
+
+    /// Builds the SSH_MSG_UNIMPLEMENTED reply to the last message received.
+    ///
+    /// RFC 4253 asks for this rather than a disconnect when [`recv`] fails
+    /// with [`TransportError::Malformed`] wrapping [`UnknownMessageType`] or
+    /// [`UnsupportedMessage`].
+    ///
+    /// [`recv`]: Transport::recv
+    /// [`UnknownMessageType`]: crate::message::ParseError::UnknownMessageType
+    /// [`UnsupportedMessage`]: crate::message::ParseError::UnsupportedMessage
+    pub fn unimplemented(&self) -> Message {
+        Message::Unimplemented(Unimplemented {
+            seq: self.codec().recv_seq().wrapping_sub(1),
+        })
+    }
+
+    /// How many SSH_MSG_IGNORE, SSH_MSG_DEBUG and SSH_MSG_UNIMPLEMENTED the
+    /// peer sent before its first KEXINIT.
+    ///
+    /// Nothing needs sending that early, a peer normally starts with its
+    /// KEXINIT. Messages ahead of it are the signature of the Terrapin attack
+    /// (CVE-2023-48795): a man in the middle injects them to advance the
+    /// receiver's sequence number, then drops as many packets at the start of
+    /// the encrypted stream without the MACs noticing.
+    pub fn messages_before_kexinit(&self) -> u32 {
+        self.messages_before_kexinit
+    }
+
+    /// Turns keepalives on or off, they are off by default.
+    ///
+    /// With them on, [`recv`](Transport::recv) sends one whenever the peer
+    /// has been quiet for [`Keepalive::interval`] and fails with
+    /// [`TransportError::KeepaliveTimeout`] once too many went unanswered,
+    /// instead of [`Timeouts::read`] applying. Only turn them on after
+    /// authentication, servers may not answer global requests before.
+    pub fn set_keepalive(&mut self, keepalive: Option<Keepalive>) {
+        self.keepalive = keepalive;
+    }
+
+    /// Keepalives sent without a reply so far
+    pub fn unanswered_keepalives(&self) -> u32 {
+        self.unanswered_keepalives
+    }
+
+    pub fn timeouts(&self) -> Timeouts {
+        self.timeouts
+    }
+
+    /// Replaces the read and write timeouts, the version exchange being over
+    /// already
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.timeouts = timeouts;
+    }
+
+    /// Payload of the last KEXINIT sent, byte for byte.
+    ///
+    /// Together with [`remote_kexinit`](Self::remote_kexinit) it goes into
+    /// the exchange hash as I_C and I_S, serializing the parsed message again
+    /// wouldn't necessarily give the same bytes.
+    pub fn local_kexinit(&self) -> Option<&[u8]> {
+        self.local_kexinit.as_deref()
+    }
+
+    /// Payload of the last KEXINIT received, byte for byte
+    pub fn remote_kexinit(&self) -> Option<&[u8]> {
+        self.remote_kexinit.as_deref()
+    }
+
+    /// Exchange hash of the first key exchange, `None` until one finished.
+    ///
+    /// It identifies the session, `publickey` authentication signs it and
+    /// later key exchanges derive their keys from it without changing it.
+    pub fn session_id(&self) -> Option<&[u8]> {
+        self.session_id.as_deref()
+    }
+
+    /// Records the exchange hash of a finished key exchange, only the first
+    /// one becomes the [`session_id`](Self::session_id)
+    pub(crate) fn set_session_id(&mut self, exchange_hash: &[u8]) {
+        if self.session_id.is_none() {
+            self.session_id = Some(exchange_hash.to_vec());
+        }
+    }
+
+    /// Whether user authentication succeeded, in either direction, and the
+    /// `ssh-connection` service with its channels is running
+    pub fn is_authenticated(&self) -> bool {
+        self.authenticated
+    }
+
+    /// Identification the peer sent during the version exchange
+    pub fn remote_id(&self) -> &RemoteId {
+        &self.remote_id
+    }
+
+    pub fn codec(&self) -> &PacketCodec {
+        self.framed.codec()
+    }
+
+    pub fn codec_mut(&mut self) -> &mut PacketCodec {
+        self.framed.codec_mut()
+    }
+}
+
+/// Runs `future`, giving up with [`TransportError::Timeout`] after `duration`
+async fn with_timeout<T, E, F>(duration: Option<Duration>, future: F) -> Result<T, TransportError>
+where
+    F: Future<Output = Result<T, E>>,
+    E: Into<TransportError>,
+{
+    match duration {
+        Some(duration) => match timeout(duration, future).await {
+            Ok(result) => result.map_err(Into::into),
+            Err(_) => Err(TransportError::Timeout),
+        },
+        None => future.await.map_err(Into::into),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::duplex;
+
+    use super::*;
+    use crate::message::Ignore;
+
+    #[tokio::test]
+    async fn session_id_is_the_first_exchange_hash() {
+        let (client, server) = duplex(1024);
+        let (client, _server) =
+            tokio::join!(Transport::connect(client), Transport::connect(server));
+        let mut transport = client.unwrap();
+
+        assert_eq!(transport.session_id(), None);
+
+        transport.set_session_id(b"first exchange hash");
+        assert_eq!(transport.session_id(), Some(&b"first exchange hash"[..]));
+
+        // A rekey doesn't change it
+        transport.set_session_id(b"second exchange hash");
+        assert_eq!(transport.session_id(), Some(&b"first exchange hash"[..]));
+    }
+
+    #[tokio::test]
+    async fn strict_kex_skips_nothing() {
+        let (client, server) = duplex(1024);
+        let (client, server) = tokio::join!(Transport::connect(client), Transport::connect(server));
+        let (mut client, mut server) = (client.unwrap(), server.unwrap());
+
+        let ignore = || Message::Ignore(Ignore { data: Bytes::new() });
+
+        server.send(ignore()).await.unwrap();
+        server.send(Message::Newkeys).await.unwrap();
+        assert_eq!(client.recv().await.unwrap(), Message::Newkeys);
+
+        // During a strict key exchange the caller gets to reject it
+        client.set_strict_kex(true);
+        server.send(ignore()).await.unwrap();
+        assert_eq!(client.recv().await.unwrap(), ignore());
+    }
+}
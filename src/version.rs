@@ -0,0 +1,269 @@
+use std::io;
+
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::tracing::debug;
+
+/// Longest identification line allowed by RFC 4253, CR LF included
+const MAX_LINE_LENGTH: usize = 255;
+
+/// Lines a server may send before its identification before we give up
+const MAX_PRE_BANNER_LINES: usize = 1024;
+
+#[derive(Debug, thiserror::Error)]
+pub enum VersionError {
+    #[error("Identification string doesn't start with SSH-")]
+    MissingPrefix,
+    #[error("Malformed identification string")]
+    Malformed,
+    #[error("Unsupported SSH protocol version: {0}")]
+    UnsupportedProtocol(String),
+}
+
+/// The parts of an identification string
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SshVersion {
+    /// Either `2.0` or `1.99`, the latter meaning a server that speaks both
+    /// SSH 1 and 2
+    pub proto_version: String,
+    /// e.g. `OpenSSH_9.6`
+    pub software_version: String,
+    pub comments: Option<String>,
+}
+
+/// Identification of the remote end of the connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteId {
+    pub version: SshVersion,
+    /// The whole identification line without the trailing CR LF, as needed
+    /// for the exchange hash
+    pub identification: String,
+}
+
+/// Sends our identification string and reads the peer's, see RFC 4253
+/// section 4.2.
+///
+/// `local_id` is the full identification without the line ending, e.g.
+/// `SSH-2.0-softpaw_0.1.0`. Lines that don't start with `SSH-` before the
+/// peer's identification are skipped, as servers may send other text first.
+///
+/// The stream is buffered, anything read past the identification line stays
+/// in its buffer for the packet codec.
+pub async fn exchange_versions<S>(stream: &mut S, local_id: &str) -> io::Result<RemoteId>
+where
+    S: AsyncBufRead + AsyncWrite + Unpin,
+{
+    if local_id.len() + 2 > MAX_LINE_LENGTH {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "SSH identification string too long",
+        ));
+    }
+
+    stream.write_all(local_id.as_bytes()).await?;
+    stream.write_all(b"\r\n").await?;
+    stream.flush().await?;
+
+    for _ in 0..MAX_PRE_BANNER_LINES {
+        let line = read_line(stream).await?;
+
+        if line.starts_with("SSH-") {
+            let version = parse_version_string(&line)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+            return Ok(RemoteId {
+                version,
+                identification: line,
+            });
+        }
+
+        debug!(?line, "Skipping pre-banner line");
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "Too many lines before the SSH identification string",
+    ))
+}
+
+/// Reads one line of at most `MAX_LINE_LENGTH` bytes, stripping the line
+/// ending.
+///
+/// RFC 4253 asks for CR LF, a lone LF is accepted too like OpenSSH does.
+async fn read_line<S: AsyncBufRead + Unpin>(stream: &mut S) -> io::Result<String> {
+    let mut line = Vec::new();
+
+    // One byte extra to tell a line that is exactly at the limit from one
+    // that's over it
+    (&mut *stream)
+        .take(MAX_LINE_LENGTH as u64 + 1)
+        .read_until(b'\n', &mut line)
+        .await?;
+
+    if line.len() > MAX_LINE_LENGTH {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("SSH identification line longer than {MAX_LINE_LENGTH} bytes"),
+        ));
+    }
+
+    if line.pop() != Some(b'\n') {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+
+    String::from_utf8(line).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "SSH identification line is not valid UTF-8",
+        )
+    })
+}
+
+/// Splits an identification string into its parts, see RFC 4253 section
+/// 4.2.
+///
+/// A trailing CR LF is stripped if present. Only protocol versions `2.0` and
+/// `1.99` are accepted, anything else is an SSH 1 only peer or garbage.
+pub fn parse_version_string(line: &str) -> Result<SshVersion, VersionError> {
+    let line = line.trim_end_matches(['\r', '\n']);
+
+    // SSH-protoversion-softwareversion SP comments
+    let rest = line
+        .strip_prefix("SSH-")
+        .ok_or(VersionError::MissingPrefix)?;
+    let (proto_version, rest) = rest.split_once('-').ok_or(VersionError::Malformed)?;
+    let (software_version, comments) = match rest.split_once(' ') {
+        Some((software_version, comments)) => (software_version, Some(comments)),
+        None => (rest, None),
+    };
+
+    if !matches!(proto_version, "2.0" | "1.99") {
+        return Err(VersionError::UnsupportedProtocol(proto_version.to_owned()));
+    }
+
+    if software_version.is_empty() {
+        return Err(VersionError::Malformed);
+    }
+
+    Ok(SshVersion {
+        proto_version: proto_version.to_owned(),
+        software_version: software_version.to_owned(),
+        comments: comments.map(str::to_owned),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{BufStream, duplex};
+
+    use super::*;
+
+    /// Exchanges versions with a peer that sends `peer`
+    async fn exchange(peer: &[u8]) -> io::Result<RemoteId> {
+        let (local, mut remote) = duplex(64 * 1024);
+        remote.write_all(peer).await?;
+        // Reading past what was sent is an EOF rather than a wait
+        remote.shutdown().await?;
+
+        let remote_id = exchange_versions(&mut BufStream::new(local), "SSH-2.0-test").await;
+
+        let mut sent = [0; 14];
+        remote.read_exact(&mut sent).await?;
+        assert_eq!(&sent, b"SSH-2.0-test\r\n");
+
+        remote_id
+    }
+
+    #[tokio::test]
+    async fn identification() {
+        let remote_id = exchange(b"SSH-2.0-OpenSSH_9.6\r\n").await.unwrap();
+
+        assert_eq!(remote_id.identification, "SSH-2.0-OpenSSH_9.6");
+        assert_eq!(remote_id.version.software_version, "OpenSSH_9.6");
+    }
+
+    #[tokio::test]
+    async fn missing_cr() {
+        let remote_id = exchange(b"SSH-2.0-dropbear\n").await.unwrap();
+
+        assert_eq!(remote_id.identification, "SSH-2.0-dropbear");
+    }
+
+    #[tokio::test]
+    async fn pre_banner_lines() {
+        let remote_id = exchange(b"Welcome\r\n\r\nno SSH- here\nSSH-2.0-OpenSSH_9.6\r\n")
+            .await
+            .unwrap();
+
+        assert_eq!(remote_id.identification, "SSH-2.0-OpenSSH_9.6");
+
+        // Only so many of them
+        let mut peer = b"spam\r\n".repeat(MAX_PRE_BANNER_LINES);
+        peer.extend_from_slice(b"SSH-2.0-OpenSSH_9.6\r\n");
+        let err = exchange(&peer).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn line_length() {
+        // 255 bytes CR LF included is still fine
+        let mut line = format!("SSH-2.0-{}", "x".repeat(MAX_LINE_LENGTH - 10));
+        line.push_str("\r\n");
+        let remote_id = exchange(line.as_bytes()).await.unwrap();
+        assert_eq!(remote_id.identification.len(), MAX_LINE_LENGTH - 2);
+
+        let line = format!("SSH-2.0-{}\r\n", "x".repeat(MAX_LINE_LENGTH));
+        let err = exchange(line.as_bytes()).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // Nor is a line without an end
+        let err = exchange(b"SSH-2.0-OpenSSH_9.6").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn comments() {
+        let version = parse_version_string("SSH-2.0-OpenSSH_9.6 Ubuntu-3ubuntu13.5\r\n").unwrap();
+
+        assert_eq!(
+            version,
+            SshVersion {
+                proto_version: "2.0".into(),
+                software_version: "OpenSSH_9.6".into(),
+                comments: Some("Ubuntu-3ubuntu13.5".into()),
+            }
+        );
+
+        // Everything past the first space, spaces and all
+        let version = parse_version_string("SSH-1.99-Cisco-1.25 a b").unwrap();
+        assert_eq!(version.software_version, "Cisco-1.25");
+        assert_eq!(version.comments.as_deref(), Some("a b"));
+
+        let version = parse_version_string("SSH-2.0-softpaw").unwrap();
+        assert_eq!(version.comments, None);
+    }
+
+    #[test]
+    fn malformed() {
+        assert!(matches!(
+            parse_version_string("OpenSSH_9.6"),
+            Err(VersionError::MissingPrefix)
+        ));
+        assert!(matches!(
+            parse_version_string("SSH-2.0"),
+            Err(VersionError::Malformed)
+        ));
+        assert!(matches!(
+            parse_version_string("SSH-2.0- comment"),
+            Err(VersionError::Malformed)
+        ));
+        assert!(matches!(
+            parse_version_string("SSH-1.5-OpenSSH_2.0"),
+            Err(VersionError::UnsupportedProtocol(version)) if version == "1.5"
+        ));
+    }
+}
@@ -0,0 +1,626 @@
+use std::{cell::Cell, io, time::Duration};
+
+use bytes::{Bytes, BytesMut};
+use softpaw::{
+    cipher::CipherAlgorithm,
+    client::{Client, ClientConfig, ClientError},
+    codec::{Direction, Packet, PacketCodec},
+    hostkey::{HostKey, HostKeyVerifier, VerifyError},
+    kex::{Curve25519Sha256, ExchangeContext, STRICT_KEX_CLIENT, STRICT_KEX_SERVER},
+    message::{
+        ExtInfo, Ignore, KexEcdhInit, KexEcdhReply, Kexinit, Message, MessageType, ParseContext,
+        ServiceAccept,
+    },
+    openssh_key::PrivateKey,
+    server::{HostKeyStore, Server, ServerConfig},
+    transport::{LOCAL_ID, Transport},
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, duplex, split};
+
+fn server_config() -> ServerConfig {
+    let mut host_keys = HostKeyStore::new();
+    host_keys.insert(PrivateKey::parse(include_str!("data/id_ed25519")).unwrap());
+
+    ServerConfig::new(host_keys)
+}
+
+/// A verifier accepting only the server's first host key, for `example.com`
+fn verifier(
+    server_config: &ServerConfig,
+) -> impl Fn(&str, &HostKey) -> Result<(), VerifyError> + '_ {
+    let host_key = server_config.host_keys.iter().next().unwrap().public_key();
+
+    move |hostname: &str, key: &HostKey| {
+        assert_eq!(hostname, "example.com");
+        assert_eq!(key, host_key);
+        Ok(())
+    }
+}
+
+/// Runs both halves of the handshake against each other, the server
+/// accepting the client's `ssh-userauth` service request
+async fn handshake(
+    client_config: &ClientConfig,
+    server_config: &ServerConfig,
+) -> (Client<DuplexStream>, Transport<DuplexStream>) {
+    handshake_with(client_config, server_config, &verifier(server_config)).await
+}
+
+/// Like [`handshake`] with the client checking the host key with `verifier`
+async fn handshake_with(
+    client_config: &ClientConfig,
+    server_config: &ServerConfig,
+    verifier: &impl HostKeyVerifier,
+) -> (Client<DuplexStream>, Transport<DuplexStream>) {
+    let (client, server) = duplex(64 * 1024);
+
+    let server = async {
+        let mut transport = Server::handshake(server, server_config).await.unwrap();
+
+        let Message::ServiceRequest(request) = transport.recv().await.unwrap() else {
+            panic!("Expected a service request");
+        };
+        assert_eq!(request.service_name, "ssh-userauth");
+
+        transport
+            .send(Message::ServiceAccept(ServiceAccept {
+                service_name: request.service_name,
+            }))
+            .await
+            .unwrap();
+
+        transport
+    };
+
+    let (client, server) = tokio::join!(
+        Client::handshake(client, "example.com", client_config, verifier),
+        server
+    );
+
+    (client.unwrap(), server)
+}
+
+#[tokio::test]
+async fn key_exchange() {
+    let server_config = server_config();
+
+    for kex in [
+        "curve25519-sha256",
+        "ecdh-sha2-nistp256",
+        "diffie-hellman-group14-sha256",
+    ] {
+        let client_config = ClientConfig {
+            kex_algorithms: [kex, STRICT_KEX_CLIENT].map(str::to_owned).to_vec(),
+            ..ClientConfig::default()
+        };
+
+        let (client, server) = handshake(&client_config, &server_config).await;
+
+        assert_eq!(Some(client.session_id()), server.session_id(), "{kex}");
+
+        // Strict key exchange restarts both sequence numbers at NEWKEYS, only
+        // the service request and accept came after
+        for codec in [client.transport().codec(), server.codec()] {
+            assert_eq!(codec.send_seq(), 1, "{kex}");
+            assert_eq!(codec.recv_seq(), 1, "{kex}");
+        }
+    }
+}
+
+#[tokio::test]
+async fn ext_info() {
+    let server_config = server_config();
+    let verifier = verifier(&server_config);
+    let (client, server) = duplex(64 * 1024);
+
+    // OpenSSH sends its EXT_INFO right after NEWKEYS, before the accept
+    let server = async {
+        let mut transport = Server::handshake(server, &server_config).await.unwrap();
+
+        assert!(matches!(
+            transport.recv().await.unwrap(),
+            Message::ServiceRequest(_)
+        ));
+        transport
+            .send(Message::ExtInfo(ExtInfo {
+                extensions: vec![
+                    (
+                        ExtInfo::SERVER_SIG_ALGS.to_owned(),
+                        Bytes::from_static(b"ssh-ed25519,rsa-sha2-512"),
+                    ),
+                    ("ping@openssh.com".to_owned(), Bytes::from_static(b"0")),
+                ],
+            }))
+            .await
+            .unwrap();
+        transport
+            .send(Message::ServiceAccept(ServiceAccept {
+                service_name: "ssh-userauth".to_owned(),
+            }))
+            .await
+            .unwrap();
+
+        transport
+    };
+
+    // Which offers `ext-info-c`
+    let client_config = ClientConfig::default();
+
+    let (client, _server) = tokio::join!(
+        Client::handshake(client, "example.com", &client_config, &verifier),
+        server
+    );
+    let client = client.unwrap();
+    let ext_info = client.ext_info().unwrap();
+
+    assert_eq!(
+        ext_info.server_sig_algs().unwrap().unwrap(),
+        ["ssh-ed25519", "rsa-sha2-512"]
+    );
+    assert_eq!(ext_info.get("ping@openssh.com").unwrap(), "0");
+}
+
+#[tokio::test]
+async fn host_key_selection() {
+    let mut host_keys = HostKeyStore::new();
+    host_keys.insert(PrivateKey::parse(include_str!("data/id_ed25519")).unwrap());
+    host_keys.insert(PrivateKey::parse(include_str!("data/id_rsa")).unwrap());
+    let server_config = ServerConfig::new(host_keys);
+
+    let ed25519 = HostKey::from_authorized_keys_line(include_str!("data/id_ed25519.pub"))
+        .unwrap()
+        .key;
+    let rsa = HostKey::from_authorized_keys_line(include_str!("data/id_rsa.pub"))
+        .unwrap()
+        .key;
+
+    for (algorithm, expected) in [
+        ("ssh-ed25519", &ed25519),
+        ("rsa-sha2-256", &rsa),
+        ("rsa-sha2-512", &rsa),
+    ] {
+        let client_config = ClientConfig {
+            host_key_algorithms: vec![algorithm.to_owned()],
+            ..ClientConfig::default()
+        };
+
+        // The signature over H checked out before the verifier is asked
+        let verified = Cell::new(false);
+        let verifier = |_: &str, key: &HostKey| {
+            assert_eq!(key, expected, "{algorithm}");
+            verified.set(true);
+            Ok(())
+        };
+
+        handshake_with(&client_config, &server_config, &verifier).await;
+        assert!(verified.get(), "{algorithm}");
+    }
+}
+
+#[tokio::test]
+async fn without_strict_kex() {
+    let server_config = server_config();
+    assert!(
+        server_config
+            .kex_algorithms
+            .iter()
+            .any(|name| name == STRICT_KEX_SERVER)
+    );
+
+    let client_config = ClientConfig {
+        kex_algorithms: vec!["curve25519-sha256".to_owned()],
+        ..ClientConfig::default()
+    };
+
+    let (client, server) = handshake(&client_config, &server_config).await;
+
+    assert_eq!(Some(client.session_id()), server.session_id());
+
+    // KEXINIT, the kex init or reply and NEWKEYS, then the service request
+    // or accept
+    for codec in [client.transport().codec(), server.codec()] {
+        assert_eq!(codec.send_seq(), 4);
+        assert_eq!(codec.recv_seq(), 4);
+    }
+}
+
+/// Sits between `client` and `server` as the man in the middle of the
+/// Terrapin attack (CVE-2023-48795).
+///
+/// It slips an SSH_MSG_IGNORE in ahead of the server's KEXINIT, then drops
+/// the server's first packet under the new keys, which brings the client's
+/// sequence number back in line with the server's. ChaCha20-Poly1305 takes
+/// its nonce from the sequence number alone, so the client decrypts what
+/// follows just fine.
+async fn terrapin(client: DuplexStream, server: DuplexStream) -> io::Result<()> {
+    let (mut client_read, mut client_write) = split(client);
+    let (mut server_read, mut server_write) = split(server);
+
+    let ignore = || Packet {
+        payload: Message::Ignore(Ignore { data: Bytes::new() }).into_payload(),
+        mac: None,
+    };
+
+    // The packet to drop is the server's empty SSH_MSG_IGNORE, its length
+    // is encrypted but the same under any key
+    let mut dropped = BytesMut::new();
+    let algorithm = CipherAlgorithm::ChaCha20Poly1305;
+    let mut codec = PacketCodec::builder().build();
+    codec.set_cipher(
+        Direction::Outgoing,
+        algorithm,
+        &vec![0; algorithm.key_length()],
+        &vec![0; algorithm.iv_length()],
+    )?;
+    codec.encode_packet(ignore(), &mut dropped)?;
+
+    let to_client = async {
+        forward_line(&mut server_read, &mut client_write).await?;
+
+        let mut injected = BytesMut::new();
+        PacketCodec::builder()
+            .build()
+            .encode_packet(ignore(), &mut injected)?;
+        client_write.write_all(&injected).await?;
+
+        // Everything up to NEWKEYS is in the clear
+        loop {
+            let packet = read_packet(&mut server_read).await?;
+            client_write.write_all(&packet).await?;
+
+            if packet[5] == MessageType::Newkeys as u8 {
+                break;
+            }
+        }
+
+        server_read.read_exact(&mut dropped).await?;
+
+        tokio::io::copy(&mut server_read, &mut client_write).await
+    };
+
+    let to_server = tokio::io::copy(&mut client_read, &mut server_write);
+
+    // Either side closing ends the connection for both
+    tokio::select! {
+        copied = to_client => copied.map(drop),
+        copied = to_server => copied.map(drop),
+    }
+}
+
+async fn forward_line(
+    src: &mut (impl AsyncRead + Unpin),
+    dst: &mut (impl AsyncWrite + Unpin),
+) -> io::Result<()> {
+    loop {
+        let byte = src.read_u8().await?;
+        dst.write_u8(byte).await?;
+
+        if byte == b'\n' {
+            return Ok(());
+        }
+    }
+}
+
+/// Reads a whole unencrypted packet, length field included
+async fn read_packet(src: &mut (impl AsyncRead + Unpin)) -> io::Result<Vec<u8>> {
+    let length = src.read_u32().await?;
+
+    let mut packet = vec![0; 4 + length as usize];
+    packet[..4].copy_from_slice(&length.to_be_bytes());
+    src.read_exact(&mut packet[4..]).await?;
+
+    Ok(packet)
+}
+
+/// Runs the handshake through [`terrapin`], the server sending an
+/// SSH_MSG_IGNORE for it to drop ahead of its SERVICE_ACCEPT
+async fn attacked_handshake(
+    client_config: &ClientConfig,
+) -> Result<Client<DuplexStream>, ClientError> {
+    let server_config = server_config();
+    let verifier = verifier(&server_config);
+
+    let (client, proxy_client) = duplex(64 * 1024);
+    let (proxy_server, server) = duplex(64 * 1024);
+    tokio::spawn(terrapin(proxy_client, proxy_server));
+
+    let server = async {
+        let mut transport = Server::handshake(server, &server_config).await.ok()?;
+        transport
+            .send(Message::Ignore(Ignore { data: Bytes::new() }))
+            .await
+            .ok()?;
+
+        let Message::ServiceRequest(request) = transport.recv().await.ok()? else {
+            panic!("Expected a service request");
+        };
+        transport
+            .send(Message::ServiceAccept(ServiceAccept {
+                service_name: request.service_name,
+            }))
+            .await
+            .ok()
+    };
+
+    let (client, _) = tokio::join!(
+        Client::handshake(client, "example.com", client_config, &verifier),
+        server
+    );
+
+    client
+}
+
+fn terrapin_config() -> ClientConfig {
+    ClientConfig {
+        encryption_algorithms: vec!["chacha20-poly1305@openssh.com".to_owned()],
+        ..ClientConfig::default()
+    }
+}
+
+#[tokio::test]
+async fn messages_before_kexinit_rejected() {
+    let err = attacked_handshake(&terrapin_config()).await.unwrap_err();
+    assert!(
+        matches!(err, ClientError::MessagesBeforeKexinit(1)),
+        "{err:?}"
+    );
+
+    // Strict key exchange catches it too
+    let client_config = ClientConfig {
+        reject_messages_before_kexinit: false,
+        ..terrapin_config()
+    };
+    let err = attacked_handshake(&client_config).await.unwrap_err();
+    assert!(matches!(err, ClientError::StrictKex), "{err:?}");
+}
+
+#[tokio::test]
+async fn messages_before_kexinit_allowed() {
+    let client_config = ClientConfig {
+        kex_algorithms: vec!["curve25519-sha256".to_owned()],
+        reject_messages_before_kexinit: false,
+        ..terrapin_config()
+    };
+
+    // Without either guard the attack goes unnoticed
+    let client = attacked_handshake(&client_config).await.unwrap();
+    assert_eq!(client.transport().messages_before_kexinit(), 1);
+}
+
+/// Plays a client whose KEXINIT has `first_kex_packet_follows` set, guessing
+/// the server agrees to its first kex algorithm, and checks the server
+/// signs an exchange hash over its `curve25519-sha256` key.
+///
+/// A wrong guess is a KEX_ECDH_INIT the server couldn't use, followed by the
+/// real one.
+async fn guess_kex(kex_algorithms: &[&str]) {
+    // Waiting for a KEX_ECDH_INIT that never comes fails rather than hangs
+    let mut server_config = server_config();
+    server_config.timeouts.read = Some(Duration::from_secs(5));
+    let (client, server) = duplex(64 * 1024);
+
+    let client = async {
+        let mut transport = Transport::connect(client).await.unwrap();
+
+        let kexinit = Kexinit::builder()
+            .kex_algorithms(kex_algorithms.iter().copied())
+            .server_host_key_algorithms(["ssh-ed25519"])
+            .encryption_algorithms(["aes128-ctr"])
+            .mac_algorithms(["hmac-sha2-256"])
+            .compression_algorithms(["none"])
+            .first_kex_packet_follows(true)
+            .build()
+            .unwrap();
+        transport
+            .send(Message::Kexinit(Box::new(kexinit)))
+            .await
+            .unwrap();
+
+        let kex = Curve25519Sha256::new().unwrap();
+        if kex_algorithms[0] != Curve25519Sha256::NAME {
+            let guess = KexEcdhInit {
+                ephemeral_public_key: Bytes::from_static(b"not a curve25519 key"),
+            };
+            transport.send(Message::KexEcdhInit(guess)).await.unwrap();
+        }
+        transport
+            .send(Message::KexEcdhInit(kex.init_message()))
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            transport.recv().await.unwrap(),
+            Message::Kexinit(_)
+        ));
+
+        let parse_context = ParseContext {
+            kex: Some(Curve25519Sha256::NAME),
+            ..Default::default()
+        };
+        let Message::KexEcdhReply(reply) =
+            transport.recv_with_context(parse_context).await.unwrap()
+        else {
+            panic!("Expected a KEX_ECDH_REPLY");
+        };
+
+        let context = ExchangeContext {
+            client_version: LOCAL_ID.as_bytes(),
+            server_version: transport.remote_id().identification.as_bytes(),
+            client_kexinit: transport.local_kexinit().unwrap(),
+            server_kexinit: transport.remote_kexinit().unwrap(),
+        };
+        let output = kex.finish(&context, reply).unwrap();
+
+        HostKey::parse(&output.host_key)
+            .unwrap()
+            .verify(&output.exchange_hash, &output.signature)
+            .unwrap();
+    };
+
+    // The server only finishes with the client's NEWKEYS, which never comes
+    tokio::select! {
+        () = client => {}
+        result = Server::handshake(server, &server_config) => panic!("{:?}", result.err()),
+    }
+}
+
+#[tokio::test]
+async fn first_kex_packet_guessed_right() {
+    // The guess is the client's only KEX_ECDH_INIT, the server uses it
+    guess_kex(&[Curve25519Sha256::NAME]).await;
+}
+
+#[tokio::test]
+async fn first_kex_packet_guessed_wrong() {
+    // The server doesn't do group exchange, it has to skip the guess and
+    // wait for the KEX_ECDH_INIT after it
+    guess_kex(&[
+        "diffie-hellman-group-exchange-sha256",
+        Curve25519Sha256::NAME,
+    ])
+    .await;
+}
+
+#[tokio::test]
+async fn client_skips_wrong_guess() {
+    let server_config = server_config();
+    let verifier = verifier(&server_config);
+    let (client, server) = duplex(64 * 1024);
+
+    // A server following its KEXINIT with a packet for group exchange, which
+    // the client doesn't offer
+    let server = async {
+        let mut transport = Transport::connect(server).await.unwrap();
+
+        let kexinit = Kexinit::builder()
+            .kex_algorithms([
+                "diffie-hellman-group-exchange-sha256",
+                Curve25519Sha256::NAME,
+            ])
+            .server_host_key_algorithms(["ssh-ed25519"])
+            .encryption_algorithms(["aes128-ctr"])
+            .mac_algorithms(["hmac-sha2-256"])
+            .compression_algorithms(["none"])
+            .first_kex_packet_follows(true)
+            .build()
+            .unwrap();
+        transport
+            .send(Message::Kexinit(Box::new(kexinit)))
+            .await
+            .unwrap();
+        transport
+            .send(Message::KexEcdhReply(KexEcdhReply {
+                host_key: Bytes::from_static(b"guess"),
+                ephemeral_public_key: Bytes::from_static(b"guess"),
+                signature: Bytes::from_static(b"guess"),
+            }))
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            transport.recv().await.unwrap(),
+            Message::Kexinit(_)
+        ));
+
+        let parse_context = ParseContext {
+            kex: Some(Curve25519Sha256::NAME),
+            ..Default::default()
+        };
+        let Message::KexEcdhInit(init) = transport.recv_with_context(parse_context).await.unwrap()
+        else {
+            panic!("Expected a KEX_ECDH_INIT");
+        };
+
+        let kex = Curve25519Sha256::new().unwrap();
+        let ephemeral_public_key = Bytes::copy_from_slice(kex.public_key());
+        let context = ExchangeContext {
+            client_version: transport.remote_id().identification.as_bytes(),
+            server_version: LOCAL_ID.as_bytes(),
+            client_kexinit: transport.remote_kexinit().unwrap(),
+            server_kexinit: transport.local_kexinit().unwrap(),
+        };
+        let host_key = server_config.host_keys.iter().next().unwrap().public_key();
+        let output = kex.respond(&context, host_key.to_blob(), init).unwrap();
+        let signature = server_config
+            .host_keys
+            .sign("ssh-ed25519", &output.exchange_hash)
+            .unwrap();
+
+        transport
+            .send(Message::KexEcdhReply(KexEcdhReply {
+                host_key: output.host_key,
+                ephemeral_public_key,
+                signature,
+            }))
+            .await
+            .unwrap();
+
+        // The client took the real reply, not the guess
+        assert_eq!(transport.recv().await.unwrap(), Message::Newkeys);
+    };
+
+    let client_config = ClientConfig {
+        kex_algorithms: vec![Curve25519Sha256::NAME.to_owned()],
+        ..ClientConfig::default()
+    };
+
+    tokio::select! {
+        () = server => {}
+        result = Client::handshake(client, "example.com", &client_config, &verifier) => {
+            panic!("{:?}", result.err());
+        }
+    }
+}
+
+#[tokio::test]
+async fn strict_kex_rejects_other_messages() {
+    let server_config = server_config();
+    let verifier = verifier(&server_config);
+    let (client, server) = duplex(64 * 1024);
+
+    // A server agreeing to strict key exchange, then sending an
+    // SSH_MSG_IGNORE where the reply belongs
+    let server = async {
+        let mut transport = Transport::connect(server).await.unwrap();
+
+        let kexinit = Kexinit::builder()
+            .kex_algorithms([Curve25519Sha256::NAME, STRICT_KEX_SERVER])
+            .server_host_key_algorithms(["ssh-ed25519"])
+            .encryption_algorithms(["aes128-ctr"])
+            .mac_algorithms(["hmac-sha2-256"])
+            .compression_algorithms(["none"])
+            .build()
+            .unwrap();
+        transport
+            .send(Message::Kexinit(Box::new(kexinit)))
+            .await
+            .unwrap();
+        transport
+            .send(Message::Ignore(Ignore { data: Bytes::new() }))
+            .await
+            .unwrap();
+
+        transport
+    };
+
+    let client_config = ClientConfig {
+        kex_algorithms: [Curve25519Sha256::NAME, STRICT_KEX_CLIENT]
+            .map(str::to_owned)
+            .to_vec(),
+        ..ClientConfig::default()
+    };
+
+    let (client, _server) = tokio::join!(
+        Client::handshake(client, "example.com", &client_config, &verifier),
+        server
+    );
+
+    let err = client.unwrap_err();
+    assert!(
+        matches!(
+            err,
+            ClientError::UnexpectedMessage(MessageType::KexEcdhReply)
+        ),
+        "{err:?}"
+    );
+}
@@ -0,0 +1,234 @@
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use softpaw::{
+    codec::{Packet, PacketCodec},
+    message::{
+        ChannelOpen, ChannelOpenType, GlobalRequest, GlobalRequestType, Kexinit, Message,
+        MessageType,
+    },
+    transport::{KEEPALIVE_REQUEST, Keepalive, LOCAL_ID, Timeouts, Transport, TransportError},
+};
+use tokio::{
+    io::{AsyncWriteExt, duplex},
+    time::Instant,
+};
+
+fn kexinit() -> Kexinit {
+    Kexinit::builder()
+        .kex_algorithms(["curve25519-sha256"])
+        .server_host_key_algorithms(["ssh-ed25519"])
+        .encryption_algorithms(["aes128-ctr"])
+        .mac_algorithms(["hmac-sha2-256"])
+        .compression_algorithms(["none"])
+        .build()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn echo_kexinit() {
+    let (client, server) = duplex(64 * 1024);
+
+    let (client, server) = tokio::join!(Transport::connect(client), Transport::connect(server));
+    let (mut client, mut server) = (client.unwrap(), server.unwrap());
+
+    assert_eq!(client.remote_id().identification, LOCAL_ID);
+    assert_eq!(server.remote_id().identification, LOCAL_ID);
+
+    let sent = kexinit();
+    client
+        .send(Message::Kexinit(Box::new(sent.clone())))
+        .await
+        .unwrap();
+
+    // The server sends back what it got
+    let received = server.recv().await.unwrap();
+    assert_eq!(received, Message::Kexinit(Box::new(sent.clone())));
+    server.send(received).await.unwrap();
+
+    let echoed = client.recv().await.unwrap();
+    assert_eq!(echoed, Message::Kexinit(Box::new(sent)));
+
+    // Both ends keep the exact bytes for the exchange hash
+    assert_eq!(client.local_kexinit(), server.remote_kexinit());
+    assert_eq!(client.local_kexinit(), client.remote_kexinit());
+    assert_eq!(client.codec().send_seq(), 1);
+    assert_eq!(client.codec().recv_seq(), 1);
+}
+
+#[tokio::test]
+async fn packet_after_identification() {
+    let (stream, mut peer) = duplex(64 * 1024);
+
+    // The peer's identification and first packet arrive in a single read, so
+    // the packet is already buffered once the identification is parsed
+    let payload = Message::Kexinit(Box::new(kexinit())).into_payload();
+    let mut sent = BytesMut::from("SSH-2.0-peer\r\n");
+    PacketCodec::builder()
+        .build()
+        .encode_packet(
+            Packet {
+                payload: payload.clone(),
+                mac: None,
+            },
+            &mut sent,
+        )
+        .unwrap();
+    peer.write_all(&sent).await.unwrap();
+
+    let mut transport = Transport::connect(stream).await.unwrap();
+    assert_eq!(transport.remote_id().identification, "SSH-2.0-peer");
+
+    // Nothing more is coming, lost bytes would time out
+    transport.set_timeouts(Timeouts {
+        read: Some(Duration::from_secs(5)),
+        ..transport.timeouts()
+    });
+    transport.recv().await.unwrap();
+    assert_eq!(transport.remote_kexinit(), Some(&payload[..]));
+    assert_eq!(transport.codec().recv_seq(), 1);
+}
+
+#[tokio::test]
+async fn silent_peer() {
+    // The other end stays open but never sends its identification
+    let (stream, _peer) = duplex(1024);
+
+    let timeouts = Timeouts {
+        version_exchange: Some(Duration::from_millis(50)),
+        ..Timeouts::default()
+    };
+
+    assert!(matches!(
+        Transport::connect_with_timeouts(stream, timeouts).await,
+        Err(TransportError::Timeout)
+    ));
+}
+
+#[tokio::test]
+async fn read_timeout() {
+    let (client, server) = duplex(1024);
+
+    let (client, server) = tokio::join!(Transport::connect(client), Transport::connect(server));
+    let (mut client, _server) = (client.unwrap(), server.unwrap());
+
+    // Idle sessions aren't torn down unless asked for
+    assert_eq!(client.timeouts().read, None);
+
+    client.set_timeouts(Timeouts {
+        read: Some(Duration::from_millis(50)),
+        ..client.timeouts()
+    });
+
+    assert!(matches!(client.recv().await, Err(TransportError::Timeout)));
+}
+
+#[tokio::test]
+async fn peer_closes() {
+    let (client, server) = duplex(1024);
+
+    let (client, server) = tokio::join!(Transport::connect(client), Transport::connect(server));
+    let mut client = client.unwrap();
+    drop(server);
+
+    assert!(matches!(
+        client.recv().await,
+        Err(TransportError::ConnectionClosed)
+    ));
+}
+
+#[tokio::test]
+async fn peer_sends_garbage() {
+    let (client, mut server) = duplex(1024);
+
+    // A packet whose padding is longer than the packet itself
+    server
+        .write_all(b"SSH-2.0-garbage\r\n\0\0\0\x0c\xc8garbage....")
+        .await
+        .unwrap();
+
+    let mut client = Transport::connect(client).await.unwrap();
+
+    assert!(matches!(
+        client.recv().await,
+        Err(TransportError::Protocol(_))
+    ));
+}
+
+#[tokio::test]
+async fn connection_messages_need_auth() {
+    let (client, server) = duplex(1024);
+
+    let (client, server) = tokio::join!(Transport::connect(client), Transport::connect(server));
+    let (mut client, mut server) = (client.unwrap(), server.unwrap());
+
+    let open = || {
+        Message::ChannelOpen(ChannelOpen {
+            channel_type: ChannelOpenType::Session,
+            sender_channel: 0,
+            initial_window_size: 65536,
+            maximum_packet_size: 32768,
+        })
+    };
+
+    client.send(open()).await.unwrap();
+    assert!(matches!(
+        server.recv().await,
+        Err(TransportError::NotAuthenticated(MessageType::ChannelOpen))
+    ));
+
+    server.send(Message::UserauthSuccess).await.unwrap();
+    assert_eq!(client.recv().await.unwrap(), Message::UserauthSuccess);
+    assert!(client.is_authenticated() && server.is_authenticated());
+
+    client.send(open()).await.unwrap();
+    assert_eq!(server.recv().await.unwrap(), open());
+}
+
+#[tokio::test(start_paused = true)]
+async fn keepalive() {
+    let (client, server) = duplex(1024);
+
+    let (client, server) = tokio::join!(Transport::connect(client), Transport::connect(server));
+    let (mut client, mut server) = (client.unwrap(), server.unwrap());
+
+    // Keepalives are global requests, which the server only takes once the
+    // client is authenticated
+    server.send(Message::UserauthSuccess).await.unwrap();
+    assert_eq!(client.recv().await.unwrap(), Message::UserauthSuccess);
+
+    client.set_keepalive(Some(Keepalive {
+        interval: Duration::from_secs(15),
+        max_missed: 2,
+    }));
+    let sent = Message::GlobalRequest(GlobalRequest {
+        want_reply: true,
+        request: GlobalRequestType::Unknown {
+            request_name: KEEPALIVE_REQUEST.into(),
+            data: Bytes::new(),
+        },
+    });
+
+    // An answered keepalive is swallowed
+    let start = Instant::now();
+    let (received, ()) = tokio::join!(client.recv(), async {
+        assert_eq!(server.recv().await.unwrap(), sent);
+        server.send(Message::RequestFailure).await.unwrap();
+        server.send(Message::Newkeys).await.unwrap();
+    });
+    assert_eq!(received.unwrap(), Message::Newkeys);
+    assert_eq!(start.elapsed(), Duration::from_secs(15));
+    assert_eq!(client.unanswered_keepalives(), 0);
+
+    // Quiet for another interval past the last one missed
+    let start = Instant::now();
+    assert!(matches!(
+        client.recv().await,
+        Err(TransportError::KeepaliveTimeout)
+    ));
+    assert_eq!(start.elapsed(), Duration::from_secs(45));
+    assert_eq!(client.unanswered_keepalives(), 2);
+
+    assert_eq!(server.recv().await.unwrap(), sent);
+    assert_eq!(server.recv().await.unwrap(), sent);
+}